@@ -0,0 +1,71 @@
+//! Replacing files without leaving them half-written.
+//!
+//! The new content is written to a temporary file next to the destination
+//! and then renamed over it, so a failure part-way through leaves any
+//! existing file untouched.
+
+use std::path::{Path, PathBuf};
+
+/// Write `data` to `dest`, replacing it in one step.
+pub(crate) fn write(dest: &Path, data: &[u8]) -> crate::Result<()> {
+    write_with(dest, |tmp_path| Ok(std::fs::write(tmp_path, data)?))
+}
+
+/// Create `dest` by calling `write` on a temporary path, then renaming the
+/// result into place. The temporary file is removed if anything fails.
+pub(crate) fn write_with(
+    dest: &Path,
+    write: impl FnOnce(&Path) -> crate::Result<()>,
+) -> crate::Result<()> {
+    let tmp_path = tmp_path(dest);
+    let result = write(&tmp_path).and_then(|()| Ok(std::fs::rename(&tmp_path, dest)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// The temporary file for `dest`: its name with `.tmp` added, in the same
+/// directory so that the rename doesn't cross file systems.
+fn tmp_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    dest.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ebook-tools-atomic-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn replaces_the_file() {
+        let path = temp_path("replace.txt");
+        std::fs::write(&path, "old").unwrap();
+        let result = write(&path, b"new");
+        let data = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(data.unwrap(), b"new");
+        assert!(!tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn failure_keeps_the_original() {
+        let path = temp_path("failure.txt");
+        std::fs::write(&path, "old").unwrap();
+        let result = write_with(&path, |tmp_path| {
+            std::fs::write(tmp_path, "partial")?;
+            Err(Error::InvalidBook("failed".into()))
+        });
+        let data = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(data.unwrap(), b"old");
+        assert!(!tmp_path(&path).exists());
+    }
+}
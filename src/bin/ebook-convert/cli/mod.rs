@@ -1,7 +1,11 @@
+use std::fs;
 use std::path::PathBuf;
 
-use anyhow::{bail, Result};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser};
+
+use ebook_tools::kepub::{self, KepubOptions};
+use ebook_tools::{mobi, Format};
 
 /// ebook-convert: Convert ebooks between formats.
 #[derive(Parser, Debug)]
@@ -16,20 +20,81 @@ pub struct Cli {
 
     /// Target format (epub, kepub, mobi, azw3).
     #[arg(short, long)]
-    to: Option<ebook_tools::Format>,
+    to: Option<Format>,
+
+    #[command(flatten)]
+    kepub: KepubArgs,
+}
+
+/// Options for conversions to KePub.
+#[derive(Args, Debug)]
+#[command(next_help_heading = "KePub options")]
+struct KepubArgs {
+    /// Turn on hyphenation for the whole book.
+    #[arg(long, conflicts_with = "no_hyphenate")]
+    hyphenate: bool,
+
+    /// Turn off hyphenation, even where the book's styles turn it on.
+    #[arg(long)]
+    no_hyphenate: bool,
+
+    /// Remove embedded fonts, so the reader's own fonts are used.
+    #[arg(long)]
+    remove_fonts: bool,
+
+    /// Let full-page images fill the screen without being cut off.
+    #[arg(long)]
+    fullscreen_fixes: bool,
+
+    /// Add the styles in this CSS file, overriding the book's own.
+    #[arg(long, value_name = "FILE")]
+    css: Option<PathBuf>,
+
+    /// Remove Adobe Digital Editions page maps.
+    #[arg(long)]
+    remove_page_map: bool,
+
+    /// Use curly quotes, em dashes and ellipses in body text.
+    #[arg(long)]
+    smart_punctuation: bool,
+
+    /// Remove a start reference that points to a missing document.
+    #[arg(long)]
+    fix_invalid_start: bool,
+}
+
+impl KepubArgs {
+    fn to_options(&self) -> Result<KepubOptions> {
+        let extra_css = match &self.css {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?,
+            ),
+            None => None,
+        };
+        Ok(KepubOptions {
+            hyphenate: match (self.hyphenate, self.no_hyphenate) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            remove_fonts: self.remove_fonts,
+            fullscreen_fixes: self.fullscreen_fixes,
+            extra_css,
+            remove_page_map: self.remove_page_map,
+            smart_punctuation: self.smart_punctuation,
+            fix_invalid_start: self.fix_invalid_start,
+        })
+    }
 }
 
 impl Cli {
     pub fn execute(self) -> Result<()> {
-        let input_format = ebook_tools::Format::from_path(&self.input);
-
         // Determine the target format: --to flag first, then output extension.
         let target_format = if let Some(fmt) = self.to {
             Some(fmt)
         } else {
-            self.output
-                .as_ref()
-                .and_then(|p| ebook_tools::Format::from_path(p))
+            self.output.as_ref().and_then(|p| Format::from_path(p))
         };
 
         let target_format = match target_format {
@@ -43,26 +108,38 @@ impl Cli {
 
         // Derive output path if not provided.
         let output = self.output.unwrap_or_else(|| {
-            let stem = self.input.file_stem().unwrap_or_default();
-            self.input.with_file_name(format!(
-                "{}.{}",
-                stem.to_string_lossy(),
-                target_format.extension()
-            ))
+            // Strip the whole input extension, so `book.kepub.epub` becomes `book.epub`.
+            let name = self.input.file_name().unwrap_or_default().to_string_lossy();
+            let stem = Format::strip_extension(&name);
+            self.input
+                .with_file_name(format!("{stem}.{}", target_format.extension()))
         });
 
-        match input_format {
-            Some(fmt) => {
-                println!("Input:  {} ({fmt})", self.input.display());
-                println!("Output: {} ({target_format})", output.display());
-                println!();
-                println!("TODO: Convert ebook");
-            }
-            None => {
-                bail!("Unknown input format: {}", self.input.display());
-            }
+        let (input_format, warning) = Format::sniff_with_warning(&self.input)?;
+        if let Some(warning) = warning {
+            eprintln!("Warning: {warning}");
+        }
+        if output == self.input {
+            bail!("Output would overwrite the input: {}", output.display());
+        }
+        let options = self.kepub.to_options()?;
+        if options != KepubOptions::default() && target_format != Format::Kepub {
+            bail!("KePub options can only be used when converting to kepub");
+        }
+
+        println!("Input:  {} ({input_format})", self.input.display());
+        println!("Output: {} ({target_format})", output.display());
+
+        match (input_format, target_format) {
+            (Format::Epub, Format::Kepub) => kepub::from_epub(&self.input, &output, &options)?,
+            (Format::Kepub, Format::Epub) => kepub::to_epub(&self.input, &output)?,
+            (Format::Mobi | Format::Azw3, Format::Epub) => mobi::to_epub(&self.input, &output)?,
+            (Format::Epub, Format::Azw3) => mobi::from_epub(&self.input, &output)?,
+            _ => bail!("Converting {input_format} to {target_format} is not supported"),
         }
 
+        println!();
+        println!("Converted: {}", output.display());
         Ok(())
     }
 }
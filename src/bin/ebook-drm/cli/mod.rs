@@ -1,10 +1,12 @@
 //! CLI command structure for ebook-drm.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use clap::{Parser, Subcommand};
 
+use ebook_tools::{DrmDetector, DrmStatus, EpubBook, Format, KindleFile, MobiBook};
+
 /// ebook-drm: DRM removal tool for ebooks
 #[derive(Parser, Debug)]
 #[command(name = "ebook-drm")]
@@ -30,23 +32,39 @@ impl Cli {
     pub fn execute(self) -> Result<()> {
         match self.command {
             Commands::Clean { input, output } => {
-                let format = ebook_tools::Format::from_path(&input);
-                match format {
-                    Some(fmt) => {
-                        println!("File:   {}", input.display());
-                        println!("Format: {fmt}");
-                        if let Some(out) = output {
-                            println!("Output: {}", out.display());
-                        }
-                        println!();
-                        println!("TODO: Remove DRM from ebook");
-                    }
-                    None => {
-                        bail!("Unknown ebook format: {}", input.display());
-                    }
+                let (fmt, warning) = Format::sniff_with_warning(&input)?;
+                if let Some(warning) = warning {
+                    eprintln!("Warning: {warning}");
+                }
+                println!("File:   {}", input.display());
+                println!("Format: {fmt}");
+                let drm = drm_status(&input, fmt)?;
+                if let Some(ref drm) = drm {
+                    println!("DRM:    {drm}");
+                }
+                if let Some(out) = output {
+                    println!("Output: {}", out.display());
+                }
+                println!();
+                if drm == Some(DrmStatus::None) {
+                    println!("No DRM found, nothing to remove");
+                } else {
+                    println!("TODO: Remove DRM from ebook");
                 }
                 Ok(())
             }
         }
     }
 }
+
+/// The DRM status of the book at `path`, or `None` for formats whose DRM
+/// isn't detected.
+fn drm_status(path: &Path, format: Format) -> Result<Option<DrmStatus>> {
+    let status = match format {
+        Format::Epub | Format::Kepub => EpubBook::open(path)?.drm_status()?,
+        Format::Mobi | Format::Azw3 => MobiBook::open(path)?.drm_status()?,
+        Format::Topaz | Format::Kfx | Format::KfxZip => KindleFile::open(path)?.drm_status()?,
+        Format::Pdf => return Ok(None),
+    };
+    Ok(Some(status))
+}
@@ -0,0 +1,417 @@
+//! `ebook-edit metadata --interactive`: edit metadata as TOML in `$EDITOR`.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+use ebook_tools::{normalize_language, Isbn, Metadata};
+
+/// Top-level keys accepted in the edited document.
+const KEYS: &[&str] = &[
+    "title",
+    "title_sort",
+    "authors",
+    "author_sort",
+    "author_links",
+    "contributors",
+    "description",
+    "publisher",
+    "language",
+    "isbn",
+    "identifiers",
+    "publication_date",
+    "creation_date",
+    "modification_date",
+    "subjects",
+    "tags",
+    "series",
+    "series_index",
+    "rating",
+    "timestamp",
+    "custom",
+];
+
+/// Let the user edit `start` in their editor, then show the changes against
+/// `original` and ask for confirmation.
+///
+/// Returns `None` if the user made no changes or declined to apply them.
+pub(super) fn edit(file: &Path, original: &Metadata, start: &Metadata) -> Result<Option<Metadata>> {
+    let tmp =
+        TempFile(std::env::temp_dir().join(format!("ebook-edit-{}.toml", std::process::id())));
+    let mut text = render(file, start);
+
+    let edited = loop {
+        fs::write(&tmp.0, &text).with_context(|| format!("Failed to write {}", tmp.0.display()))?;
+        run_editor(&tmp.0)?;
+        text = fs::read_to_string(&tmp.0)
+            .with_context(|| format!("Failed to read {}", tmp.0.display()))?;
+
+        match parse(&text, original) {
+            Ok(metadata) => break metadata,
+            Err(e) => {
+                eprintln!("Error: {e:#}");
+                if !confirm("Edit again?", true)? {
+                    return Ok(None);
+                }
+            }
+        }
+    };
+
+    let changes = diff(
+        &values(&render(file, original)),
+        &values(&render(file, &edited)),
+    );
+    if changes.is_empty() {
+        println!("No changes.");
+        return Ok(None);
+    }
+    for line in &changes {
+        println!("{line}");
+    }
+    println!();
+
+    Ok(confirm("Apply these changes?", false)?.then_some(edited))
+}
+
+/// Render metadata as an annotated TOML document.
+///
+/// Custom column values aren't included; they are kept as they are.
+fn render(file: &Path, m: &Metadata) -> String {
+    let mut doc = format!(
+        "# Metadata for {}\n\
+         #\n\
+         # Edit the values below, then save and close the editor. Comment out or\n\
+         # delete a line to clear that field. Dates are quoted W3CDTF strings\n\
+         # (\"2010\", \"2010-05\" or \"2010-05-12\").\n",
+        file.display()
+    );
+
+    let string = |s: &String| toml::Value::String(s.clone()).to_string();
+    let strings = |v: &[String]| {
+        toml::Value::Array(v.iter().cloned().map(toml::Value::String).collect()).to_string()
+    };
+
+    field(&mut doc, "title", "Title", m.title.as_ref().map(string));
+    field(
+        &mut doc,
+        "title_sort",
+        "Title used for sorting, e.g. \"Hobbit, The\"",
+        m.title_sort.as_ref().map(string),
+    );
+    field(
+        &mut doc,
+        "authors",
+        "Authors in display form (\"First Last\"), in order",
+        Some(strings(&m.authors)),
+    );
+    field(
+        &mut doc,
+        "description",
+        "Description (HTML is allowed)",
+        m.description.as_ref().map(string),
+    );
+    field(
+        &mut doc,
+        "publisher",
+        "Publisher",
+        m.publisher.as_ref().map(string),
+    );
+    field(
+        &mut doc,
+        "language",
+        "Language as a BCP 47 tag, e.g. \"en\" or \"pt-BR\"",
+        m.language.as_ref().map(string),
+    );
+    field(
+        &mut doc,
+        "isbn",
+        "ISBN-10 or ISBN-13",
+        m.isbn.as_ref().map(string),
+    );
+    field(
+        &mut doc,
+        "publication_date",
+        "Publication date",
+        m.publication_date.map(|d| string(&d.to_string())),
+    );
+    field(
+        &mut doc,
+        "creation_date",
+        "Creation date of the work",
+        m.creation_date.map(|d| string(&d.to_string())),
+    );
+    field(
+        &mut doc,
+        "modification_date",
+        "Last modification (EPUB 3 books update this on every write)",
+        m.modification_date.map(|d| string(&d.to_string())),
+    );
+    field(
+        &mut doc,
+        "subjects",
+        "Subjects, as given by the publisher",
+        Some(strings(&m.subjects)),
+    );
+    field(
+        &mut doc,
+        "tags",
+        "Personal tags, kept apart from the subjects",
+        Some(strings(&m.tags)),
+    );
+    field(
+        &mut doc,
+        "series",
+        "Series name",
+        m.series.as_ref().map(string),
+    );
+    field(
+        &mut doc,
+        "series_index",
+        "Position in the series, e.g. 3 or 2.5",
+        m.series_index.map(|i| toml::Value::Float(i).to_string()),
+    );
+    field(
+        &mut doc,
+        "rating",
+        "Rating out of 5, in half-star steps",
+        m.rating.map(|r| toml::Value::Float(r).to_string()),
+    );
+    field(
+        &mut doc,
+        "timestamp",
+        "When the book was added to the library",
+        m.timestamp.as_ref().map(string),
+    );
+
+    table(
+        &mut doc,
+        "author_sort",
+        "Sort names (\"Last, First\") keyed by author",
+        m.author_sort.iter(),
+    );
+    table(
+        &mut doc,
+        "author_links",
+        "Links for authors, keyed by author",
+        m.author_links.iter(),
+    );
+    table(
+        &mut doc,
+        "identifiers",
+        "Other identifiers keyed by scheme, e.g. uuid, asin, google",
+        m.identifiers.iter(),
+    );
+    table(
+        &mut doc,
+        "custom",
+        "Custom fields, e.g. read_status = \"read\"",
+        m.custom.iter(),
+    );
+
+    doc.push_str(
+        "\n# Contributors other than the authors. Roles are MARC relator codes,\n\
+         # e.g. \"trl\" (translator), \"ill\" (illustrator), \"edt\" (editor).\n",
+    );
+    if m.contributors.is_empty() {
+        doc.push_str("# [[contributors]]\n# name = \"\"\n# role = \"trl\"\n");
+    }
+    for contributor in &m.contributors {
+        doc.push_str("[[contributors]]\n");
+        doc.push_str(&format!("name = {}\n", string(&contributor.name)));
+        if let Some(role) = &contributor.role {
+            doc.push_str(&format!("role = {}\n", string(role)));
+        }
+    }
+
+    doc
+}
+
+fn field(doc: &mut String, key: &str, comment: &str, value: Option<String>) {
+    doc.push_str(&format!("\n# {comment}\n"));
+    match value {
+        Some(value) => doc.push_str(&format!("{key} = {value}\n")),
+        None => doc.push_str(&format!("# {key} = \"\"\n")),
+    }
+}
+
+fn table<'a>(
+    doc: &mut String,
+    name: &str,
+    comment: &str,
+    entries: impl Iterator<Item = (&'a String, &'a String)>,
+) {
+    doc.push_str(&format!("\n# {comment}\n[{name}]\n"));
+    for (key, value) in entries {
+        let value = toml::Value::String(value.clone());
+        doc.push_str(&format!("{} = {value}\n", toml_key(key)));
+    }
+}
+
+/// A TOML key, quoted unless it is a valid bare key.
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    }
+}
+
+/// Parse and validate an edited document.
+///
+/// The ISBN and language are only checked if they were changed, so a book
+/// with a bad value can still be edited in other ways.
+fn parse(text: &str, original: &Metadata) -> Result<Metadata> {
+    let table: toml::Table = text.parse().context("Invalid TOML")?;
+    if let Some(key) = table.keys().find(|k| !KEYS.contains(&k.as_str())) {
+        bail!("Unknown field: {key}");
+    }
+
+    let mut metadata: Metadata = toml::Value::Table(table)
+        .try_into()
+        .context("Invalid metadata")?;
+    for value in [
+        &mut metadata.title,
+        &mut metadata.title_sort,
+        &mut metadata.description,
+        &mut metadata.publisher,
+        &mut metadata.language,
+        &mut metadata.isbn,
+        &mut metadata.series,
+        &mut metadata.timestamp,
+    ] {
+        if value.as_deref().is_some_and(|v| v.trim().is_empty()) {
+            *value = None;
+        }
+    }
+    metadata.tags.retain(|t| !t.trim().is_empty());
+    metadata.custom.retain(|_, v| !v.trim().is_empty());
+    metadata.user_metadata = original.user_metadata.clone();
+
+    if let Some(isbn) = &metadata.isbn
+        && metadata.isbn != original.isbn
+    {
+        Isbn::parse(isbn)?;
+    }
+    if let Some(language) = &metadata.language
+        && metadata.language != original.language
+        && normalize_language(language).is_none()
+    {
+        bail!("Not a valid language tag: {language}");
+    }
+    if let Some(rating) = metadata.rating
+        && !(0.0..=5.0).contains(&rating)
+    {
+        bail!("Rating must be between 0 and 5");
+    }
+
+    Ok(metadata)
+}
+
+/// The lines of a rendered document that carry values.
+fn values(doc: &str) -> Vec<&str> {
+    doc.lines()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .collect()
+}
+
+/// A line diff, with removed lines prefixed by `-` and added lines by `+`.
+///
+/// Table headers are kept as context so changed keys can be placed.
+fn diff(old: &[&str], new: &[&str]) -> Vec<String> {
+    // Longest common subsequence table.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut header = None;
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            if old[i].starts_with('[') {
+                header = Some(old[i]);
+            }
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            push_change(&mut out, &mut header, '-', old[i]);
+            i += 1;
+        } else {
+            push_change(&mut out, &mut header, '+', new[j]);
+            j += 1;
+        }
+    }
+
+    out
+}
+
+fn push_change(out: &mut Vec<String>, header: &mut Option<&str>, sign: char, line: &str) {
+    // Show the enclosing table header unless the change starts a table.
+    if let Some(h) = header.take()
+        && !line.starts_with('[')
+    {
+        out.push(format!("  {h}"));
+    }
+    out.push(format!("{sign} {line}"));
+}
+
+/// Open a file in `$VISUAL` or `$EDITOR` (falling back to `vi`) and wait
+/// for it to exit.
+fn run_editor(path: &Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+
+    // The variable may include arguments, as in "code --wait".
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor: {editor}"))?;
+    if !status.success() {
+        bail!("Editor exited with {status}");
+    }
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal.
+pub(super) fn confirm(prompt: &str, default: bool) -> Result<bool> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    print!("{prompt} {hint} ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(match answer.trim().to_lowercase().as_str() {
+        "" => default,
+        "y" | "yes" => true,
+        _ => false,
+    })
+}
+
+/// A temporary file that is removed when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
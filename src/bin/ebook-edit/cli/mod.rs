@@ -1,7 +1,23 @@
-use std::path::PathBuf;
+mod interactive;
+mod organize;
+mod template;
 
-use anyhow::{bail, Result};
-use clap::{Parser, Subcommand};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use regex_lite::Regex;
+use walkdir::WalkDir;
+
+use ebook_tools::{
+    epub, metadata_from_path, normalize_language, truncate_graphemes, CoverWriter, Description,
+    EpubBook, FieldPatch, FilenamePattern, Format, Isbn, ListPatch, MergePolicy, Metadata,
+    MetadataField, MetadataPatch, MetadataProvider, MetadataWriter, MobiBook, W3cDate,
+};
+
+use organize::OrganizeArgs;
+use template::{format_index, Template, TemplateContext};
 
 /// ebook-edit: Edit ebook metadata and cover images.
 #[derive(Parser, Debug)]
@@ -14,53 +30,534 @@ pub struct Cli {
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// Edit metadata fields of an ebook.
-    Metadata {
-        /// Path to the ebook file.
-        file: PathBuf,
+    /// Edit metadata fields of one or more ebooks.
+    ///
+    /// Values are templates over each book's current metadata and path, such
+    /// as `--title '{series} {series_index}'` or, with `--path-regex`,
+    /// `--series-index '{path_capture:1}'`. Write `{{` and `}}` for literal
+    /// braces. Fields: title, title_sort, author, authors, author_sort,
+    /// series, series_index, publisher, language, isbn, publication_date,
+    /// year, path, file_name, stem, ext, dir and path_capture. Numbers take
+    /// a width, as in `{series_index:02}`.
+    Metadata(Box<MetadataArgs>),
+
+    /// Rename books and file them into folders by their metadata.
+    ///
+    /// The target path is a template such as the default
+    /// `{author_sort}/{series}/{series_index:02} - {title}.{ext}`. Unsafe
+    /// characters in values are replaced, and name collisions get a " (2)"
+    /// suffix. Every move is recorded in an undo log for `--undo`.
+    Organize(Box<OrganizeArgs>),
+
+    /// Manage the cover image of an ebook.
+    Cover {
+        #[command(subcommand)]
+        action: CoverAction,
+    },
+}
 
-        /// Set the title.
-        #[arg(long)]
-        title: Option<String>,
+#[derive(Args, Debug)]
+pub struct MetadataArgs {
+    /// Ebook files, or directories to search for ebooks.
+    #[arg(required = true, value_name = "FILE")]
+    files: Vec<PathBuf>,
 
-        /// Set an author (repeatable for multiple authors).
-        #[arg(long)]
-        author: Vec<String>,
+    /// Set the title.
+    #[arg(long)]
+    title: Option<String>,
 
-        /// Set the description.
-        #[arg(long)]
-        description: Option<String>,
+    /// Set an author, replacing existing authors (repeatable for multiple authors).
+    #[arg(long)]
+    author: Vec<String>,
 
-        /// Set the publisher.
-        #[arg(long)]
-        publisher: Option<String>,
+    /// Add an author, keeping existing ones (repeatable).
+    #[arg(long)]
+    add_author: Vec<String>,
 
-        /// Set the language.
-        #[arg(long)]
-        language: Option<String>,
+    /// Remove an author (repeatable).
+    #[arg(long)]
+    remove_author: Vec<String>,
 
-        /// Set the ISBN.
-        #[arg(long)]
-        isbn: Option<String>,
+    /// Set the description.
+    #[arg(long)]
+    description: Option<String>,
 
-        /// Set the publication date.
-        #[arg(long)]
-        publication_date: Option<String>,
+    /// Set the description from a Markdown file (`-` for stdin). It is
+    /// stored as sanitized XHTML.
+    #[arg(long, value_name = "FILE", conflicts_with = "description")]
+    description_file: Option<PathBuf>,
 
-        /// Set the series name.
-        #[arg(long)]
-        series: Option<String>,
+    /// Set the publisher.
+    #[arg(long)]
+    publisher: Option<String>,
 
-        /// Set the series index.
-        #[arg(long)]
-        series_index: Option<f64>,
-    },
+    /// Set the language (a BCP 47 tag such as "en-GB", an ISO 639 code or an
+    /// English language name).
+    #[arg(long)]
+    language: Option<String>,
 
-    /// Manage the cover image of an ebook.
-    Cover {
-        #[command(subcommand)]
-        action: CoverAction,
-    },
+    /// Set the ISBN (ISBN-10 or ISBN-13, hyphens allowed; the check digit is verified).
+    #[arg(long)]
+    isbn: Option<String>,
+
+    /// Set the publication date (YYYY, YYYY-MM or YYYY-MM-DD).
+    #[arg(long)]
+    publication_date: Option<String>,
+
+    /// Set a subject, replacing existing subjects (repeatable).
+    #[arg(long)]
+    subject: Vec<String>,
+
+    /// Add a subject, keeping existing ones (repeatable).
+    #[arg(long)]
+    add_subject: Vec<String>,
+
+    /// Remove a subject (repeatable).
+    #[arg(long)]
+    remove_subject: Vec<String>,
+
+    /// Set the series name.
+    #[arg(long)]
+    series: Option<String>,
+
+    /// Set the series index.
+    #[arg(long)]
+    series_index: Option<String>,
+
+    /// Set a custom field (repeatable), e.g. `--set read_status=read`. An
+    /// empty value removes the field.
+    ///
+    /// `rating` (0 to 5) and `tags` (comma-separated) set those fields, and
+    /// `#name` sets the value of an existing Calibre custom column.
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_assignment)]
+    set: Vec<(String, String)>,
+
+    /// Clear a field (repeatable), e.g. `--clear description`.
+    #[arg(long, value_name = "FIELD")]
+    clear: Vec<MetadataField>,
+
+    /// Normalize the language tag, fill in missing author and title sort
+    /// names, and turn author names written as "Last, First" into "First Last".
+    #[arg(long)]
+    normalize: bool,
+
+    /// Replace all metadata with the contents of a JSON file (`-` for stdin).
+    ///
+    /// The file uses the same layout as `ebook-info --json`. Other flags are
+    /// applied on top of it.
+    #[arg(long, value_name = "FILE", conflicts_with = "from_opf")]
+    from_json: Option<PathBuf>,
+
+    /// Replace all metadata with the metadata of a standalone OPF file.
+    ///
+    /// Other flags are applied on top of it.
+    #[arg(long, value_name = "FILE")]
+    from_opf: Option<PathBuf>,
+
+    /// Merge in metadata guessed from the file name and folders, such as
+    /// `Author - Series 03 - Title.epub` or `Author/Series/03 Title.epub`.
+    ///
+    /// Other flags are applied on top of it.
+    #[arg(long)]
+    from_filename: bool,
+
+    /// A pattern to try before the built-in ones for `--from-filename`
+    /// (repeatable). Placeholders: {title}, {author}, {series},
+    /// {series_index}, {year}, {publisher}, {isbn} and {*} to skip text; `/`
+    /// separates directories.
+    #[arg(long, value_name = "PATTERN", requires = "from_filename")]
+    filename_pattern: Vec<FilenamePattern>,
+
+    /// How `--from-filename` merges: `fill-empty` only fills in missing
+    /// fields, `overwrite` replaces fields it has a value for.
+    #[arg(long, value_name = "POLICY", default_value_t = MergePolicy::FillEmpty)]
+    merge: MergePolicy,
+
+    /// Move series information found in the title, as in "Leviathan Wakes
+    /// (The Expanse Book 1)", into the series and series index.
+    ///
+    /// The change is shown for confirmation before it is applied.
+    #[arg(long)]
+    detect_series: bool,
+
+    /// Apply `--detect-series` without asking for confirmation.
+    #[arg(long, short, requires = "detect_series")]
+    yes: bool,
+
+    /// Edit the metadata as a TOML document in `$EDITOR`, then review the
+    /// changes before they are written.
+    ///
+    /// Other flags are applied before the editor opens.
+    #[arg(long, short)]
+    interactive: bool,
+
+    /// A regular expression matched against each file's path. Its capture
+    /// groups can be used in values as `{path_capture:1}` or
+    /// `{path_capture:name}`.
+    #[arg(long, value_name = "REGEX")]
+    path_regex: Option<Regex>,
+
+    /// Show a table of the planned changes without writing anything.
+    #[arg(long, short = 'n', conflicts_with = "interactive")]
+    dry_run: bool,
+}
+
+/// Changes to one field of one book, for `--dry-run`.
+struct Change {
+    field: String,
+    old: String,
+    new: String,
+}
+
+impl MetadataArgs {
+    /// The values given for templated flags, with the flag names.
+    fn templates(&self) -> Vec<(&'static str, &str)> {
+        let single = [
+            ("title", &self.title),
+            ("description", &self.description),
+            ("publisher", &self.publisher),
+            ("language", &self.language),
+            ("isbn", &self.isbn),
+            ("publication-date", &self.publication_date),
+            ("series", &self.series),
+            ("series-index", &self.series_index),
+        ];
+        let lists = [
+            ("author", &self.author),
+            ("add-author", &self.add_author),
+            ("remove-author", &self.remove_author),
+            ("subject", &self.subject),
+            ("add-subject", &self.add_subject),
+            ("remove-subject", &self.remove_subject),
+        ];
+
+        let mut templates: Vec<_> = single
+            .into_iter()
+            .filter_map(|(flag, value)| Some((flag, value.as_deref()?)))
+            .collect();
+        for (flag, values) in lists {
+            templates.extend(values.iter().map(|v| (flag, v.as_str())));
+        }
+        templates.extend(self.set.iter().map(|(_, v)| ("set", v.as_str())));
+        templates
+    }
+
+    /// Check template syntax up front, so mistakes are reported once
+    /// rather than for every file.
+    fn check_templates(&self) -> Result<()> {
+        for (flag, value) in self.templates() {
+            let template: Template = value.parse().with_context(|| format!("In --{flag}"))?;
+            if template.uses_path_captures() && self.path_regex.is_none() {
+                bail!("--{flag} uses {{path_capture}}, which needs --path-regex");
+            }
+        }
+        Ok(())
+    }
+
+    /// Build a patch for one book from the command-line flags.
+    ///
+    /// Values are rendered as templates against the book's current metadata
+    /// and path. Rendered values are trimmed, and a template that renders
+    /// empty leaves its field unchanged.
+    fn to_patch(
+        &self,
+        ctx: &TemplateContext,
+        description_file: Option<&str>,
+    ) -> Result<MetadataPatch> {
+        let render = |flag: &str, value: &str| -> Result<Option<String>> {
+            let template: Template = value.parse()?;
+            let rendered = template
+                .render(ctx)
+                .with_context(|| format!("In --{flag}"))?;
+            if !template.has_fields() {
+                return Ok(Some(rendered));
+            }
+            let rendered = rendered.trim();
+            Ok((!rendered.is_empty()).then(|| rendered.to_string()))
+        };
+        let set = |flag: &str, value: &Option<String>| -> Result<FieldPatch<String>> {
+            Ok(match value {
+                Some(v) => render(flag, v)?.map_or(FieldPatch::Keep, FieldPatch::Set),
+                None => FieldPatch::Keep,
+            })
+        };
+        let list = |flag: &str, values: &[String]| -> Result<Vec<String>> {
+            let mut rendered = Vec::new();
+            for value in values {
+                rendered.extend(render(flag, value)?);
+            }
+            Ok(rendered)
+        };
+        let list_patch = |set: Vec<String>, add: Vec<String>, remove: Vec<String>| ListPatch {
+            set: (!set.is_empty()).then_some(set),
+            add,
+            remove,
+        };
+        fn parsed<T>(
+            patch: FieldPatch<String>,
+            parse: impl Fn(&str) -> Result<T>,
+        ) -> Result<FieldPatch<T>> {
+            Ok(match patch {
+                FieldPatch::Set(v) => FieldPatch::Set(parse(&v)?),
+                FieldPatch::Keep => FieldPatch::Keep,
+                FieldPatch::Clear => FieldPatch::Clear,
+            })
+        }
+
+        let description = match description_file {
+            Some(html) => FieldPatch::Set(html.to_string()),
+            None => set("description", &self.description)?,
+        };
+
+        let mut patch = MetadataPatch {
+            title: set("title", &self.title)?,
+            authors: list_patch(
+                list("author", &self.author)?,
+                list("add-author", &self.add_author)?,
+                list("remove-author", &self.remove_author)?,
+            ),
+            description,
+            publisher: set("publisher", &self.publisher)?,
+            language: parsed(set("language", &self.language)?, |s| {
+                parse_language(s).map_err(anyhow::Error::msg)
+            })?,
+            isbn: parsed(set("isbn", &self.isbn)?, |s| {
+                Ok(s.parse::<Isbn>()?.to_string())
+            })?,
+            publication_date: parsed(set("publication-date", &self.publication_date)?, |s| {
+                s.parse::<W3cDate>().map_err(anyhow::Error::msg)
+            })?,
+            subjects: list_patch(
+                list("subject", &self.subject)?,
+                list("add-subject", &self.add_subject)?,
+                list("remove-subject", &self.remove_subject)?,
+            ),
+            series: set("series", &self.series)?,
+            series_index: parsed(set("series-index", &self.series_index)?, |s| {
+                s.trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid series index: {s}"))
+            })?,
+            ..MetadataPatch::default()
+        };
+
+        for (key, value) in &self.set {
+            // A literal empty value removes the field; a template that
+            // renders empty leaves it alone.
+            let value = match render("set", value)? {
+                Some(v) if v.trim().is_empty() => FieldPatch::Clear,
+                Some(v) => FieldPatch::Set(v),
+                None => FieldPatch::Keep,
+            };
+            match key.as_str() {
+                "rating" => patch.rating = parsed(value, parse_rating)?,
+                "tags" => {
+                    patch.tags.set = match value {
+                        FieldPatch::Set(v) => Some(split_tags(&v)),
+                        FieldPatch::Clear => Some(Vec::new()),
+                        FieldPatch::Keep => None,
+                    }
+                }
+                column if column.starts_with('#') => {
+                    if !ctx.metadata.user_metadata.contains_key(column) {
+                        bail!("No Calibre custom column {column} in this book");
+                    }
+                    // Column values are JSON, but plain text is taken as a string.
+                    let value = parsed(value, |v| {
+                        Ok(serde_json::from_str(v)
+                            .unwrap_or_else(|_| serde_json::Value::String(v.to_string())))
+                    })?;
+                    patch.columns.insert(key.clone(), value);
+                }
+                _ => {
+                    patch.custom.insert(key.clone(), value);
+                }
+            }
+        }
+
+        for &field in &self.clear {
+            if patch.touches(field) {
+                bail!("Cannot both clear and change {field}");
+            }
+            patch.clear(field);
+        }
+
+        Ok(patch)
+    }
+
+    /// Load replacement metadata from `--from-json` or `--from-opf`, if given.
+    fn base_metadata(&self) -> Result<Option<Metadata>> {
+        if let Some(path) = &self.from_json {
+            let json = read_input(path)?;
+            let metadata = serde_json::from_str(&json)
+                .with_context(|| format!("Invalid metadata JSON in {}", path.display()))?;
+            return Ok(Some(metadata));
+        }
+
+        if let Some(path) = &self.from_opf {
+            let xml = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            return Ok(Some(epub::metadata_from_opf(&xml)?));
+        }
+
+        Ok(None)
+    }
+
+    fn execute(self) -> Result<()> {
+        let files = ebook_files(&self.files)?;
+        if files.is_empty() {
+            bail!("No ebook files found");
+        }
+
+        self.check_templates()?;
+        let description_file = match &self.description_file {
+            Some(path) => {
+                let markdown = read_input(path)?;
+                Some(Description::from_markdown(&markdown).html().to_string())
+            }
+            None => None,
+        };
+        let base = self.base_metadata()?;
+        let has_changes =
+            !self.templates().is_empty() || description_file.is_some() || !self.clear.is_empty();
+        if base.is_none()
+            && !has_changes
+            && !self.from_filename
+            && !self.detect_series
+            && !self.normalize
+            && !self.interactive
+        {
+            bail!("No metadata changes given");
+        }
+
+        if let [file] = files.as_slice() {
+            let changes = self.edit_file(file, base.as_ref(), description_file.as_deref())?;
+            match changes {
+                Some(changes) if self.dry_run => print_changes(&[(file, changes)]),
+                Some(_) => println!("Updated metadata: {}", file.display()),
+                None => println!("Metadata unchanged: {}", file.display()),
+            }
+            return Ok(());
+        }
+
+        let mut planned = Vec::new();
+        let mut unchanged = 0;
+        let mut failed = Vec::new();
+        for file in &files {
+            match self.edit_file(file, base.as_ref(), description_file.as_deref()) {
+                Ok(Some(changes)) => {
+                    if !self.dry_run {
+                        println!("Updated metadata: {}", file.display());
+                    }
+                    planned.push((file, changes));
+                }
+                Ok(None) => unchanged += 1,
+                Err(e) => {
+                    eprintln!("Error: {}: {e:#}", file.display());
+                    failed.push(file);
+                }
+            }
+        }
+
+        if self.dry_run {
+            print_changes(&planned);
+        }
+        println!();
+        let verb = if self.dry_run { "To update" } else { "Updated" };
+        println!("{verb}: {}", planned.len());
+        println!("Unchanged: {unchanged}");
+        println!("Failed:    {}", failed.len());
+        for file in &failed {
+            println!("  {}", file.display());
+        }
+
+        if !failed.is_empty() {
+            bail!("{} of {} files failed", failed.len(), files.len());
+        }
+        Ok(())
+    }
+
+    /// Apply the requested changes to one book, returning what changed, or
+    /// `None` if nothing did. Nothing is written for `--dry-run`.
+    fn edit_file(
+        &self,
+        file: &Path,
+        base: Option<&Metadata>,
+        description_file: Option<&str>,
+    ) -> Result<Option<Vec<Change>>> {
+        match detect_format(file)? {
+            Format::Epub | Format::Kepub => {
+                self.edit_book(&mut EpubBook::open(file)?, file, base, description_file)
+            }
+            Format::Mobi | Format::Azw3 => {
+                self.edit_book(&mut MobiBook::open(file)?, file, base, description_file)
+            }
+            format => bail!("Unsupported format: {format}"),
+        }
+    }
+
+    fn edit_book<B: MetadataProvider + MetadataWriter>(
+        &self,
+        book: &mut B,
+        file: &Path,
+        base: Option<&Metadata>,
+        description_file: Option<&str>,
+    ) -> Result<Option<Vec<Change>>> {
+        let original = book.metadata()?;
+        let ctx = TemplateContext {
+            path: file,
+            metadata: &original,
+            path_regex: self.path_regex.as_ref(),
+        };
+        let patch = self.to_patch(&ctx, description_file)?;
+
+        let mut metadata = base.cloned().unwrap_or_else(|| original.clone());
+        if self.from_filename
+            && let Some(guessed) = metadata_from_path(file, &self.filename_pattern)
+        {
+            metadata.merge(&guessed, self.merge);
+        }
+        if self.detect_series {
+            self.detect_series(file, &mut metadata)?;
+        }
+        patch.apply(&mut metadata);
+        if self.normalize {
+            metadata.normalize_language();
+            metadata.normalize_sort_names();
+        }
+        if self.interactive {
+            match interactive::edit(file, &original, &metadata)? {
+                Some(edited) => metadata = edited,
+                None => return Ok(None),
+            }
+        }
+
+        if metadata == original {
+            return Ok(None);
+        }
+        if !self.dry_run {
+            book.set_metadata(&metadata)?;
+        }
+        Ok(Some(changes(&original, &metadata)))
+    }
+
+    /// Move series information out of the title, asking first unless
+    /// `--yes`, `--dry-run` or `--interactive` make that unnecessary.
+    fn detect_series(&self, file: &Path, metadata: &mut Metadata) -> Result<()> {
+        let before = metadata.clone();
+        if !metadata.detect_series() || self.yes || self.dry_run || self.interactive {
+            return Ok(());
+        }
+
+        println!("Series found in title: {}", file.display());
+        for change in changes(&before, metadata) {
+            println!("  {}: {} -> {}", change.field, change.old, change.new);
+        }
+        if !interactive::confirm("Apply?", false)? {
+            *metadata = before;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -88,52 +585,28 @@ pub enum CoverAction {
 impl Cli {
     pub fn execute(self) -> Result<()> {
         match self.command {
-            Commands::Metadata { file, .. } => {
-                let format = ebook_tools::Format::from_path(&file);
-                match format {
-                    Some(fmt) => {
-                        println!("File:   {}", file.display());
-                        println!("Format: {fmt}");
-                        println!();
-                        println!("TODO: Write metadata to ebook");
-                    }
-                    None => {
-                        bail!("Unknown ebook format: {}", file.display());
-                    }
-                }
-            }
+            Commands::Metadata(args) => args.execute()?,
+            Commands::Organize(args) => args.execute()?,
             Commands::Cover { action } => match action {
                 CoverAction::Extract { file, output } => {
-                    let format = ebook_tools::Format::from_path(&file);
-                    match format {
-                        Some(fmt) => {
-                            println!("File:   {}", file.display());
-                            println!("Format: {fmt}");
-                            if let Some(out) = output {
-                                println!("Output: {}", out.display());
-                            }
-                            println!();
-                            println!("TODO: Extract cover image");
-                        }
-                        None => {
-                            bail!("Unknown ebook format: {}", file.display());
-                        }
+                    let fmt = detect_format(&file)?;
+                    println!("File:   {}", file.display());
+                    println!("Format: {fmt}");
+                    if let Some(out) = output {
+                        println!("Output: {}", out.display());
                     }
+                    println!();
+                    println!("TODO: Extract cover image");
                 }
                 CoverAction::Set { file, image } => {
-                    let format = ebook_tools::Format::from_path(&file);
-                    match format {
-                        Some(fmt) => {
-                            println!("File:   {}", file.display());
-                            println!("Format: {fmt}");
-                            println!("Image:  {}", image.display());
-                            println!();
-                            println!("TODO: Set cover image");
-                        }
-                        None => {
-                            bail!("Unknown ebook format: {}", file.display());
-                        }
+                    let data = std::fs::read(&image)
+                        .with_context(|| format!("Failed to read {}", image.display()))?;
+                    match detect_format(&file)? {
+                        Format::Epub | Format::Kepub => EpubBook::open(&file)?.set_cover(&data)?,
+                        Format::Mobi | Format::Azw3 => MobiBook::open(&file)?.set_cover(&data)?,
+                        format => bail!("Unsupported format: {format}"),
                     }
+                    println!("Updated cover: {}", file.display());
                 }
             },
         }
@@ -141,3 +614,227 @@ impl Cli {
         Ok(())
     }
 }
+
+/// Read a file given on the command line, or stdin for `-`.
+fn read_input(path: &Path) -> Result<String> {
+    if path.as_os_str() == "-" {
+        return Ok(std::io::read_to_string(std::io::stdin())?);
+    }
+    std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Parse a `--set KEY=VALUE` argument.
+fn parse_assignment(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {s:?}"))?;
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(format!("invalid key: {key:?}"));
+    }
+    if let Ok(field) = key.parse::<MetadataField>()
+        && !matches!(field, MetadataField::Tags | MetadataField::Rating)
+    {
+        return Err(format!("{field} is a standard field with its own flag"));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse a rating out of 5, rounded to the nearest half star.
+fn parse_rating(s: &str) -> Result<f64> {
+    let rating: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid rating: {s}"))?;
+    if !(0.0..=5.0).contains(&rating) {
+        bail!("Rating must be between 0 and 5");
+    }
+    Ok((rating * 2.0).round() / 2.0)
+}
+
+/// Split a comma-separated list of tags.
+fn split_tags(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Validate a `--language` value and convert it to a canonical BCP 47 tag.
+fn parse_language(s: &str) -> Result<String, String> {
+    normalize_language(s).ok_or_else(|| format!("not a valid language tag: {s}"))
+}
+
+/// Expand directories into the ebook files they contain, sorted by name.
+fn ebook_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        for entry in WalkDir::new(path).sort_by_file_name() {
+            let entry = entry.with_context(|| format!("Failed to read {}", path.display()))?;
+            // PDF, Topaz and KFX books can't be edited, so they are left out.
+            let editable = Format::from_path(entry.path()).is_some_and(|f| {
+                !matches!(
+                    f,
+                    Format::Pdf | Format::Topaz | Format::Kfx | Format::KfxZip
+                )
+            });
+            if entry.file_type().is_file() && editable {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Detect a book's format from its content, warning if the extension
+/// suggests a different one.
+fn detect_format(file: &Path) -> Result<Format> {
+    let (format, warning) = Format::sniff_with_warning(file)?;
+    if let Some(warning) = warning {
+        eprintln!("Warning: {warning}");
+    }
+    Ok(format)
+}
+
+/// The fields that differ between two versions of a book's metadata.
+fn changes(old: &Metadata, new: &Metadata) -> Vec<Change> {
+    let mut changes: Vec<Change> = MetadataField::ALL
+        .into_iter()
+        .filter_map(|field| {
+            let (old, new) = (field_text(old, field), field_text(new, field));
+            (old != new).then(|| Change {
+                field: field.to_string(),
+                old,
+                new,
+            })
+        })
+        .collect();
+
+    let custom: BTreeSet<&String> = old.custom.keys().chain(new.custom.keys()).collect();
+    for name in custom {
+        let (old, new) = (old.custom.get(name), new.custom.get(name));
+        if old != new {
+            changes.push(Change {
+                field: name.clone(),
+                old: old.cloned().unwrap_or_default(),
+                new: new.cloned().unwrap_or_default(),
+            });
+        }
+    }
+    for (name, column) in &new.user_metadata {
+        let (old, new) = (
+            old.user_metadata.get(name).map(column_text),
+            column_text(column),
+        );
+        if old.as_ref().is_some_and(|old| *old != new) {
+            changes.push(Change {
+                field: name.clone(),
+                old: old.unwrap_or_default(),
+                new,
+            });
+        }
+    }
+
+    // Fields without a command-line flag only change through --from-json,
+    // --from-opf, --normalize or --interactive.
+    if changes.is_empty() && old != new {
+        changes.push(Change {
+            field: "other".to_string(),
+            old: String::new(),
+            new: "(changed)".to_string(),
+        });
+    }
+    changes
+}
+
+/// A field's value as shown in the `--dry-run` table.
+fn field_text(m: &Metadata, field: MetadataField) -> String {
+    let text = |v: &Option<String>| v.clone().unwrap_or_default();
+    match field {
+        MetadataField::Title => text(&m.title),
+        MetadataField::Authors => m.authors.join(" & "),
+        MetadataField::Description => text(&m.description),
+        MetadataField::Publisher => text(&m.publisher),
+        MetadataField::Language => text(&m.language),
+        MetadataField::Isbn => text(&m.isbn),
+        MetadataField::PublicationDate => m
+            .publication_date
+            .map(|d| d.to_string())
+            .unwrap_or_default(),
+        MetadataField::Subjects => m.subjects.join(", "),
+        MetadataField::Tags => m.tags.join(", "),
+        MetadataField::Series => text(&m.series),
+        MetadataField::SeriesIndex => m.series_index.map(format_index).unwrap_or_default(),
+        MetadataField::Rating => m.rating.map(format_index).unwrap_or_default(),
+    }
+}
+
+/// The value of a Calibre custom column as shown in the `--dry-run` table.
+fn column_text(column: &serde_json::Value) -> String {
+    match column.get("#value#") {
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(serde_json::Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Print planned changes as a table with one row per changed field.
+fn print_changes(planned: &[(&PathBuf, Vec<Change>)]) {
+    const MAX_VALUE: usize = 40;
+
+    let cell = |s: &str| {
+        let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        let short = truncate_graphemes(&s, MAX_VALUE);
+        if short.len() < s.len() {
+            format!("{}...", short.trim_end())
+        } else {
+            s
+        }
+    };
+
+    let mut rows = vec![[
+        "File".to_string(),
+        "Field".to_string(),
+        "Old".to_string(),
+        "New".to_string(),
+    ]];
+    for (file, changes) in planned {
+        for (n, change) in changes.iter().enumerate() {
+            let name = if n == 0 {
+                file.display().to_string()
+            } else {
+                String::new()
+            };
+            rows.push([
+                name,
+                change.field.clone(),
+                cell(&change.old),
+                cell(&change.new),
+            ]);
+        }
+    }
+    if rows.len() == 1 {
+        println!("No changes.");
+        return;
+    }
+
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
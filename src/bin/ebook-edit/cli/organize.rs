@@ -0,0 +1,419 @@
+//! `ebook-edit organize`: rename and file books by their metadata.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use ebook_tools::{
+    truncate_graphemes, EpubBook, Format, Metadata, MetadataProvider, MobiBook, W3cDate,
+};
+
+use super::template::{Template, TemplateContext};
+use super::{detect_format, ebook_files};
+
+const DEFAULT_TEMPLATE: &str = "{author_sort}/{series}/{series_index:02} - {title}.{ext}";
+
+/// File names are shortened to this many bytes, leaving room for a
+/// collision suffix within common 255-byte limits.
+const MAX_NAME_BYTES: usize = 200;
+
+#[derive(Args, Debug)]
+pub struct OrganizeArgs {
+    /// Ebook files, or directories to search for ebooks.
+    #[arg(value_name = "FILE", required_unless_present = "undo")]
+    files: Vec<PathBuf>,
+
+    /// Directory to file the books into.
+    #[arg(long, value_name = "DIR", required_unless_present = "undo")]
+    to: Option<PathBuf>,
+
+    /// Path of each book below the destination, using the same fields as
+    /// `metadata` values; `/` separates directories. Parts left empty by
+    /// missing fields are dropped, so books without a series go directly
+    /// into the author's directory with the default template.
+    #[arg(long, default_value = DEFAULT_TEMPLATE)]
+    template: String,
+
+    /// Copy books instead of moving them.
+    #[arg(long)]
+    copy: bool,
+
+    /// Show where each book would go without touching anything.
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+
+    /// Where to write the undo log (default: `organize-undo-<time>.jsonl`
+    /// in the destination directory).
+    #[arg(long, value_name = "FILE")]
+    undo_log: Option<PathBuf>,
+
+    /// Revert the moves and copies recorded in an undo log.
+    #[arg(long, value_name = "LOG", conflicts_with_all = ["files", "to", "copy", "undo_log"])]
+    undo: Option<PathBuf>,
+}
+
+/// One line of the undo log.
+#[derive(Debug, Serialize, Deserialize)]
+struct LogEntry {
+    action: Action,
+    from: PathBuf,
+    to: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Move,
+    Copy,
+}
+
+impl OrganizeArgs {
+    pub(super) fn execute(self) -> Result<()> {
+        if let Some(log) = &self.undo {
+            return undo(log, self.dry_run);
+        }
+        let Some(dest) = &self.to else {
+            bail!("--to is required");
+        };
+
+        let template: Template = self.template.parse().context("In --template")?;
+        if template.uses_path_captures() {
+            bail!("--template can't use {{path_capture}}");
+        }
+        let files = ebook_files(&self.files)?;
+        if files.is_empty() {
+            bail!("No ebook files found");
+        }
+
+        let mut taken = HashSet::new();
+        let mut plan = Vec::new();
+        let mut in_place = 0;
+        let mut failed = Vec::new();
+        for file in &files {
+            match target(file, dest, &template, &mut taken) {
+                Ok(Some(target)) => plan.push((file, target)),
+                Ok(None) => in_place += 1,
+                Err(e) => {
+                    eprintln!("Error: {}: {e:#}", file.display());
+                    failed.push(file);
+                }
+            }
+        }
+
+        let action = if self.copy {
+            Action::Copy
+        } else {
+            Action::Move
+        };
+        let mut done = 0;
+        if self.dry_run {
+            for (from, to) in &plan {
+                println!("{} -> {}", from.display(), to.display());
+            }
+        } else if !plan.is_empty() {
+            let log_path = self.undo_log.clone().unwrap_or_else(|| {
+                let stamp = W3cDate::now().to_string().replace([':', '-'], "");
+                dest.join(format!("organize-undo-{stamp}.jsonl"))
+            });
+            let mut log = open_log(&log_path)?;
+
+            for (from, to) in &plan {
+                match transfer(from, to, action) {
+                    Ok(()) => {
+                        let entry = LogEntry {
+                            action,
+                            from: absolute(from),
+                            to: absolute(to),
+                        };
+                        writeln!(log, "{}", serde_json::to_string(&entry)?)
+                            .and_then(|()| log.flush())
+                            .with_context(|| format!("Failed to write {}", log_path.display()))?;
+                        println!("{} -> {}", from.display(), to.display());
+                        done += 1;
+                    }
+                    Err(e) => {
+                        eprintln!("Error: {}: {e:#}", from.display());
+                        failed.push(from);
+                    }
+                }
+            }
+            println!();
+            println!("Undo log: {}", log_path.display());
+        }
+
+        println!();
+        let verb = match (self.dry_run, action) {
+            (true, Action::Move) => "To move: ",
+            (true, Action::Copy) => "To copy: ",
+            (false, Action::Move) => "Moved:   ",
+            (false, Action::Copy) => "Copied:  ",
+        };
+        let count = if self.dry_run { plan.len() } else { done };
+        println!("{verb}{count}");
+        println!("In place: {in_place}");
+        println!("Failed:   {}", failed.len());
+
+        if !failed.is_empty() {
+            bail!("{} of {} files failed", failed.len(), files.len());
+        }
+        Ok(())
+    }
+}
+
+/// Work out where a book should go, or `None` if it is already there.
+///
+/// `taken` holds the targets planned so far, so two books with the same
+/// metadata don't end up with the same name.
+fn target(
+    file: &Path,
+    dest: &Path,
+    template: &Template,
+    taken: &mut HashSet<PathBuf>,
+) -> Result<Option<PathBuf>> {
+    let metadata = read_metadata(file)?;
+    let ctx = TemplateContext {
+        path: file,
+        metadata: &metadata,
+        path_regex: None,
+    };
+    let rendered = template.render_with(&ctx, |value| sanitize(&value))?;
+    let extension = Format::from_path(file).map(|f| f.extension());
+    let relative = relative_path(&rendered, extension)?;
+
+    let Some(target) = place(file, dest.join(relative), taken) else {
+        return Ok(None);
+    };
+    taken.insert(target.clone());
+    Ok(Some(target))
+}
+
+fn read_metadata(file: &Path) -> Result<Metadata> {
+    match detect_format(file)? {
+        Format::Epub | Format::Kepub => Ok(EpubBook::open(file)?.metadata()?),
+        Format::Mobi | Format::Azw3 => Ok(MobiBook::open(file)?.metadata()?),
+        format => bail!("Unsupported format: {format}"),
+    }
+}
+
+/// Make a metadata value safe to use in a file name.
+fn sanitize(value: &str) -> String {
+    // "Title: Subtitle" reads better as "Title - Subtitle" than with an
+    // underscore.
+    let cleaned: String = value
+        .replace(':', " -")
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect();
+    cleaned.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Turn a rendered template into a relative path, dropping components
+/// left empty and separators left dangling by missing fields.
+fn relative_path(rendered: &str, extension: Option<&str>) -> Result<PathBuf> {
+    let mut components: Vec<String> = rendered
+        .split('/')
+        .map(tidy)
+        .filter(|c| !c.is_empty())
+        .collect();
+
+    let Some(name) = components.pop() else {
+        bail!("Template rendered an empty path");
+    };
+    // Tidy the name without its extension, so "{title}.{ext}" with no
+    // title isn't left as just the extension.
+    let suffix = extension
+        .map(|ext| format!(".{ext}"))
+        .filter(|suffix| name.len() > suffix.len() && name.ends_with(suffix.as_str()));
+    let (stem, suffix) = match &suffix {
+        Some(suffix) => (tidy(&name[..name.len() - suffix.len()]), suffix.as_str()),
+        None => (name.clone(), ""),
+    };
+    if stem.is_empty() {
+        bail!("Template rendered an empty file name");
+    }
+    components.push(format!(
+        "{}{suffix}",
+        shorten(&stem, MAX_NAME_BYTES - suffix.len())
+    ));
+
+    Ok(components
+        .iter()
+        .map(|c| shorten(c, MAX_NAME_BYTES))
+        .collect())
+}
+
+/// Clean up one path component: drop empty brackets, collapse whitespace
+/// and trim separators from the ends. Leading dots are trimmed too, which
+/// rules out `..` and hidden files.
+fn tidy(component: &str) -> String {
+    let mut text = component.to_string();
+    for empty in ["()", "[]", "( )", "[ ]"] {
+        text = text.replace(empty, "");
+    }
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    text.trim_start_matches([' ', '-', '_', ',', '.'])
+        .trim_end_matches([' ', '-', '_', ','])
+        .to_string()
+}
+
+fn shorten(text: &str, max_bytes: usize) -> String {
+    let mut graphemes = max_bytes;
+    let mut short = text;
+    while short.len() > max_bytes {
+        graphemes = graphemes.saturating_sub(8);
+        short = truncate_graphemes(text, graphemes);
+    }
+    short.trim_end().to_string()
+}
+
+/// Find a free name for `file` at `target`, adding " (2)", " (3)", ...
+/// before the extension as needed. Returns `None` if the file already sits
+/// at the target or one of its numbered variants.
+fn place(file: &Path, target: PathBuf, taken: &HashSet<PathBuf>) -> Option<PathBuf> {
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = Format::from_path(&target)
+        .map(|f| format!(".{}", f.extension()))
+        .filter(|ext| name.len() > ext.len() && name.ends_with(ext.as_str()))
+        .unwrap_or_default();
+    let stem = &name[..name.len() - extension.len()];
+
+    let numbered = (2..).map(|n| target.with_file_name(format!("{stem} ({n}){extension}")));
+    for candidate in std::iter::once(target.clone()).chain(numbered) {
+        if same_file(file, &candidate) {
+            return None;
+        }
+        if !candidate.exists() && !taken.contains(&candidate) {
+            return Some(candidate);
+        }
+    }
+    unreachable!("numbered names are unbounded")
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Move or copy a file, creating the target's directories. Moves fall back
+/// to copy and delete across filesystems.
+fn transfer(from: &Path, to: &Path, action: Action) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    match action {
+        Action::Copy => {
+            fs::copy(from, to).with_context(|| format!("Failed to copy to {}", to.display()))?;
+        }
+        Action::Move => {
+            if fs::rename(from, to).is_err() {
+                fs::copy(from, to)
+                    .with_context(|| format!("Failed to move to {}", to.display()))?;
+                fs::remove_file(from)
+                    .with_context(|| format!("Failed to remove {}", from.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// Revert an undo log, newest entry first. Directories left empty below
+/// the log's directory are removed, and so is the log once every entry has
+/// been reverted.
+fn undo(log: &Path, dry_run: bool) -> Result<()> {
+    let text =
+        fs::read_to_string(log).with_context(|| format!("Failed to read {}", log.display()))?;
+    let entries = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<LogEntry>, _>>()
+        .with_context(|| format!("Invalid undo log: {}", log.display()))?;
+
+    let mut failed = 0;
+    for entry in entries.iter().rev() {
+        let (verb, result) = match entry.action {
+            Action::Move if entry.from.exists() => (
+                "Restore",
+                Err(anyhow::anyhow!("{} already exists", entry.from.display())),
+            ),
+            Action::Move if dry_run => ("Restore", Ok(())),
+            Action::Move => ("Restore", transfer(&entry.to, &entry.from, Action::Move)),
+            Action::Copy if dry_run => ("Remove", Ok(())),
+            Action::Copy => (
+                "Remove",
+                fs::remove_file(&entry.to)
+                    .with_context(|| format!("Failed to remove {}", entry.to.display())),
+            ),
+        };
+        if result.is_ok() && !dry_run {
+            prune(&entry.to, log);
+        }
+        match result {
+            Ok(()) if entry.action == Action::Move => {
+                println!("{verb} {} -> {}", entry.to.display(), entry.from.display());
+            }
+            Ok(()) => println!("{verb} {}", entry.to.display()),
+            Err(e) => {
+                eprintln!("Error: {}: {e:#}", entry.to.display());
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "{failed} of {} entries could not be reverted",
+            entries.len()
+        );
+    }
+    if !dry_run {
+        fs::remove_file(log).with_context(|| format!("Failed to remove {}", log.display()))?;
+    }
+    Ok(())
+}
+
+/// Remove the directories containing `file` while they are empty, stopping
+/// at the directory of the undo log.
+fn prune(file: &Path, log: &Path) {
+    let Some(root) = log.parent().map(absolute) else {
+        return;
+    };
+    let mut dir = file.parent();
+    while let Some(d) = dir {
+        if !d.starts_with(&root) || d == root || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
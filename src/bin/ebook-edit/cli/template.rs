@@ -0,0 +1,284 @@
+//! Value templates for batch editing, such as `{series} {series_index:02}`
+//! or `{path_capture:1}`.
+//!
+//! A template is literal text with `{field}` or `{field:arg}` placeholders;
+//! `{{` and `}}` stand for literal braces. Fields without a value render as
+//! an empty string.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use regex_lite::Regex;
+
+use ebook_tools::{author_sort_name, Format, Metadata};
+
+/// Placeholder names, with a short description for error messages.
+const FIELDS: &[(&str, &str)] = &[
+    ("title", "title"),
+    ("title_sort", "title sort name"),
+    ("author", "first author"),
+    ("authors", "all authors, joined by \" & \""),
+    ("author_sort", "sort name of the first author"),
+    ("series", "series name"),
+    (
+        "series_index",
+        "series index (format: `:02` pads to 2 digits)",
+    ),
+    ("publisher", "publisher"),
+    ("language", "language tag"),
+    ("isbn", "ISBN"),
+    ("publication_date", "publication date"),
+    ("year", "publication year"),
+    ("path", "the file path"),
+    ("file_name", "the file name"),
+    ("stem", "the file name without extension"),
+    ("ext", "the file extension"),
+    ("dir", "name of the containing directory"),
+    (
+        "path_capture",
+        "a capture group of --path-regex, e.g. `:1` or `:name`",
+    ),
+];
+
+/// A parsed value template.
+#[derive(Debug, Clone)]
+pub(super) struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field { name: String, arg: Option<String> },
+}
+
+/// What a template is rendered against.
+pub(super) struct TemplateContext<'a> {
+    pub path: &'a Path,
+    pub metadata: &'a Metadata,
+    pub path_regex: Option<&'a Regex>,
+}
+
+impl Template {
+    /// Whether the template contains placeholders.
+    pub fn has_fields(&self) -> bool {
+        self.parts.iter().any(|p| matches!(p, Part::Field { .. }))
+    }
+
+    /// Whether the template refers to `--path-regex` captures.
+    pub fn uses_path_captures(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|p| matches!(p, Part::Field { name, .. } if name == "path_capture"))
+    }
+
+    pub fn render(&self, ctx: &TemplateContext) -> Result<String> {
+        self.render_with(ctx, |value| value)
+    }
+
+    /// Render, passing each field value through `escape`; literal text is
+    /// kept as it is.
+    pub fn render_with(
+        &self,
+        ctx: &TemplateContext,
+        escape: impl Fn(String) -> String,
+    ) -> Result<String> {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Field { name, arg } => {
+                    out.push_str(&escape(field(ctx, name, arg.as_deref())?));
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => bail!("Unclosed '{{' in template: {s}"),
+                        }
+                    }
+                    let (name, arg) = match placeholder.split_once(':') {
+                        Some((name, arg)) => (name.trim(), Some(arg.trim().to_string())),
+                        None => (placeholder.trim(), None),
+                    };
+                    check_field(name, arg.as_deref())?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field {
+                        name: name.to_string(),
+                        arg,
+                    });
+                }
+                '}' => bail!("Unmatched '}}' in template: {s} (write '}}}}' for a brace)"),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+}
+
+fn check_field(name: &str, arg: Option<&str>) -> Result<()> {
+    if !FIELDS.iter().any(|(f, _)| *f == name) {
+        let known: Vec<String> = FIELDS.iter().map(|(f, d)| format!("  {f}: {d}")).collect();
+        bail!(
+            "Unknown template field: {{{name}}}\nAvailable fields:\n{}",
+            known.join("\n")
+        );
+    }
+    match (name, arg) {
+        ("path_capture", None) => bail!("{{path_capture}} needs a group, e.g. {{path_capture:1}}"),
+        ("path_capture", Some(_)) | (_, None) => Ok(()),
+        ("series_index" | "year", Some(spec)) => parse_width(spec).map(|_| ()),
+        (_, Some(_)) => bail!("{{{name}}} doesn't take a format"),
+    }
+}
+
+/// Parse a number format such as `02` (zero-pad to two digits) or `3`
+/// (pad with spaces), returning the width and whether to pad with zeros.
+fn parse_width(spec: &str) -> Result<(usize, bool)> {
+    let width = spec
+        .parse()
+        .map_err(|_| anyhow!("Invalid number format: {spec} (expected e.g. 02)"))?;
+    Ok((width, spec.starts_with('0')))
+}
+
+fn field(ctx: &TemplateContext, name: &str, arg: Option<&str>) -> Result<String> {
+    let m = ctx.metadata;
+    let text = |v: &Option<String>| v.clone().unwrap_or_default();
+
+    let value = match name {
+        "title" => text(&m.title),
+        "title_sort" => text(&m.title_sort),
+        "author" => m.authors.first().cloned().unwrap_or_default(),
+        "authors" => m.authors.join(" & "),
+        "author_sort" => match m.authors.first() {
+            Some(author) => m
+                .author_sort
+                .get(author)
+                .cloned()
+                .unwrap_or_else(|| author_sort_name(author)),
+            None => String::new(),
+        },
+        "series" => text(&m.series),
+        "series_index" => m.series_index.map(format_index).unwrap_or_default(),
+        "publisher" => text(&m.publisher),
+        "language" => text(&m.language),
+        "isbn" => text(&m.isbn),
+        "publication_date" => m
+            .publication_date
+            .map(|d| d.to_string())
+            .unwrap_or_default(),
+        "year" => m
+            .publication_date
+            .map(|d| d.year().to_string())
+            .unwrap_or_default(),
+        "path" => ctx.path.display().to_string(),
+        "file_name" => file_name(ctx.path).to_string(),
+        "stem" => {
+            let name = file_name(ctx.path);
+            let ext = extension(ctx.path);
+            name.strip_suffix(&format!(".{ext}"))
+                .unwrap_or(name)
+                .to_string()
+        }
+        "ext" => extension(ctx.path),
+        "dir" => ctx
+            .path
+            .parent()
+            .and_then(Path::file_name)
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        "path_capture" => path_capture(ctx, arg.unwrap_or_default())?,
+        _ => bail!("Unknown template field: {{{name}}}"),
+    };
+
+    match arg {
+        Some(spec) if name != "path_capture" && !value.is_empty() => {
+            let (width, zeros) = parse_width(spec)?;
+            // Only the integer part is padded, so 2.5 becomes "02.5".
+            let int_len = value.find('.').unwrap_or(value.len());
+            let fill = if zeros { "0" } else { " " };
+            Ok(format!(
+                "{}{value}",
+                fill.repeat(width.saturating_sub(int_len))
+            ))
+        }
+        _ => Ok(value),
+    }
+}
+
+/// Format a series index without a trailing `.0` for whole numbers.
+pub(super) fn format_index(index: f64) -> String {
+    if index.fract() == 0.0 {
+        format!("{index:.0}")
+    } else {
+        index.to_string()
+    }
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+}
+
+/// The file's extension, keeping compound extensions like `kepub.epub`.
+fn extension(path: &Path) -> String {
+    match Format::from_path(path) {
+        Some(format) => format.extension().to_string(),
+        None => path
+            .extension()
+            .map(|e| e.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+fn path_capture(ctx: &TemplateContext, group: &str) -> Result<String> {
+    let Some(regex) = ctx.path_regex else {
+        bail!("{{path_capture:{group}}} needs --path-regex");
+    };
+    let path = ctx.path.to_string_lossy();
+    let Some(captures) = regex.captures(&path) else {
+        bail!("Path doesn't match --path-regex");
+    };
+
+    let capture = match group.parse::<usize>() {
+        Ok(n) if n < regex.captures_len() => captures.get(n),
+        Ok(n) => bail!("--path-regex has no capture group {n}"),
+        Err(_) if regex.capture_names().flatten().any(|g| g == group) => captures.name(group),
+        Err(_) => bail!("--path-regex has no capture group named {group}"),
+    };
+    Ok(capture.map(|c| c.as_str().to_string()).unwrap_or_default())
+}
@@ -3,7 +3,10 @@ use std::path::PathBuf;
 use anyhow::{bail, Result};
 use clap::Parser;
 
-use ebook_tools::{DrmDetector, EpubBook, Format, MetadataProvider};
+use ebook_tools::{
+    metadata_from_path, normalize_language, truncate_graphemes, Description, DrmDetector, EpubBook,
+    FilenamePattern, Format, Isbn, IsbnError, KindleFile, Metadata, MetadataProvider, MobiBook,
+};
 
 /// ebook-info: Display information about an ebook file.
 #[derive(Parser, Debug)]
@@ -16,18 +19,53 @@ pub struct Cli {
     /// Increase verbosity (-v, -vv, -vvv).
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
+
+    /// Print the metadata as JSON instead of a summary.
+    #[arg(long)]
+    json: bool,
+
+    /// Also show the metadata guessed from the file name and folders.
+    #[arg(long)]
+    from_filename: bool,
+
+    /// A pattern to try before the built-in ones for `--from-filename`
+    /// (repeatable), e.g. "{author} - {series} {series_index} - {title}".
+    #[arg(long, value_name = "PATTERN", requires = "from_filename")]
+    filename_pattern: Vec<FilenamePattern>,
 }
 
 impl Cli {
     pub fn execute(self) -> Result<()> {
-        let format = Format::from_path(&self.file);
-
-        let Some(format) = format else {
-            bail!("Unknown ebook format: {}", self.file.display());
-        };
+        let (format, warning) = Format::sniff_with_warning(&self.file)?;
+        if let Some(warning) = warning {
+            eprintln!("Warning: {warning}");
+        }
 
         match format {
-            Format::Epub | Format::Kepub => self.show_epub()?,
+            Format::Epub | Format::Kepub => {
+                let book = EpubBook::open(&self.file)?;
+                self.show(
+                    &book,
+                    book.format(),
+                    book.epub_version().map(str::to_string),
+                    book.cover_info().map(|info| info.size),
+                    book.warnings(),
+                )?;
+            }
+            Format::Mobi | Format::Azw3 => {
+                let book = MobiBook::open(&self.file)?;
+                self.show(
+                    &book,
+                    book.format(),
+                    Some(book.mobi_version().to_string()),
+                    book.cover_info().map(|info| info.size),
+                    book.warnings(),
+                )?;
+            }
+            Format::Topaz | Format::Kfx | Format::KfxZip => {
+                let book = KindleFile::open(&self.file)?;
+                self.show(&book, book.format(), None, None, book.warnings())?;
+            }
             _ => {
                 bail!("Unsupported format: {format}");
             }
@@ -36,19 +74,38 @@ impl Cli {
         Ok(())
     }
 
-    fn show_epub(&self) -> Result<()> {
-        let book = EpubBook::open(&self.file)?;
-
+    fn show(
+        &self,
+        book: &(impl MetadataProvider + DrmDetector),
+        format: Format,
+        version: Option<String>,
+        cover_size: Option<u64>,
+        warnings: &[String],
+    ) -> Result<()> {
         let metadata = book.metadata()?;
+        let guessed = self
+            .from_filename
+            .then(|| metadata_from_path(&self.file, &self.filename_pattern))
+            .flatten();
+        if self.json {
+            if self.from_filename {
+                let both = serde_json::json!({
+                    "embedded": metadata,
+                    "from_filename": guessed,
+                });
+                println!("{}", serde_json::to_string_pretty(&both)?);
+            } else {
+                println!("{}", serde_json::to_string_pretty(&metadata)?);
+            }
+            return Ok(());
+        }
+
         let drm = book.drm_status()?;
 
         // File info
         println!("File:      {}", self.file.display());
-        let version_suffix = book
-            .epub_version()
-            .map(|v| format!(" {v}"))
-            .unwrap_or_default();
-        println!("Format:    {}{version_suffix}", book.format());
+        let version_suffix = version.map(|v| format!(" {v}")).unwrap_or_default();
+        println!("Format:    {format}{version_suffix}");
         println!("DRM:       {drm}");
         println!();
 
@@ -60,29 +117,52 @@ impl Cli {
             println!("Authors:   {}", metadata.authors.join(", "));
         }
         if let Some(ref language) = metadata.language {
-            println!("Language:  {language}");
+            match normalize_language(language) {
+                Some(tag) if tag != *language => println!("Language:  {language} ({tag})"),
+                _ => println!("Language:  {language}"),
+            }
         }
         if let Some(ref publisher) = metadata.publisher {
             println!("Publisher: {publisher}");
         }
         if let Some(ref date) = metadata.publication_date {
-            println!("Date:      {date}");
+            println!("Published: {date}");
+        }
+        if let Some(ref date) = metadata.creation_date {
+            println!("Created:   {date}");
+        }
+        if let Some(ref date) = metadata.modification_date {
+            println!("Modified:  {date}");
         }
         if let Some(ref isbn) = metadata.isbn {
-            println!("ISBN:      {isbn}");
+            match Isbn::parse(isbn) {
+                Ok(valid) => {
+                    let display = valid.hyphenated().unwrap_or_else(|| valid.to_string());
+                    println!("ISBN:      {display}");
+                }
+                Err(IsbnError::CheckDigit { expected, .. }) => {
+                    println!("ISBN:      {isbn} (bad check digit, expected {expected})");
+                }
+                Err(IsbnError::Malformed(_)) => println!("ISBN:      {isbn} (not a valid ISBN)"),
+            }
+        }
+        if let Some(asin) = metadata.identifiers.get("asin") {
+            println!("ASIN:      {asin}");
         }
         if let Some(ref description) = metadata.description {
-            // Truncate long descriptions
-            let desc = if description.len() > 200 {
-                format!("{}...", &description[..200])
-            } else {
-                description.clone()
-            };
-            println!("Desc:      {desc}");
+            // Show long descriptions as a single truncated line of plain text
+            let text = Description::parse(description).to_text();
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            let short = truncate_graphemes(&text, 200).trim_end();
+            let ellipsis = if short.len() < text.len() { "..." } else { "" };
+            println!("Desc:      {short}{ellipsis}");
         }
         if !metadata.subjects.is_empty() {
             println!("Subjects:  {}", metadata.subjects.join(", "));
         }
+        if !metadata.tags.is_empty() {
+            println!("Tags:      {}", metadata.tags.join(", "));
+        }
         if let Some(ref series) = metadata.series {
             let idx = metadata
                 .series_index
@@ -90,17 +170,27 @@ impl Cli {
                 .unwrap_or_default();
             println!("Series:    {series}{idx}");
         }
+        if let Some(rating) = metadata.rating {
+            println!("Rating:    {rating}/5");
+        }
+
+        if self.verbose > 0 {
+            show_custom(&metadata);
+        }
+
+        if self.from_filename {
+            show_guessed(&metadata, guessed.as_ref());
+        }
 
         // Cover
         println!();
-        if let Some(info) = book.cover_info() {
-            println!("Cover:     Yes ({} bytes)", info.size);
+        if let Some(size) = cover_size {
+            println!("Cover:     Yes ({size} bytes)");
         } else {
             println!("Cover:     No");
         }
 
         // Warnings
-        let warnings = book.warnings();
         if !warnings.is_empty() {
             println!();
             println!("Warnings:");
@@ -112,3 +202,74 @@ impl Cli {
         Ok(())
     }
 }
+
+/// Print custom `<meta>` fields and Calibre custom column values.
+fn show_custom(metadata: &Metadata) {
+    let columns: Vec<(&String, String)> = metadata
+        .user_metadata
+        .iter()
+        .filter_map(|(name, column)| {
+            let value = match column.get("#value#")? {
+                serde_json::Value::Null => return None,
+                serde_json::Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            Some((name, value))
+        })
+        .collect();
+    if metadata.custom.is_empty() && columns.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("Custom fields:");
+    for (name, value) in &metadata.custom {
+        println!("  {name}: {value}");
+    }
+    for (name, value) in columns {
+        println!("  {name}: {value}  (Calibre column)");
+    }
+}
+
+/// Print metadata guessed from the file name, noting where it differs from
+/// the embedded metadata.
+fn show_guessed(embedded: &Metadata, guessed: Option<&Metadata>) {
+    println!();
+    let Some(guessed) = guessed else {
+        println!("From filename: nothing recognized");
+        return;
+    };
+
+    println!("From filename:");
+    let series = |m: &Metadata| {
+        m.series.as_ref().map(|s| match m.series_index {
+            Some(i) => format!("{s} #{i}"),
+            None => s.clone(),
+        })
+    };
+    let authors = |m: &Metadata| (!m.authors.is_empty()).then(|| m.authors.join(", "));
+    let fields = [
+        ("Title:    ", guessed.title.clone(), embedded.title.clone()),
+        ("Authors:  ", authors(guessed), authors(embedded)),
+        ("Series:   ", series(guessed), series(embedded)),
+        (
+            "Published:",
+            guessed.publication_date.map(|d| d.to_string()),
+            embedded.publication_date.map(|d| d.to_string()),
+        ),
+        (
+            "Publisher:",
+            guessed.publisher.clone(),
+            embedded.publisher.clone(),
+        ),
+        ("ISBN:     ", guessed.isbn.clone(), embedded.isbn.clone()),
+    ];
+    for (label, guess, current) in fields {
+        let Some(guess) = guess else { continue };
+        match current {
+            Some(current) if current == guess => println!("  {label} {guess}"),
+            Some(current) => println!("  {label} {guess}  (embedded: {current})"),
+            None => println!("  {label} {guess}  (not embedded)"),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Calibre library sidecar files.
+//!
+//! Calibre keeps each book in its own folder, next to a `metadata.opf` with
+//! the library's copy of the metadata and a `cover.jpg`. The sidecar is often
+//! richer than what is embedded in the book itself, so it can be read and
+//! applied to a book, or generated from one.
+
+use std::path::Path;
+
+use crate::epub::{self, opf::OpfDocument};
+use crate::{CoverProvider, CoverWriter, Error, Metadata, MetadataProvider, MetadataWriter};
+
+/// Name of the metadata file in a Calibre book folder.
+pub const METADATA_FILE: &str = "metadata.opf";
+
+/// Name of the cover image in a Calibre book folder.
+pub const COVER_FILE: &str = "cover.jpg";
+
+/// The metadata and cover Calibre stores alongside a book.
+#[derive(Debug, Clone, Default)]
+pub struct Sidecar {
+    pub metadata: Metadata,
+    /// Raw bytes of the cover image, if there is one.
+    pub cover: Option<Vec<u8>>,
+}
+
+impl Sidecar {
+    /// Read `metadata.opf` and, if present, `cover.jpg` from a book folder.
+    pub fn read(dir: &Path) -> crate::Result<Sidecar> {
+        let opf_path = dir.join(METADATA_FILE);
+        let xml = std::fs::read_to_string(&opf_path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Error::FileNotFound(opf_path.clone())
+            } else {
+                Error::Io(e)
+            }
+        })?;
+        let metadata = epub::metadata_from_opf(&xml)?;
+
+        let cover = match std::fs::read(dir.join(COVER_FILE)) {
+            Ok(data) => Some(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Sidecar { metadata, cover })
+    }
+
+    /// Read the sidecar from the folder containing `book_path`.
+    pub fn for_book(book_path: &Path) -> crate::Result<Sidecar> {
+        let dir = book_path.parent().unwrap_or(Path::new("."));
+        Sidecar::read(dir)
+    }
+
+    /// Build a sidecar from a book's embedded metadata and cover.
+    pub fn from_book<B: MetadataProvider + CoverProvider>(book: &B) -> crate::Result<Sidecar> {
+        Ok(Sidecar {
+            metadata: book.metadata()?,
+            cover: book.cover()?,
+        })
+    }
+
+    /// Replace a book's embedded metadata (and cover, if the sidecar has one)
+    /// with the sidecar's.
+    pub fn apply_to<B: MetadataWriter + CoverWriter>(&self, book: &mut B) -> crate::Result<()> {
+        book.set_metadata(&self.metadata)?;
+        if let Some(cover) = &self.cover {
+            book.set_cover(cover)?;
+        }
+        Ok(())
+    }
+
+    /// Write `metadata.opf` and, if there is a cover, `cover.jpg` into a
+    /// book folder.
+    ///
+    /// The cover is written as-is; Calibre expects a JPEG.
+    pub fn write(&self, dir: &Path) -> crate::Result<()> {
+        std::fs::write(dir.join(METADATA_FILE), self.to_opf())?;
+        if let Some(cover) = &self.cover {
+            std::fs::write(dir.join(COVER_FILE), cover)?;
+        }
+        Ok(())
+    }
+
+    /// Render the sidecar's `metadata.opf` in the layout Calibre writes.
+    pub fn to_opf(&self) -> String {
+        let has_uuid = self.metadata.identifiers.contains_key("uuid");
+        let unique_identifier = if has_uuid {
+            " unique-identifier=\"uuid_id\""
+        } else {
+            ""
+        };
+        let guide = if self.cover.is_some() {
+            format!(
+                "    <guide>\n        <reference type=\"cover\" title=\"Cover\" href=\"{COVER_FILE}\"/>\n    </guide>\n"
+            )
+        } else {
+            String::new()
+        };
+        let template = format!(
+            "<?xml version='1.0' encoding='utf-8'?>\n\
+             <package xmlns=\"http://www.idpf.org/2007/opf\"{unique_identifier} version=\"2.0\">\n    \
+             <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">\n    \
+             </metadata>\n\
+             {guide}\
+             </package>\n"
+        );
+
+        let mut opf = OpfDocument::parse(template).expect("sidecar template is valid XML");
+        opf.update_metadata(&self.metadata);
+
+        // Calibre looks its own identifiers up by id.
+        for el in &mut opf.metadata {
+            if el.local_name() != "identifier" {
+                continue;
+            }
+            match el.attr("scheme") {
+                Some("uuid") => el.attrs.push(("id".into(), "uuid_id".into())),
+                Some("calibre") => el.attrs.push(("id".into(), "calibre_id".into())),
+                _ => {}
+            }
+        }
+
+        opf.to_xml()
+    }
+}
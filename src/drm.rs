@@ -16,7 +16,14 @@ pub enum DrmStatus {
 pub enum DrmScheme {
     AdobeAdept,
     KoboProtected,
+    /// The original Mobipocket encryption of MOBI books.
+    Mobipocket,
+    /// Kindle encryption of MOBI and AZW3 books, keyed to a device's PID.
     AmazonKindle,
+    /// Encrypted records of a Topaz book.
+    AmazonTopaz,
+    /// KFX containers wrapped in DRMION, unlocked by a voucher.
+    AmazonKfx,
     Other(String),
 }
 
@@ -35,7 +42,10 @@ impl fmt::Display for DrmScheme {
         match self {
             DrmScheme::AdobeAdept => write!(f, "Adobe ADEPT"),
             DrmScheme::KoboProtected => write!(f, "Kobo Protected"),
+            DrmScheme::Mobipocket => write!(f, "Mobipocket"),
             DrmScheme::AmazonKindle => write!(f, "Amazon Kindle"),
+            DrmScheme::AmazonTopaz => write!(f, "Amazon Topaz"),
+            DrmScheme::AmazonKfx => write!(f, "Amazon KFX"),
             DrmScheme::Other(name) => write!(f, "{name}"),
         }
     }
@@ -1,15 +1,22 @@
-use std::io::{Read, Seek};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
 
 use quick_xml::events::Event;
 use quick_xml::Reader;
-use zip::ZipArchive;
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
 
 use crate::{
-    BookReader, CoverProvider, DrmDetector, DrmScheme, DrmStatus, Error, Format, Metadata,
-    MetadataProvider,
+    atomic, image, normalize_language, BookReader, CoverProvider, CoverWriter, DrmDetector,
+    DrmScheme, DrmStatus, Error, Format, Metadata, MetadataProvider, MetadataWriter,
 };
 
+pub(crate) mod opf;
+
+use opf::{ManifestItem, OpfDocument};
+
 /// Information about a cover image found in the EPUB.
 #[derive(Debug, Clone)]
 pub struct CoverInfo {
@@ -33,7 +40,10 @@ pub struct EpubBook {
 impl EpubBook {
     /// Open and parse an EPUB file at the given path.
     pub fn open(path: &Path) -> crate::Result<Self> {
-        let format = Format::from_path(path).ok_or_else(|| Error::UnknownFormat(path.into()))?;
+        let format = Format::from_file(path)?;
+        if !matches!(format, Format::Epub | Format::Kepub) {
+            return Err(Error::UnsupportedFormat(format));
+        }
 
         let file = std::fs::File::open(path).map_err(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
@@ -108,6 +118,79 @@ impl MetadataProvider for EpubBook {
     }
 }
 
+impl MetadataWriter for EpubBook {
+    fn set_metadata(&mut self, metadata: &Metadata) -> crate::Result<()> {
+        let mut zip = open_archive(&self.path)?;
+        let mut warnings = Vec::new();
+        let opf_path = parse_container(&mut zip, &mut warnings)?;
+        let mut opf = read_opf(&mut zip, &opf_path, &mut warnings)?;
+        drop(zip);
+
+        if !opf.has_metadata_section() {
+            return Err(Error::InvalidBook("OPF has no <metadata> element".into()));
+        }
+
+        opf.update_metadata(metadata);
+        opf.touch_modified();
+
+        let mut replacements = BTreeMap::new();
+        replacements.insert(opf_path, opf.to_xml().into_bytes());
+        rewrite_archive(&self.path, &replacements)?;
+
+        // Re-read so metadata, cover info and warnings reflect the new file.
+        *self = EpubBook::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl CoverWriter for EpubBook {
+    fn set_cover(&mut self, image_data: &[u8]) -> crate::Result<()> {
+        let media_type = image::media_type(image_data).ok_or(Error::UnsupportedImage)?;
+
+        let mut zip = open_archive(&self.path)?;
+        let mut warnings = Vec::new();
+        let opf_path = parse_container(&mut zip, &mut warnings)?;
+        let mut opf = read_opf(&mut zip, &opf_path, &mut warnings)?;
+        drop(zip);
+
+        let opf_dir = opf_dir(&opf_path);
+        let mut replacements = BTreeMap::new();
+
+        match &self.cover_info {
+            Some(info) => {
+                // Replace the existing image in place, updating its media
+                // type if the image format changed.
+                let id = opf
+                    .manifest
+                    .iter()
+                    .find(|item| {
+                        format!("{opf_dir}{}", item.href) == info.href || item.href == info.href
+                    })
+                    .map(|item| item.id.clone());
+                if let Some(id) = id {
+                    opf.set_media_type(&id, media_type);
+                }
+                replacements.insert(info.href.clone(), image_data.to_vec());
+            }
+            None => {
+                let id = opf.unused_manifest_id("cover-image");
+                let href = format!("{id}.{}", image::extension(media_type));
+                let properties = opf.is_epub3().then_some("cover-image");
+                opf.add_manifest_item(ManifestItem::new(&id, &href, media_type, properties));
+                opf.set_named_meta("cover", Some(&id));
+                replacements.insert(format!("{opf_dir}{href}"), image_data.to_vec());
+            }
+        }
+        opf.touch_modified();
+
+        replacements.insert(opf_path, opf.to_xml().into_bytes());
+        rewrite_archive(&self.path, &replacements)?;
+
+        *self = EpubBook::open(&self.path)?;
+        Ok(())
+    }
+}
+
 impl DrmDetector for EpubBook {
     fn drm_status(&self) -> crate::Result<DrmStatus> {
         Ok(self.drm_status.clone())
@@ -121,9 +204,7 @@ impl CoverProvider for EpubBook {
             None => return Ok(None),
         };
 
-        let file = std::fs::File::open(&self.path)?;
-        let mut zip = ZipArchive::new(file)
-            .map_err(|e| Error::InvalidBook(format!("not a valid ZIP archive: {e}")))?;
+        let mut zip = open_archive(&self.path)?;
 
         let href = &cover_info.href;
         let mut entry = zip
@@ -136,6 +217,14 @@ impl CoverProvider for EpubBook {
     }
 }
 
+/// Parse the metadata from a standalone OPF package document, such as one
+/// extracted from another book.
+pub fn metadata_from_opf(xml: &str) -> crate::Result<Metadata> {
+    let opf = OpfDocument::parse(xml.to_string())
+        .map_err(|e| Error::InvalidBook(format!("failed to parse OPF: {e}")))?;
+    Ok(opf.to_metadata())
+}
+
 // ---------------------------------------------------------------------------
 // Internal helpers
 // ---------------------------------------------------------------------------
@@ -166,20 +255,19 @@ fn validate_mimetype<R: Read + Seek>(zip: &mut ZipArchive<R>, warnings: &mut Vec
     let mut contents = String::new();
     // Need to drop the borrow and re-read
     drop(entry);
-    if let Ok(mut entry) = zip.by_name("mimetype") {
-        if entry.read_to_string(&mut contents).is_ok() {
-            if contents.trim() != "application/epub+zip" {
-                warnings.push(format!(
-                    "mimetype file: expected 'application/epub+zip', got '{}'",
-                    contents.trim()
-                ));
-            }
-        }
+    if let Ok(mut entry) = zip.by_name("mimetype")
+        && entry.read_to_string(&mut contents).is_ok()
+        && contents.trim() != "application/epub+zip"
+    {
+        warnings.push(format!(
+            "mimetype file: expected 'application/epub+zip', got '{}'",
+            contents.trim()
+        ));
     }
 }
 
 /// Parse META-INF/container.xml to find the OPF path.
-fn parse_container<R: Read + Seek>(
+pub(crate) fn parse_container<R: Read + Seek>(
     zip: &mut ZipArchive<R>,
     warnings: &mut Vec<String>,
 ) -> crate::Result<String> {
@@ -222,12 +310,12 @@ fn parse_container<R: Read + Seek>(
     ))
 }
 
-/// Parse the OPF file to extract the EPUB version, metadata, and cover info.
-fn parse_opf<R: Read + Seek>(
+/// Read and parse the OPF package document.
+pub(crate) fn read_opf<R: Read + Seek>(
     zip: &mut ZipArchive<R>,
     opf_path: &str,
     warnings: &mut Vec<String>,
-) -> crate::Result<(Option<String>, Metadata, Option<CoverInfo>)> {
+) -> crate::Result<OpfDocument> {
     let mut entry = zip.by_name(opf_path).map_err(|_| {
         warnings.push(format!("OPF file not found in ZIP: {opf_path}"));
         Error::InvalidBook(format!("OPF file not found: {opf_path}"))
@@ -237,179 +325,68 @@ fn parse_opf<R: Read + Seek>(
     entry.read_to_string(&mut xml_content)?;
     drop(entry);
 
-    // Compute base directory of OPF for resolving relative hrefs
-    let opf_dir = match opf_path.rfind('/') {
-        Some(i) => &opf_path[..=i],
-        None => "",
-    };
-
-    let mut reader = Reader::from_str(&xml_content);
-
-    let mut epub_version: Option<String> = None;
-    let mut metadata = Metadata::default();
-    let mut in_metadata = false;
-    let mut current_element: Option<String> = None;
-    let mut current_text = String::new();
+    OpfDocument::parse(xml_content).map_err(|e| {
+        warnings.push(format!("OPF parse error: {e}"));
+        Error::InvalidBook(format!("failed to parse OPF: {e}"))
+    })
+}
 
-    // Cover detection: meta name="cover" content="item-id"
-    let mut cover_meta_id: Option<String> = None;
-    // Manifest items: id -> (href, properties)
-    let mut manifest_items: Vec<(String, String, Option<String>)> = Vec::new();
-    let mut in_manifest = false;
+/// Parse the OPF file to extract the EPUB version, metadata, and cover info.
+fn parse_opf<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    opf_path: &str,
+    warnings: &mut Vec<String>,
+) -> crate::Result<(Option<String>, Metadata, Option<CoverInfo>)> {
+    let opf = read_opf(zip, opf_path, warnings)?;
+    let metadata = opf.to_metadata();
 
-    loop {
-        match reader.read_event() {
-            Ok(Event::Start(ref e)) => {
-                let name = e.name();
-                let local = local_name(name.as_ref());
-                match local {
-                    b"package" => {
-                        for attr in e.attributes().flatten() {
-                            if attr.key.as_ref() == b"version" {
-                                epub_version = Some(
-                                    String::from_utf8_lossy(&attr.value).into_owned(),
-                                );
-                            }
-                        }
-                    }
-                    b"metadata" => in_metadata = true,
-                    b"manifest" => in_manifest = true,
-                    _ if in_metadata => {
-                        current_element = Some(String::from_utf8_lossy(local).into_owned());
-                        current_text.clear();
-                    }
-                    _ => {}
-                }
-            }
-            Ok(Event::End(ref e)) => {
-                let name = e.name();
-                let local = local_name(name.as_ref());
-                match local {
-                    b"metadata" => in_metadata = false,
-                    b"manifest" => in_manifest = false,
-                    _ if in_metadata => {
-                        if let Some(ref elem) = current_element {
-                            let text = current_text.trim().to_string();
-                            if !text.is_empty() {
-                                match elem.as_str() {
-                                    "title" => metadata.title = Some(text),
-                                    "creator" => metadata.authors.push(text),
-                                    "description" => metadata.description = Some(text),
-                                    "publisher" => metadata.publisher = Some(text),
-                                    "language" => metadata.language = Some(text),
-                                    "identifier" => {
-                                        // Try to detect ISBN
-                                        if metadata.isbn.is_none() && looks_like_isbn(&text) {
-                                            metadata.isbn = Some(text);
-                                        }
-                                    }
-                                    "date" => metadata.publication_date = Some(text),
-                                    "subject" => metadata.subjects.push(text),
-                                    _ => {}
-                                }
-                            }
-                        }
-                        current_element = None;
-                    }
-                    _ => {}
-                }
-            }
-            Ok(Event::Empty(ref e)) => {
-                let ename = e.name();
-                let local = local_name(ename.as_ref());
-                if local == b"meta" && in_metadata {
-                    let mut name = None;
-                    let mut content = None;
-                    for attr in e.attributes().flatten() {
-                        match attr.key.as_ref() {
-                            b"name" => {
-                                name = Some(String::from_utf8_lossy(&attr.value).into_owned())
-                            }
-                            b"content" => {
-                                content = Some(String::from_utf8_lossy(&attr.value).into_owned())
-                            }
-                            _ => {}
-                        }
-                    }
-                    if let (Some(n), Some(c)) = (name, content) {
-                        match n.as_str() {
-                            "cover" => cover_meta_id = Some(c),
-                            "calibre:series" => metadata.series = Some(c),
-                            "calibre:series_index" => {
-                                metadata.series_index = c.parse().ok();
-                            }
-                            _ => {}
-                        }
-                    }
-                } else if local == b"item" && in_manifest {
-                    let mut id = String::new();
-                    let mut href = String::new();
-                    let mut properties = None;
-                    for attr in e.attributes().flatten() {
-                        match attr.key.as_ref() {
-                            b"id" => {
-                                id = String::from_utf8_lossy(&attr.value).into_owned();
-                            }
-                            b"href" => {
-                                href = String::from_utf8_lossy(&attr.value).into_owned();
-                            }
-                            b"properties" => {
-                                properties =
-                                    Some(String::from_utf8_lossy(&attr.value).into_owned());
-                            }
-                            _ => {}
-                        }
-                    }
-                    if !id.is_empty() {
-                        manifest_items.push((id, href, properties));
-                    }
-                }
-            }
-            Ok(Event::Text(ref e)) => {
-                if current_element.is_some() {
-                    if let Ok(text) = e.unescape() {
-                        current_text.push_str(&text);
-                    }
-                }
-            }
-            Ok(Event::Eof) => break,
-            Err(e) => {
-                warnings.push(format!("OPF parse error: {e}"));
-                return Err(Error::InvalidBook(format!("failed to parse OPF: {e}")));
-            }
-            _ => {}
-        }
-    }
+    // Compute base directory of OPF for resolving relative hrefs
+    let opf_dir = opf_dir(opf_path);
 
     // Validate required metadata
     if metadata.title.is_none() {
         warnings.push("OPF: missing required <dc:title>".into());
     }
-    if metadata.language.is_none() {
-        warnings.push("OPF: missing required <dc:language>".into());
+    match &metadata.language {
+        None => warnings.push("OPF: missing required <dc:language>".into()),
+        Some(language) if normalize_language(language).is_none() => {
+            warnings.push(format!("OPF: invalid language tag: {language:?}"));
+        }
+        Some(_) => {}
+    }
+    if !opf
+        .metadata
+        .iter()
+        .any(|el| el.local_name() == "identifier")
+    {
+        warnings.push("OPF: missing required <dc:identifier>".into());
     }
-
-    // Check for dc:identifier (we only stored ISBN-looking ones, but we should warn if none at all)
-    // Re-check by looking at whether we found any identifier element
-    // (we'll do a simpler check: if no ISBN was found, that's fine, but we need at least one identifier)
-    // For simplicity, we already parse identifiers above. Let's track if we saw any.
-    // Actually, we need to re-check. Let's just warn if no ISBN - the plan says dc:identifier is required.
-    // We'll handle this by noting we may have skipped non-ISBN identifiers.
 
     // Detect cover image
-    let cover_info = detect_cover(zip, opf_dir, &cover_meta_id, &manifest_items, warnings);
+    let cover_meta_id = opf.named_meta("cover").map(str::to_string);
+    let cover_info = detect_cover(zip, opf_dir, &cover_meta_id, &opf.manifest, warnings);
 
     // Validate manifest items reference files in ZIP
-    for (id, href, _) in &manifest_items {
-        let full_path = format!("{opf_dir}{href}");
-        if zip.by_name(&full_path).is_err() && zip.by_name(href).is_err() {
+    for item in &opf.manifest {
+        let full_path = format!("{opf_dir}{}", item.href);
+        if zip.by_name(&full_path).is_err() && zip.by_name(&item.href).is_err() {
             warnings.push(format!(
-                "manifest item '{id}' references '{href}' which is not in the ZIP"
+                "manifest item '{}' references '{}' which is not in the ZIP",
+                item.id, item.href
             ));
         }
     }
 
-    Ok((epub_version, metadata, cover_info))
+    Ok((opf.version, metadata, cover_info))
+}
+
+/// The directory part of the OPF path (with trailing slash), used to resolve
+/// manifest hrefs.
+pub(crate) fn opf_dir(opf_path: &str) -> &str {
+    match opf_path.rfind('/') {
+        Some(i) => &opf_path[..=i],
+        None => "",
+    }
 }
 
 /// Detect cover image from manifest items.
@@ -417,14 +394,14 @@ fn detect_cover<R: Read + Seek>(
     zip: &mut ZipArchive<R>,
     opf_dir: &str,
     cover_meta_id: &Option<String>,
-    manifest_items: &[(String, String, Option<String>)],
+    manifest_items: &[ManifestItem],
     warnings: &mut Vec<String>,
 ) -> Option<CoverInfo> {
     // Strategy 1: <meta name="cover" content="item-id">
     if let Some(cover_id) = cover_meta_id {
-        if let Some((_, href, _)) = manifest_items.iter().find(|(id, _, _)| id == cover_id) {
-            let full_path = format!("{opf_dir}{href}");
-            return resolve_cover(zip, &full_path, href, warnings);
+        if let Some(item) = manifest_items.iter().find(|item| &item.id == cover_id) {
+            let full_path = format!("{opf_dir}{}", item.href);
+            return resolve_cover(zip, &full_path, &item.href, warnings);
         }
         warnings.push(format!(
             "cover meta references item '{cover_id}' which is not in the manifest"
@@ -432,13 +409,12 @@ fn detect_cover<R: Read + Seek>(
     }
 
     // Strategy 2: manifest item with properties="cover-image" (EPUB 3)
-    for (_, href, props) in manifest_items {
-        if let Some(p) = props {
-            if p.split_whitespace().any(|w| w == "cover-image") {
-                let full_path = format!("{opf_dir}{href}");
-                return resolve_cover(zip, &full_path, href, warnings);
-            }
-        }
+    if let Some(item) = manifest_items
+        .iter()
+        .find(|item| item.has_property("cover-image"))
+    {
+        let full_path = format!("{opf_dir}{}", item.href);
+        return resolve_cover(zip, &full_path, &item.href, warnings);
     }
 
     None
@@ -467,8 +443,75 @@ fn resolve_cover<R: Read + Seek>(
     None
 }
 
+/// Open an existing file as a ZIP archive.
+pub(crate) fn open_archive(path: &Path) -> crate::Result<ZipArchive<File>> {
+    ZipArchive::new(File::open(path)?)
+        .map_err(|e| Error::InvalidBook(format!("not a valid ZIP archive: {e}")))
+}
+
+/// Rewrite the ZIP archive at `path`, replacing the contents of the named
+/// entries and appending any that don't exist yet.
+///
+/// All other entries are copied without recompression. The new archive is
+/// written next to the original and then renamed over it, so a failure
+/// part-way through leaves the original untouched.
+fn rewrite_archive(path: &Path, replacements: &BTreeMap<String, Vec<u8>>) -> crate::Result<()> {
+    write_archive(path, path, replacements, &[])
+}
+
+/// Write a copy of the ZIP archive at `src` to `dest` with the named
+/// entries replaced, as [`rewrite_archive`] does in place, and the
+/// `removed` entries left out.
+pub(crate) fn write_archive(
+    src: &Path,
+    dest: &Path,
+    replacements: &BTreeMap<String, Vec<u8>>,
+    removed: &[String],
+) -> crate::Result<()> {
+    atomic::write_with(dest, |tmp_path| {
+        copy_archive(src, tmp_path, replacements, removed)
+    })
+}
+
+fn copy_archive(
+    src: &Path,
+    dest: &Path,
+    replacements: &BTreeMap<String, Vec<u8>>,
+    removed: &[String],
+) -> crate::Result<()> {
+    let mut zip = open_archive(src)?;
+    let mut writer = ZipWriter::new(File::create(dest)?);
+
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i)?;
+        if removed.iter().any(|name| name == entry.name()) {
+            continue;
+        }
+        match replacements.get(entry.name()) {
+            Some(data) => {
+                let name = entry.name().to_string();
+                let options = SimpleFileOptions::default().compression_method(entry.compression());
+                drop(entry);
+                writer.start_file(name, options)?;
+                writer.write_all(data)?;
+            }
+            None => writer.raw_copy_file(entry)?,
+        }
+    }
+
+    for (name, data) in replacements {
+        if zip.index_for_name(name).is_none() {
+            writer.start_file(name.as_str(), SimpleFileOptions::default())?;
+            writer.write_all(data)?;
+        }
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
 /// Detect DRM by checking META-INF/encryption.xml.
-fn detect_drm<R: Read + Seek>(zip: &mut ZipArchive<R>) -> DrmStatus {
+pub(crate) fn detect_drm<R: Read + Seek>(zip: &mut ZipArchive<R>) -> DrmStatus {
     let mut entry = match zip.by_name("META-INF/encryption.xml") {
         Ok(e) => e,
         Err(_) => return DrmStatus::None,
@@ -548,15 +591,9 @@ fn detect_drm<R: Read + Seek>(zip: &mut ZipArchive<R>) -> DrmStatus {
 
 /// Extract the local name from a possibly-namespaced XML tag.
 /// e.g. b"dc:title" -> b"title", b"item" -> b"item"
-fn local_name(name: &[u8]) -> &[u8] {
+pub(crate) fn local_name(name: &[u8]) -> &[u8] {
     match name.iter().position(|&b| b == b':') {
         Some(i) => &name[i + 1..],
         None => name,
     }
 }
-
-/// Heuristic check if a string looks like an ISBN.
-fn looks_like_isbn(s: &str) -> bool {
-    let digits: String = s.chars().filter(|c| c.is_ascii_digit() || *c == 'X').collect();
-    digits.len() == 10 || digits.len() == 13
-}
@@ -0,0 +1,1442 @@
+//! A lightweight model of the OPF package document.
+//!
+//! Only the children of `<metadata>` and the manifest items are parsed. When
+//! the document is serialized again, everything outside `<metadata>` is
+//! copied from the original source untouched.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use quick_xml::escape::{escape, partial_escape};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use super::local_name;
+use crate::{Contributor, Isbn, Metadata, W3cDate};
+
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+const OPF_NAMESPACE: &str = "http://www.idpf.org/2007/opf";
+
+/// Prefix of the `<meta name>` Calibre uses for custom columns.
+const USER_METADATA_PREFIX: &str = "calibre:user_metadata:";
+
+/// `<meta name>` holding [`Metadata::tags`] as a JSON array, so tags stay
+/// apart from `dc:subject`.
+const TAGS_META: &str = "ebook-tools:tags";
+
+/// `<meta>` names and properties that are managed elsewhere and never
+/// treated as custom fields.
+const RESERVED_META: &[&str] = &["cover", "belongs-to-collection"];
+
+/// A direct child element of the OPF `<metadata>` section.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MetaElement {
+    /// Qualified name as written in the source (e.g. `dc:title`, `meta`).
+    pub name: String,
+    /// Attributes in source order, with unescaped values.
+    pub attrs: Vec<(String, String)>,
+    /// Unescaped text content.
+    pub text: String,
+    /// The original content markup, reused when writing an unmodified element.
+    raw: Option<String>,
+}
+
+impl MetaElement {
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        MetaElement {
+            name: name.into(),
+            attrs: Vec::new(),
+            text: text.into(),
+            raw: None,
+        }
+    }
+
+    pub fn with_attr(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attrs.push((key.into(), value.into()));
+        self
+    }
+
+    /// The element name without any namespace prefix.
+    pub fn local_name(&self) -> &str {
+        self.name.rsplit(':').next().unwrap_or(&self.name)
+    }
+
+    /// Look up an attribute by local name, ignoring any namespace prefix.
+    pub fn attr(&self, local: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(k, _)| k.rsplit(':').next() == Some(local))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.attr("id")
+    }
+
+    /// The id of the element this `<meta refines="#id">` applies to.
+    pub fn refines(&self) -> Option<&str> {
+        self.attr("refines").map(|r| r.trim_start_matches('#'))
+    }
+
+    /// The trimmed text content.
+    pub fn value(&self) -> &str {
+        self.text.trim()
+    }
+
+    /// Replace the text content.
+    pub fn set_text(&mut self, text: impl Into<String>) {
+        self.text = text.into();
+        self.raw = None;
+    }
+
+    /// Whether this is an EPUB 2 style `<meta name="..." content="..."/>`.
+    pub fn is_named_meta(&self, name: &str) -> bool {
+        self.local_name() == "meta" && self.attr("name") == Some(name)
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push('<');
+        out.push_str(&self.name);
+        for (key, value) in &self.attrs {
+            out.push(' ');
+            out.push_str(key);
+            out.push_str("=\"");
+            out.push_str(&escape(value.as_str()));
+            out.push('"');
+        }
+        let content = match &self.raw {
+            Some(raw) => Cow::Borrowed(raw.as_str()),
+            None => partial_escape(self.text.as_str()),
+        };
+        if content.is_empty() {
+            out.push_str("/>");
+        } else {
+            out.push('>');
+            out.push_str(&content);
+            out.push_str("</");
+            out.push_str(&self.name);
+            out.push('>');
+        }
+    }
+}
+
+/// An `<item>` in the OPF manifest.
+#[derive(Debug, Clone)]
+pub(crate) struct ManifestItem {
+    pub id: String,
+    pub href: String,
+    pub media_type: String,
+    pub properties: Option<String>,
+    /// All attributes as found in the source, so unknown ones survive a rewrite.
+    attrs: Vec<(String, String)>,
+    /// Byte range of the `<item>` tag in the source; `None` for new items.
+    span: Option<Range<usize>>,
+    /// Whether the item was changed and must be written out again.
+    dirty: bool,
+}
+
+impl ManifestItem {
+    pub fn new(id: &str, href: &str, media_type: &str, properties: Option<&str>) -> Self {
+        ManifestItem {
+            id: id.to_string(),
+            href: href.to_string(),
+            media_type: media_type.to_string(),
+            properties: properties.map(str::to_string),
+            attrs: Vec::new(),
+            span: None,
+            dirty: true,
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        let mut attrs = self.attrs.clone();
+        let known = [
+            ("id", Some(self.id.as_str())),
+            ("href", Some(self.href.as_str())),
+            ("media-type", Some(self.media_type.as_str())),
+            ("properties", self.properties.as_deref()),
+        ];
+        for (key, value) in known {
+            match (attrs.iter_mut().find(|(k, _)| k == key), value) {
+                (Some(attr), Some(value)) => attr.1 = value.to_string(),
+                (None, Some(value)) => attrs.push((key.to_string(), value.to_string())),
+                (Some(_), None) => attrs.retain(|(k, _)| k != key),
+                (None, None) => {}
+            }
+        }
+
+        out.push_str("<item");
+        for (key, value) in &attrs {
+            out.push(' ');
+            out.push_str(key);
+            out.push_str("=\"");
+            out.push_str(&escape(value.as_str()));
+            out.push('"');
+        }
+        out.push_str("/>");
+    }
+
+    /// Add a word to the space-separated `properties` attribute.
+    pub fn add_property(&mut self, property: &str) {
+        if self.has_property(property) {
+            return;
+        }
+        self.properties = Some(match self.properties.take() {
+            Some(p) if !p.trim().is_empty() => format!("{} {property}", p.trim()),
+            _ => property.to_string(),
+        });
+        self.dirty = true;
+    }
+
+    /// Remove a word from the space-separated `properties` attribute,
+    /// dropping the attribute if nothing is left.
+    pub fn remove_property(&mut self, property: &str) {
+        if !self.has_property(property) {
+            return;
+        }
+        let rest: Vec<&str> = self
+            .properties
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter(|w| *w != property)
+            .collect();
+        self.properties = (!rest.is_empty()).then(|| rest.join(" "));
+        self.dirty = true;
+    }
+
+    /// Whether the space-separated `properties` attribute contains `property`.
+    pub fn has_property(&self, property: &str) -> bool {
+        self.properties
+            .as_deref()
+            .is_some_and(|p| p.split_whitespace().any(|w| w == property))
+    }
+}
+
+/// A `<reference>` in the OPF `<guide>`.
+#[derive(Debug, Clone)]
+pub(crate) struct GuideReference {
+    /// The `type` attribute, e.g. `cover` or `text`.
+    pub kind: String,
+    pub title: Option<String>,
+    pub href: String,
+    /// Byte range of the `<reference>` tag in the source.
+    span: Range<usize>,
+}
+
+/// The `<spine>` start tag, kept so its attributes can be changed.
+#[derive(Debug, Clone)]
+struct SpineTag {
+    name: String,
+    attrs: Vec<(String, String)>,
+    /// Whether the tag is self-closing.
+    empty: bool,
+    span: Range<usize>,
+    dirty: bool,
+}
+
+/// The `<metadata>` element's location within the source document.
+#[derive(Debug, Clone)]
+struct MetadataSpan {
+    /// Byte range of the whole element, including its start and end tags.
+    range: Range<usize>,
+    /// Contents of the start tag between `<` and `>`.
+    start_tag: String,
+    /// Qualified element name, for writing the end tag.
+    name: String,
+    /// Indentation of the `<metadata>` line.
+    indent: String,
+    /// Indentation used for child elements.
+    child_indent: String,
+}
+
+impl MetadataSpan {
+    fn new(
+        source: &str,
+        range: Range<usize>,
+        start_tag: String,
+        name: String,
+        child_indent: Option<String>,
+    ) -> Self {
+        let indent = line_indent(source, range.start);
+        MetadataSpan {
+            range,
+            start_tag,
+            name,
+            child_indent: child_indent.unwrap_or_else(|| format!("{indent}  ")),
+            indent,
+        }
+    }
+}
+
+/// A parsed OPF package document.
+#[derive(Debug, Clone)]
+pub(crate) struct OpfDocument {
+    source: String,
+    span: Option<MetadataSpan>,
+    pub version: Option<String>,
+    pub unique_identifier: Option<String>,
+    pub metadata: Vec<MetaElement>,
+    pub manifest: Vec<ManifestItem>,
+    pub guide: Vec<GuideReference>,
+    /// The `idref` of each spine `<itemref>`, in reading order.
+    pub spine_items: Vec<String>,
+    spine: Option<SpineTag>,
+    /// Where new manifest items are inserted: the start of the `</manifest>` line.
+    manifest_end: Option<usize>,
+    /// Source ranges of removed manifest items and guide references.
+    removed: Vec<Range<usize>>,
+    manifest_indent: String,
+    dc_prefix: Option<String>,
+    opf_prefix: Option<String>,
+}
+
+impl OpfDocument {
+    /// Parse an OPF document from its source text.
+    pub fn parse(source: String) -> Result<Self, quick_xml::Error> {
+        let mut doc = OpfDocument {
+            source: String::new(),
+            span: None,
+            version: None,
+            unique_identifier: None,
+            metadata: Vec::new(),
+            manifest: Vec::new(),
+            guide: Vec::new(),
+            spine_items: Vec::new(),
+            spine: None,
+            manifest_end: None,
+            removed: Vec::new(),
+            manifest_indent: String::new(),
+            dc_prefix: None,
+            opf_prefix: None,
+        };
+
+        let mut reader = Reader::from_str(&source);
+        let mut in_metadata = false;
+        let mut in_manifest = false;
+        // Metadata child currently being read, and the depth of any markup nested inside it.
+        let mut current: Option<MetaElement> = None;
+        let mut depth = 0usize;
+        let mut current_start = 0usize;
+        // Start offset and start tag of `<metadata>`, plus where its content begins.
+        let mut metadata_start = 0usize;
+        let mut metadata_tag = String::new();
+        let mut content_start = 0usize;
+        let mut first_child: Option<usize> = None;
+
+        loop {
+            let before = reader.buffer_position() as usize;
+            let event = reader.read_event()?;
+
+            if let Some(element) = current.as_mut() {
+                match event {
+                    Event::Start(_) => depth += 1,
+                    Event::End(_) if depth > 0 => depth -= 1,
+                    Event::End(_) => {
+                        element.raw = Some(source[current_start..before].to_string());
+                        doc.metadata.extend(current.take());
+                    }
+                    // Unknown entities (e.g. `&nbsp;`) are kept verbatim rather than failing the parse.
+                    Event::Text(e) => match e.unescape() {
+                        Ok(text) => element.text.push_str(&text),
+                        Err(_) => element.text.push_str(&String::from_utf8_lossy(&e)),
+                    },
+                    Event::CData(e) => element.text.push_str(&String::from_utf8_lossy(&e)),
+                    Event::Eof => break,
+                    _ => {}
+                }
+                continue;
+            }
+
+            let is_empty = matches!(event, Event::Empty(_));
+            match event {
+                Event::Start(ref e) | Event::Empty(ref e) if in_metadata => {
+                    first_child.get_or_insert(before);
+                    let element = meta_element(e)?;
+                    if is_empty {
+                        doc.metadata.push(element);
+                    } else {
+                        current = Some(element);
+                        current_start = reader.buffer_position() as usize;
+                        depth = 0;
+                    }
+                }
+                Event::Start(ref e) | Event::Empty(ref e) => match local_name(e.name().as_ref()) {
+                    b"package" => {
+                        for attr in e.attributes().flatten() {
+                            let value = attr.unescape_value()?.into_owned();
+                            match attr.key.as_ref() {
+                                b"version" => doc.version = Some(value),
+                                b"unique-identifier" => doc.unique_identifier = Some(value),
+                                _ => {}
+                            }
+                        }
+                        doc.record_prefixes(e)?;
+                    }
+                    b"metadata" => {
+                        doc.record_prefixes(e)?;
+                        metadata_start = before;
+                        metadata_tag = String::from_utf8_lossy(e).into_owned();
+                        content_start = reader.buffer_position() as usize;
+                        if is_empty {
+                            doc.span = Some(MetadataSpan::new(
+                                &source,
+                                metadata_start..content_start,
+                                metadata_tag.clone(),
+                                String::from_utf8_lossy(e.name().as_ref()).into_owned(),
+                                None,
+                            ));
+                        } else {
+                            in_metadata = true;
+                        }
+                    }
+                    b"manifest" => in_manifest = !is_empty,
+                    b"spine" => {
+                        let mut attrs = Vec::new();
+                        for attr in e.attributes().flatten() {
+                            attrs.push((
+                                String::from_utf8_lossy(attr.key.as_ref()).into_owned(),
+                                attr.unescape_value()?.into_owned(),
+                            ));
+                        }
+                        doc.spine = Some(SpineTag {
+                            name: String::from_utf8_lossy(e.name().as_ref()).into_owned(),
+                            attrs,
+                            empty: is_empty,
+                            span: before..reader.buffer_position() as usize,
+                            dirty: false,
+                        });
+                    }
+                    b"itemref" => {
+                        for attr in e.attributes().flatten() {
+                            if attr.key.as_ref() == b"idref" {
+                                doc.spine_items.push(attr.unescape_value()?.into_owned());
+                            }
+                        }
+                    }
+                    b"reference" => {
+                        let mut reference = GuideReference {
+                            kind: String::new(),
+                            title: None,
+                            href: String::new(),
+                            span: before..reader.buffer_position() as usize,
+                        };
+                        for attr in e.attributes().flatten() {
+                            match attr.key.as_ref() {
+                                b"type" => reference.kind = attr.unescape_value()?.into_owned(),
+                                b"title" => {
+                                    reference.title = Some(attr.unescape_value()?.into_owned())
+                                }
+                                b"href" => reference.href = attr.unescape_value()?.into_owned(),
+                                _ => {}
+                            }
+                        }
+                        doc.guide.push(reference);
+                    }
+                    b"item" if in_manifest => {
+                        let mut item = ManifestItem::new("", "", "", None);
+                        item.span = Some(before..reader.buffer_position() as usize);
+                        item.dirty = false;
+                        for attr in e.attributes().flatten() {
+                            let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+                            let value = attr.unescape_value()?.into_owned();
+                            match key.as_str() {
+                                "id" => item.id = value.clone(),
+                                "href" => item.href = value.clone(),
+                                "media-type" => item.media_type = value.clone(),
+                                "properties" => item.properties = Some(value.clone()),
+                                _ => {}
+                            }
+                            item.attrs.push((key, value));
+                        }
+                        if doc.manifest.is_empty() {
+                            doc.manifest_indent = line_indent(&source, before);
+                        }
+                        if !item.id.is_empty() {
+                            doc.manifest.push(item);
+                        }
+                    }
+                    _ => {}
+                },
+                Event::End(ref e) => match local_name(e.name().as_ref()) {
+                    b"metadata" if in_metadata => {
+                        in_metadata = false;
+                        let end = reader.buffer_position() as usize;
+                        // Reuse the indentation of the first child, if it sits on its own line.
+                        let child_indent = first_child
+                            .and_then(|pos| source[content_start..pos].rsplit_once('\n'))
+                            .map(|(_, indent)| indent.to_string())
+                            .filter(|i| !i.is_empty() && i.chars().all(char::is_whitespace));
+                        doc.span = Some(MetadataSpan::new(
+                            &source,
+                            metadata_start..end,
+                            metadata_tag.clone(),
+                            String::from_utf8_lossy(e.name().as_ref()).into_owned(),
+                            child_indent,
+                        ));
+                    }
+                    b"manifest" => {
+                        in_manifest = false;
+                        let line_start = source[..before].rfind('\n').map_or(0, |i| i + 1);
+                        doc.manifest_end = if source[line_start..before].trim().is_empty() {
+                            Some(line_start)
+                        } else {
+                            Some(before)
+                        };
+                    }
+                    _ => {}
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        doc.source = source;
+        Ok(doc)
+    }
+
+    fn record_prefixes(&mut self, e: &BytesStart) -> Result<(), quick_xml::Error> {
+        for attr in e.attributes().flatten() {
+            let Some(prefix) = attr.key.as_ref().strip_prefix(b"xmlns:") else {
+                continue;
+            };
+            let prefix = String::from_utf8_lossy(prefix).into_owned();
+            match attr.unescape_value()?.as_ref() {
+                DC_NAMESPACE => self.dc_prefix = Some(prefix),
+                OPF_NAMESPACE => self.opf_prefix = Some(prefix),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the package declares EPUB 3 or later.
+    pub fn is_epub3(&self) -> bool {
+        self.version
+            .as_deref()
+            .and_then(|v| v.split('.').next())
+            .and_then(|major| major.trim().parse::<u32>().ok())
+            .is_some_and(|major| major >= 3)
+    }
+
+    /// Whether the document has a `<metadata>` element that can be rewritten.
+    pub fn has_metadata_section(&self) -> bool {
+        self.span.is_some()
+    }
+
+    /// The `content` of the first `<meta name="..." content="..."/>` with this name.
+    pub fn named_meta(&self, name: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|el| el.is_named_meta(name))
+            .and_then(|el| el.attr("content"))
+    }
+
+    /// Set or remove a `<meta name="..." content="..."/>` element.
+    pub fn set_named_meta(&mut self, name: &str, content: Option<&str>) {
+        let elements = content.map(|c| named_meta(name, c)).into_iter().collect();
+        self.replace(|el| el.is_named_meta(name), elements);
+    }
+
+    /// A manifest id based on `base` that isn't used yet.
+    pub fn unused_manifest_id(&self, base: &str) -> String {
+        let mut id = base.to_string();
+        let mut n = 1;
+        while self.manifest.iter().any(|item| item.id == id) {
+            n += 1;
+            id = format!("{base}-{n}");
+        }
+        id
+    }
+
+    /// Add a new item to the manifest.
+    pub fn add_manifest_item(&mut self, item: ManifestItem) {
+        self.manifest.push(item);
+    }
+
+    /// Remove an item from the manifest, returning it if it was there.
+    pub fn remove_manifest_item(&mut self, id: &str) -> Option<ManifestItem> {
+        let index = self.manifest.iter().position(|item| item.id == id)?;
+        let item = self.manifest.remove(index);
+        if let Some(range) = &item.span {
+            self.remove_source(range.clone());
+        }
+        Some(item)
+    }
+
+    /// Remove a reference from the guide.
+    pub fn remove_guide_reference(&mut self, index: usize) -> GuideReference {
+        let reference = self.guide.remove(index);
+        self.remove_source(reference.span.clone());
+        reference
+    }
+
+    /// Drop a tag from the source when serializing, taking the whole line
+    /// if the tag is alone on it.
+    fn remove_source(&mut self, range: Range<usize>) {
+        let line_start = self.source[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.source[range.end..]
+            .find('\n')
+            .map_or(self.source.len(), |i| range.end + i + 1);
+        let alone = self.source[line_start..range.start].trim().is_empty()
+            && self.source[range.end..line_end].trim().is_empty();
+        self.removed
+            .push(if alone { line_start..line_end } else { range });
+    }
+
+    /// An attribute of the `<spine>` element, such as `toc` or `page-map`.
+    pub fn spine_attr(&self, name: &str) -> Option<&str> {
+        let spine = self.spine.as_ref()?;
+        spine
+            .attrs
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Remove an attribute from the `<spine>` element.
+    pub fn remove_spine_attr(&mut self, name: &str) {
+        if let Some(spine) = &mut self.spine
+            && spine.attrs.iter().any(|(k, _)| k == name)
+        {
+            spine.attrs.retain(|(k, _)| k != name);
+            spine.dirty = true;
+        }
+    }
+
+    /// Change the media type of an existing manifest item.
+    pub fn set_media_type(&mut self, id: &str, media_type: &str) {
+        if let Some(item) = self.manifest.iter_mut().find(|item| item.id == id)
+            && item.media_type != media_type
+        {
+            item.media_type = media_type.to_string();
+            item.dirty = true;
+        }
+    }
+
+    /// Extract the book-level [`Metadata`] from the metadata elements.
+    pub fn to_metadata(&self) -> Metadata {
+        let mut metadata = Metadata::default();
+
+        for el in &self.metadata {
+            let text = el.value();
+            if el.local_name() == "meta" {
+                if let (Some(name), Some(content)) = (el.attr("name"), el.attr("content")) {
+                    read_named_meta(&mut metadata, name, content);
+                } else if let Some(event) = date_event(el) {
+                    read_date(&mut metadata, event, text);
+                } else if let Some(property) = el.attr("property")
+                    && el.refines().is_none()
+                    && !text.is_empty()
+                    && !RESERVED_META.contains(&property)
+                {
+                    metadata
+                        .custom
+                        .entry(property.to_string())
+                        .or_insert_with(|| text.to_string());
+                }
+                continue;
+            }
+            if text.is_empty() {
+                continue;
+            }
+            let text = text.to_string();
+            match el.local_name() {
+                "title" => metadata.title = Some(text),
+                "creator" => {
+                    if let Some(sort) = self.file_as(el) {
+                        metadata.author_sort.insert(text.clone(), sort.to_string());
+                    }
+                    metadata.authors.push(text);
+                }
+                "contributor" => metadata.contributors.push(Contributor {
+                    role: self.role(el).map(str::to_string),
+                    name: text,
+                }),
+                "description" => metadata.description = Some(text),
+                "publisher" => metadata.publisher = Some(text),
+                "language" => metadata.language = Some(text),
+                "identifier" => match parse_identifier(el) {
+                    Some((scheme, value)) if scheme == "isbn" => {
+                        metadata.isbn.get_or_insert(value);
+                    }
+                    Some((scheme, value)) => {
+                        metadata.identifiers.entry(scheme).or_insert(value);
+                    }
+                    None => {}
+                },
+                "date" => {
+                    if let Some(event) = date_event(el) {
+                        read_date(&mut metadata, event, &text);
+                    }
+                }
+                "subject" => metadata.subjects.push(text),
+                _ => {}
+            }
+        }
+
+        metadata
+    }
+
+    /// Rewrite the metadata elements so that [`OpfDocument::to_metadata`]
+    /// returns `new`.
+    ///
+    /// Only fields that differ from the current metadata are touched, so
+    /// attributes and refinements on unchanged elements are preserved.
+    pub fn update_metadata(&mut self, new: &Metadata) {
+        let old = self.to_metadata();
+
+        if old.title != new.title {
+            let elements = new.title.iter().map(|t| self.dc("title", t)).collect();
+            self.replace(|el| el.local_name() == "title", elements);
+        }
+        if old.title_sort != new.title_sort {
+            self.set_named_meta("calibre:title_sort", new.title_sort.as_deref());
+        }
+        if old.authors != new.authors {
+            self.replace_list(
+                |el| el.local_name() == "creator",
+                &new.authors,
+                |doc, name| doc.creator(name),
+            );
+        }
+        if old.authors != new.authors || old.author_sort != new.author_sort {
+            self.update_author_sort(&new.author_sort);
+        }
+        if old.contributors != new.contributors {
+            self.update_contributors(&new.contributors);
+        }
+        if old.author_links != new.author_links {
+            let json = (!new.author_links.is_empty())
+                .then(|| serde_json::to_string(&new.author_links).unwrap_or_default());
+            self.set_named_meta("calibre:author_link_map", json.as_deref());
+        }
+        if old.description != new.description {
+            let elements = new
+                .description
+                .iter()
+                .map(|d| self.dc("description", d))
+                .collect();
+            self.replace(|el| el.local_name() == "description", elements);
+        }
+        if old.publisher != new.publisher {
+            let elements = new
+                .publisher
+                .iter()
+                .map(|p| self.dc("publisher", p))
+                .collect();
+            self.replace(|el| el.local_name() == "publisher", elements);
+        }
+        if old.language != new.language {
+            let elements = new
+                .language
+                .iter()
+                .map(|l| self.dc("language", l))
+                .collect();
+            self.replace(|el| el.local_name() == "language", elements);
+        }
+        if old.isbn != new.isbn {
+            self.update_identifier("isbn", new.isbn.as_deref());
+        }
+        if old.identifiers != new.identifiers {
+            let schemes: BTreeSet<&String> = old
+                .identifiers
+                .keys()
+                .chain(new.identifiers.keys())
+                .collect();
+            for scheme in schemes {
+                let value = new.identifiers.get(scheme);
+                if old.identifiers.get(scheme) != value {
+                    self.update_identifier(scheme, value.map(String::as_str));
+                }
+            }
+        }
+        for event in [
+            DateEvent::Publication,
+            DateEvent::Creation,
+            DateEvent::Modification,
+        ] {
+            let date = event.field(new);
+            if event.field(&old) != date {
+                self.set_date(event, date.as_ref());
+            }
+        }
+        if old.subjects != new.subjects {
+            self.replace_list(
+                |el| el.local_name() == "subject",
+                &new.subjects,
+                |doc, subject| doc.dc("subject", subject),
+            );
+        }
+        if old.tags != new.tags {
+            let json = (!new.tags.is_empty())
+                .then(|| serde_json::to_string(&new.tags).unwrap_or_default());
+            self.set_named_meta(TAGS_META, json.as_deref());
+        }
+        if old.series != new.series {
+            self.set_named_meta("calibre:series", new.series.as_deref());
+        }
+        if old.series_index != new.series_index {
+            let index = new.series_index.map(|i| i.to_string());
+            self.set_named_meta("calibre:series_index", index.as_deref());
+        }
+        if old.rating != new.rating {
+            // Calibre stores ratings out of 10.
+            let rating = new.rating.map(|r| ((r * 2.0).round() as i64).to_string());
+            self.set_named_meta("calibre:rating", rating.as_deref());
+        }
+        if old.timestamp != new.timestamp {
+            self.set_named_meta("calibre:timestamp", new.timestamp.as_deref());
+        }
+        if old.user_metadata != new.user_metadata {
+            let elements = new
+                .user_metadata
+                .iter()
+                .map(|(key, value)| {
+                    named_meta(
+                        &format!("{USER_METADATA_PREFIX}{key}"),
+                        &serde_json::to_string(value).unwrap_or_default(),
+                    )
+                })
+                .collect();
+            self.replace(
+                |el| {
+                    el.local_name() == "meta"
+                        && el
+                            .attr("name")
+                            .is_some_and(|n| n.starts_with(USER_METADATA_PREFIX))
+                },
+                elements,
+            );
+        }
+        if old.custom != new.custom {
+            let names: BTreeSet<&String> = old.custom.keys().chain(new.custom.keys()).collect();
+            for name in names {
+                let value = new.custom.get(name);
+                if old.custom.get(name) != value {
+                    self.set_custom_meta(name, value.map(String::as_str));
+                }
+            }
+        }
+    }
+
+    /// Set or remove a custom field, keeping it in the form it was found in:
+    /// `<meta property="...">` stays a property, anything else is written as
+    /// `<meta name="..." content="..."/>`.
+    fn set_custom_meta(&mut self, name: &str, value: Option<&str>) {
+        let is_property = |el: &MetaElement| {
+            el.local_name() == "meta" && el.refines().is_none() && el.attr("property") == Some(name)
+        };
+        let as_property = self.metadata.iter().any(is_property);
+        let element = value.map(|value| {
+            if as_property {
+                MetaElement::new("meta", value).with_attr("property", name)
+            } else {
+                named_meta(name, value)
+            }
+        });
+        self.replace(
+            |el| el.is_named_meta(name) || is_property(el),
+            element.into_iter().collect(),
+        );
+    }
+
+    /// The text of a `<meta refines="#id" property="...">` refinement.
+    fn refinement(&self, id: &str, property: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|el| el.refines() == Some(id) && el.attr("property") == Some(property))
+            .map(|el| el.value())
+    }
+
+    /// The sort name of an element, from an `opf:file-as` attribute or an
+    /// EPUB 3 `file-as` refinement.
+    fn file_as<'a>(&'a self, el: &'a MetaElement) -> Option<&'a str> {
+        el.attr("file-as")
+            .or_else(|| el.id().and_then(|id| self.refinement(id, "file-as")))
+            .filter(|s| !s.trim().is_empty())
+    }
+
+    /// The MARC relator role of a creator or contributor, from an `opf:role`
+    /// attribute or an EPUB 3 `role` refinement.
+    fn role<'a>(&'a self, el: &'a MetaElement) -> Option<&'a str> {
+        el.attr("role")
+            .or_else(|| el.id().and_then(|id| self.refinement(id, "role")))
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+    }
+
+    /// An element id based on `base` that isn't used yet.
+    fn unused_metadata_id(&self, base: &str) -> String {
+        (1..)
+            .map(|n| format!("{base}{n}"))
+            .find(|id| !self.metadata.iter().any(|el| el.id() == Some(id)))
+            .unwrap_or_default()
+    }
+
+    /// Make each creator's sort name match `author_sort`.
+    ///
+    /// Existing `opf:file-as` attributes are updated in place; otherwise EPUB 3
+    /// books get a `file-as` refinement and EPUB 2 books an attribute.
+    fn update_author_sort(&mut self, author_sort: &BTreeMap<String, String>) {
+        let mut i = 0;
+        while i < self.metadata.len() {
+            let el = &self.metadata[i];
+            if el.local_name() != "creator" {
+                i += 1;
+                continue;
+            }
+            let desired = author_sort.get(el.value()).map(String::as_str);
+            if self.file_as(el) == desired {
+                i += 1;
+                continue;
+            }
+
+            if let Some(pos) = el
+                .attrs
+                .iter()
+                .position(|(k, _)| k == "file-as" || k.ends_with(":file-as"))
+            {
+                let el = &mut self.metadata[i];
+                match desired {
+                    Some(sort) => el.attrs[pos].1 = sort.to_string(),
+                    None => {
+                        el.attrs.remove(pos);
+                    }
+                }
+            } else if self.is_epub3() {
+                let id = match el.id() {
+                    Some(id) => id.to_string(),
+                    None => {
+                        let id = self.unused_metadata_id("creator");
+                        self.metadata[i].attrs.push(("id".into(), id.clone()));
+                        id
+                    }
+                };
+                self.metadata.retain(|el| {
+                    !(el.refines() == Some(id.as_str()) && el.attr("property") == Some("file-as"))
+                });
+                let index = self
+                    .metadata
+                    .iter()
+                    .position(|el| el.id() == Some(id.as_str()))
+                    .unwrap_or(i);
+                if let Some(sort) = desired {
+                    let refinement = MetaElement::new("meta", sort)
+                        .with_attr("refines", format!("#{id}"))
+                        .with_attr("property", "file-as");
+                    self.metadata.insert(index + 1, refinement);
+                }
+                i = index;
+            } else if let (Some(attr), Some(sort)) = (self.opf_attr("file-as"), desired) {
+                self.metadata[i].attrs.push((attr, sort.to_string()));
+            }
+            i += 1;
+        }
+    }
+
+    /// Replace the contributor elements, reusing existing ones (and their
+    /// refinements) where the name and role match.
+    ///
+    /// Roles are `opf:role` attributes in EPUB 2 and `role` refinements in
+    /// EPUB 3.
+    fn update_contributors(&mut self, contributors: &[Contributor]) {
+        let is_contributor = |el: &MetaElement| el.local_name() == "contributor";
+        let mut existing: Vec<MetaElement> = self
+            .metadata
+            .iter()
+            .filter(|el| is_contributor(el))
+            .cloned()
+            .collect();
+
+        let mut elements = Vec::new();
+        let mut refinements = Vec::new();
+        let mut n = 1;
+        for contributor in contributors {
+            let role = contributor.role.as_deref();
+            if let Some(i) = existing
+                .iter()
+                .position(|el| el.value() == contributor.name && self.role(el) == role)
+            {
+                elements.push(existing.remove(i));
+                continue;
+            }
+
+            let mut el = self.dc("contributor", &contributor.name);
+            match role {
+                Some(role) if self.is_epub3() => {
+                    let id = loop {
+                        let id = format!("contributor{n}");
+                        n += 1;
+                        if !self.metadata.iter().any(|el| el.id() == Some(&id)) {
+                            break id;
+                        }
+                    };
+                    refinements.push(
+                        MetaElement::new("meta", role)
+                            .with_attr("refines", format!("#{id}"))
+                            .with_attr("property", "role")
+                            .with_attr("scheme", "marc:relators"),
+                    );
+                    el = el.with_attr("id", id);
+                }
+                Some(role) => {
+                    if let Some(attr) = self.opf_attr("role") {
+                        el = el.with_attr(attr, role);
+                    }
+                }
+                None => {}
+            }
+            elements.push(el);
+        }
+
+        self.replace(is_contributor, elements);
+        for refinement in refinements {
+            let id = refinement.refines().unwrap_or_default().to_string();
+            let index = self
+                .metadata
+                .iter()
+                .position(|el| el.id() == Some(id.as_str()))
+                .map_or(self.metadata.len(), |i| i + 1);
+            self.metadata.insert(index, refinement);
+        }
+    }
+
+    /// Set `dcterms:modified` to the current time, as EPUB 3 requires
+    /// whenever a publication changes. EPUB 2 books are left as they are.
+    pub fn touch_modified(&mut self) {
+        if self.is_epub3() {
+            self.set_date(DateEvent::Modification, Some(&W3cDate::now()));
+        }
+    }
+
+    /// Set or remove the date for an event.
+    ///
+    /// Publication dates are plain `dc:date` elements. Other events use
+    /// `dcterms:` properties in EPUB 3 and `dc:date` with an `opf:event`
+    /// attribute in EPUB 2.
+    fn set_date(&mut self, event: DateEvent, date: Option<&W3cDate>) {
+        let element = date.and_then(|date| {
+            let text = date.to_string();
+            match event {
+                DateEvent::Publication => Some(self.dc("date", &text)),
+                _ if self.is_epub3() => {
+                    Some(MetaElement::new("meta", text).with_attr("property", event.property()))
+                }
+                _ => match self.opf_attr("event") {
+                    Some(attr) => Some(self.dc("date", &text).with_attr(attr, event.opf_event())),
+                    None => {
+                        log::warn!("OPF namespace has no prefix; not writing {event:?} date");
+                        None
+                    }
+                },
+            }
+        });
+        self.replace(
+            |el| date_event(el) == Some(event),
+            element.into_iter().collect(),
+        );
+    }
+
+    /// Set or remove the identifier with the given (lowercase) scheme.
+    fn update_identifier(&mut self, scheme: &str, value: Option<&str>) {
+        let unique_id = self.unique_identifier.clone();
+        let is_unique = |el: &MetaElement| unique_id.is_some() && el.id() == unique_id.as_deref();
+        let has_scheme = |el: &MetaElement| {
+            el.local_name() == "identifier"
+                && parse_identifier(el).is_some_and(|(s, _)| s == scheme)
+        };
+
+        // The package's unique identifier can't be removed, so if it holds
+        // this scheme it is updated in place instead.
+        if let Some(el) = self
+            .metadata
+            .iter_mut()
+            .find(|el| is_unique(el) && has_scheme(el))
+        {
+            match value {
+                Some(value) => {
+                    let text = identifier_text(el.value(), scheme, value);
+                    el.set_text(text);
+                }
+                None => log::warn!(
+                    "{scheme} identifier is the package's unique identifier; leaving it in place"
+                ),
+            }
+            self.replace(|el| has_scheme(el) && !is_unique(el), Vec::new());
+            return;
+        }
+
+        let elements = value
+            .map(|value| match self.opf_attr("scheme") {
+                Some(attr) if !self.is_epub3() => {
+                    let label = if scheme == "isbn" { "ISBN" } else { scheme };
+                    self.dc("identifier", value).with_attr(attr, label)
+                }
+                _ if matches!(scheme, "isbn" | "uuid") => {
+                    self.dc("identifier", &format!("urn:{scheme}:{value}"))
+                }
+                _ => self.dc("identifier", &format!("{scheme}:{value}")),
+            })
+            .into_iter()
+            .collect();
+        self.replace(|el| has_scheme(el) && !is_unique(el), elements);
+    }
+
+    /// Replace every element matching `matches` with `elements`.
+    ///
+    /// The replacements are inserted where the first matching element was,
+    /// or appended if there was none. `<meta refines>` elements pointing at
+    /// removed elements are removed as well, unless a replacement keeps the
+    /// same id.
+    fn replace(&mut self, matches: impl Fn(&MetaElement) -> bool, elements: Vec<MetaElement>) {
+        let kept_ids: Vec<&str> = elements.iter().filter_map(|el| el.id()).collect();
+        let removed_ids: Vec<String> = self
+            .metadata
+            .iter()
+            .filter(|el| matches(el))
+            .filter_map(|el| el.id())
+            .filter(|id| !kept_ids.contains(id))
+            .map(str::to_string)
+            .collect();
+
+        let mut position = None;
+        let mut index = 0;
+        self.metadata.retain(|el| {
+            let remove = matches(el)
+                || el
+                    .refines()
+                    .is_some_and(|r| removed_ids.iter().any(|id| id == r));
+            if remove && matches(el) && position.is_none() {
+                position = Some(index);
+            }
+            if !remove {
+                index += 1;
+            }
+            !remove
+        });
+
+        let position = position.unwrap_or(self.metadata.len());
+        self.metadata.splice(position..position, elements);
+    }
+
+    /// Replace a list of elements, reusing existing elements (and their
+    /// attributes and refinements) whose text matches a new value.
+    fn replace_list(
+        &mut self,
+        matches: impl Fn(&MetaElement) -> bool,
+        values: &[String],
+        make: impl Fn(&Self, &str) -> MetaElement,
+    ) {
+        let mut existing: Vec<MetaElement> = self
+            .metadata
+            .iter()
+            .filter(|el| matches(el))
+            .cloned()
+            .collect();
+
+        let elements = values
+            .iter()
+            .map(
+                |value| match existing.iter().position(|el| el.value() == value) {
+                    Some(i) => existing.remove(i),
+                    None => make(self, value),
+                },
+            )
+            .collect();
+
+        self.replace(matches, elements);
+    }
+
+    /// A new Dublin Core element using the document's prefix.
+    fn dc(&self, local: &str, text: &str) -> MetaElement {
+        let prefix = self.dc_prefix.as_deref().unwrap_or("dc");
+        MetaElement::new(format!("{prefix}:{local}"), text)
+    }
+
+    /// The prefixed name of an OPF attribute (e.g. `opf:role`), if the OPF
+    /// namespace is bound to a prefix.
+    fn opf_attr(&self, local: &str) -> Option<String> {
+        self.opf_prefix.as_ref().map(|p| format!("{p}:{local}"))
+    }
+
+    fn creator(&self, name: &str) -> MetaElement {
+        let el = self.dc("creator", name);
+        match self.opf_attr("role") {
+            Some(role) if !self.is_epub3() => el.with_attr(role, "aut"),
+            _ => el,
+        }
+    }
+
+    /// Serialize the document, regenerating the `<metadata>` section and any
+    /// changed manifest items.
+    pub fn to_xml(&self) -> String {
+        // Non-overlapping (range, replacement) pairs, applied in source order.
+        let mut splices: Vec<(Range<usize>, String)> = Vec::new();
+
+        if let Some(span) = &self.span {
+            let mut out = String::new();
+            out.push('<');
+            out.push_str(&span.start_tag);
+            out.push_str(">\n");
+            for el in &self.metadata {
+                out.push_str(&span.child_indent);
+                el.write(&mut out);
+                out.push('\n');
+            }
+            out.push_str(&span.indent);
+            out.push_str("</");
+            out.push_str(&span.name);
+            out.push('>');
+            splices.push((span.range.clone(), out));
+        }
+
+        for range in &self.removed {
+            splices.push((range.clone(), String::new()));
+        }
+        if let Some(spine) = self.spine.as_ref().filter(|spine| spine.dirty) {
+            let mut out = format!("<{}", spine.name);
+            for (key, value) in &spine.attrs {
+                out.push_str(&format!(" {key}=\"{}\"", escape(value.as_str())));
+            }
+            out.push_str(if spine.empty { "/>" } else { ">" });
+            splices.push((spine.span.clone(), out));
+        }
+
+        let mut added = String::new();
+        for item in self.manifest.iter().filter(|item| item.dirty) {
+            match &item.span {
+                Some(range) => {
+                    let mut out = String::new();
+                    item.write(&mut out);
+                    splices.push((range.clone(), out));
+                }
+                None => {
+                    added.push_str(&self.manifest_indent);
+                    item.write(&mut added);
+                    added.push('\n');
+                }
+            }
+        }
+        if let Some(end) = self.manifest_end
+            && !added.is_empty()
+        {
+            splices.push((end..end, added));
+        }
+
+        splices.sort_by_key(|(range, _)| range.start);
+
+        let mut out = String::with_capacity(self.source.len() + 256);
+        let mut pos = 0;
+        for (range, replacement) in splices {
+            out.push_str(&self.source[pos..range.start]);
+            out.push_str(&replacement);
+            pos = range.end;
+        }
+        out.push_str(&self.source[pos..]);
+        out
+    }
+}
+
+/// Fill in a field from a `<meta name="..." content="..."/>` element.
+fn read_named_meta(metadata: &mut Metadata, name: &str, content: &str) {
+    match name {
+        "calibre:series" => metadata.series = Some(content.to_string()),
+        "calibre:series_index" => metadata.series_index = content.parse().ok(),
+        "calibre:title_sort" => metadata.title_sort = Some(content.to_string()),
+        "calibre:timestamp" => metadata.timestamp = Some(content.to_string()),
+        "calibre:rating" => {
+            // Calibre stores ratings out of 10, with 0 meaning unrated.
+            metadata.rating = content
+                .parse::<f64>()
+                .ok()
+                .filter(|r| *r > 0.0)
+                .map(|r| r / 2.0);
+        }
+        "calibre:author_link_map" => {
+            if let Ok(links) = serde_json::from_str::<BTreeMap<String, String>>(content) {
+                metadata.author_links = links.into_iter().filter(|(_, l)| !l.is_empty()).collect();
+            }
+        }
+        TAGS_META => {
+            if let Ok(tags) = serde_json::from_str::<Vec<String>>(content) {
+                metadata.tags = tags.into_iter().filter(|t| !t.trim().is_empty()).collect();
+            }
+        }
+        _ if RESERVED_META.contains(&name) => {}
+        _ => {
+            if let Some(key) = name.strip_prefix(USER_METADATA_PREFIX) {
+                if let Ok(value) = serde_json::from_str(content) {
+                    metadata.user_metadata.insert(key.to_string(), value);
+                }
+            } else {
+                metadata
+                    .custom
+                    .entry(name.to_string())
+                    .or_insert_with(|| content.to_string());
+            }
+        }
+    }
+}
+
+/// The kind of event a date in the OPF records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DateEvent {
+    Publication,
+    Creation,
+    Modification,
+}
+
+impl DateEvent {
+    fn field(self, metadata: &Metadata) -> &Option<W3cDate> {
+        match self {
+            DateEvent::Publication => &metadata.publication_date,
+            DateEvent::Creation => &metadata.creation_date,
+            DateEvent::Modification => &metadata.modification_date,
+        }
+    }
+
+    /// The EPUB 3 property for this event.
+    fn property(self) -> &'static str {
+        match self {
+            DateEvent::Publication => "dcterms:issued",
+            DateEvent::Creation => "dcterms:created",
+            DateEvent::Modification => "dcterms:modified",
+        }
+    }
+
+    /// The EPUB 2 `opf:event` value for this event.
+    fn opf_event(self) -> &'static str {
+        match self {
+            DateEvent::Publication => "publication",
+            DateEvent::Creation => "creation",
+            DateEvent::Modification => "modification",
+        }
+    }
+}
+
+/// The event recorded by a `dc:date` element or a book-level `dcterms:`
+/// meta property, if the element is one of those.
+///
+/// `dc:date` without an `opf:event` attribute is the publication date.
+/// Events other than publication, creation and modification (such as
+/// Calibre's `conversion`) are ignored.
+fn date_event(el: &MetaElement) -> Option<DateEvent> {
+    match el.local_name() {
+        "date" => match el
+            .attr("event")
+            .map(|e| e.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("" | "publication" | "published" | "issued") => {
+                Some(DateEvent::Publication)
+            }
+            Some("creation" | "created" | "original-publication") => Some(DateEvent::Creation),
+            Some("modification" | "modified") => Some(DateEvent::Modification),
+            Some(_) => None,
+        },
+        "meta" if el.refines().is_none() => match el.attr("property")? {
+            "dcterms:created" => Some(DateEvent::Creation),
+            "dcterms:modified" => Some(DateEvent::Modification),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Record a date for an event. The first valid date for each event wins;
+/// Calibre's placeholder for an unknown date is skipped.
+fn read_date(metadata: &mut Metadata, event: DateEvent, text: &str) {
+    let field = match event {
+        DateEvent::Publication => &mut metadata.publication_date,
+        DateEvent::Creation => &mut metadata.creation_date,
+        DateEvent::Modification => &mut metadata.modification_date,
+    };
+    if field.is_some() {
+        return;
+    }
+    match W3cDate::parse(text) {
+        Some(date) if !date.is_undefined() => *field = Some(date),
+        Some(_) => {}
+        None => log::warn!("ignoring invalid date {text:?}"),
+    }
+}
+
+/// Split an identifier into a lowercase scheme and its value.
+///
+/// The scheme comes from an `opf:scheme` attribute, a `urn:scheme:` or
+/// `scheme:` prefix, or, for bare values that are valid ISBNs, is assumed to
+/// be ISBN.
+fn parse_identifier(el: &MetaElement) -> Option<(String, String)> {
+    let text = el.value();
+
+    if let Some(scheme) = el.attr("scheme").filter(|s| !s.trim().is_empty()) {
+        let scheme = scheme.trim().to_lowercase();
+        let prefix = format!("{scheme}:");
+        let value = strip_prefix_ignore_case(text, "urn:")
+            .and_then(|rest| strip_prefix_ignore_case(rest, &prefix))
+            .or_else(|| strip_prefix_ignore_case(text, &prefix))
+            .unwrap_or(text);
+        return Some((scheme, value.to_string()));
+    }
+
+    let rest = strip_prefix_ignore_case(text, "urn:").unwrap_or(text);
+    if let Some((scheme, value)) = rest.split_once(':') {
+        let is_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if is_scheme && !value.is_empty() && !matches!(scheme, "http" | "https") {
+            return Some((scheme.to_lowercase(), value.to_string()));
+        }
+    }
+
+    Isbn::parse(text)
+        .is_ok()
+        .then(|| ("isbn".to_string(), text.to_string()))
+}
+
+/// Build the text for an updated identifier, keeping the prefix style of the
+/// original.
+fn identifier_text(original: &str, scheme: &str, value: &str) -> String {
+    if strip_prefix_ignore_case(original, "urn:").is_some() {
+        format!("urn:{scheme}:{value}")
+    } else if strip_prefix_ignore_case(original, &format!("{scheme}:")).is_some() {
+        format!("{scheme}:{value}")
+    } else {
+        value.to_string()
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &s[prefix.len()..])
+}
+
+fn named_meta(name: &str, content: &str) -> MetaElement {
+    MetaElement::new("meta", "")
+        .with_attr("name", name)
+        .with_attr("content", content)
+}
+
+fn meta_element(e: &BytesStart) -> Result<MetaElement, quick_xml::Error> {
+    let mut element = MetaElement::new(String::from_utf8_lossy(e.name().as_ref()), "");
+    for attr in e.attributes().flatten() {
+        element.attrs.push((
+            String::from_utf8_lossy(attr.key.as_ref()).into_owned(),
+            attr.unescape_value()?.into_owned(),
+        ));
+    }
+    Ok(element)
+}
+
+/// The leading whitespace of the line containing `pos`.
+fn line_indent(source: &str, pos: usize) -> String {
+    let line_start = source[..pos].rfind('\n').map_or(0, |i| i + 1);
+    source[line_start..pos]
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .collect()
+}
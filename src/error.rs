@@ -19,9 +19,18 @@ pub enum Error {
     #[error("invalid ebook: {0}")]
     InvalidBook(String),
 
+    #[error("invalid filename pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("unsupported image format")]
+    UnsupportedImage,
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error("ZIP error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -1,16 +1,47 @@
 use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
 use std::path::Path;
 use std::str::FromStr;
 
+use zip::ZipArchive;
+
+use crate::Error;
+
 /// Supported ebook formats.
+///
+/// PDF, Topaz and KFX books can be identified, but not read or converted.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Format {
     Epub,
     Kepub,
     Mobi,
     Azw3,
+    Pdf,
+    /// Amazon's Topaz format, which stores pages as positioned glyphs.
+    Topaz,
+    Kfx,
+    /// A KFX book's container and its sidecar files, zipped together.
+    KfxZip,
 }
 
+/// How many bytes at the start of a file are looked at for magic numbers.
+const SNIFF_LEN: u64 = 1024;
+
+/// How many bytes at the start of each content document are searched for
+/// Kobo spans.
+const KOBO_SNIFF_LEN: u64 = 64 * 1024;
+
+/// Magic numbers of KFX containers, plain and DRM-protected.
+pub(crate) const KFX_MAGIC: &[&[u8]] = &[b"CONT", b"\xeaDRMION\xee"];
+
+/// The binary version marker that starts Amazon ION data, such as the
+/// vouchers and metadata in a KFX-ZIP.
+pub(crate) const ION_MAGIC: &[u8] = b"\xe0\x01\x00\xea";
+
+/// Magic number of Topaz books.
+pub(crate) const TOPAZ_MAGIC: &[u8] = b"TPZ";
+
 impl Format {
     /// Detect the format of an ebook from its file path extension.
     ///
@@ -22,16 +53,105 @@ impl Format {
         if name.ends_with(".kepub.epub") {
             return Some(Format::Kepub);
         }
+        if name.ends_with(".kfx-zip") {
+            return Some(Format::KfxZip);
+        }
 
         let ext = path.extension()?.to_string_lossy().to_lowercase();
         match ext.as_str() {
             "epub" => Some(Format::Epub),
-            "mobi" => Some(Format::Mobi),
-            "azw3" => Some(Format::Azw3),
+            // Older Kindle and Mobipocket books use `.azw` and `.prc`, and
+            // Print Replica books `.azw4`.
+            "mobi" | "azw" | "prc" => Some(Format::Mobi),
+            "azw3" | "azw4" => Some(Format::Azw3),
+            "pdf" => Some(Format::Pdf),
+            "azw1" | "tpz" => Some(Format::Topaz),
+            "kfx" => Some(Format::Kfx),
             _ => None,
         }
     }
 
+    /// Detect the format of an ebook from its content.
+    ///
+    /// EPUBs are recognized by their `mimetype` entry or container, and are
+    /// reported as KePub if any content document has Kobo spans. MOBI and
+    /// AZW3 share a Palm database header and are told apart by the header
+    /// version and EXTH of their first record: joint files, whose KF8
+    /// boundary adds a KF8 section after the MOBI one, are reported as MOBI
+    /// since every Kindle can read them. ZIP archives of KFX containers are reported as KFX-ZIP.
+    /// Returns `None` if the content isn't recognized.
+    pub fn detect<R: Read + Seek>(mut reader: R) -> crate::Result<Option<Format>> {
+        let mut head = Vec::new();
+        reader.by_ref().take(SNIFF_LEN).read_to_end(&mut head)?;
+
+        if head.starts_with(b"PK\x03\x04") {
+            reader.rewind()?;
+            return Ok(detect_zip(reader));
+        }
+        if head.get(60..68) == Some(b"BOOKMOBI") {
+            reader.rewind()?;
+            return Ok(Some(crate::mobi::detect_format(reader)));
+        }
+        if KFX_MAGIC.iter().any(|magic| head.starts_with(magic)) {
+            return Ok(Some(Format::Kfx));
+        }
+        if head.starts_with(TOPAZ_MAGIC) {
+            return Ok(Some(Format::Topaz));
+        }
+        // Readers accept a PDF header anywhere in the first kilobyte.
+        if head.windows(5).any(|w| w == b"%PDF-") {
+            return Ok(Some(Format::Pdf));
+        }
+        Ok(None)
+    }
+
+    /// Detect the format of the ebook at `path` from its content, falling
+    /// back to its extension if the content isn't recognized.
+    pub fn from_file(path: &Path) -> crate::Result<Format> {
+        let file = File::open(path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Error::FileNotFound(path.into())
+            } else {
+                Error::Io(e)
+            }
+        })?;
+        match Format::detect(BufReader::new(file))? {
+            Some(format) => Ok(format),
+            None => Format::from_path(path).ok_or_else(|| Error::UnknownFormat(path.into())),
+        }
+    }
+
+    /// A file name without its extension, taking `.kepub.epub` as a single
+    /// extension. Names with no extension, or that are only one, such as
+    /// `.epub`, are returned as they are.
+    pub fn strip_extension(name: &str) -> &str {
+        const KEPUB: &str = ".kepub.epub";
+        if let Some(start) = name.len().checked_sub(KEPUB.len()).filter(|&i| i > 0)
+            && name
+                .get(start..)
+                .is_some_and(|end| end.eq_ignore_ascii_case(KEPUB))
+        {
+            return &name[..start];
+        }
+        match name.rfind('.') {
+            Some(start) if start > 0 => &name[..start],
+            _ => name,
+        }
+    }
+
+    /// Detect the format of the ebook at `path` like [`Format::from_file`],
+    /// along with a warning if its extension suggests a different format.
+    pub fn sniff_with_warning(path: &Path) -> crate::Result<(Format, Option<String>)> {
+        let format = Format::from_file(path)?;
+        let warning = (Format::from_path(path) != Some(format)).then(|| {
+            format!(
+                "{} contains {format}, which doesn't match its extension",
+                path.display()
+            )
+        });
+        Ok((format, warning))
+    }
+
     /// The canonical file extension for this format (without leading dot).
     pub fn extension(&self) -> &'static str {
         match self {
@@ -39,6 +159,10 @@ impl Format {
             Format::Kepub => "kepub.epub",
             Format::Mobi => "mobi",
             Format::Azw3 => "azw3",
+            Format::Pdf => "pdf",
+            Format::Topaz => "azw1",
+            Format::Kfx => "kfx",
+            Format::KfxZip => "kfx-zip",
         }
     }
 
@@ -49,6 +173,10 @@ impl Format {
             Format::Kepub => "Kobo KePub",
             Format::Mobi => "Mobipocket",
             Format::Azw3 => "Kindle AZW3",
+            Format::Pdf => "PDF",
+            Format::Topaz => "Kindle Topaz",
+            Format::Kfx => "Kindle KFX",
+            Format::KfxZip => "Kindle KFX-ZIP",
         }
     }
 }
@@ -66,9 +194,183 @@ impl FromStr for Format {
         match s.to_lowercase().as_str() {
             "epub" => Ok(Format::Epub),
             "kepub" => Ok(Format::Kepub),
-            "mobi" => Ok(Format::Mobi),
-            "azw3" => Ok(Format::Azw3),
+            "mobi" | "azw" | "prc" => Ok(Format::Mobi),
+            "azw3" | "azw4" => Ok(Format::Azw3),
+            "pdf" => Ok(Format::Pdf),
+            "topaz" | "azw1" | "tpz" => Ok(Format::Topaz),
+            "kfx" => Ok(Format::Kfx),
+            "kfx-zip" => Ok(Format::KfxZip),
             _ => Err(format!("unknown format: {s}")),
         }
     }
 }
+
+/// Tell EPUB from KePub and KFX-ZIP, or return `None` for other ZIP
+/// archives.
+fn detect_zip<R: Read + Seek>(reader: R) -> Option<Format> {
+    let mut zip = ZipArchive::new(reader).ok()?;
+
+    let mut mimetype = String::new();
+    let has_mimetype = zip
+        .by_name("mimetype")
+        .is_ok_and(|mut entry| entry.read_to_string(&mut mimetype).is_ok())
+        && mimetype.trim() == "application/epub+zip";
+    if !has_mimetype && zip.by_name("META-INF/container.xml").is_err() {
+        return is_kfx_zip(&mut zip).then_some(Format::KfxZip);
+    }
+
+    let documents: Vec<String> = zip
+        .file_names()
+        .filter(|name| {
+            let name = name.to_lowercase();
+            [".xhtml", ".html", ".htm"]
+                .iter()
+                .any(|ext| name.ends_with(ext))
+        })
+        .map(str::to_string)
+        .collect();
+    // Spans start right after `<body>`, so only the start of each document
+    // is read.
+    for name in documents {
+        let mut head = Vec::new();
+        if let Ok(entry) = zip.by_name(&name)
+            && entry.take(KOBO_SNIFF_LEN).read_to_end(&mut head).is_ok()
+            && head.windows(8).any(|w| w == b"koboSpan")
+        {
+            return Some(Format::Kepub);
+        }
+    }
+    Some(Format::Epub)
+}
+
+/// Whether a ZIP archive holds a KFX container or its ION sidecar files.
+fn is_kfx_zip<R: Read + Seek>(zip: &mut ZipArchive<R>) -> bool {
+    (0..zip.len()).any(|i| {
+        let mut head = Vec::new();
+        zip.by_index(i)
+            .is_ok_and(|entry| entry.take(8).read_to_end(&mut head).is_ok())
+            && (KFX_MAGIC.iter().any(|magic| head.starts_with(magic))
+                || head.starts_with(ION_MAGIC))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+    use crate::mobi::testing;
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn detect(data: &[u8]) -> Option<Format> {
+        Format::detect(Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn epub() {
+        let book = zip(&[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER.as_bytes()),
+            ("ch1.xhtml", b"<html><body><p>Text</p></body></html>"),
+        ]);
+        assert_eq!(detect(&book), Some(Format::Epub));
+
+        // The container alone is enough.
+        let book = zip(&[("META-INF/container.xml", CONTAINER.as_bytes())]);
+        assert_eq!(detect(&book), Some(Format::Epub));
+    }
+
+    #[test]
+    fn kepub() {
+        let span =
+            br#"<html><body><p><span class="koboSpan" id="kobo.1.1">Text</span></p></body></html>"#;
+        let book = zip(&[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER.as_bytes()),
+            ("cover.xhtml", b"<html><body><svg/></body></html>"),
+            ("ch1.xhtml", span),
+        ]);
+        assert_eq!(detect(&book), Some(Format::Kepub));
+    }
+
+    #[test]
+    fn mobi_azw3_and_joint() {
+        assert_eq!(detect(&testing::mobi6_file()), Some(Format::Mobi));
+        assert_eq!(detect(&testing::azw3_file()), Some(Format::Azw3));
+        assert_eq!(detect(&testing::joint_file()), Some(Format::Mobi));
+    }
+
+    #[test]
+    fn pdf() {
+        assert_eq!(detect(b"%PDF-1.7\n"), Some(Format::Pdf));
+        // Readers skip junk before the header.
+        assert_eq!(detect(b"\xef\xbb\xbf\r\n%PDF-1.4\n"), Some(Format::Pdf));
+    }
+
+    #[test]
+    fn kfx() {
+        assert_eq!(detect(b"CONT\x02\x00\x12\x00"), Some(Format::Kfx));
+        assert_eq!(detect(b"\xeaDRMION\xee\x00"), Some(Format::Kfx));
+
+        let book = zip(&[
+            ("book.kfx", b"CONT\x02\x00"),
+            ("book.sdr/book.kfx", b"\xe0\x01\x00\xea"),
+        ]);
+        assert_eq!(detect(&book), Some(Format::KfxZip));
+        let sidecar = zip(&[("book.voucher", b"\xe0\x01\x00\xea\x00")]);
+        assert_eq!(detect(&sidecar), Some(Format::KfxZip));
+    }
+
+    #[test]
+    fn topaz() {
+        assert_eq!(detect(b"TPZ0\x00\x64"), Some(Format::Topaz));
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(detect(&zip(&[("readme.txt", b"hello")])), None);
+        assert_eq!(detect(b"plain text"), None);
+        assert_eq!(detect(b""), None);
+    }
+
+    #[test]
+    fn kindle_extensions() {
+        for (name, format) in [
+            ("book.azw", Format::Mobi),
+            ("BOOK.PRC", Format::Mobi),
+            ("book.azw4", Format::Azw3),
+            ("book.tpz", Format::Topaz),
+            ("book.kfx-zip", Format::KfxZip),
+        ] {
+            assert_eq!(Format::from_path(Path::new(name)), Some(format), "{name}");
+        }
+    }
+
+    #[test]
+    fn strip_extension() {
+        assert_eq!(Format::strip_extension("Книга.tpz"), "Книга");
+        assert_eq!(Format::strip_extension("Book.KePub.Epub"), "Book");
+        assert_eq!(Format::strip_extension("book.kepub"), "book");
+        assert_eq!(Format::strip_extension("Mr. Book.azw3"), "Mr. Book");
+        assert_eq!(Format::strip_extension("Book"), "Book");
+        assert_eq!(Format::strip_extension(".epub"), ".epub");
+        assert_eq!(Format::strip_extension(".kepub.epub"), ".kepub");
+    }
+}
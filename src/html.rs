@@ -0,0 +1,472 @@
+//! A small, forgiving HTML tokenizer and sanitizer for book descriptions.
+//!
+//! Descriptions come from many tools and are rarely well-formed XML, so this
+//! doesn't use the XML reader: unclosed tags, HTML entities and stray `<`
+//! characters are all tolerated.
+
+/// A piece of an HTML fragment.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    /// An opening tag with its lowercase name and attributes.
+    Start {
+        name: String,
+        attrs: Vec<(String, String)>,
+    },
+    /// A closing tag with its lowercase name.
+    End(String),
+    /// Text with entities decoded.
+    Text(String),
+}
+
+/// Tags kept by [`sanitize`]. Everything else is dropped, keeping its text.
+const ALLOWED: &[&str] = &[
+    "a",
+    "b",
+    "blockquote",
+    "br",
+    "cite",
+    "code",
+    "del",
+    "div",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "ins",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "s",
+    "small",
+    "strike",
+    "strong",
+    "sub",
+    "sup",
+    "u",
+    "ul",
+];
+
+/// Tags dropped together with their content.
+const DROPPED: &[&str] = &[
+    "script", "style", "head", "title", "iframe", "object", "embed", "noscript", "template", "svg",
+    "math",
+];
+
+/// Tags whose content is not parsed as HTML.
+const RAW_TEXT: &[&str] = &["script", "style"];
+
+/// Tags that never have content.
+const VOID: &[&str] = &[
+    "br", "hr", "img", "input", "meta", "link", "area", "col", "wbr",
+];
+
+/// Block-level tags, which close an open paragraph.
+const BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "ul",
+    "ol",
+    "li",
+    "blockquote",
+    "pre",
+    "hr",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+];
+
+/// Whether a string contains HTML markup, as opposed to plain text that
+/// happens to contain a `<`.
+pub(crate) fn looks_like_html(s: &str) -> bool {
+    const COMMON: &[&str] = &["span", "font", "html", "body", "center", "img"];
+    s.match_indices('<').any(|(i, _)| {
+        let rest = s[i + 1..].strip_prefix('/').unwrap_or(&s[i + 1..]);
+        let name_len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        let name = rest[..name_len].to_ascii_lowercase();
+        let terminated = rest[name_len..].starts_with(['>', '/', ' ', '\t', '\n', '\r']);
+        terminated && (ALLOWED.contains(&name.as_str()) || COMMON.contains(&name.as_str()))
+    })
+}
+
+/// Split an HTML fragment into tokens.
+///
+/// Comments, doctypes and processing instructions are skipped, and the
+/// content of `<script>` and `<style>` is dropped.
+pub(crate) fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut rest = html;
+
+    while let Some(i) = rest.find('<') {
+        text.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+            continue;
+        }
+
+        let is_end = rest[1..].starts_with('/');
+        let name_start = if is_end { 2 } else { 1 };
+        let name_len = rest[name_start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == ':' || c == '-'))
+            .unwrap_or(rest.len() - name_start);
+        if name_len == 0 || !rest[name_start..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            // A literal "<", as in "a < b".
+            text.push('<');
+            rest = &rest[1..];
+            continue;
+        }
+        let name = tag_name(&rest[name_start..name_start + name_len]);
+        let (attrs, tag_len) = parse_attributes(&rest[name_start + name_len..]);
+        rest = &rest[name_start + name_len + tag_len..];
+
+        if !text.is_empty() {
+            tokens.push(Token::Text(decode_entities(&text)));
+            text.clear();
+        }
+        if is_end {
+            tokens.push(Token::End(name));
+            continue;
+        }
+
+        if RAW_TEXT.contains(&name.as_str()) {
+            let close = format!("</{name}");
+            let end = rest.to_ascii_lowercase().find(&close).unwrap_or(rest.len());
+            rest = &rest[end..];
+        }
+        tokens.push(Token::Start { name, attrs });
+    }
+
+    text.push_str(rest);
+    if !text.is_empty() {
+        tokens.push(Token::Text(decode_entities(&text)));
+    }
+    tokens
+}
+
+/// Reduce an HTML fragment to a safe, well-formed XHTML subset.
+///
+/// Only basic formatting tags are kept (see [`ALLOWED`]), links keep only
+/// `http`, `https` and `mailto` targets, all other attributes are removed,
+/// and unclosed tags are closed.
+pub(crate) fn sanitize(html: &str) -> String {
+    let mut out = String::new();
+    let mut open: Vec<String> = Vec::new();
+    let mut dropping = 0usize;
+
+    for token in tokenize(html) {
+        match token {
+            Token::Start { name, .. } if DROPPED.contains(&name.as_str()) => {
+                if !VOID.contains(&name.as_str()) {
+                    dropping += 1;
+                }
+            }
+            Token::End(name) if DROPPED.contains(&name.as_str()) => {
+                dropping = dropping.saturating_sub(1);
+            }
+            _ if dropping > 0 => {}
+            Token::Start { name, attrs } if is_allowed(&name, &attrs) => {
+                if BLOCKS.contains(&name.as_str()) {
+                    // A block can't sit inside a paragraph or inline element.
+                    while let Some(top) = open.last()
+                        && !BLOCKS.contains(&top.as_str())
+                    {
+                        close(&mut out, &mut open);
+                    }
+                    if open.last().is_some_and(|top| top == "p") {
+                        close(&mut out, &mut open);
+                    }
+                }
+                if name == "li"
+                    && let Some(li) = open.iter().rposition(|t| t == "li")
+                    && !open[li..].iter().any(|t| t == "ul" || t == "ol")
+                {
+                    while open.len() > li {
+                        close(&mut out, &mut open);
+                    }
+                }
+
+                out.push('<');
+                out.push_str(&name);
+                if name == "a"
+                    && let Some(href) = safe_href(&attrs)
+                {
+                    out.push_str(" href=\"");
+                    out.push_str(&escape(href.trim(), true));
+                    out.push('"');
+                }
+                if VOID.contains(&name.as_str()) {
+                    out.push_str("/>");
+                } else {
+                    out.push('>');
+                    open.push(name);
+                }
+            }
+            Token::End(name) => {
+                if let Some(i) = open.iter().rposition(|t| *t == name) {
+                    while open.len() > i {
+                        close(&mut out, &mut open);
+                    }
+                }
+            }
+            Token::Text(text) => out.push_str(&escape(&text, false)),
+            Token::Start { .. } => {}
+        }
+    }
+
+    while !open.is_empty() {
+        close(&mut out, &mut open);
+    }
+    out.trim().to_string()
+}
+
+fn close(out: &mut String, open: &mut Vec<String>) {
+    if let Some(name) = open.pop() {
+        out.push_str("</");
+        out.push_str(&name);
+        out.push('>');
+    }
+}
+
+/// Whether a tag is kept by the sanitizer. Links are only kept if they
+/// have a safe target.
+fn is_allowed(name: &str, attrs: &[(String, String)]) -> bool {
+    ALLOWED.contains(&name) && (name != "a" || safe_href(attrs).is_some())
+}
+
+/// The `href` of a link, if it uses the `http`, `https` or `mailto` scheme.
+fn safe_href(attrs: &[(String, String)]) -> Option<&str> {
+    let (_, href) = attrs.iter().find(|(k, _)| k == "href")?;
+    let url = href.trim().to_ascii_lowercase();
+    ["http://", "https://", "mailto:"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+        .then_some(href.as_str())
+}
+
+/// Escape text for XHTML output.
+pub(crate) fn escape(text: &str, in_attribute: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if in_attribute => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Lowercase a tag name and drop any namespace prefix.
+fn tag_name(raw: &str) -> String {
+    let local = raw.rsplit(':').next().unwrap_or(raw);
+    local.to_ascii_lowercase()
+}
+
+/// Parse attributes up to the end of a tag, returning them and the number
+/// of bytes consumed (including the closing `>`).
+fn parse_attributes(s: &str) -> (Vec<(String, String)>, usize) {
+    let mut attrs = Vec::new();
+    let bytes = s.as_bytes();
+    let mut i = 0;
+
+    loop {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        if i >= bytes.len() {
+            return (attrs, i);
+        }
+        if bytes[i] == b'>' {
+            return (attrs, i + 1);
+        }
+
+        let name_start = i;
+        while i < bytes.len()
+            && !matches!(bytes[i], b'=' | b'>' | b'/')
+            && !bytes[i].is_ascii_whitespace()
+        {
+            i += 1;
+        }
+        let name = tag_name(&s[name_start..i]);
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let mut value = String::new();
+        if i < bytes.len() && bytes[i] == b'=' {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
+                let quote = bytes[i];
+                let start = i + 1;
+                i = start;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += 1;
+                }
+                value = decode_entities(&s[start..i]);
+                i = (i + 1).min(bytes.len());
+            } else {
+                let start = i;
+                while i < bytes.len() && bytes[i] != b'>' && !bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                value = decode_entities(&s[start..i]);
+            }
+        }
+        if !name.is_empty() {
+            attrs.push((name, value));
+        }
+    }
+}
+
+/// Decode HTML character references. Unknown entities are left as they are.
+pub(crate) fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 32)
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(num) = entity.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code).filter(|c| *c != '\0');
+    }
+
+    let c = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "ensp" => '\u{2002}',
+        "emsp" => '\u{2003}',
+        "thinsp" => '\u{2009}',
+        "shy" => '\u{ad}',
+        "ndash" => '–',
+        "mdash" => '—',
+        "hellip" => '…',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "sbquo" => '‚',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "bdquo" => '„',
+        "laquo" => '«',
+        "raquo" => '»',
+        "lsaquo" => '‹',
+        "rsaquo" => '›',
+        "bull" => '•',
+        "middot" => '·',
+        "deg" => '°',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "sect" => '§',
+        "para" => '¶',
+        "dagger" => '†',
+        "times" => '×',
+        "divide" => '÷',
+        "euro" => '€',
+        "pound" => '£',
+        "yen" => '¥',
+        "cent" => '¢',
+        "iexcl" => '¡',
+        "iquest" => '¿',
+        "agrave" => 'à',
+        "aacute" => 'á',
+        "acirc" => 'â',
+        "atilde" => 'ã',
+        "auml" => 'ä',
+        "aring" => 'å',
+        "aelig" => 'æ',
+        "ccedil" => 'ç',
+        "egrave" => 'è',
+        "eacute" => 'é',
+        "ecirc" => 'ê',
+        "euml" => 'ë',
+        "igrave" => 'ì',
+        "iacute" => 'í',
+        "icirc" => 'î',
+        "iuml" => 'ï',
+        "ntilde" => 'ñ',
+        "ograve" => 'ò',
+        "oacute" => 'ó',
+        "ocirc" => 'ô',
+        "otilde" => 'õ',
+        "ouml" => 'ö',
+        "oslash" => 'ø',
+        "ugrave" => 'ù',
+        "uacute" => 'ú',
+        "ucirc" => 'û',
+        "uuml" => 'ü',
+        "yacute" => 'ý',
+        "yuml" => 'ÿ',
+        "szlig" => 'ß',
+        "Agrave" => 'À',
+        "Aacute" => 'Á',
+        "Acirc" => 'Â',
+        "Auml" => 'Ä',
+        "Aring" => 'Å',
+        "AElig" => 'Æ',
+        "Ccedil" => 'Ç',
+        "Egrave" => 'È',
+        "Eacute" => 'É',
+        "Ecirc" => 'Ê',
+        "Iacute" => 'Í',
+        "Ntilde" => 'Ñ',
+        "Oacute" => 'Ó',
+        "Ouml" => 'Ö',
+        "Oslash" => 'Ø',
+        "Uacute" => 'Ú',
+        "Uuml" => 'Ü',
+        _ => return None,
+    };
+    Some(c)
+}
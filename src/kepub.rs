@@ -0,0 +1,1043 @@
+//! Converting EPUB books to Kobo's KePub flavour.
+//!
+//! A KePub is an ordinary EPUB whose content documents have every sentence
+//! wrapped in a `<span class="koboSpan" id="kobo.N.M">`, where `N` counts
+//! paragraphs and `M` sentences within them. Kobo readers use these spans
+//! for reading statistics, highlights and page turns. The body content is
+//! also wrapped in `book-columns` and `book-inner` divs, which Kobo's
+//! stylesheet expects. [`to_epub`] undoes all of this.
+//!
+//! [`KepubOptions`] controls optional tweaks made along the way, such as
+//! hyphenation or removing embedded fonts.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::epub::opf::{ManifestItem, OpfDocument};
+use crate::epub::{self, local_name};
+use crate::{normalize_language, DrmStatus, Error, Format};
+
+/// Styles added to each content document, matching what Kobo's own
+/// conversion adds.
+const KOBO_STYLE: &str = "div#book-inner { margin-top: 0; margin-bottom: 0; }";
+
+/// Styles for [`KepubOptions::hyphenate`].
+const HYPHENATE_STYLE: &str = "* { -webkit-hyphens: auto; hyphens: auto; \
+                               -webkit-hyphenate-limit-after: 3; -webkit-hyphenate-limit-before: 3; \
+                               -webkit-hyphenate-limit-lines: 2; } \
+                               h1, h2, h3, h4, h5, h6, td { -webkit-hyphens: none; hyphens: none; }";
+const NO_HYPHENATE_STYLE: &str =
+    "* { -webkit-hyphens: none !important; hyphens: none !important; }";
+
+/// Styles for [`KepubOptions::fullscreen_fixes`].
+const FULLSCREEN_STYLE: &str = "div#book-columns, div#book-inner { margin: 0 !important; \
+                                padding: 0 !important; } \
+                                img { max-width: 100% !important; max-height: 100vh !important; \
+                                object-fit: contain; }";
+
+/// Media type of Adobe Digital Editions page maps.
+const PAGE_MAP_TYPE: &str = "application/oebps-page-map+xml";
+
+/// Guide reference types Kobo readers open a book at.
+const START_REFERENCES: &[&str] = &["text", "start"];
+
+/// Elements whose text is kept exactly by the smart punctuation pass.
+const VERBATIM: &[&str] = &["pre", "code", "kbd", "samp", "tt", "var"];
+
+/// Elements whose text is never wrapped in spans.
+const SKIPPED: &[&str] = &[
+    "script", "style", "svg", "math", "textarea", "select", "option", "title",
+];
+
+/// Elements that start a new paragraph number.
+const BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "dt",
+    "dd",
+    "td",
+    "th",
+    "caption",
+    "figcaption",
+    "blockquote",
+    "pre",
+    "br",
+    "hr",
+    "table",
+    "ul",
+    "ol",
+];
+
+/// Media types of documents that are converted.
+const CONTENT_TYPES: &[&str] = &["application/xhtml+xml", "text/html"];
+
+/// Optional changes made while converting to KePub.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KepubOptions {
+    /// Turn hyphenation on (`Some(true)`) or off (`Some(false)`) for the
+    /// whole book. `None` leaves it to the book's own styles.
+    ///
+    /// Kobo readers only hyphenate documents that declare a language, so
+    /// turning it on also gives each document without one the book's
+    /// language.
+    pub hyphenate: Option<bool>,
+    /// Remove embedded fonts and their `@font-face` rules, so the reader's
+    /// own fonts are used.
+    pub remove_fonts: bool,
+    /// Let full-page images, such as covers and illustrations, fill the
+    /// screen without being cut off or pushed to a second page.
+    pub fullscreen_fixes: bool,
+    /// CSS added after the book's own styles, so it takes precedence.
+    pub extra_css: Option<String>,
+    /// Remove Adobe Digital Editions page maps, which can stop Kobo readers
+    /// from opening a book.
+    pub remove_page_map: bool,
+    /// Turn straight quotes into curly ones, `--` into em dashes and `...`
+    /// into ellipses in body text.
+    pub smart_punctuation: bool,
+    /// Remove a guide `text` or `start` reference that points to a file
+    /// that isn't a content document, which Kobo readers fail to open.
+    pub fix_invalid_start: bool,
+}
+
+impl KepubOptions {
+    /// The contents of the style element added to each content document.
+    fn style(&self) -> String {
+        let mut style = String::from(KOBO_STYLE);
+        match self.hyphenate {
+            Some(true) => style.extend([" ", HYPHENATE_STYLE]),
+            Some(false) => style.extend([" ", NO_HYPHENATE_STYLE]),
+            None => {}
+        }
+        if self.fullscreen_fixes {
+            style.extend([" ", FULLSCREEN_STYLE]);
+        }
+        if let Some(css) = &self.extra_css {
+            style.push('\n');
+            style.push_str(css);
+        }
+        // The style sits in XHTML, where `<` and `&` must be escaped.
+        let style = style.replace('&', "&amp;").replace('<', "&lt;");
+        format!("<style type=\"text/css\" class=\"kobostylehacks\">{style}</style>")
+    }
+}
+
+/// Convert the EPUB at `input` to a KePub at `output`.
+///
+/// Only the content documents, stylesheets and the package document are
+/// rewritten; everything else is copied as it is. Documents that already
+/// contain Kobo spans, or that can't be parsed, are left unchanged.
+pub fn from_epub(input: &Path, output: &Path, options: &KepubOptions) -> crate::Result<()> {
+    if Format::from_file(input)? == Format::Kepub {
+        return Err(Error::InvalidBook(format!(
+            "{} is already a KePub",
+            input.display()
+        )));
+    }
+
+    let mut zip = epub::open_archive(input)?;
+    let mut warnings = Vec::new();
+    let opf_path = epub::parse_container(&mut zip, &mut warnings)?;
+    let mut opf = epub::read_opf(&mut zip, &opf_path, &mut warnings)?;
+    let opf_dir = epub::opf_dir(&opf_path).to_string();
+
+    let style = options.style();
+    let language = match options.hyphenate {
+        Some(true) => opf
+            .to_metadata()
+            .language
+            .as_deref()
+            .and_then(normalize_language),
+        _ => None,
+    };
+    let mut replacements = BTreeMap::new();
+    for item in &opf.manifest {
+        let is_css = item.media_type == "text/css";
+        if !CONTENT_TYPES.contains(&item.media_type.as_str()) && !is_css {
+            continue;
+        }
+        let path = format!("{opf_dir}{}", item.href);
+        let Ok(mut entry) = zip.by_name(&path) else {
+            log::warn!("content document not found: {path}");
+            continue;
+        };
+        let mut source = String::new();
+        if entry.read_to_string(&mut source).is_err() {
+            log::warn!("{path} is not UTF-8; leaving it unchanged");
+            continue;
+        }
+        drop(entry);
+
+        if is_css {
+            if options.remove_fonts
+                && let Some(css) = strip_font_faces(&source)
+            {
+                replacements.insert(path, css.into_bytes());
+            }
+            continue;
+        }
+        match add_spans(&source, options, &style, language.as_deref()) {
+            Ok(Some(converted)) => {
+                replacements.insert(path, converted.into_bytes());
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to parse {path}; leaving it unchanged: {e}"),
+        }
+    }
+
+    let mut removed = Vec::new();
+    let mut remove_items = |opf: &mut OpfDocument, remove: &dyn Fn(&ManifestItem) -> bool| {
+        let ids: Vec<String> = opf
+            .manifest
+            .iter()
+            .filter(|item| remove(item))
+            .map(|item| item.id.clone())
+            .collect();
+        for id in ids {
+            if let Some(item) = opf.remove_manifest_item(&id) {
+                removed.push(format!("{opf_dir}{}", item.href));
+            }
+        }
+    };
+    if options.remove_fonts {
+        remove_items(&mut opf, &is_font);
+    }
+    if options.remove_page_map {
+        let page_map = opf.spine_attr("page-map").map(str::to_string);
+        remove_items(&mut opf, &|item| {
+            item.media_type == PAGE_MAP_TYPE || Some(&item.id) == page_map.as_ref()
+        });
+        opf.remove_spine_attr("page-map");
+    }
+    if options.remove_fonts && zip.by_name("META-INF/encryption.xml").is_ok() {
+        // Once the fonts are gone, an encryption.xml that only obfuscated
+        // them points at nothing.
+        if epub::detect_drm(&mut zip) == DrmStatus::None {
+            removed.push("META-INF/encryption.xml".to_string());
+        }
+    }
+    drop(zip);
+
+    if options.fix_invalid_start {
+        let documents: Vec<&str> = opf
+            .manifest
+            .iter()
+            .filter(|item| CONTENT_TYPES.contains(&item.media_type.as_str()))
+            .map(|item| item.href.as_str())
+            .collect();
+        let invalid: Vec<usize> = opf
+            .guide
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| {
+                let target = r.href.split('#').next().unwrap_or_default();
+                START_REFERENCES.contains(&r.kind.as_str()) && !documents.contains(&target)
+            })
+            .map(|(i, _)| i)
+            .collect();
+        for i in invalid.into_iter().rev() {
+            let reference = opf.remove_guide_reference(i);
+            log::info!("removed invalid start reference to {}", reference.href);
+        }
+    }
+
+    // Kobo finds the cover of EPUB 3 books through the manifest property.
+    // EPUB 2 has no `properties` attribute, so those books keep relying on
+    // `<meta name="cover">`.
+    let cover_id = opf.named_meta("cover").map(str::to_string);
+    if opf.is_epub3()
+        && let Some(item) = opf
+            .manifest
+            .iter_mut()
+            .find(|item| Some(&item.id) == cover_id.as_ref())
+    {
+        item.add_property("cover-image");
+    }
+    replacements.insert(opf_path, opf.to_xml().into_bytes());
+
+    epub::write_archive(input, output, &replacements, &removed)
+}
+
+/// Convert the KePub at `input` back to a plain EPUB at `output`.
+///
+/// Kobo spans and wrapper divs are removed, keeping their content, along
+/// with the styles and scripts Kobo tools add. Text content is left exactly
+/// as it was.
+pub fn to_epub(input: &Path, output: &Path) -> crate::Result<()> {
+    let mut zip = epub::open_archive(input)?;
+    let mut warnings = Vec::new();
+    let opf_path = epub::parse_container(&mut zip, &mut warnings)?;
+    let mut opf = epub::read_opf(&mut zip, &opf_path, &mut warnings)?;
+    let opf_dir = epub::opf_dir(&opf_path).to_string();
+
+    let mut replacements = BTreeMap::new();
+    for item in &opf.manifest {
+        if !CONTENT_TYPES.contains(&item.media_type.as_str()) {
+            continue;
+        }
+        let path = format!("{opf_dir}{}", item.href);
+        let Ok(mut entry) = zip.by_name(&path) else {
+            log::warn!("content document not found: {path}");
+            continue;
+        };
+        let mut source = String::new();
+        if entry.read_to_string(&mut source).is_err() {
+            log::warn!("{path} is not UTF-8; leaving it unchanged");
+            continue;
+        }
+        match strip_kobo(&source) {
+            Ok(Some(stripped)) => {
+                replacements.insert(path, stripped.into_bytes());
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to parse {path}; leaving it unchanged: {e}"),
+        }
+    }
+    drop(zip);
+
+    let kobo_files: Vec<String> = opf
+        .manifest
+        .iter()
+        .filter(|item| is_kobo_file(&item.href))
+        .map(|item| item.id.clone())
+        .collect();
+    let mut removed = Vec::new();
+    for id in kobo_files {
+        if let Some(item) = opf.remove_manifest_item(&id) {
+            removed.push(format!("{opf_dir}{}", item.href));
+        }
+    }
+    // The cover property some Kobo tools add isn't valid in EPUB 2.
+    if !opf.is_epub3() {
+        for item in &mut opf.manifest {
+            item.remove_property("cover-image");
+        }
+    }
+    replacements.insert(opf_path, opf.to_xml().into_bytes());
+
+    epub::write_archive(input, output, &replacements, &removed)
+}
+
+/// Add Kobo spans, the wrapper divs and the `style` element to a content
+/// document, returning `None` if it has already been converted. `language`
+/// is given to the `html` element if it doesn't declare one.
+fn add_spans(
+    source: &str,
+    options: &KepubOptions,
+    style: &str,
+    language: Option<&str>,
+) -> Result<Option<String>, quick_xml::Error> {
+    if source.contains("koboSpan") || source.contains("book-inner") {
+        return Ok(None);
+    }
+
+    let mut reader = Reader::from_str(source);
+    let mut out = String::with_capacity(source.len() * 2);
+    let mut in_body = false;
+    // Depth of skipped, verbatim and `<style>` elements the reader is inside.
+    let mut skipped = 0usize;
+    let mut verbatim = 0usize;
+    let mut in_style = 0usize;
+    let mut spans = Spans::default();
+    // The last character of body text, for the smart punctuation pass.
+    let mut previous = ' ';
+    let mut drop_line = false;
+
+    loop {
+        let before = reader.buffer_position() as usize;
+        let event = reader.read_event()?;
+        let raw = &source[before..reader.buffer_position() as usize];
+
+        match event {
+            Event::Start(ref e) => {
+                let name = e.name();
+                let name = local_name(name.as_ref());
+                match language {
+                    Some(language)
+                        // Matches both `lang` and `xml:lang`.
+                        if name == b"html" && attribute(e, b"lang").is_none() =>
+                    {
+                        let open = raw.strip_suffix('>').unwrap_or(raw);
+                        out.push_str(&format!(
+                            "{open} lang=\"{language}\" xml:lang=\"{language}\">"
+                        ));
+                    }
+                    _ => out.push_str(raw),
+                }
+                if name == b"body" {
+                    in_body = true;
+                    out.push_str("<div id=\"book-columns\"><div id=\"book-inner\">");
+                }
+                if is_one_of(name, SKIPPED) {
+                    skipped += 1;
+                }
+                if is_one_of(name, VERBATIM) {
+                    verbatim += 1;
+                }
+                if name == b"style" {
+                    in_style += 1;
+                }
+                if is_one_of(name, BLOCKS) {
+                    spans.new_paragraph();
+                    previous = ' ';
+                }
+            }
+            Event::End(ref e) => {
+                let name = e.name();
+                let name = local_name(name.as_ref());
+                match name {
+                    b"body" => {
+                        in_body = false;
+                        out.push_str("</div></div>");
+                    }
+                    b"head" => out.push_str(style),
+                    _ => {}
+                }
+                if is_one_of(name, SKIPPED) {
+                    skipped = skipped.saturating_sub(1);
+                }
+                if is_one_of(name, VERBATIM) {
+                    verbatim = verbatim.saturating_sub(1);
+                }
+                if name == b"style" {
+                    in_style = in_style.saturating_sub(1);
+                }
+                if is_one_of(name, BLOCKS) {
+                    spans.new_paragraph();
+                    previous = ' ';
+                }
+                out.push_str(raw);
+            }
+            Event::Empty(ref e) if options.remove_page_map && is_page_map_reference(e) => {
+                drop_line = trim_line(&mut out);
+                continue;
+            }
+            Event::Text(_) if drop_line && !in_body => out.push_str(rest_of_line(raw)),
+            Event::Empty(ref e) => {
+                let name = e.name();
+                let name = local_name(name.as_ref());
+                if name == b"img" && in_body && skipped == 0 {
+                    // Images get a paragraph of their own.
+                    spans.new_paragraph();
+                    spans.wrap(&mut out, raw);
+                    spans.new_paragraph();
+                } else {
+                    out.push_str(raw);
+                }
+                if is_one_of(name, BLOCKS) {
+                    spans.new_paragraph();
+                }
+            }
+            Event::Text(_) if in_body && skipped == 0 && !raw.trim().is_empty() => {
+                let text = if options.smart_punctuation && verbatim == 0 {
+                    Cow::Owned(smarten(raw, &mut previous))
+                } else {
+                    Cow::Borrowed(raw)
+                };
+                for sentence in sentences(&text) {
+                    spans.wrap(&mut out, sentence);
+                }
+            }
+            Event::Text(_) if in_style > 0 && options.remove_fonts => {
+                out.push_str(&strip_font_faces(raw).unwrap_or_else(|| raw.to_string()));
+            }
+            Event::Eof => break,
+            _ => out.push_str(raw),
+        }
+        drop_line = false;
+    }
+
+    Ok(Some(out))
+}
+
+/// Remove Kobo spans, wrapper divs, styles and scripts from a content
+/// document, returning `None` if it has none.
+fn strip_kobo(source: &str) -> Result<Option<String>, quick_xml::Error> {
+    if !source.contains("kobo") && !source.contains("book-inner") {
+        return Ok(None);
+    }
+
+    let mut reader = Reader::from_str(source);
+    let mut out = String::with_capacity(source.len());
+    // For each open `<span>` and `<div>`, whether it was added by Kobo.
+    let mut wrappers: Vec<bool> = Vec::new();
+    // Depth inside a Kobo `<style>` or `<script>` being dropped.
+    let mut dropping = 0usize;
+    // Whether a dropped element was alone on its line, so the rest of the
+    // line should go too.
+    let mut drop_line = false;
+
+    loop {
+        let before = reader.buffer_position() as usize;
+        let event = reader.read_event()?;
+        let raw = &source[before..reader.buffer_position() as usize];
+
+        if dropping > 0 {
+            match event {
+                Event::Start(_) => dropping += 1,
+                Event::End(_) => dropping -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(ref e) => {
+                let name = e.name();
+                match local_name(name.as_ref()) {
+                    b"span" | b"div" => {
+                        let kobo = is_kobo_wrapper(e);
+                        wrappers.push(kobo);
+                        if !kobo {
+                            out.push_str(raw);
+                        }
+                    }
+                    b"style" | b"script" | b"link" if is_kobo_resource(e) => {
+                        dropping = 1;
+                        drop_line = trim_line(&mut out);
+                        continue;
+                    }
+                    _ => out.push_str(raw),
+                }
+            }
+            Event::End(ref e) => {
+                let name = e.name();
+                let kobo = match local_name(name.as_ref()) {
+                    b"span" | b"div" => wrappers.pop().unwrap_or(false),
+                    _ => false,
+                };
+                if !kobo {
+                    out.push_str(raw);
+                }
+            }
+            Event::Empty(ref e) if is_kobo_wrapper(e) || is_kobo_resource(e) => {
+                drop_line = trim_line(&mut out);
+                continue;
+            }
+            Event::Text(_) if drop_line => out.push_str(rest_of_line(raw)),
+            Event::Eof => break,
+            _ => out.push_str(raw),
+        }
+        drop_line = false;
+    }
+
+    Ok(Some(out))
+}
+
+/// Remove the indentation before an element that is being dropped,
+/// returning whether the element started its line.
+fn trim_line(out: &mut String) -> bool {
+    let trimmed = out.trim_end_matches([' ', '\t']).len();
+    let starts_line = trimmed == 0 || out[..trimmed].ends_with('\n');
+    if starts_line {
+        out.truncate(trimmed);
+    }
+    starts_line
+}
+
+/// The text after a dropped element, without the line break that ended its
+/// line.
+fn rest_of_line(text: &str) -> &str {
+    let rest = text.trim_start_matches([' ', '\t']);
+    rest.strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))
+        .unwrap_or(text)
+}
+
+/// Whether an element is a Kobo span or one of the wrapper divs.
+fn is_kobo_wrapper(e: &BytesStart) -> bool {
+    let name = e.name();
+    match local_name(name.as_ref()) {
+        b"span" => {
+            attribute(e, b"class").is_some_and(|c| c.split_whitespace().any(|c| c == "koboSpan"))
+        }
+        b"div" => attribute(e, b"id").is_some_and(|id| id == "book-columns" || id == "book-inner"),
+        _ => false,
+    }
+}
+
+/// Whether an element is a style or script added by Kobo tools: the
+/// `kobostylehacks` style or a link to one of Kobo's own files.
+fn is_kobo_resource(e: &BytesStart) -> bool {
+    let name = e.name();
+    match local_name(name.as_ref()) {
+        b"style" => attribute(e, b"class").is_some_and(|c| c.contains("kobostylehacks")),
+        b"script" => attribute(e, b"src").is_some_and(|src| is_kobo_file(&src)),
+        b"link" => attribute(e, b"href").is_some_and(|href| is_kobo_file(&href)),
+        _ => false,
+    }
+}
+
+/// Whether a path names one of the scripts or stylesheets Kobo adds, such
+/// as `kobo.js` or `css/kobo.css`.
+fn is_kobo_file(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path).to_lowercase();
+    name.starts_with("kobo") && (name.ends_with(".js") || name.ends_with(".css"))
+}
+
+fn attribute(e: &BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|a| local_name(a.key.as_ref()) == name)
+        .and_then(|a| a.unescape_value().ok())
+        .map(|v| v.into_owned())
+}
+
+/// Whether a manifest item is an embedded font.
+fn is_font(item: &ManifestItem) -> bool {
+    let media_type = item.media_type.to_lowercase();
+    let href = item.href.to_lowercase();
+    media_type.starts_with("font/")
+        || media_type.starts_with("application/font")
+        || media_type.starts_with("application/x-font")
+        || media_type == "application/vnd.ms-opentype"
+        || [".ttf", ".otf", ".woff", ".woff2"]
+            .iter()
+            .any(|ext| href.ends_with(ext))
+}
+
+/// Remove `@font-face` rules from a stylesheet, returning `None` if it has
+/// none.
+fn strip_font_faces(css: &str) -> Option<String> {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    let mut found = false;
+    while let Some(start) = rest.to_ascii_lowercase().find("@font-face") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        found = true;
+        out.push_str(rest[..start].trim_end_matches([' ', '\t']));
+        rest = &rest[start + end + 1..];
+        // Drop the rest of the line if the rule ended it.
+        if let Some(next) = rest.trim_start_matches([' ', '\t']).strip_prefix('\n') {
+            rest = next;
+        }
+    }
+    out.push_str(rest);
+    found.then_some(out)
+}
+
+/// Whether an element links to an Adobe page map or carries Adobe
+/// Digital Editions metadata.
+fn is_page_map_reference(e: &BytesStart) -> bool {
+    let name = e.name();
+    match local_name(name.as_ref()) {
+        b"link" => {
+            attribute(e, b"type").is_some_and(|t| t == PAGE_MAP_TYPE)
+                || attribute(e, b"rel").is_some_and(|r| r.contains("page-map"))
+        }
+        b"meta" => attribute(e, b"name").is_some_and(|n| n.starts_with("Adept.")),
+        _ => false,
+    }
+}
+
+/// Turn straight quotes, `--` and `...` into typographic punctuation.
+///
+/// `previous` is the last character of the text before this, which decides
+/// whether a quote opens or closes, and is updated as the text is read.
+fn smarten(text: &str, previous: &mut char) -> String {
+    let opens = |c: char| c.is_whitespace() || matches!(c, '(' | '[' | '{' | '—' | '–' | '“' | '‘');
+
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        let (replacement, len) = if rest.starts_with("...") {
+            ('…', 3)
+        } else if rest.starts_with("---") {
+            ('—', 3)
+        } else if rest.starts_with("--") {
+            ('—', 2)
+        } else {
+            let replacement = match c {
+                '"' if opens(*previous) => '“',
+                '"' => '”',
+                '\'' if opens(*previous) => '‘',
+                '\'' => '’',
+                c => c,
+            };
+            (replacement, c.len_utf8())
+        };
+        out.push(replacement);
+        *previous = replacement;
+        i += len;
+    }
+    out
+}
+
+/// Numbering for `kobo.N.M` span ids.
+#[derive(Default)]
+struct Spans {
+    paragraph: u32,
+    sentence: u32,
+    /// Whether the next span starts a new paragraph.
+    pending: bool,
+}
+
+impl Spans {
+    fn new_paragraph(&mut self) {
+        self.pending = true;
+    }
+
+    fn wrap(&mut self, out: &mut String, content: &str) {
+        if self.pending || self.paragraph == 0 {
+            self.paragraph += 1;
+            self.sentence = 0;
+            self.pending = false;
+        }
+        self.sentence += 1;
+        out.push_str(&format!(
+            "<span class=\"koboSpan\" id=\"kobo.{}.{}\">{content}</span>",
+            self.paragraph, self.sentence
+        ));
+    }
+}
+
+/// Split text into sentences, each keeping its trailing punctuation and
+/// whitespace, so that joining them gives back the original text.
+fn sentences(text: &str) -> Vec<&str> {
+    let is_end = |c: char| matches!(c, '.' | '!' | '?' | '…');
+    let is_closing = |c: char| matches!(c, '"' | '\'' | '”' | '’' | ')' | ']' | '»');
+
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if !is_end(c) {
+            continue;
+        }
+        while chars
+            .next_if(|&(_, c)| is_end(c) || is_closing(c))
+            .is_some()
+        {}
+        if chars.peek().is_none_or(|&(_, c)| !c.is_whitespace()) {
+            continue;
+        }
+        while chars.next_if(|&(_, c)| c.is_whitespace()).is_some() {}
+        let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+        out.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        out.push(&text[start..]);
+    }
+    out
+}
+
+fn is_one_of(name: &[u8], names: &[&str]) -> bool {
+    names
+        .iter()
+        .any(|n| n.as_bytes().eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="id">test-book</dc:identifier>
+    <dc:title>Test Book</dc:title>
+    <dc:language>English</dc:language>
+    <meta name="cover" content="cover"/>
+  </metadata>
+  <manifest>
+    <item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="css" href="style.css" media-type="text/css"/>
+    <item id="font" href="serif.ttf" media-type="font/ttf"/>
+    <item id="map" href="page-map.xml" media-type="application/oebps-page-map+xml"/>
+    <item id="cover" href="cover.jpg" media-type="image/jpeg"/>
+  </manifest>
+  <spine page-map="map">
+    <itemref idref="ch1"/>
+  </spine>
+  <guide>
+    <reference type="text" title="Start" href="cover.jpg"/>
+  </guide>
+</package>"#;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head>
+  <title>Chapter</title>
+  <link rel="page-map" type="application/oebps-page-map+xml" href="page-map.xml"/>
+  <style>@font-face { font-family: Serif; src: url(serif.ttf); }
+p { margin: 0; }</style>
+</head>
+<body>
+  <p>"Hello," she said -- and waited... It's late.</p>
+  <pre>"as is"</pre>
+</body>
+</html>"#;
+
+    const CSS: &str =
+        "@font-face { font-family: Serif; src: url(serif.ttf); }\nbody { color: black; }\n";
+
+    /// Write the fixture book, with `chapter` as its only content document.
+    fn fixture(name: &str, chapter: &str) -> PathBuf {
+        fixture_with_package(name, OPF, chapter)
+    }
+
+    /// Write the fixture book with `opf` as its package document.
+    fn fixture_with_package(name: &str, opf: &str, chapter: &str) -> PathBuf {
+        let path = temp_path(name, "epub");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let stored =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let files = [
+            ("mimetype", "application/epub+zip"),
+            (
+                "META-INF/container.xml",
+                r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#,
+            ),
+            ("OEBPS/content.opf", opf),
+            ("OEBPS/ch1.xhtml", chapter),
+            ("OEBPS/style.css", CSS),
+            ("OEBPS/serif.ttf", "font"),
+            ("OEBPS/page-map.xml", "<page-map/>"),
+            ("OEBPS/cover.jpg", "jpeg"),
+        ];
+        for (name, content) in files {
+            zip.start_file(name, stored).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    fn temp_path(name: &str, extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "ebook-tools-kepub-{}-{name}.{extension}",
+            std::process::id()
+        ))
+    }
+
+    /// Convert the fixture book with `options`, returning the files of the
+    /// KePub.
+    fn convert(name: &str, options: &KepubOptions) -> BTreeMap<String, String> {
+        let input = fixture(name, CHAPTER);
+        let output = temp_path(name, "kepub.epub");
+        from_epub(&input, &output, options).unwrap();
+        let files = read_files(&output);
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+        files
+    }
+
+    fn read_files(path: &Path) -> BTreeMap<String, String> {
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+        let mut files = BTreeMap::new();
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i).unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            files.insert(entry.name().to_string(), content);
+        }
+        files
+    }
+
+    /// The body text of a document, without its markup.
+    fn text(source: &str) -> String {
+        let start = source.find("<body").unwrap();
+        let end = source.find("</body>").unwrap() + "</body>".len();
+        let mut reader = Reader::from_str(&source[start..end]);
+        let mut text = String::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Text(t) => text.push_str(&t.unescape().unwrap()),
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn default_options_only_add_spans() {
+        let files = convert("default", &KepubOptions::default());
+        let chapter = &files["OEBPS/ch1.xhtml"];
+        assert!(chapter.contains(r#"<span class="koboSpan" id="kobo.1.1">"#));
+        assert!(chapter.contains(r#"<div id="book-columns"><div id="book-inner">"#));
+        assert!(!chapter.contains("hyphens"));
+        assert!(!chapter.contains("lang="));
+        assert!(chapter.contains("\"Hello,\""));
+        assert!(files.contains_key("OEBPS/serif.ttf"));
+        assert!(files.contains_key("OEBPS/page-map.xml"));
+        assert!(files["OEBPS/content.opf"].contains(r#"href="cover.jpg""#));
+    }
+
+    #[test]
+    fn hyphenate() {
+        let options = KepubOptions {
+            hyphenate: Some(true),
+            ..KepubOptions::default()
+        };
+        let files = convert("hyphenate", &options);
+        let chapter = &files["OEBPS/ch1.xhtml"];
+        assert!(chapter.contains("-webkit-hyphens: auto"));
+        // The book's language is given to the document, which had none.
+        assert!(chapter
+            .contains(r#"<html xmlns="http://www.w3.org/1999/xhtml" lang="en" xml:lang="en">"#));
+
+        let options = KepubOptions {
+            hyphenate: Some(false),
+            ..KepubOptions::default()
+        };
+        let chapter = &convert("no-hyphenate", &options)["OEBPS/ch1.xhtml"];
+        assert!(chapter.contains("-webkit-hyphens: none !important"));
+        assert!(!chapter.contains("lang="));
+    }
+
+    #[test]
+    fn hyphenate_keeps_declared_language() {
+        let source = CHAPTER.replace("<html ", r#"<html xml:lang="fr" "#);
+        let options = KepubOptions {
+            hyphenate: Some(true),
+            ..KepubOptions::default()
+        };
+        let converted = add_spans(&source, &options, "", Some("en"))
+            .unwrap()
+            .unwrap();
+        assert!(converted.contains(r#"<html xml:lang="fr" xmlns="#));
+        assert!(!converted.contains(r#"lang="en""#));
+    }
+
+    #[test]
+    fn remove_fonts() {
+        let options = KepubOptions {
+            remove_fonts: true,
+            ..KepubOptions::default()
+        };
+        let files = convert("fonts", &options);
+        assert!(!files.contains_key("OEBPS/serif.ttf"));
+        assert!(!files["OEBPS/content.opf"].contains("serif.ttf"));
+        assert_eq!(files["OEBPS/style.css"], "body { color: black; }\n");
+        let chapter = &files["OEBPS/ch1.xhtml"];
+        assert!(!chapter.contains("@font-face"));
+        assert!(chapter.contains("p { margin: 0; }"));
+    }
+
+    #[test]
+    fn fullscreen_fixes() {
+        let options = KepubOptions {
+            fullscreen_fixes: true,
+            ..KepubOptions::default()
+        };
+        let chapter = &convert("fullscreen", &options)["OEBPS/ch1.xhtml"];
+        assert!(chapter.contains("object-fit: contain"));
+    }
+
+    #[test]
+    fn extra_css() {
+        let options = KepubOptions {
+            extra_css: Some("p > em { color: red & blue; }".into()),
+            ..KepubOptions::default()
+        };
+        let chapter = &convert("css", &options)["OEBPS/ch1.xhtml"];
+        // The CSS comes last, escaped for XHTML.
+        assert!(chapter.contains("\np > em { color: red &amp; blue; }</style>"));
+    }
+
+    #[test]
+    fn remove_page_map() {
+        let options = KepubOptions {
+            remove_page_map: true,
+            ..KepubOptions::default()
+        };
+        let files = convert("page-map", &options);
+        assert!(!files.contains_key("OEBPS/page-map.xml"));
+        let opf = &files["OEBPS/content.opf"];
+        assert!(!opf.contains("page-map"));
+        let chapter = &files["OEBPS/ch1.xhtml"];
+        assert!(!chapter.contains("page-map"));
+        assert!(chapter.contains("<title>Chapter</title>\n  <style>"));
+    }
+
+    #[test]
+    fn smart_punctuation() {
+        let options = KepubOptions {
+            smart_punctuation: true,
+            ..KepubOptions::default()
+        };
+        let chapter = &convert("punctuation", &options)["OEBPS/ch1.xhtml"];
+        assert_eq!(
+            text(chapter),
+            "“Hello,” she said — and waited… It’s late. \"as is\""
+        );
+    }
+
+    #[test]
+    fn fix_invalid_start() {
+        let options = KepubOptions {
+            fix_invalid_start: true,
+            ..KepubOptions::default()
+        };
+        let opf = &convert("start", &options)["OEBPS/content.opf"];
+        assert!(!opf.contains(r#"type="text""#));
+    }
+
+    #[test]
+    fn round_trip_keeps_text() {
+        let input = fixture("round-trip", CHAPTER);
+        let kepub = temp_path("round-trip", "kepub.epub");
+        let output = temp_path("round-trip-back", "epub");
+        from_epub(&input, &kepub, &KepubOptions::default()).unwrap();
+        to_epub(&kepub, &output).unwrap();
+
+        let original = read_files(&input);
+        let converted = read_files(&kepub);
+        let restored = read_files(&output);
+        for file in [&input, &kepub, &output] {
+            std::fs::remove_file(file).unwrap();
+        }
+
+        let chapter = "OEBPS/ch1.xhtml";
+        assert_eq!(text(&converted[chapter]), text(&original[chapter]));
+        assert_eq!(restored[chapter], original[chapter]);
+    }
+
+    #[test]
+    fn cover_property_only_in_epub3() {
+        let files = convert("cover-epub2", &KepubOptions::default());
+        let opf = &files["OEBPS/content.opf"];
+        assert!(!opf.contains("properties="), "{opf}");
+        assert!(opf.contains(r#"<meta name="cover" content="cover"/>"#));
+
+        let package = OPF.replace(r#"version="2.0""#, r#"version="3.0""#);
+        let input = fixture_with_package("cover-epub3", &package, CHAPTER);
+        let output = temp_path("cover-epub3", "kepub.epub");
+        let result = from_epub(&input, &output, &KepubOptions::default());
+        let files = result.as_ref().ok().map(|()| read_files(&output));
+        std::fs::remove_file(input).unwrap();
+        let _ = std::fs::remove_file(output);
+        result.unwrap();
+        assert!(files.unwrap()["OEBPS/content.opf"].contains(
+            r#"<item id="cover" href="cover.jpg" media-type="image/jpeg" properties="cover-image"/>"#
+        ));
+    }
+}
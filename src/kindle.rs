@@ -0,0 +1,297 @@
+//! Kindle files that can be identified but not read: Topaz books and KFX
+//! containers, loose or zipped.
+//!
+//! A Topaz book starts with `TPZ0` and a table of named records, each
+//! located by its offset into the payload that follows the table. Books
+//! with DRM carry their encrypted key in a `dkey` record. The `metadata`
+//! record is never encrypted, so the title and authors can be read either
+//! way.
+//!
+//! A KFX book is a container starting with `CONT`, or with `DRMION` when it
+//! is encrypted, along with sidecar files. A KFX-ZIP holds them all,
+//! together with the vouchers that unlock DRMION parts.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::format::KFX_MAGIC;
+use crate::{
+    author_display_name, is_sort_form, BookReader, DrmDetector, DrmScheme, DrmStatus, Error,
+    Format, Metadata, MetadataProvider,
+};
+
+/// Magic number of KFX containers wrapped in DRM.
+const DRMION_MAGIC: &[u8] = b"\xeaDRMION\xee";
+
+/// Marks each entry of the Topaz record table.
+const TOPAZ_RECORD: u8 = 0x63;
+
+/// Ends the Topaz record table.
+const TOPAZ_PAYLOAD: u8 = 0x64;
+
+/// A Topaz book or KFX container.
+pub struct KindleFile {
+    path: PathBuf,
+    format: Format,
+    metadata: Metadata,
+    drm_status: DrmStatus,
+    warnings: Vec<String>,
+}
+
+impl KindleFile {
+    /// Open a Topaz book, KFX container or KFX-ZIP at the given path.
+    pub fn open(path: &Path) -> crate::Result<Self> {
+        let format = Format::from_file(path)?;
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut warnings = Vec::new();
+
+        let (metadata, drm_status) = match format {
+            Format::Topaz => read_topaz(&mut reader, &mut warnings)?,
+            Format::Kfx => {
+                let mut head = Vec::new();
+                reader.take(8).read_to_end(&mut head)?;
+                let drm_status = if head.starts_with(DRMION_MAGIC) {
+                    DrmStatus::Protected(DrmScheme::AmazonKfx)
+                } else {
+                    DrmStatus::None
+                };
+                (Metadata::default(), drm_status)
+            }
+            Format::KfxZip => (Metadata::default(), kfx_zip_drm(reader)?),
+            format => return Err(Error::UnsupportedFormat(format)),
+        };
+
+        Ok(KindleFile {
+            path: path.into(),
+            format,
+            metadata,
+            drm_status,
+            warnings,
+        })
+    }
+
+    /// The file path this book was opened from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The detected format: Topaz, KFX or KFX-ZIP.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Any validation warnings collected during parsing.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+impl BookReader for KindleFile {
+    type Book = KindleFile;
+
+    fn open(path: &Path) -> crate::Result<Self::Book> {
+        KindleFile::open(path)
+    }
+}
+
+impl MetadataProvider for KindleFile {
+    /// The metadata of a Topaz book. KFX metadata is not read, so it is
+    /// always empty.
+    fn metadata(&self) -> crate::Result<Metadata> {
+        Ok(self.metadata.clone())
+    }
+}
+
+impl DrmDetector for KindleFile {
+    fn drm_status(&self) -> crate::Result<DrmStatus> {
+        Ok(self.drm_status.clone())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Internal helpers
+// ---------------------------------------------------------------------------
+
+/// Read the record table of a Topaz book, returning its metadata and
+/// whether it has DRM.
+fn read_topaz<R: Read + Seek>(
+    reader: &mut R,
+    warnings: &mut Vec<String>,
+) -> crate::Result<(Metadata, DrmStatus)> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != b"TPZ0" {
+        return Err(invalid("not a Topaz book"));
+    }
+
+    // The offset of the first part of each record.
+    let mut records = HashMap::new();
+    let count = read_count(reader)?;
+    for _ in 0..count {
+        if read_byte(reader)? != TOPAZ_RECORD {
+            return Err(invalid("Topaz record table is corrupt"));
+        }
+        let name = read_string(reader)?;
+        // Each part has an offset, a length and a compressed length.
+        let parts = read_count(reader)?;
+        let mut first = None;
+        for _ in 0..parts {
+            let offset = read_number(reader)?;
+            read_number(reader)?;
+            read_number(reader)?;
+            first.get_or_insert(offset);
+        }
+        if let Some(offset) = first {
+            records.insert(name, offset);
+        }
+    }
+    if read_byte(reader)? != TOPAZ_PAYLOAD {
+        return Err(invalid("Topaz record table is corrupt"));
+    }
+    let payload = reader.stream_position()?;
+
+    let drm_status = if records.contains_key("dkey") {
+        DrmStatus::Protected(DrmScheme::AmazonTopaz)
+    } else {
+        DrmStatus::None
+    };
+
+    let metadata = match records.get("metadata") {
+        Some(&offset) => match read_topaz_metadata(reader, payload.saturating_add_signed(offset)) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warnings.push(format!("cannot read the metadata record: {e}"));
+                Metadata::default()
+            }
+        },
+        None => {
+            warnings.push("book has no metadata record".into());
+            Metadata::default()
+        }
+    };
+    Ok((metadata, drm_status))
+}
+
+/// Read the `metadata` record of a Topaz book, a list of key and value
+/// pairs, at `offset` in the file.
+fn read_topaz_metadata<R: Read + Seek>(reader: &mut R, offset: u64) -> crate::Result<Metadata> {
+    reader.seek(SeekFrom::Start(offset))?;
+    if read_string(reader)? != "metadata" {
+        return Err(invalid("record name does not match"));
+    }
+    // Flags, then the number of pairs.
+    read_byte(reader)?;
+    let count = read_byte(reader)?;
+
+    let mut metadata = Metadata::default();
+    for _ in 0..count {
+        let key = read_string(reader)?;
+        let value = read_string(reader)?.trim().to_string();
+        if value.is_empty() {
+            continue;
+        }
+        match key.as_str() {
+            "Title" => metadata.title = Some(value),
+            "Authors" => {
+                for name in value
+                    .split(['&', ';'])
+                    .map(str::trim)
+                    .filter(|n| !n.is_empty())
+                {
+                    if is_sort_form(name) {
+                        let display = author_display_name(name);
+                        metadata
+                            .author_sort
+                            .insert(display.clone(), name.to_string());
+                        metadata.authors.push(display);
+                    } else {
+                        metadata.authors.push(name.to_string());
+                    }
+                }
+            }
+            "ASIN" => {
+                metadata.identifiers.insert("asin".into(), value);
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+/// Whether a KFX-ZIP has DRM: an encrypted container or a voucher to
+/// unlock one.
+fn kfx_zip_drm<R: Read + Seek>(reader: R) -> crate::Result<DrmStatus> {
+    let mut zip = zip::ZipArchive::new(reader)
+        .map_err(|e| invalid(&format!("not a valid ZIP archive: {e}")))?;
+    let mut has_container = false;
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        if entry.name().to_lowercase().ends_with(".voucher") {
+            return Ok(DrmStatus::Protected(DrmScheme::AmazonKfx));
+        }
+        let mut head = Vec::new();
+        entry.take(8).read_to_end(&mut head)?;
+        if head.starts_with(DRMION_MAGIC) {
+            return Ok(DrmStatus::Protected(DrmScheme::AmazonKfx));
+        }
+        has_container |= KFX_MAGIC.iter().any(|magic| head.starts_with(magic));
+    }
+    Ok(if has_container {
+        DrmStatus::None
+    } else {
+        DrmStatus::Unknown
+    })
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> crate::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader
+        .read_exact(&mut byte)
+        .map_err(|_| invalid("Topaz header is truncated"))?;
+    Ok(byte[0])
+}
+
+/// Read a Topaz number: seven bits per byte, most significant first, with
+/// the high bit set on every byte but the last. A leading `0xFF` makes it
+/// negative.
+fn read_number<R: Read>(reader: &mut R) -> crate::Result<i64> {
+    let mut byte = read_byte(reader)?;
+    let negative = byte == 0xFF;
+    if negative {
+        byte = read_byte(reader)?;
+    }
+    let mut value = i64::from(byte & 0x7F);
+    // Nine bytes are more than any real value needs.
+    let mut len = 1;
+    while byte & 0x80 != 0 {
+        if len == 9 {
+            return Err(invalid("Topaz number is too long"));
+        }
+        byte = read_byte(reader)?;
+        value = (value << 7) | i64::from(byte & 0x7F);
+        len += 1;
+    }
+    Ok(if negative { -value } else { value })
+}
+
+/// Read a count, which can't be negative.
+fn read_count<R: Read>(reader: &mut R) -> crate::Result<u64> {
+    u64::try_from(read_number(reader)?).map_err(|_| invalid("Topaz count is negative"))
+}
+
+/// Read a string: its length as a number, then its bytes.
+fn read_string<R: Read>(reader: &mut R) -> crate::Result<String> {
+    let len = read_count(reader)?;
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(invalid("Topaz string is truncated"));
+    }
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(message.to_string())
+}
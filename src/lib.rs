@@ -1,13 +1,27 @@
+mod atomic;
+pub mod calibre;
 mod drm;
 pub mod epub;
 mod error;
 mod format;
+mod html;
+mod image;
+pub mod kepub;
+pub mod kindle;
 mod metadata;
+pub mod mobi;
 mod traits;
 
 pub use drm::{DrmScheme, DrmStatus};
 pub use epub::EpubBook;
 pub use error::{Error, Result};
 pub use format::Format;
-pub use metadata::Metadata;
+pub use kindle::KindleFile;
+pub use metadata::{
+    author_display_name, author_sort_name, detect_series, is_sort_form, metadata_from_path,
+    normalize_language, title_sort_name, truncate_graphemes, Contributor, Description, FieldPatch,
+    FilenamePattern, Isbn, IsbnError, ListPatch, MergePolicy, Metadata, MetadataField,
+    MetadataPatch, SeriesInfo, W3cDate, BUILTIN_FILENAME_PATTERNS,
+};
+pub use mobi::MobiBook;
 pub use traits::{BookReader, CoverProvider, CoverWriter, DrmDetector, MetadataProvider, MetadataWriter};
@@ -1,14 +0,0 @@
-/// Metadata associated with an ebook.
-#[derive(Debug, Clone, Default)]
-pub struct Metadata {
-    pub title: Option<String>,
-    pub authors: Vec<String>,
-    pub description: Option<String>,
-    pub publisher: Option<String>,
-    pub language: Option<String>,
-    pub isbn: Option<String>,
-    pub publication_date: Option<String>,
-    pub subjects: Vec<String>,
-    pub series: Option<String>,
-    pub series_index: Option<f64>,
-}
@@ -0,0 +1,225 @@
+//! W3CDTF dates as used by `dc:date` and `dcterms:modified`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A date in W3CDTF form, which may be partial: a year (`2010`), a month
+/// (`2010-05`), a day (`2010-05-12`) or a full timestamp
+/// (`2010-05-12T10:00:00Z`).
+///
+/// Serializes as its normalized string form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct W3cDate {
+    year: i32,
+    month: Option<u8>,
+    day: Option<u8>,
+    time: Option<Time>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Time {
+    hour: u8,
+    minute: u8,
+    second: u8,
+    /// Offset from UTC in minutes, if the timestamp gave one.
+    offset: Option<i16>,
+}
+
+impl W3cDate {
+    /// The current time in UTC, to the second.
+    pub fn now() -> W3cDate {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+        let secs_of_day = secs % 86_400;
+        W3cDate {
+            year,
+            month: Some(month),
+            day: Some(day),
+            time: Some(Time {
+                hour: (secs_of_day / 3600) as u8,
+                minute: (secs_of_day / 60 % 60) as u8,
+                second: (secs_of_day % 60) as u8,
+                offset: Some(0),
+            }),
+        }
+    }
+
+    /// Parse a W3CDTF date.
+    ///
+    /// Fractional seconds are dropped, and a space is accepted in place of
+    /// the `T` separator. Returns `None` if the string isn't a valid date.
+    pub fn parse(s: &str) -> Option<W3cDate> {
+        let s = s.trim();
+        let (date, time) = match s.find(['T', 't', ' ']) {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        let mut parts = date.split('-');
+        let year = parse_number(parts.next()?, 4)?;
+        let mut field = || match parts.next() {
+            Some(p) => parse_number(p, 2).map(|n| Some(n as u8)),
+            None => Some(None),
+        };
+        let month = field()?;
+        let day = field()?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        if month.is_some_and(|m| !(1..=12).contains(&m)) {
+            return None;
+        }
+        if let (Some(month), Some(day)) = (month, day)
+            && (day == 0 || day > days_in_month(year, month))
+        {
+            return None;
+        }
+
+        let time = match time {
+            Some(time) if day.is_some() => Some(parse_time(time)?),
+            Some(_) => return None,
+            None => None,
+        };
+
+        Some(W3cDate {
+            year,
+            month,
+            day,
+            time,
+        })
+    }
+
+    pub fn year(&self) -> i32 {
+        self.year
+    }
+
+    pub fn month(&self) -> Option<u8> {
+        self.month
+    }
+
+    pub fn day(&self) -> Option<u8> {
+        self.day
+    }
+
+    /// Whether this is a placeholder for an unknown date. Calibre writes
+    /// `0101-01-01` for books without a publication date.
+    pub fn is_undefined(&self) -> bool {
+        self.year <= 101
+    }
+}
+
+impl fmt::Display for W3cDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}", self.year)?;
+        if let Some(month) = self.month {
+            write!(f, "-{month:02}")?;
+        }
+        if let Some(day) = self.day {
+            write!(f, "-{day:02}")?;
+        }
+        if let Some(time) = &self.time {
+            write!(f, "T{:02}:{:02}:{:02}", time.hour, time.minute, time.second)?;
+            match time.offset {
+                Some(0) => f.write_str("Z")?,
+                Some(offset) => {
+                    let sign = if offset < 0 { '-' } else { '+' };
+                    let offset = offset.unsigned_abs();
+                    write!(f, "{sign}{:02}:{:02}", offset / 60, offset % 60)?;
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for W3cDate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        W3cDate::parse(s).ok_or_else(|| format!("invalid date: {s} (expected YYYY[-MM[-DD]])"))
+    }
+}
+
+impl Serialize for W3cDate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for W3cDate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parse `hh:mm[:ss[.s]]` followed by an optional `Z` or `±hh:mm` zone.
+fn parse_time(s: &str) -> Option<Time> {
+    let (clock, offset) = if let Some(clock) = s.strip_suffix(['Z', 'z']) {
+        (clock, Some(0))
+    } else if let Some(i) = s.rfind(['+', '-']) {
+        let (hours, minutes) = s[i + 1..].split_once(':').unwrap_or((&s[i + 1..], "00"));
+        let minutes = parse_number(hours, 2)? * 60 + parse_number(minutes, 2)?;
+        let sign = if s[i..].starts_with('-') { -1 } else { 1 };
+        (&s[..i], Some(sign * minutes as i16))
+    } else {
+        (s, None)
+    };
+
+    let mut parts = clock.split(':');
+    let hour = parse_number(parts.next()?, 2)? as u8;
+    let minute = parse_number(parts.next()?, 2)? as u8;
+    let second = match parts.next() {
+        Some(s) => parse_number(s.split('.').next()?, 2)? as u8,
+        None => 0,
+    };
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    Some(Time {
+        hour,
+        minute,
+        second,
+        offset,
+    })
+}
+
+/// Parse a field of exactly `width` ASCII digits.
+fn parse_number(s: &str, width: usize) -> Option<i32> {
+    (s.len() == width && s.bytes().all(|b| b.is_ascii_digit()))
+        .then(|| s.parse().ok())
+        .flatten()
+}
+
+fn days_in_month(year: i32, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    // Howard Hinnant's algorithm, counting from 0000-03-01.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year as i32, month, day)
+}
@@ -0,0 +1,321 @@
+//! HTML-aware handling of book descriptions.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::markdown;
+use crate::html::{self, Token};
+
+/// A book description, held as sanitized XHTML.
+///
+/// Descriptions in the wild are plain text, HTML, or HTML that was escaped
+/// once more than it should have been; [`Description::parse`] accepts all
+/// of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    html: String,
+}
+
+impl Description {
+    /// Interpret a description as found in a book's metadata.
+    ///
+    /// HTML is sanitized down to basic formatting. Plain text is split into
+    /// paragraphs at blank lines, with single newlines kept as line breaks.
+    pub fn parse(description: &str) -> Description {
+        if html::looks_like_html(description) {
+            return Description {
+                html: html::sanitize(description),
+            };
+        }
+
+        // HTML that was escaped twice shows up as "&lt;p&gt;" after the
+        // XML parser has done its unescaping.
+        if description.contains("&lt;") {
+            let decoded = html::decode_entities(description);
+            if html::looks_like_html(&decoded) {
+                return Description {
+                    html: html::sanitize(&decoded),
+                };
+            }
+        }
+
+        Description {
+            html: text_to_html(description),
+        }
+    }
+
+    /// Convert Markdown to a sanitized description.
+    pub fn from_markdown(markdown: &str) -> Description {
+        Description {
+            html: html::sanitize(&markdown::to_html(markdown)),
+        }
+    }
+
+    /// Whether a description contains HTML markup (unescaped or escaped).
+    pub fn is_html(description: &str) -> bool {
+        html::looks_like_html(description)
+            || (description.contains("&lt;")
+                && html::looks_like_html(&html::decode_entities(description)))
+    }
+
+    /// The sanitized XHTML. Only basic formatting tags are kept and links
+    /// are limited to `http`, `https` and `mailto` targets.
+    pub fn html(&self) -> &str {
+        &self.html
+    }
+
+    /// Render as plain text, with blank lines between paragraphs and `-` or
+    /// numbered markers for list items.
+    pub fn to_text(&self) -> String {
+        self.render(false)
+    }
+
+    /// Render as Markdown.
+    pub fn to_markdown(&self) -> String {
+        self.render(true)
+    }
+
+    fn render(&self, markdown: bool) -> String {
+        let mut w = Renderer {
+            markdown,
+            ..Renderer::default()
+        };
+
+        for token in html::tokenize(&self.html) {
+            match token {
+                Token::Start { name, attrs } => match name.as_str() {
+                    "br" => w.line_break(),
+                    "hr" => {
+                        w.block(2);
+                        w.push_raw(if markdown { "---" } else { "----" });
+                        w.block(2);
+                    }
+                    "p" | "div" => w.block(2),
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                        w.block(2);
+                        if markdown {
+                            let level = name[1..].parse().unwrap_or(1);
+                            w.push_raw(&format!("{} ", "#".repeat(level)));
+                        }
+                    }
+                    "blockquote" => {
+                        w.block(2);
+                        w.quote_depth += 1;
+                    }
+                    "pre" => {
+                        w.block(2);
+                        if markdown {
+                            w.push_raw("```");
+                            w.block(1);
+                        }
+                        w.pre = true;
+                    }
+                    "ul" | "ol" => {
+                        w.block(if w.lists.is_empty() { 2 } else { 1 });
+                        w.lists.push((name == "ol").then_some(0));
+                    }
+                    "li" => {
+                        w.block(1);
+                        let indent = "  ".repeat(w.lists.len().saturating_sub(1));
+                        let marker = match w.lists.last_mut() {
+                            Some(Some(n)) => {
+                                *n += 1;
+                                format!("{n}. ")
+                            }
+                            _ => "- ".to_string(),
+                        };
+                        w.push_raw(&format!("{indent}{marker}"));
+                    }
+                    "b" | "strong" if markdown => w.push_raw("**"),
+                    "i" | "em" | "cite" if markdown => w.push_raw("*"),
+                    "code" if markdown && !w.pre => w.push_raw("`"),
+                    "a" if markdown => {
+                        w.push_raw("[");
+                        let href = attrs.into_iter().find(|(k, _)| k == "href");
+                        w.links.push(href.map(|(_, v)| v).unwrap_or_default());
+                    }
+                    _ => {}
+                },
+                Token::End(name) => match name.as_str() {
+                    "p" | "div" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => w.block(2),
+                    "blockquote" => {
+                        w.block(2);
+                        w.quote_depth = w.quote_depth.saturating_sub(1);
+                    }
+                    "pre" => {
+                        w.pre = false;
+                        if markdown {
+                            w.block(1);
+                            w.push_raw("```");
+                        }
+                        w.block(2);
+                    }
+                    "ul" | "ol" => {
+                        w.lists.pop();
+                        w.block(if w.lists.is_empty() { 2 } else { 1 });
+                    }
+                    "b" | "strong" if markdown => w.push_raw("**"),
+                    "i" | "em" | "cite" if markdown => w.push_raw("*"),
+                    "code" if markdown && !w.pre => w.push_raw("`"),
+                    "a" if markdown => {
+                        let href = w.links.pop().unwrap_or_default();
+                        w.push_raw(&format!("]({href})"));
+                    }
+                    _ => {}
+                },
+                Token::Text(text) => w.text(&text),
+            }
+        }
+
+        w.out.trim_end().to_string()
+    }
+}
+
+/// Shorten text to at most `max` grapheme clusters, never splitting a
+/// character or a combining sequence.
+pub fn truncate_graphemes(text: &str, max: usize) -> &str {
+    match text.grapheme_indices(true).nth(max) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Wrap plain text in paragraphs.
+fn text_to_html(text: &str) -> String {
+    let mut out = String::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        let lines: Vec<String> = paragraph
+            .lines()
+            .map(|l| html::escape(l.trim(), false))
+            .collect();
+        out.push_str("<p>");
+        out.push_str(&lines.join("<br/>"));
+        out.push_str("</p>");
+    }
+    out
+}
+
+/// Accumulates text output, collapsing whitespace and inserting line breaks
+/// between blocks.
+#[derive(Default)]
+struct Renderer {
+    markdown: bool,
+    out: String,
+    /// Newlines to insert before the next text.
+    pending: usize,
+    /// The block quote depth when the pending newlines were requested.
+    pending_depth: usize,
+    quote_depth: usize,
+    /// Open lists, with the last number used for numbered lists.
+    lists: Vec<Option<usize>>,
+    /// Targets of open links.
+    links: Vec<String>,
+    pre: bool,
+}
+
+impl Renderer {
+    /// End the current block with at least `newlines` line breaks.
+    fn block(&mut self, newlines: usize) {
+        if !self.out.is_empty() {
+            if self.pending == 0 {
+                self.pending_depth = self.quote_depth;
+            }
+            self.pending = self.pending.max(newlines);
+            self.pending_depth = self.pending_depth.min(self.quote_depth);
+        }
+    }
+
+    fn line_break(&mut self) {
+        if self.markdown {
+            self.push_raw("\\");
+        }
+        self.block(1);
+    }
+
+    /// Write pending line breaks, prefixing new lines inside block quotes.
+    fn flush(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        // Blank lines only continue a block quote if it is open on both
+        // sides of them.
+        let depth = self.pending_depth.min(self.quote_depth);
+        for n in 0..self.pending {
+            self.out.push('\n');
+            if n + 1 < self.pending && depth > 0 && self.markdown {
+                self.out.push_str(&">".repeat(depth));
+            }
+        }
+        self.pending = 0;
+        self.start_line();
+    }
+
+    fn start_line(&mut self) {
+        if self.quote_depth > 0 {
+            let prefix = if self.markdown { "> " } else { "    " };
+            self.out.push_str(&prefix.repeat(self.quote_depth));
+        }
+    }
+
+    fn push_raw(&mut self, s: &str) {
+        if self.out.is_empty() {
+            self.start_line();
+        }
+        self.flush();
+        self.out.push_str(s);
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.pre {
+            for (n, line) in text.split('\n').enumerate() {
+                if n > 0 {
+                    self.block(1);
+                }
+                if !line.is_empty() {
+                    self.push_raw(line);
+                }
+            }
+            return;
+        }
+
+        let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if collapsed.is_empty() {
+            if !text.is_empty() && !self.at_line_start() && self.pending == 0 {
+                self.out.push(' ');
+            }
+            return;
+        }
+
+        let leading = text.starts_with(char::is_whitespace);
+        let trailing = text.ends_with(char::is_whitespace);
+        let escaped = if self.markdown {
+            escape_markdown(&collapsed)
+        } else {
+            collapsed
+        };
+
+        if leading && self.pending == 0 && !self.at_line_start() {
+            self.out.push(' ');
+        }
+        self.push_raw(&escaped);
+        if trailing {
+            self.out.push(' ');
+        }
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with(['\n', ' '])
+    }
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
@@ -0,0 +1,287 @@
+//! Guessing metadata from file names and folder structure, such as
+//! `Author Name - Series 03 - Title.epub` or `Author/Series/03 Title.epub`.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex_lite::Regex;
+
+use super::{author_display_name, author_sort_name, is_sort_form, Metadata, W3cDate};
+use crate::{Error, Format};
+
+/// Patterns tried by [`metadata_from_path`] after any user-supplied ones,
+/// most specific first.
+pub const BUILTIN_FILENAME_PATTERNS: &[&str] = &[
+    "{author} - {series} {series_index} - {title}",
+    "{author} - [{series} {series_index}] - {title}",
+    "{author} - {title} ({series} {series_index})",
+    "{author} - {title} [{series} {series_index}]",
+    "{author}/{series}/{series_index} - {title}",
+    "{author}/{series}/{series_index} {title}",
+    "{author} - {title} ({year})",
+    "{author} - {title}",
+];
+
+/// A pattern describing how metadata is laid out in a file path, such as
+/// `{author} - {series} {series_index} - {title}`.
+///
+/// Placeholders are `{title}`, `{author}`, `{series}`, `{series_index}`,
+/// `{year}`, `{publisher}`, `{isbn}` and `{*}`, which matches anything and
+/// is discarded. A `/` matches a directory separator, so
+/// `{author}/{series}/{series_index} {title}` reads the two enclosing
+/// directories as well. Spaces also match underscores, and the file
+/// extension is ignored.
+#[derive(Debug, Clone)]
+pub struct FilenamePattern {
+    pattern: String,
+    regex: Regex,
+    fields: Vec<Field>,
+    /// The number of path components the pattern covers.
+    components: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Title,
+    Author,
+    Series,
+    SeriesIndex,
+    Year,
+    Publisher,
+    Isbn,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        Some(match name {
+            "title" => Field::Title,
+            "author" | "authors" => Field::Author,
+            "series" => Field::Series,
+            "series_index" => Field::SeriesIndex,
+            "year" => Field::Year,
+            "publisher" => Field::Publisher,
+            "isbn" => Field::Isbn,
+            _ => return None,
+        })
+    }
+
+    /// The regex a placeholder expands to.
+    fn regex(self) -> &'static str {
+        match self {
+            Field::SeriesIndex => r"(?:#|[Bb]ook |[Vv]ol\.? ?)?(\d{1,4}(?:\.\d+)?)",
+            Field::Year => r"(\d{4})",
+            Field::Isbn => r"((?:97[89][- ]?)?(?:\d[- ]?){9}[\dXx])",
+            _ => r"([^/]+?)",
+        }
+    }
+}
+
+impl FilenamePattern {
+    /// Compile a pattern.
+    pub fn new(pattern: &str) -> Result<FilenamePattern, Error> {
+        let invalid = |reason: &str| Error::InvalidPattern(format!("{pattern}: {reason}"));
+
+        let mut regex = String::from("^");
+        let mut fields = Vec::new();
+        let mut rest = pattern;
+        while !rest.is_empty() {
+            let Some(start) = rest.find('{') else {
+                regex.push_str(&literal(rest));
+                break;
+            };
+            regex.push_str(&literal(&rest[..start]));
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed '{'"))?;
+            let name = rest[start + 1..start + end].trim();
+            if name == "*" {
+                regex.push_str(".*?");
+            } else {
+                let field = Field::from_name(name)
+                    .ok_or_else(|| invalid(&format!("unknown placeholder {{{name}}}")))?;
+                if fields.contains(&field) {
+                    return Err(invalid(&format!("{{{name}}} appears twice")));
+                }
+                fields.push(field);
+                regex.push_str(field.regex());
+            }
+            rest = &rest[start + end + 1..];
+        }
+        regex.push('$');
+
+        if fields.is_empty() {
+            return Err(invalid("no placeholders"));
+        }
+        let regex = Regex::new(&regex).map_err(|e| invalid(&e.to_string()))?;
+
+        Ok(FilenamePattern {
+            pattern: pattern.to_string(),
+            regex,
+            fields,
+            components: pattern.matches('/').count() + 1,
+        })
+    }
+
+    /// The built-in patterns, in the order they are tried.
+    pub fn builtin() -> Vec<FilenamePattern> {
+        BUILTIN_FILENAME_PATTERNS
+            .iter()
+            .map(|p| FilenamePattern::new(p).expect("built-in pattern is valid"))
+            .collect()
+    }
+
+    /// The pattern as written.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Match the end of a path against this pattern, returning the metadata
+    /// it describes.
+    pub fn parse(&self, path: &Path) -> Option<Metadata> {
+        let subject = path_tail(path, self.components)?;
+        let captures = self.regex.captures(&subject)?;
+
+        let mut metadata = Metadata::default();
+        for (field, capture) in self.fields.iter().zip(captures.iter().skip(1)) {
+            let value = clean(capture?.as_str());
+            if value.is_empty() {
+                continue;
+            }
+            match field {
+                Field::Title => metadata.title = Some(value),
+                Field::Author => set_authors(&mut metadata, &value),
+                Field::Series => metadata.series = Some(value),
+                Field::SeriesIndex => metadata.series_index = value.parse().ok(),
+                Field::Year => metadata.publication_date = W3cDate::parse(&value),
+                Field::Publisher => metadata.publisher = Some(value),
+                Field::Isbn => metadata.isbn = Some(value.replace(['-', ' '], "")),
+            }
+        }
+
+        Some(metadata)
+    }
+}
+
+impl fmt::Display for FilenamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl FromStr for FilenamePattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FilenamePattern::new(s)
+    }
+}
+
+/// Guess metadata from a file's name and the directories containing it.
+///
+/// The given patterns are tried first, then [`BUILTIN_FILENAME_PATTERNS`];
+/// the first one that matches wins. If none does, the file name is taken
+/// as the title. Returns `None` only for paths without a file name.
+pub fn metadata_from_path(path: &Path, patterns: &[FilenamePattern]) -> Option<Metadata> {
+    patterns
+        .iter()
+        .find_map(|p| p.parse(path))
+        .or_else(|| {
+            FilenamePattern::builtin()
+                .iter()
+                .find_map(|p| p.parse(path))
+        })
+        .or_else(|| {
+            // Fall back to the bare file name as the title.
+            let title = clean(&path_tail(path, 1)?);
+            (!title.is_empty()).then(|| Metadata {
+                title: Some(title),
+                ..Metadata::default()
+            })
+        })
+}
+
+/// The last `components` components of a path, joined with `/`, without
+/// the file extension and with underscores turned into spaces.
+fn path_tail(path: &Path, components: usize) -> Option<String> {
+    let names: Vec<String> = path
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    if names.len() < components {
+        return None;
+    }
+
+    let mut tail = names[names.len() - components..].to_vec();
+    if let Some(name) = tail.last_mut() {
+        *name = Format::strip_extension(name).to_string();
+    }
+    Some(tail.join("/").replace('_', " "))
+}
+
+/// Escape literal pattern text, letting spaces match runs of whitespace.
+fn literal(text: &str) -> String {
+    text.split(' ')
+        .map(regex_lite::escape)
+        .collect::<Vec<_>>()
+        .join(r"\s+")
+}
+
+fn clean(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Split an author field on " & ", " and " or ";" and record sort names
+/// for names written as "Last, First".
+fn set_authors(metadata: &mut Metadata, value: &str) {
+    let names = value
+        .split(';')
+        .flat_map(|part| part.split(" & "))
+        .flat_map(|part| part.split(" and "))
+        .map(str::trim)
+        .filter(|name| !name.is_empty());
+
+    for name in names {
+        if is_sort_form(name) {
+            let display = author_display_name(name);
+            metadata
+                .author_sort
+                .insert(display.clone(), name.to_string());
+            metadata.authors.push(display);
+        } else {
+            metadata
+                .author_sort
+                .insert(name.to_string(), author_sort_name(name));
+            metadata.authors.push(name.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_tail_strips_real_extension() {
+        assert_eq!(
+            path_tail(Path::new("/tmp/tpz/Book.tpz"), 1).unwrap(),
+            "Book"
+        );
+        assert_eq!(
+            path_tail(Path::new("Shelf/My_Book.KEPUB.EPUB"), 2).unwrap(),
+            "Shelf/My Book"
+        );
+        assert_eq!(path_tail(Path::new(".epub"), 1).unwrap(), ".epub");
+    }
+
+    #[test]
+    fn non_ascii_name_with_mismatched_extension() {
+        let path = Path::new("/tmp/tpz/Книга.tpz");
+        assert_eq!(path_tail(path, 1).unwrap(), "Книга");
+        let metadata = metadata_from_path(path, &[]).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Книга"));
+    }
+}
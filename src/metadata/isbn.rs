@@ -0,0 +1,235 @@
+//! ISBN validation, normalization and conversion.
+
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// Reasons a string is not a valid ISBN.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum IsbnError {
+    #[error("not an ISBN: {0}")]
+    Malformed(String),
+
+    #[error("bad check digit in ISBN {isbn} (expected {expected})")]
+    CheckDigit { isbn: String, expected: char },
+}
+
+/// A validated ISBN-10 or ISBN-13, stored without hyphens.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Isbn {
+    digits: String,
+}
+
+impl Isbn {
+    /// Parse an ISBN, ignoring hyphens, spaces and a leading `urn:isbn:` or
+    /// `ISBN` label, and verify its check digit.
+    pub fn parse(s: &str) -> Result<Isbn, IsbnError> {
+        let malformed = || IsbnError::Malformed(s.trim().to_string());
+
+        let digits: String = strip_label(s.trim())
+            .chars()
+            .filter(|c| !matches!(c, '-' | ' ' | '\u{2010}'..='\u{2015}'))
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+
+        let (body, check) = match digits.len() {
+            10 => digits.split_at(9),
+            13 if digits.starts_with("978") || digits.starts_with("979") => digits.split_at(12),
+            _ => return Err(malformed()),
+        };
+        if !body.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(malformed());
+        }
+
+        let expected = check_digit(body);
+        match check.chars().next() {
+            Some(c) if c == expected => Ok(Isbn { digits }),
+            Some(c) if c.is_ascii_digit() || (c == 'X' && body.len() == 9) => {
+                Err(IsbnError::CheckDigit {
+                    isbn: digits,
+                    expected,
+                })
+            }
+            _ => Err(malformed()),
+        }
+    }
+
+    /// The ISBN without hyphens.
+    pub fn as_str(&self) -> &str {
+        &self.digits
+    }
+
+    /// Whether this is a 13-digit ISBN.
+    pub fn is_isbn13(&self) -> bool {
+        self.digits.len() == 13
+    }
+
+    /// The ISBN-13 form, adding the `978` prefix to an ISBN-10.
+    pub fn to_isbn13(&self) -> Isbn {
+        if self.is_isbn13() {
+            return self.clone();
+        }
+        let body = format!("978{}", &self.digits[..9]);
+        let check = check_digit(&body);
+        Isbn {
+            digits: format!("{body}{check}"),
+        }
+    }
+
+    /// The ISBN-10 form, if there is one. Only ISBN-13s with the `978`
+    /// prefix have an ISBN-10 equivalent.
+    pub fn to_isbn10(&self) -> Option<Isbn> {
+        if !self.is_isbn13() {
+            return Some(self.clone());
+        }
+        let body = self.digits.strip_prefix("978")?.get(..9)?;
+        let check = check_digit(body);
+        Some(Isbn {
+            digits: format!("{body}{check}"),
+        })
+    }
+
+    /// The ISBN split into its elements with hyphens, e.g. `978-0-306-40615-7`.
+    ///
+    /// The registration group comes from the ISBN agency's group ranges. The
+    /// registrant ranges are only known for the larger language areas, so
+    /// `None` is returned for ISBNs from other groups.
+    pub fn hyphenated(&self) -> Option<String> {
+        let isbn13 = self.to_isbn13();
+        let (prefix, rest) = isbn13.digits.split_at(3);
+        let rest = &rest[..9];
+
+        let group_len = range_length(GROUP_RANGES, prefix, rest)?;
+        let (group, rest) = rest.split_at(group_len);
+        let registrant_len = range_length(REGISTRANT_RANGES, &format!("{prefix}-{group}"), rest)?;
+        let (registrant, publication) = rest.split_at(registrant_len);
+
+        let check = &self.digits[self.digits.len() - 1..];
+        Some(if self.is_isbn13() {
+            format!("{prefix}-{group}-{registrant}-{publication}-{check}")
+        } else {
+            format!("{group}-{registrant}-{publication}-{check}")
+        })
+    }
+}
+
+impl fmt::Display for Isbn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.digits)
+    }
+}
+
+impl FromStr for Isbn {
+    type Err = IsbnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Isbn::parse(s)
+    }
+}
+
+/// Strip a leading `urn:isbn:`, `isbn:` or `ISBN-13 ` style label.
+fn strip_label(s: &str) -> &str {
+    let mut rest = s;
+    if rest
+        .get(..4)
+        .is_some_and(|p| p.eq_ignore_ascii_case("urn:"))
+    {
+        rest = &rest[4..];
+    }
+    if rest
+        .get(..4)
+        .is_some_and(|p| p.eq_ignore_ascii_case("isbn"))
+    {
+        rest = &rest[4..];
+        rest = rest
+            .strip_prefix("-13")
+            .or_else(|| rest.strip_prefix("-10"))
+            .unwrap_or(rest);
+        rest = rest.trim_start_matches([':', ' ']);
+    }
+    rest
+}
+
+/// The check digit for the first 9 (ISBN-10) or 12 (ISBN-13) digits.
+fn check_digit(body: &str) -> char {
+    let digits = body.bytes().map(|b| u32::from(b - b'0'));
+    if body.len() == 9 {
+        let sum: u32 = digits.zip((2..=10).rev()).map(|(d, w)| d * w).sum();
+        match (11 - sum % 11) % 11 {
+            10 => 'X',
+            n => char::from_digit(n, 10).unwrap_or('0'),
+        }
+    } else {
+        let sum: u32 = digits
+            .zip([1, 3].into_iter().cycle())
+            .map(|(d, w)| d * w)
+            .sum();
+        char::from_digit((10 - sum % 10) % 10, 10).unwrap_or('0')
+    }
+}
+
+/// Find the element length for `digits` in the ranges listed under `key`.
+///
+/// Ranges are compared against the first seven digits, padded with zeros
+/// as in the agency's range tables.
+fn range_length(ranges: &[(&str, u32, u32, usize)], key: &str, digits: &str) -> Option<usize> {
+    let value: u32 = format!("{digits:0<7}").get(..7)?.parse().ok()?;
+    ranges
+        .iter()
+        .find(|(k, lo, hi, _)| *k == key && (*lo..=*hi).contains(&value))
+        .map(|(_, _, _, len)| *len)
+        .filter(|len| *len > 0 && *len < digits.len())
+}
+
+/// Registration group element lengths for each EAN prefix.
+const GROUP_RANGES: &[(&str, u32, u32, usize)] = &[
+    ("978", 0, 5_999_999, 1),
+    ("978", 6_000_000, 6_499_999, 3),
+    ("978", 6_500_000, 6_599_999, 2),
+    ("978", 7_000_000, 7_999_999, 1),
+    ("978", 8_000_000, 9_499_999, 2),
+    ("978", 9_500_000, 9_899_999, 3),
+    ("978", 9_900_000, 9_989_999, 4),
+    ("978", 9_990_000, 9_999_999, 5),
+    ("979", 1_000_000, 1_299_999, 2),
+    ("979", 8_000_000, 8_999_999, 1),
+];
+
+/// Registrant element lengths for the English, French and German language
+/// groups.
+const REGISTRANT_RANGES: &[(&str, u32, u32, usize)] = &[
+    ("978-0", 0, 1_999_999, 2),
+    ("978-0", 2_000_000, 6_999_999, 3),
+    ("978-0", 7_000_000, 8_499_999, 4),
+    ("978-0", 8_500_000, 8_999_999, 5),
+    ("978-0", 9_000_000, 9_499_999, 6),
+    ("978-0", 9_500_000, 9_999_999, 7),
+    ("978-1", 0, 999_999, 2),
+    ("978-1", 1_000_000, 3_999_999, 3),
+    ("978-1", 4_000_000, 5_499_999, 4),
+    ("978-1", 5_500_000, 8_697_999, 5),
+    ("978-1", 8_698_000, 9_989_999, 6),
+    ("978-1", 9_990_000, 9_999_999, 7),
+    ("978-2", 0, 1_999_999, 2),
+    ("978-2", 2_000_000, 3_499_999, 3),
+    ("978-2", 3_500_000, 3_999_999, 5),
+    ("978-2", 4_000_000, 6_999_999, 3),
+    ("978-2", 7_000_000, 8_399_999, 4),
+    ("978-2", 8_400_000, 8_999_999, 5),
+    ("978-2", 9_000_000, 9_499_999, 6),
+    ("978-2", 9_500_000, 9_999_999, 7),
+    ("978-3", 0, 299_999, 2),
+    ("978-3", 300_000, 339_999, 3),
+    ("978-3", 340_000, 369_999, 4),
+    ("978-3", 370_000, 399_999, 5),
+    ("978-3", 400_000, 1_999_999, 2),
+    ("978-3", 2_000_000, 6_999_999, 3),
+    ("978-3", 7_000_000, 8_499_999, 4),
+    ("978-3", 8_500_000, 8_999_999, 5),
+    ("978-3", 9_000_000, 9_499_999, 6),
+    ("978-3", 9_500_000, 9_539_999, 7),
+    ("978-3", 9_540_000, 9_699_999, 5),
+    ("978-3", 9_700_000, 9_849_999, 7),
+    ("978-3", 9_850_000, 9_999_999, 5),
+];
@@ -0,0 +1,224 @@
+//! BCP 47 language tag normalization.
+
+/// Languages with a two-letter ISO 639-1 code: the code, the English name
+/// and the ISO 639-2/3 codes that map to it.
+const LANGUAGES: &[(&str, &str, &[&str])] = &[
+    ("af", "afrikaans", &["afr"]),
+    ("am", "amharic", &["amh"]),
+    ("ar", "arabic", &["ara"]),
+    ("az", "azerbaijani", &["aze"]),
+    ("be", "belarusian", &["bel"]),
+    ("bg", "bulgarian", &["bul"]),
+    ("bn", "bengali", &["ben"]),
+    ("bo", "tibetan", &["tib", "bod"]),
+    ("br", "breton", &["bre"]),
+    ("bs", "bosnian", &["bos"]),
+    ("ca", "catalan", &["cat"]),
+    ("co", "corsican", &["cos"]),
+    ("cs", "czech", &["cze", "ces"]),
+    ("cy", "welsh", &["wel", "cym"]),
+    ("da", "danish", &["dan"]),
+    ("de", "german", &["ger", "deu"]),
+    ("el", "greek", &["gre", "ell"]),
+    ("en", "english", &["eng"]),
+    ("eo", "esperanto", &["epo"]),
+    ("es", "spanish", &["spa"]),
+    ("et", "estonian", &["est"]),
+    ("eu", "basque", &["baq", "eus"]),
+    ("fa", "persian", &["per", "fas"]),
+    ("fi", "finnish", &["fin"]),
+    ("fo", "faroese", &["fao"]),
+    ("fr", "french", &["fre", "fra"]),
+    ("fy", "western frisian", &["fry"]),
+    ("ga", "irish", &["gle"]),
+    ("gd", "scottish gaelic", &["gla"]),
+    ("gl", "galician", &["glg"]),
+    ("gu", "gujarati", &["guj"]),
+    ("ha", "hausa", &["hau"]),
+    ("he", "hebrew", &["heb"]),
+    ("hi", "hindi", &["hin"]),
+    ("hr", "croatian", &["hrv"]),
+    ("hu", "hungarian", &["hun"]),
+    ("hy", "armenian", &["arm", "hye"]),
+    ("id", "indonesian", &["ind"]),
+    ("ig", "igbo", &["ibo"]),
+    ("is", "icelandic", &["ice", "isl"]),
+    ("it", "italian", &["ita"]),
+    ("ja", "japanese", &["jpn"]),
+    ("jv", "javanese", &["jav"]),
+    ("ka", "georgian", &["geo", "kat"]),
+    ("kk", "kazakh", &["kaz"]),
+    ("km", "khmer", &["khm"]),
+    ("kn", "kannada", &["kan"]),
+    ("ko", "korean", &["kor"]),
+    ("ku", "kurdish", &["kur"]),
+    ("la", "latin", &["lat"]),
+    ("lb", "luxembourgish", &["ltz"]),
+    ("lo", "lao", &["lao"]),
+    ("lt", "lithuanian", &["lit"]),
+    ("lv", "latvian", &["lav"]),
+    ("mi", "maori", &["mao", "mri"]),
+    ("mk", "macedonian", &["mac", "mkd"]),
+    ("ml", "malayalam", &["mal"]),
+    ("mn", "mongolian", &["mon"]),
+    ("mr", "marathi", &["mar"]),
+    ("ms", "malay", &["may", "msa"]),
+    ("mt", "maltese", &["mlt"]),
+    ("my", "burmese", &["bur", "mya"]),
+    ("nb", "norwegian bokmål", &["nob"]),
+    ("ne", "nepali", &["nep"]),
+    ("nl", "dutch", &["dut", "nld"]),
+    ("nn", "norwegian nynorsk", &["nno"]),
+    ("no", "norwegian", &["nor"]),
+    ("oc", "occitan", &["oci"]),
+    ("pa", "punjabi", &["pan"]),
+    ("pl", "polish", &["pol"]),
+    ("ps", "pashto", &["pus"]),
+    ("pt", "portuguese", &["por"]),
+    ("rm", "romansh", &["roh"]),
+    ("ro", "romanian", &["rum", "ron"]),
+    ("ru", "russian", &["rus"]),
+    ("sd", "sindhi", &["snd"]),
+    ("se", "northern sami", &["sme"]),
+    ("si", "sinhala", &["sin"]),
+    ("sk", "slovak", &["slo", "slk"]),
+    ("sl", "slovenian", &["slv"]),
+    ("so", "somali", &["som"]),
+    ("sq", "albanian", &["alb", "sqi"]),
+    ("sr", "serbian", &["srp"]),
+    ("su", "sundanese", &["sun"]),
+    ("sv", "swedish", &["swe"]),
+    ("sw", "swahili", &["swa"]),
+    ("ta", "tamil", &["tam"]),
+    ("te", "telugu", &["tel"]),
+    ("th", "thai", &["tha"]),
+    ("tl", "tagalog", &["tgl"]),
+    ("tr", "turkish", &["tur"]),
+    ("tt", "tatar", &["tat"]),
+    ("uk", "ukrainian", &["ukr"]),
+    ("ur", "urdu", &["urd"]),
+    ("uz", "uzbek", &["uzb"]),
+    ("vi", "vietnamese", &["vie"]),
+    ("xh", "xhosa", &["xho"]),
+    ("yi", "yiddish", &["yid"]),
+    ("yo", "yoruba", &["yor"]),
+    ("zh", "chinese", &["chi", "zho"]),
+    ("zu", "zulu", &["zul"]),
+];
+
+/// Every ISO 639-1 code, for validating two-letter primary subtags.
+const ISO_639_1: &str = "aa ab ae af ak am an ar as av ay az ba be bg bh bi bm bn bo br bs ca \
+    ce ch co cr cs cu cv cy da de dv dz ee el en eo es et eu fa ff fi fj fo fr fy ga gd gl gn \
+    gu gv ha he hi ho hr ht hu hy hz ia id ie ig ii ik io is it iu ja jv ka kg ki kj kk kl km \
+    kn ko kr ks ku kv kw ky la lb lg li ln lo lt lu lv mg mh mi mk ml mn mr ms mt my na nb nd \
+    ne ng nl nn no nr nv ny oc oj om or os pa pi pl ps pt qu rm rn ro ru rw sa sc sd se sg si \
+    sk sl sm sn so sq sr ss st su sv sw ta te tg th ti tk tl tn to tr ts tt tw ty ug uk ur uz \
+    ve vi vo wa wo xh yi yo za zh zu";
+
+/// Codes replaced by newer ones in ISO 639-1.
+const DEPRECATED: &[(&str, &str)] = &[("iw", "he"), ("in", "id"), ("ji", "yi")];
+
+/// Normalize a language given as a BCP 47 tag, an ISO 639-2/3 code or an
+/// English language name into a canonical BCP 47 tag.
+///
+/// Subtags get their canonical case (`en-GB`, `zh-Hant-TW`), underscores
+/// become hyphens, and three-letter and deprecated codes are replaced by
+/// their two-letter equivalents (`eng` becomes `en`, `fre_CA` becomes
+/// `fr-CA`). Returns `None` if the value isn't a well-formed tag.
+pub fn normalize_language(language: &str) -> Option<String> {
+    let language = language.trim();
+    let lower = language.to_lowercase();
+    if let Some((code, _, _)) = LANGUAGES.iter().find(|(_, name, _)| *name == lower) {
+        return Some(code.to_string());
+    }
+
+    let mut subtags = lower.split(['-', '_']);
+    let primary = subtags.next()?;
+    let primary = match primary.len() {
+        2 if ISO_639_1.split(' ').any(|c| c == primary) => primary,
+        2 => DEPRECATED
+            .iter()
+            .find(|(old, _)| *old == primary)
+            .map(|(_, new)| *new)?,
+        3 if is_alpha(primary) => LANGUAGES
+            .iter()
+            .find(|(_, _, codes)| codes.contains(&primary))
+            .map_or(primary, |(code, _, _)| code),
+        1 if primary == "x" => primary,
+        _ => return None,
+    };
+
+    let mut tag = vec![primary.to_string()];
+    let mut state = if primary == "x" {
+        State::PrivateUse
+    } else {
+        State::Extlang(0)
+    };
+    for subtag in subtags {
+        if subtag.is_empty()
+            || subtag.len() > 8
+            || !subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+        let (next, formatted) = match state {
+            State::PrivateUse => (State::PrivateUse, subtag.to_string()),
+            State::Extension(count) if subtag.len() >= 2 => {
+                (State::Extension(count + 1), subtag.to_string())
+            }
+            State::Extension(0) => return None,
+            _ if subtag == "x" => (State::PrivateUse, subtag.to_string()),
+            _ if subtag.len() == 1 => (State::Extension(0), subtag.to_string()),
+            State::Extlang(n) if n < 3 && subtag.len() == 3 && is_alpha(subtag) => {
+                (State::Extlang(n + 1), subtag.to_string())
+            }
+            State::Extlang(_) if subtag.len() == 4 && is_alpha(subtag) => {
+                (State::Script, title_case(subtag))
+            }
+            State::Extlang(_) | State::Script if is_region(subtag) => {
+                (State::Region, subtag.to_ascii_uppercase())
+            }
+            _ if is_variant(subtag) => (State::Variant, subtag.to_string()),
+            _ => return None,
+        };
+        state = next;
+        tag.push(formatted);
+    }
+    if state == State::Extension(0) {
+        return None;
+    }
+
+    Some(tag.join("-"))
+}
+
+/// Where a subtag is in the sequence `language-extlang-script-region-variant-extension-x`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Extlang(usize),
+    Script,
+    Region,
+    Variant,
+    /// Inside an extension, counting the subtags after its singleton.
+    Extension(usize),
+    PrivateUse,
+}
+
+fn is_alpha(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_alphabetic())
+}
+
+fn is_region(s: &str) -> bool {
+    (s.len() == 2 && is_alpha(s)) || (s.len() == 3 && s.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn is_variant(s: &str) -> bool {
+    s.len() >= 5 || (s.len() == 4 && s.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+fn title_case(s: &str) -> String {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .map(|c| c.to_ascii_uppercase().to_string() + chars.as_str())
+        .unwrap_or_default()
+}
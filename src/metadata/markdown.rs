@@ -0,0 +1,239 @@
+//! Conversion of a Markdown subset to HTML, for descriptions written by hand.
+//!
+//! Supported: paragraphs, ATX headings, block quotes, bulleted and numbered
+//! lists, fenced code blocks, horizontal rules, hard line breaks, and the
+//! inline forms `**strong**`, `*emphasis*`, `` `code` `` and `[links](url)`.
+//! Raw HTML is escaped rather than passed through.
+
+use crate::html::escape;
+
+/// Render Markdown to HTML. The result may contain unbalanced inline tags,
+/// so it is meant to be passed through the sanitizer.
+pub(crate) fn to_html(markdown: &str) -> String {
+    let lines: Vec<&str> = markdown.lines().collect();
+    let mut out = String::new();
+    render_blocks(&lines, &mut out);
+    out
+}
+
+fn render_blocks(lines: &[&str], out: &mut String) {
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            i += 1;
+        } else if let Some(fence) = ["```", "~~~"].into_iter().find(|f| trimmed.starts_with(f)) {
+            let end = lines[i + 1..]
+                .iter()
+                .position(|l| l.trim().starts_with(fence))
+                .map_or(lines.len(), |p| i + 1 + p);
+            out.push_str("<pre><code>");
+            out.push_str(&escape(&lines[i + 1..end].join("\n"), false));
+            out.push_str("</code></pre>");
+            i = end + 1;
+        } else if is_rule(trimmed) {
+            out.push_str("<hr/>");
+            i += 1;
+        } else if let Some((level, text)) = heading(trimmed) {
+            out.push_str(&format!("<h{level}>{}</h{level}>", inline(text)));
+            i += 1;
+        } else if trimmed.starts_with('>') {
+            let end = block_end(lines, i, |l| l.trim_start().starts_with('>'));
+            let inner: Vec<&str> = lines[i..end]
+                .iter()
+                .map(|l| {
+                    let l = l.trim_start().trim_start_matches('>');
+                    l.strip_prefix(' ').unwrap_or(l)
+                })
+                .collect();
+            out.push_str("<blockquote>");
+            render_blocks(&inner, out);
+            out.push_str("</blockquote>");
+            i = end;
+        } else if let Some(ordered) = list_marker(trimmed).map(|(ordered, _)| ordered) {
+            let tag = if ordered { "ol" } else { "ul" };
+            out.push('<');
+            out.push_str(tag);
+            out.push('>');
+            while i < lines.len()
+                && let Some((item_ordered, text)) = list_marker(lines[i].trim())
+                && item_ordered == ordered
+            {
+                // Indented lines that follow continue the item.
+                let end = block_end(lines, i + 1, |l| {
+                    l.starts_with([' ', '\t']) && !l.trim().is_empty()
+                });
+                let mut item = vec![text];
+                item.extend(lines[i + 1..end].iter().map(|l| l.trim()));
+                out.push_str("<li>");
+                out.push_str(&paragraph_text(&item));
+                out.push_str("</li>");
+                i = end;
+            }
+            out.push_str("</");
+            out.push_str(tag);
+            out.push('>');
+        } else {
+            let end = block_end(lines, i, |l| {
+                let t = l.trim();
+                !t.is_empty()
+                    && heading(t).is_none()
+                    && !t.starts_with('>')
+                    && !t.starts_with("```")
+                    && !t.starts_with("~~~")
+                    && list_marker(t).is_none()
+                    && !is_rule(t)
+            })
+            .max(i + 1);
+            out.push_str("<p>");
+            out.push_str(&paragraph_text(&lines[i..end]));
+            out.push_str("</p>");
+            i = end;
+        }
+    }
+}
+
+/// The index of the first line at or after `start` that doesn't match.
+fn block_end(lines: &[&str], start: usize, matches: impl Fn(&str) -> bool) -> usize {
+    lines[start.min(lines.len())..]
+        .iter()
+        .position(|l| !matches(l))
+        .map_or(lines.len(), |p| start + p)
+}
+
+/// Join the lines of a paragraph, turning a trailing backslash or two
+/// trailing spaces into a line break.
+fn paragraph_text(lines: &[&str]) -> String {
+    let mut out = String::new();
+    for (n, line) in lines.iter().enumerate() {
+        let hard_break = line.ends_with("  ") || line.trim_end().ends_with('\\');
+        let text = line.trim();
+        let text = if hard_break {
+            text.strip_suffix('\\').unwrap_or(text)
+        } else {
+            text
+        };
+        out.push_str(&inline(text));
+        if n + 1 < lines.len() {
+            out.push_str(if hard_break { "<br/>" } else { " " });
+        }
+    }
+    out
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.bytes().take_while(|b| *b == b'#').count();
+    let text = line[level..].strip_prefix(' ')?;
+    (1..=6)
+        .contains(&level)
+        .then(|| (level, text.trim().trim_end_matches('#').trim_end()))
+}
+
+fn is_rule(line: &str) -> bool {
+    let compact: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    compact.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|m| compact.chars().all(|c| c == *m))
+}
+
+/// Recognize a list item, returning whether it is numbered and its text.
+fn list_marker(line: &str) -> Option<(bool, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(text) = line.strip_prefix(bullet) {
+            return Some((false, text));
+        }
+    }
+    let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+    if (1..=9).contains(&digits) {
+        let rest = &line[digits..];
+        if let Some(text) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return Some((true, text));
+        }
+    }
+    None
+}
+
+/// Render inline Markdown in a line of text.
+fn inline(text: &str) -> String {
+    let mut out = String::new();
+    let mut strong = false;
+    let mut em = false;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let next = rest[c.len_utf8()..].chars().next();
+        match c {
+            '\\' if next.is_some_and(|n| n.is_ascii_punctuation()) => {
+                let n = next.unwrap_or_default();
+                out.push_str(&escape(&n.to_string(), false));
+                rest = &rest[1 + n.len_utf8()..];
+                continue;
+            }
+            '`' => {
+                if let Some(end) = rest[1..].find('`') {
+                    out.push_str("<code>");
+                    out.push_str(&escape(&rest[1..end + 1], false));
+                    out.push_str("</code>");
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+            '*' | '_' if next == Some(c) => {
+                out.push_str(if strong { "</strong>" } else { "<strong>" });
+                strong = !strong;
+                rest = &rest[2..];
+                continue;
+            }
+            '*' | '_' if em || next.is_some_and(|n| !n.is_whitespace()) => {
+                // An underscore inside a word (snake_case) is literal.
+                let intraword =
+                    c == '_' && !em && out.chars().last().is_some_and(char::is_alphanumeric);
+                if !intraword {
+                    out.push_str(if em { "</em>" } else { "<em>" });
+                    em = !em;
+                    rest = &rest[1..];
+                    continue;
+                }
+            }
+            '[' => {
+                if let Some((label, url, len)) = link(rest) {
+                    out.push_str("<a href=\"");
+                    out.push_str(&escape(url, true));
+                    out.push_str("\">");
+                    out.push_str(&inline(label));
+                    out.push_str("</a>");
+                    rest = &rest[len..];
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        out.push_str(&escape(&c.to_string(), false));
+        rest = &rest[c.len_utf8()..];
+    }
+
+    if em {
+        out.push_str("</em>");
+    }
+    if strong {
+        out.push_str("</strong>");
+    }
+    out
+}
+
+/// Parse `[label](url)` at the start of `s`, returning the label, the URL
+/// and the length of the whole link.
+fn link(s: &str) -> Option<(&str, &str, usize)> {
+    let label_end = s.find("](")?;
+    let label = &s[1..label_end];
+    if label.contains('[') {
+        return None;
+    }
+    let url_start = label_end + 2;
+    let url_len = s[url_start..].find(')')?;
+    let url = s[url_start..url_start + url_len].trim();
+    Some((label, url, url_start + url_len + 1))
+}
@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+mod date;
+mod description;
+mod filename;
+mod isbn;
+mod language;
+mod markdown;
+mod patch;
+mod series;
+mod sort;
+
+pub use date::W3cDate;
+pub use description::{truncate_graphemes, Description};
+pub use filename::{metadata_from_path, FilenamePattern, BUILTIN_FILENAME_PATTERNS};
+pub use isbn::{Isbn, IsbnError};
+pub use language::normalize_language;
+pub use patch::{FieldPatch, ListPatch, MetadataField, MetadataPatch};
+pub use series::{detect_series, SeriesInfo};
+pub use sort::{author_display_name, author_sort_name, is_sort_form, title_sort_name};
+
+/// Metadata associated with an ebook.
+///
+/// Serializes to a flat JSON object; missing fields deserialize as empty.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    /// The title as it should be sorted (e.g. "Hobbit, The").
+    pub title_sort: Option<String>,
+    pub authors: Vec<String>,
+    /// Sort names ("Last, First") for authors, keyed by author name.
+    pub author_sort: BTreeMap<String, String>,
+    /// Links for author names, such as a Wikipedia page.
+    pub author_links: BTreeMap<String, String>,
+    /// People other than the authors who worked on the book.
+    pub contributors: Vec<Contributor>,
+    pub description: Option<String>,
+    pub publisher: Option<String>,
+    pub language: Option<String>,
+    /// The ISBN as found in the book, which may not be valid (see [`Isbn`]).
+    pub isbn: Option<String>,
+    /// Identifiers other than the ISBN, keyed by lowercase scheme (e.g. `uuid`, `amazon`).
+    pub identifiers: BTreeMap<String, String>,
+    pub publication_date: Option<W3cDate>,
+    /// When the work was created, from `opf:event="creation"` or `dcterms:created`.
+    pub creation_date: Option<W3cDate>,
+    /// When the book was last modified, from `opf:event="modification"` or
+    /// `dcterms:modified`.
+    pub modification_date: Option<W3cDate>,
+    pub subjects: Vec<String>,
+    /// Personal tags for organizing a library, kept apart from the
+    /// publisher's `subjects`.
+    pub tags: Vec<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    /// Rating out of 5 stars, in half-star steps.
+    pub rating: Option<f64>,
+    /// When the book was added to a library.
+    pub timestamp: Option<String>,
+    /// Calibre custom column values, keyed by column name (e.g. `#read`).
+    pub user_metadata: BTreeMap<String, serde_json::Value>,
+    /// Other `<meta>` values, keyed by their `name` (or EPUB 3 `property`),
+    /// such as `read_status`.
+    pub custom: BTreeMap<String, String>,
+}
+
+/// A contributor to a book, such as a translator or illustrator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contributor {
+    pub name: String,
+    /// MARC relator code for the contributor's role (e.g. `trl`, `ill`, `edt`).
+    #[serde(default)]
+    pub role: Option<String>,
+}
+
+impl Metadata {
+    /// Fill in missing sort names for the title and authors, and turn author
+    /// names written in sort form ("Doe, Jane") into display form.
+    pub fn normalize_sort_names(&mut self) {
+        for author in &mut self.authors {
+            if is_sort_form(author) {
+                let display = author_display_name(author);
+                let sort = self
+                    .author_sort
+                    .remove(author.as_str())
+                    .unwrap_or(author.clone());
+                self.author_sort.entry(display.clone()).or_insert(sort);
+                *author = display;
+            }
+        }
+
+        for author in &self.authors {
+            if !self.author_sort.contains_key(author) {
+                self.author_sort
+                    .insert(author.clone(), author_sort_name(author));
+            }
+        }
+
+        if self.title_sort.is_none()
+            && let Some(title) = &self.title
+        {
+            self.title_sort = Some(title_sort_name(title, self.language.as_deref()));
+        }
+    }
+
+    /// Merge fields from `other` into this metadata.
+    ///
+    /// Empty fields in `other` never replace anything. With
+    /// [`MergePolicy::FillEmpty`] only fields that are empty here are taken
+    /// from `other`; with [`MergePolicy::Overwrite`] every non-empty field of
+    /// `other` wins. Map entries are merged key by key, with author sort
+    /// names and links only taken for the merged authors.
+    pub fn merge(&mut self, other: &Metadata, policy: MergePolicy) {
+        let overwrite = policy == MergePolicy::Overwrite;
+
+        fn value<T: Clone>(ours: &mut Option<T>, theirs: &Option<T>, overwrite: bool) {
+            if theirs.is_some() && (overwrite || ours.is_none()) {
+                ours.clone_from(theirs);
+            }
+        }
+        fn list<T: Clone>(ours: &mut Vec<T>, theirs: &[T], overwrite: bool) {
+            if !theirs.is_empty() && (overwrite || ours.is_empty()) {
+                *ours = theirs.to_vec();
+            }
+        }
+        fn map<V: Clone>(
+            ours: &mut BTreeMap<String, V>,
+            theirs: &BTreeMap<String, V>,
+            overwrite: bool,
+        ) {
+            for (key, v) in theirs {
+                if overwrite || !ours.contains_key(key) {
+                    ours.insert(key.clone(), v.clone());
+                }
+            }
+        }
+
+        value(&mut self.title, &other.title, overwrite);
+        value(&mut self.title_sort, &other.title_sort, overwrite);
+        list(&mut self.authors, &other.authors, overwrite);
+        // Only keep author details for authors that ended up in the list.
+        for (ours, theirs) in [
+            (&mut self.author_sort, &other.author_sort),
+            (&mut self.author_links, &other.author_links),
+        ] {
+            let theirs = theirs
+                .iter()
+                .filter(|(author, _)| self.authors.contains(author))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            map(ours, &theirs, overwrite);
+        }
+        list(&mut self.contributors, &other.contributors, overwrite);
+        value(&mut self.description, &other.description, overwrite);
+        value(&mut self.publisher, &other.publisher, overwrite);
+        value(&mut self.language, &other.language, overwrite);
+        value(&mut self.isbn, &other.isbn, overwrite);
+        map(&mut self.identifiers, &other.identifiers, overwrite);
+        value(
+            &mut self.publication_date,
+            &other.publication_date,
+            overwrite,
+        );
+        value(&mut self.creation_date, &other.creation_date, overwrite);
+        value(
+            &mut self.modification_date,
+            &other.modification_date,
+            overwrite,
+        );
+        list(&mut self.subjects, &other.subjects, overwrite);
+        list(&mut self.tags, &other.tags, overwrite);
+        value(&mut self.series, &other.series, overwrite);
+        value(&mut self.series_index, &other.series_index, overwrite);
+        value(&mut self.rating, &other.rating, overwrite);
+        value(&mut self.timestamp, &other.timestamp, overwrite);
+        map(&mut self.user_metadata, &other.user_metadata, overwrite);
+        map(&mut self.custom, &other.custom, overwrite);
+    }
+
+    /// Move series information found in the title (see [`detect_series`])
+    /// into `series` and `series_index`, leaving the cleaned title.
+    ///
+    /// Books that already belong to a different series are left alone.
+    /// Returns whether anything changed.
+    pub fn detect_series(&mut self) -> bool {
+        let Some(title) = &self.title else {
+            return false;
+        };
+        let Some(found) = detect_series(title) else {
+            return false;
+        };
+        if let Some(series) = &self.series
+            && !series.eq_ignore_ascii_case(&found.series)
+        {
+            return false;
+        }
+
+        // Keep the sort title in step if it was derived from the title.
+        let language = self.language.as_deref();
+        if self.title_sort.as_deref() == Some(title_sort_name(title, language).as_str()) {
+            self.title_sort = Some(title_sort_name(&found.title, language));
+        }
+        self.title = Some(found.title);
+        if self.series.is_none() {
+            self.series = Some(found.series);
+        }
+        if found.index.is_some() {
+            self.series_index = found.index;
+        }
+        true
+    }
+
+    /// Replace the language with its canonical BCP 47 tag (see
+    /// [`normalize_language`]). Languages that can't be normalized are left
+    /// as they are.
+    pub fn normalize_language(&mut self) {
+        if let Some(tag) = self.language.as_deref().and_then(normalize_language) {
+            self.language = Some(tag);
+        }
+    }
+}
+
+/// How [`Metadata::merge`] resolves fields set on both sides.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergePolicy {
+    /// Only fill in fields that are empty.
+    #[default]
+    FillEmpty,
+    /// Replace fields with any value from the other side.
+    Overwrite,
+}
+
+impl MergePolicy {
+    /// The name used for this policy on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MergePolicy::FillEmpty => "fill-empty",
+            MergePolicy::Overwrite => "overwrite",
+        }
+    }
+}
+
+impl fmt::Display for MergePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for MergePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "fill-empty" | "fill" => Ok(MergePolicy::FillEmpty),
+            "overwrite" => Ok(MergePolicy::Overwrite),
+            _ => Err(format!(
+                "unknown merge policy: {s} (expected fill-empty or overwrite)"
+            )),
+        }
+    }
+}
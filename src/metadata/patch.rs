@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Metadata, W3cDate};
+
+/// A change to a single-valued metadata field.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldPatch<T> {
+    /// Leave the field as it is.
+    #[default]
+    Keep,
+    /// Replace the field with a new value.
+    Set(T),
+    /// Remove the field.
+    Clear,
+}
+
+impl<T: Clone> FieldPatch<T> {
+    /// Whether this patch leaves the field untouched.
+    pub fn is_keep(&self) -> bool {
+        matches!(self, FieldPatch::Keep)
+    }
+
+    /// Apply this patch to a field value.
+    pub fn apply(&self, value: &mut Option<T>) {
+        match self {
+            FieldPatch::Keep => {}
+            FieldPatch::Set(v) => *value = Some(v.clone()),
+            FieldPatch::Clear => *value = None,
+        }
+    }
+}
+
+/// A change to a list-valued metadata field.
+///
+/// Operations are applied in order: `set` replaces the list, then `remove`
+/// drops matching entries, then `add` appends entries not already present.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, bound(deserialize = "T: Deserialize<'de>"))]
+pub struct ListPatch<T> {
+    pub set: Option<Vec<T>>,
+    pub add: Vec<T>,
+    pub remove: Vec<T>,
+}
+
+impl<T> Default for ListPatch<T> {
+    fn default() -> Self {
+        ListPatch {
+            set: None,
+            add: Vec::new(),
+            remove: Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> ListPatch<T> {
+    /// Whether this patch leaves the list untouched.
+    pub fn is_keep(&self) -> bool {
+        self.set.is_none() && self.add.is_empty() && self.remove.is_empty()
+    }
+
+    /// Apply this patch to a list.
+    pub fn apply(&self, values: &mut Vec<T>) {
+        if let Some(set) = &self.set {
+            values.clone_from(set);
+        }
+        values.retain(|v| !self.remove.contains(v));
+        for v in &self.add {
+            if !values.contains(v) {
+                values.push(v.clone());
+            }
+        }
+    }
+}
+
+/// A set of field-level changes to apply on top of existing [`Metadata`].
+///
+/// Unlike [`Metadata`], which describes a complete state, a patch only
+/// describes what should change, so fields it doesn't mention are preserved.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetadataPatch {
+    pub title: FieldPatch<String>,
+    pub authors: ListPatch<String>,
+    pub description: FieldPatch<String>,
+    pub publisher: FieldPatch<String>,
+    pub language: FieldPatch<String>,
+    pub isbn: FieldPatch<String>,
+    pub publication_date: FieldPatch<W3cDate>,
+    pub subjects: ListPatch<String>,
+    pub tags: ListPatch<String>,
+    pub series: FieldPatch<String>,
+    pub series_index: FieldPatch<f64>,
+    pub rating: FieldPatch<f64>,
+    /// Changes to custom `<meta>` fields, keyed by name.
+    pub custom: BTreeMap<String, FieldPatch<String>>,
+    /// Changes to the values of existing Calibre custom columns, keyed by
+    /// column name (e.g. `#read`). Columns the book doesn't define are
+    /// ignored, since their definition is unknown.
+    pub columns: BTreeMap<String, FieldPatch<serde_json::Value>>,
+}
+
+impl MetadataPatch {
+    /// Whether this patch makes no changes at all.
+    pub fn is_empty(&self) -> bool {
+        MetadataField::ALL.iter().all(|f| !self.touches(*f))
+            && self.custom.values().all(FieldPatch::is_keep)
+            && self.columns.values().all(FieldPatch::is_keep)
+    }
+
+    /// Whether this patch changes the given field in any way.
+    pub fn touches(&self, field: MetadataField) -> bool {
+        match field {
+            MetadataField::Title => !self.title.is_keep(),
+            MetadataField::Authors => !self.authors.is_keep(),
+            MetadataField::Description => !self.description.is_keep(),
+            MetadataField::Publisher => !self.publisher.is_keep(),
+            MetadataField::Language => !self.language.is_keep(),
+            MetadataField::Isbn => !self.isbn.is_keep(),
+            MetadataField::PublicationDate => !self.publication_date.is_keep(),
+            MetadataField::Subjects => !self.subjects.is_keep(),
+            MetadataField::Tags => !self.tags.is_keep(),
+            MetadataField::Series => !self.series.is_keep(),
+            MetadataField::SeriesIndex => !self.series_index.is_keep(),
+            MetadataField::Rating => !self.rating.is_keep(),
+        }
+    }
+
+    /// Mark a field to be cleared, discarding any other change to it.
+    pub fn clear(&mut self, field: MetadataField) {
+        match field {
+            MetadataField::Title => self.title = FieldPatch::Clear,
+            MetadataField::Authors => {
+                self.authors = ListPatch {
+                    set: Some(Vec::new()),
+                    ..ListPatch::default()
+                }
+            }
+            MetadataField::Description => self.description = FieldPatch::Clear,
+            MetadataField::Publisher => self.publisher = FieldPatch::Clear,
+            MetadataField::Language => self.language = FieldPatch::Clear,
+            MetadataField::Isbn => self.isbn = FieldPatch::Clear,
+            MetadataField::PublicationDate => self.publication_date = FieldPatch::Clear,
+            MetadataField::Subjects => {
+                self.subjects = ListPatch {
+                    set: Some(Vec::new()),
+                    ..ListPatch::default()
+                }
+            }
+            MetadataField::Tags => {
+                self.tags = ListPatch {
+                    set: Some(Vec::new()),
+                    ..ListPatch::default()
+                }
+            }
+            MetadataField::Series => self.series = FieldPatch::Clear,
+            MetadataField::SeriesIndex => self.series_index = FieldPatch::Clear,
+            MetadataField::Rating => self.rating = FieldPatch::Clear,
+        }
+    }
+
+    /// Apply this patch to `metadata` in place.
+    pub fn apply(&self, metadata: &mut Metadata) {
+        self.title.apply(&mut metadata.title);
+        self.authors.apply(&mut metadata.authors);
+        self.description.apply(&mut metadata.description);
+        self.publisher.apply(&mut metadata.publisher);
+        self.language.apply(&mut metadata.language);
+        self.isbn.apply(&mut metadata.isbn);
+        self.publication_date.apply(&mut metadata.publication_date);
+        self.subjects.apply(&mut metadata.subjects);
+        self.tags.apply(&mut metadata.tags);
+        self.series.apply(&mut metadata.series);
+        self.series_index.apply(&mut metadata.series_index);
+        self.rating.apply(&mut metadata.rating);
+        for (name, patch) in &self.custom {
+            let mut value = metadata.custom.remove(name);
+            patch.apply(&mut value);
+            if let Some(value) = value {
+                metadata.custom.insert(name.clone(), value);
+            }
+        }
+        for (name, patch) in &self.columns {
+            // Calibre keeps a column's value next to its definition.
+            let Some(serde_json::Value::Object(column)) = metadata.user_metadata.get_mut(name)
+            else {
+                continue;
+            };
+            let mut value = column.get("#value#").cloned().filter(|v| !v.is_null());
+            patch.apply(&mut value);
+            column.insert(
+                "#value#".to_string(),
+                value.unwrap_or(serde_json::Value::Null),
+            );
+        }
+    }
+}
+
+/// Identifies a single field of [`Metadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetadataField {
+    Title,
+    Authors,
+    Description,
+    Publisher,
+    Language,
+    Isbn,
+    PublicationDate,
+    Subjects,
+    Tags,
+    Series,
+    SeriesIndex,
+    Rating,
+}
+
+impl MetadataField {
+    /// Every field, in display order.
+    pub const ALL: [MetadataField; 12] = [
+        MetadataField::Title,
+        MetadataField::Authors,
+        MetadataField::Description,
+        MetadataField::Publisher,
+        MetadataField::Language,
+        MetadataField::Isbn,
+        MetadataField::PublicationDate,
+        MetadataField::Subjects,
+        MetadataField::Tags,
+        MetadataField::Series,
+        MetadataField::SeriesIndex,
+        MetadataField::Rating,
+    ];
+
+    /// The name used for this field on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MetadataField::Title => "title",
+            MetadataField::Authors => "authors",
+            MetadataField::Description => "description",
+            MetadataField::Publisher => "publisher",
+            MetadataField::Language => "language",
+            MetadataField::Isbn => "isbn",
+            MetadataField::PublicationDate => "publication-date",
+            MetadataField::Subjects => "subjects",
+            MetadataField::Tags => "tags",
+            MetadataField::Series => "series",
+            MetadataField::SeriesIndex => "series-index",
+            MetadataField::Rating => "rating",
+        }
+    }
+}
+
+impl fmt::Display for MetadataField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for MetadataField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalized = s.to_lowercase().replace('_', "-");
+        match normalized.as_str() {
+            "author" => return Ok(MetadataField::Authors),
+            "subject" => return Ok(MetadataField::Subjects),
+            "tag" => return Ok(MetadataField::Tags),
+            "date" => return Ok(MetadataField::PublicationDate),
+            _ => {}
+        }
+        MetadataField::ALL
+            .into_iter()
+            .find(|f| f.name() == normalized)
+            .ok_or_else(|| format!("unknown metadata field: {s}"))
+    }
+}
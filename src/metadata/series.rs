@@ -0,0 +1,323 @@
+//! Detecting series information embedded in titles, such as
+//! "Leviathan Wakes (The Expanse Book 1)" or
+//! "Title: A Something Novel, Book Three".
+
+use std::sync::LazyLock;
+
+use regex_lite::Regex;
+
+/// Series information found in a title.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesInfo {
+    /// The title with the series information removed.
+    pub title: String,
+    pub series: String,
+    pub index: Option<f64>,
+}
+
+/// Words that introduce a number in a series, as in "Book 3" or "Vol. IV".
+const NUMBER_WORDS: &str = r"(?:book|bk\.?|vol\.?|volume|part|pt\.?|no\.?|number|#)";
+
+/// What a series is called in "A Discworld Novel".
+const KIND_WORDS: &str = r"(?:novel|novella|book|mystery|thriller|romance|adventure|story|saga)";
+
+/// Where series information sits in a title, with the series part in the
+/// `body` group.
+static PLACEMENTS: LazyLock<[Regex; 2]> = LazyLock::new(|| {
+    [
+        // Leviathan Wakes (The Expanse Book 1)
+        Regex::new(r"^(?<title>.+?)\s*[(\[](?<body>[^()\[\]]+)[)\]]\s*$").unwrap(),
+        // Title: A Something Novel, Book Three
+        Regex::new(r"^(?<title>.+?)\s*(?::|\s[-–—])\s+(?<body>.+)$").unwrap(),
+    ]
+});
+
+/// Forms of the series part, with `series` and optional `n` groups.
+static BODIES: LazyLock<[Regex; 4]> = LazyLock::new(|| {
+    [
+        // The Expanse Book 1, The Expanse, #1, Discworld no. 5
+        Regex::new(&format!(
+            r"(?i)^(?<series>.+?)[,:]?\s*{NUMBER_WORDS}\s*(?<n>[\w.]+)$"
+        ))
+        .unwrap(),
+        // Book 3 of the Something Trilogy
+        Regex::new(&format!(
+            r"(?i)^{NUMBER_WORDS}\s*(?<n>[\w.]+)\s+(?:of|in)\s+(?:the\s+)?(?<series>.+)$"
+        ))
+        .unwrap(),
+        // A Something Novel, Book Three
+        Regex::new(&format!(
+            r"(?i)^(?:an?|the)\s+(?<series>.+?)\s+{KIND_WORDS}(?:[,:]?\s*(?:{NUMBER_WORDS}\s*)?(?<n>[\w.]+))?$"
+        ))
+        .unwrap(),
+        // Discworld 5
+        Regex::new(r"^(?<series>\D.*?),?\s+(?<n>\d{1,4}(?:\.\d+)?)$").unwrap(),
+    ]
+});
+
+/// A series name that is really a whole "A Something Novel" wrapper, which
+/// the first body form would otherwise take as it is.
+static WRAPPED: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(&format!(r"(?i)^(?:an?|the)\s+.+\s+{KIND_WORDS}$")).unwrap());
+
+/// The Expanse Book 1: Leviathan Wakes
+static PREFIX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r"(?i)^(?<series>.+?)[,:]?\s*{NUMBER_WORDS}\s*(?<n>[\w.]+)\s*(?::|\s[-–—])\s+(?<title>.+)$"
+    ))
+    .unwrap()
+});
+
+/// Find series information in a title.
+///
+/// Recognizes a series in trailing brackets ("Leviathan Wakes (The Expanse
+/// Book 1)"), in a subtitle ("Title: A Something Novel, Book Three" or
+/// "Title - Book 3 of the Something Trilogy") or before the title ("The
+/// Expanse Book 1: Leviathan Wakes"). Numbers may be digits, spelled out
+/// ("Three", "Third") or Roman numerals ("IV").
+pub fn detect_series(title: &str) -> Option<SeriesInfo> {
+    let title = title.trim();
+
+    for placement in PLACEMENTS.iter() {
+        let Some(caps) = placement.captures(title) else {
+            continue;
+        };
+        if let Some((series, index)) = parse_body(&caps["body"]) {
+            return Some(SeriesInfo {
+                title: caps["title"].trim().to_string(),
+                series,
+                index,
+            });
+        }
+    }
+
+    let caps = PREFIX.captures(title)?;
+    let index = parse_number(&caps["n"])?;
+    Some(SeriesInfo {
+        title: caps["title"].trim().to_string(),
+        series: clean_series(&caps["series"])?,
+        index: Some(index),
+    })
+}
+
+fn parse_body(body: &str) -> Option<(String, Option<f64>)> {
+    let body = body.trim();
+    BODIES.iter().find_map(|form| {
+        let caps = form.captures(body)?;
+        if WRAPPED.is_match(caps["series"].trim()) {
+            return None;
+        }
+        let series = clean_series(&caps["series"])?;
+        let index = match caps.name("n") {
+            Some(n) => Some(parse_number(n.as_str())?),
+            None => None,
+        };
+        Some((series, index))
+    })
+}
+
+/// Trim punctuation left around a series name, rejecting names that are
+/// really just a word like "Book" or "Edition".
+fn clean_series(series: &str) -> Option<String> {
+    let series = series.trim().trim_end_matches([',', ':', '-']).trim();
+    let lower = series.to_lowercase();
+    let rejected = matches!(
+        lower.as_str(),
+        "a" | "the" | "book" | "volume" | "part" | "edition" | "revised edition"
+    );
+    if series.is_empty() || rejected {
+        return None;
+    }
+    Some(series.to_string())
+}
+
+/// Parse a series number written as digits, a word or a Roman numeral.
+fn parse_number(text: &str) -> Option<f64> {
+    let text = text.trim().trim_end_matches('.');
+    if let Ok(n) = text.parse::<f64>() {
+        return (n >= 0.0).then_some(n);
+    }
+    let lower = text.to_lowercase();
+    word_number(&lower)
+        .or_else(|| roman_number(&lower))
+        .map(f64::from)
+}
+
+fn word_number(word: &str) -> Option<u32> {
+    const CARDINALS: [&str; 20] = [
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+        "twenty",
+    ];
+    const ORDINALS: [&str; 20] = [
+        "first",
+        "second",
+        "third",
+        "fourth",
+        "fifth",
+        "sixth",
+        "seventh",
+        "eighth",
+        "ninth",
+        "tenth",
+        "eleventh",
+        "twelfth",
+        "thirteenth",
+        "fourteenth",
+        "fifteenth",
+        "sixteenth",
+        "seventeenth",
+        "eighteenth",
+        "nineteenth",
+        "twentieth",
+    ];
+    CARDINALS
+        .iter()
+        .position(|w| *w == word)
+        .or_else(|| ORDINALS.iter().position(|w| *w == word))
+        .map(|i| i as u32 + 1)
+}
+
+/// Parse a Roman numeral, accepting only the canonical form ("IV", not
+/// "IIII"), so ordinary words made of the same letters are rejected.
+fn roman_number(text: &str) -> Option<u32> {
+    if text.is_empty() || text.len() > 12 {
+        return None;
+    }
+    let value = |c: char| match c {
+        'i' => Some(1),
+        'v' => Some(5),
+        'x' => Some(10),
+        'l' => Some(50),
+        'c' => Some(100),
+        'd' => Some(500),
+        'm' => Some(1000),
+        _ => None,
+    };
+    let digits: Vec<u32> = text.chars().map(value).collect::<Option<_>>()?;
+    let mut total = 0;
+    for (i, &d) in digits.iter().enumerate() {
+        match digits.get(i + 1) {
+            Some(&next) if next > d => total -= d as i64,
+            _ => total += d as i64,
+        }
+    }
+    let total = u32::try_from(total).ok().filter(|n| *n > 0)?;
+    (to_roman(total) == text).then_some(total)
+}
+
+fn to_roman(mut n: u32) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut out = String::new();
+    for (value, numeral) in NUMERALS {
+        while n >= value {
+            out.push_str(numeral);
+            n -= value;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(title: &str, series: &str, index: Option<f64>) -> Option<SeriesInfo> {
+        Some(SeriesInfo {
+            title: title.to_string(),
+            series: series.to_string(),
+            index,
+        })
+    }
+
+    #[test]
+    fn bracketed_series() {
+        assert_eq!(
+            detect_series("Leviathan Wakes (The Expanse Book 1)"),
+            series("Leviathan Wakes", "The Expanse", Some(1.0))
+        );
+        assert_eq!(
+            detect_series("Caliban's War [The Expanse, #2]"),
+            series("Caliban's War", "The Expanse", Some(2.0))
+        );
+    }
+
+    #[test]
+    fn kind_word_subtitle() {
+        assert_eq!(
+            detect_series("Title: A Something Novel, Book Three"),
+            series("Title", "Something", Some(3.0))
+        );
+        assert_eq!(
+            detect_series("Mort: A Discworld Novel, Book 4"),
+            series("Mort", "Discworld", Some(4.0))
+        );
+        assert_eq!(
+            detect_series("Mort: A Discworld Novel"),
+            series("Mort", "Discworld", None)
+        );
+    }
+
+    #[test]
+    fn subtitle_and_prefix() {
+        assert_eq!(
+            detect_series("Title - Book 3 of the Something Trilogy"),
+            series("Title", "Something Trilogy", Some(3.0))
+        );
+        assert_eq!(
+            detect_series("The Expanse Book 1: Leviathan Wakes"),
+            series("Leviathan Wakes", "The Expanse", Some(1.0))
+        );
+    }
+
+    #[test]
+    fn spelled_out_and_roman_numbers() {
+        assert_eq!(
+            detect_series("Title (Something, Book Third)"),
+            series("Title", "Something", Some(3.0))
+        );
+        assert_eq!(
+            detect_series("Title (Discworld Vol. IV)"),
+            series("Title", "Discworld", Some(4.0))
+        );
+        assert_eq!(roman_number("iiii"), None);
+        assert_eq!(roman_number("mix"), Some(1009));
+    }
+
+    #[test]
+    fn plain_titles() {
+        assert_eq!(detect_series("Leviathan Wakes"), None);
+        assert_eq!(detect_series("Dune (Revised Edition)"), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Sort-name conversion for authors and titles.
+
+use super::normalize_language;
+
+/// Name suffixes that stay after the given names (e.g. "King, Martin Luther, Jr.").
+const SUFFIXES: &[&str] = &[
+    "jr", "jr.", "sr", "sr.", "ii", "iii", "iv", "v", "vi", "phd", "ph.d.", "md", "m.d.", "esq",
+    "esq.",
+];
+
+/// Surname particles. Written in lowercase they stay with the given names
+/// ("Beethoven, Ludwig van"); capitalized they are part of the surname
+/// ("Le Guin, Ursula K.").
+const PARTICLES: &[&str] = &[
+    "van", "von", "der", "den", "de", "del", "della", "di", "da", "du", "des", "la", "le", "ten",
+    "ter", "st.", "st",
+];
+
+/// Words that mark a name as belonging to an organization rather than a person.
+const CORPORATE_WORDS: &[&str] = &[
+    "inc",
+    "inc.",
+    "ltd",
+    "ltd.",
+    "llc",
+    "co.",
+    "corp",
+    "corp.",
+    "corporation",
+    "company",
+    "press",
+    "publishing",
+    "publishers",
+    "books",
+    "association",
+    "society",
+    "institute",
+    "university",
+    "foundation",
+    "council",
+    "committee",
+    "department",
+    "staff",
+    "group",
+    "team",
+    "editors",
+    "library",
+    "museum",
+    "agency",
+    "organization",
+    "organisation",
+];
+
+/// Convert a display name ("Ursula K. Le Guin") into its sort form
+/// ("Le Guin, Ursula K.").
+///
+/// Names that are already in sort form, single words and corporate names are
+/// returned unchanged (apart from whitespace cleanup).
+pub fn author_sort_name(name: &str) -> String {
+    let name = collapse_whitespace(name);
+    if is_sort_form(&name) || is_corporate(&name) {
+        return name;
+    }
+
+    let mut words = split_initials(&name.replace(',', " "));
+    let mut suffixes = Vec::new();
+    while words.len() > 2
+        && let Some(last) = words.last()
+        && is_suffix(last)
+    {
+        suffixes.insert(0, words.pop().unwrap_or_default());
+    }
+    if words.len() < 2 {
+        return name;
+    }
+
+    // The surname is the last word plus any capitalized particles before it.
+    let mut surname_start = words.len() - 1;
+    while surname_start > 1 && is_capitalized_particle(&words[surname_start - 1]) {
+        surname_start -= 1;
+    }
+
+    let surname = words[surname_start..].join(" ");
+    let given = words[..surname_start].join(" ");
+    let mut sort = format!("{surname}, {given}");
+    for suffix in suffixes {
+        sort.push_str(", ");
+        sort.push_str(&suffix);
+    }
+    sort
+}
+
+/// Convert a sort name ("King, Martin Luther, Jr.") back into display form
+/// ("Martin Luther King Jr.").
+///
+/// Names without a comma are returned unchanged.
+pub fn author_display_name(sort: &str) -> String {
+    let sort = collapse_whitespace(sort);
+    let mut parts = sort.split(',').map(str::trim).filter(|p| !p.is_empty());
+    let Some(surname) = parts.next() else {
+        return sort;
+    };
+
+    let mut given = Vec::new();
+    let mut suffixes = Vec::new();
+    for part in parts {
+        if is_suffix(part) {
+            suffixes.push(part);
+        } else {
+            given.push(part);
+        }
+    }
+    if given.is_empty() {
+        return sort;
+    }
+
+    let mut words = given;
+    words.push(surname);
+    words.extend(suffixes);
+    words.join(" ")
+}
+
+/// Whether an author name appears to be written in sort form ("Last, First"),
+/// as opposed to a display name with a comma before a suffix
+/// ("Martin Luther King, Jr.").
+pub fn is_sort_form(name: &str) -> bool {
+    let mut parts = name.split(',').map(str::trim);
+    let first = parts.next().unwrap_or_default();
+    !first.is_empty() && parts.any(|p| !p.is_empty() && !is_suffix(p)) && !is_corporate(name)
+}
+
+/// Convert a title into its sort form by moving a leading article to the
+/// end ("The Hobbit" becomes "Hobbit, The").
+///
+/// The articles depend on the book's language, which is normalized with
+/// [`normalize_language`]; English is assumed when it is unknown.
+pub fn title_sort_name(title: &str, language: Option<&str>) -> String {
+    let title = collapse_whitespace(title);
+    let articles = articles_for(language);
+
+    // Elided articles such as "L'" are attached to the next word.
+    for article in articles.iter().filter(|a| a.ends_with('\'')) {
+        if let Some(head) = title.get(..article.len())
+            && head.eq_ignore_ascii_case(article)
+            && title.len() > article.len()
+        {
+            let rest = &title[article.len()..];
+            return format!("{}, {head}", rest.trim_start());
+        }
+    }
+
+    if let Some((first, rest)) = title.split_once(' ')
+        && !rest.is_empty()
+        && articles
+            .iter()
+            .any(|a| !a.ends_with('\'') && a.eq_ignore_ascii_case(first))
+    {
+        return format!("{rest}, {first}");
+    }
+
+    title
+}
+
+/// Leading articles for a language.
+fn articles_for(language: Option<&str>) -> &'static [&'static str] {
+    let tag = language.and_then(normalize_language);
+    match tag.as_deref().and_then(|t| t.split('-').next()) {
+        Some("de") => &["der", "die", "das", "ein", "eine"],
+        Some("fr") => &["le", "la", "les", "un", "une", "l'"],
+        Some("es") => &["el", "la", "los", "las", "un", "una"],
+        Some("it") => &["il", "lo", "la", "i", "gli", "le", "un", "uno", "una", "l'"],
+        Some("nl") => &["de", "het", "een", "'t"],
+        Some("pt") => &["o", "a", "os", "as", "um", "uma"],
+        _ => &["the", "a", "an"],
+    }
+}
+
+fn is_suffix(word: &str) -> bool {
+    let lower = word.to_lowercase();
+    SUFFIXES.contains(&lower.as_str())
+}
+
+fn is_capitalized_particle(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_uppercase())
+        && PARTICLES.contains(&word.to_lowercase().as_str())
+}
+
+fn is_corporate(name: &str) -> bool {
+    name.contains('&')
+        || name.split_whitespace().any(|w| {
+            let w = w.trim_matches(|c: char| c == ',' || c == '(' || c == ')');
+            CORPORATE_WORDS.contains(&w.to_lowercase().as_str())
+        })
+}
+
+/// Split words at initials that run into the next name, so "J.K.Rowling"
+/// becomes ["J.K.", "Rowling"].
+fn split_initials(name: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for word in name.split_whitespace() {
+        match word.rfind('.') {
+            Some(i) if i + 1 < word.len() && word[i + 1..].chars().count() > 1 => {
+                words.push(word[..=i].to_string());
+                words.push(word[i + 1..].to_string());
+            }
+            _ => words.push(word.to_string()),
+        }
+    }
+    words
+}
+
+fn collapse_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
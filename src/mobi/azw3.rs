@@ -0,0 +1,746 @@
+//! Writing AZW3 (KF8) books.
+//!
+//! Each XHTML document becomes a skeleton, the document with its body
+//! emptied, followed by one fragment holding the body's content. Together
+//! they make up the first flow of the text; stylesheets and SVG images
+//! follow as further flows, located by the FDST record. Images and fonts
+//! are stored in resource records after the text.
+//!
+//! Links become `kindle:` URLs: `kindle:pos:fid:` between documents,
+//! `kindle:flow:` to flows and `kindle:embed:` to resources. Where a link
+//! between documents points is only known once every document is written,
+//! so those links are written as fixed-width placeholders and filled in at
+//! the end.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use flate2::write::ZlibEncoder;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use regex_lite::{Captures, Regex};
+
+use super::content::{resolve_href, BookContent, ContentFile, EOF_RECORD};
+use super::exth::{self, Exth};
+use super::header::{self, TextEncoding, NULL_INDEX};
+use super::index::{write_index, IndexEntry, Strings};
+use super::palmdoc;
+use crate::epub::local_name;
+use crate::{html, image, Error, Metadata};
+
+/// The uncompressed size of each text record.
+const RECORD_SIZE: usize = 4096;
+
+/// Length of the MOBI header, from its identifier to the EXTH header.
+const MOBI_HEADER_LEN: usize = 264;
+
+/// A `kindle:pos:fid:` link before its target is filled in.
+const POS_PLACEHOLDER: &str = "kindle:pos:fid:0000:off:0000000000";
+
+/// Tags of the fragment index: the selector of the element the fragment is
+/// inserted into, its file and sequence numbers, and its offset and length.
+const FRAGMENT_TAGS: &[(u8, u8, u8)] = &[(2, 1, 1), (3, 1, 2), (4, 1, 4), (6, 2, 8)];
+
+/// Tags of the skeleton index: the fragment count and the skeleton's
+/// position and length, given twice.
+const SKELETON_TAGS: &[(u8, u8, u8)] = &[(1, 1, 3), (6, 2, 12)];
+
+/// Tags of the NCX index: position, length, label, depth, parent, first and
+/// last child, and fragment and offset.
+const NCX_TAGS: &[(u8, u8, u8)] = &[
+    (1, 1, 1),
+    (2, 1, 2),
+    (3, 1, 4),
+    (4, 1, 8),
+    (21, 1, 16),
+    (22, 1, 32),
+    (23, 1, 64),
+    (6, 2, 128),
+];
+
+/// Tags of the guide index: the title, and fragment and offset.
+const GUIDE_TAGS: &[(u8, u8, u8)] = &[(1, 1, 1), (6, 2, 2)];
+
+/// The FLIS record, which has the same content in every book.
+const FLIS: &[u8] = b"FLIS\0\0\0\x08\0\x41\0\0\0\0\0\0\xff\xff\xff\xff\0\x01\0\x03\0\0\0\x03\0\0\0\x01\xff\xff\xff\xff";
+
+/// `url()` references in stylesheets.
+static CSS_URL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"url\(\s*['"]?([^'")]+?)['"]?\s*\)"#).unwrap());
+
+/// A document with its links rewritten, before it is split into a skeleton
+/// and a fragment.
+struct Document {
+    data: Vec<u8>,
+    /// Where the body's content starts and ends.
+    body: Range<usize>,
+    /// The position of each element with an id.
+    ids: HashMap<String, usize>,
+    /// Placeholder links to fill in.
+    links: Vec<Link>,
+}
+
+/// A link between documents, written as a placeholder.
+struct Link {
+    /// Where the placeholder is in the document.
+    at: usize,
+    /// The target document and element id.
+    document: usize,
+    id: Option<String>,
+}
+
+/// Where the parts of the book ended up, for record 0.
+struct Layout {
+    text_length: usize,
+    text_records: usize,
+    first_resource: Option<usize>,
+    fragment: usize,
+    skeleton: usize,
+    ncx: usize,
+    guide: Option<usize>,
+    fdst: usize,
+    flows: usize,
+    flis: usize,
+    fcis: usize,
+}
+
+/// Build the records of an AZW3 book, from record 0 to the end-of-file
+/// record.
+///
+/// `content` holds the book's files by their paths, with links between
+/// them relative to each file. The cover image is also stored as the
+/// thumbnail.
+pub(super) fn build(
+    content: &BookContent,
+    metadata: &Metadata,
+    warnings: &mut Vec<String>,
+) -> crate::Result<Vec<Vec<u8>>> {
+    if content.documents.is_empty() {
+        return Err(Error::InvalidBook("the book has no documents".into()));
+    }
+
+    // Number the resources and flows, so that links to them are known.
+    let mut urls: HashMap<&str, String> = HashMap::new();
+    let mut resources = Vec::new();
+    let mut flows = Vec::new();
+    let mut cover = None;
+    for file in &content.resources {
+        let url = if matches!(file.media_type.as_str(), "text/css" | "image/svg+xml") {
+            flows.push(file);
+            format!(
+                "kindle:flow:{}?mime={}",
+                base32(flows.len(), 4),
+                file.media_type
+            )
+        } else if let Some(media_type) = image::media_type(&file.data) {
+            resources.push(file.data.clone());
+            if content.cover.as_ref() == Some(&file.href) {
+                cover = Some(resources.len());
+            }
+            format!(
+                "kindle:embed:{}?mime={media_type}",
+                base32(resources.len(), 4)
+            )
+        } else if is_font(&file.data) {
+            resources.push(font_record(&file.data)?);
+            format!("kindle:embed:{}", base32(resources.len(), 4))
+        } else {
+            continue;
+        };
+        urls.insert(&file.href, url);
+    }
+    // Kindles show the thumbnail in the library; the cover stands in for a
+    // scaled-down copy.
+    let thumbnail = cover.map(|cover| {
+        resources.push(resources[cover - 1].clone());
+        resources.len()
+    });
+
+    let numbers: HashMap<&str, usize> = content
+        .documents
+        .iter()
+        .enumerate()
+        .map(|(i, file)| (file.href.as_str(), i))
+        .collect();
+    let mut documents: Vec<Document> = content
+        .documents
+        .iter()
+        .enumerate()
+        .map(|(i, file)| rewrite_document(i, file, &urls, &numbers, warnings))
+        .collect();
+    for i in 0..documents.len() {
+        let targets: Vec<(usize, String)> = documents[i]
+            .links
+            .iter()
+            .map(|link| {
+                let offset = target_offset(&documents[link.document], link.id.as_deref())
+                    .unwrap_or_else(|| {
+                        warnings.push(format!(
+                            "{} links to a missing anchor in {}",
+                            content.documents[i].href, content.documents[link.document].href
+                        ));
+                        0
+                    });
+                (link.at, pos_url(link.document, offset))
+            })
+            .collect();
+        for (at, url) in targets {
+            documents[i].data[at..at + url.len()].copy_from_slice(url.as_bytes());
+        }
+    }
+
+    // The first flow: each skeleton followed by its fragment.
+    let mut text = Vec::new();
+    let mut skeletons = Vec::new();
+    let mut fragments = Vec::new();
+    let mut selectors = Strings::default();
+    let mut inserts = Vec::new();
+    for (i, document) in documents.iter().enumerate() {
+        let start = text.len();
+        text.extend_from_slice(&document.data[..document.body.start]);
+        text.extend_from_slice(&document.data[document.body.end..]);
+        let skeleton_len = text.len() - start;
+        text.extend_from_slice(&document.data[document.body.clone()]);
+
+        let insert_at = start + document.body.start;
+        inserts.push(insert_at..insert_at + document.body.len());
+        let (start, skeleton_len) = (start as u32, skeleton_len as u32);
+        skeletons.push(IndexEntry::new(
+            format!("SKEL{i:010}"),
+            vec![
+                (1, vec![1]),
+                (6, vec![start, skeleton_len, start, skeleton_len]),
+            ],
+        ));
+        let selector = selectors.add(&format!("P-//*[@aid='{}']", base32(i, 4)));
+        fragments.push(IndexEntry::new(
+            format!("{insert_at:010}"),
+            vec![
+                (2, vec![selector]),
+                (3, vec![i as u32]),
+                (4, vec![i as u32]),
+                (6, vec![0, document.body.len() as u32]),
+            ],
+        ));
+    }
+
+    let mut flow_ranges = Vec::with_capacity(flows.len() + 1);
+    flow_ranges.push(0..text.len());
+    for file in &flows {
+        let start = text.len();
+        if file.media_type == "text/css" {
+            let css = String::from_utf8_lossy(&file.data);
+            text.extend_from_slice(rewrite_css(&css, &file.href, &urls).as_bytes());
+        } else {
+            text.extend_from_slice(&file.data);
+        }
+        flow_ranges.push(start..text.len());
+    }
+
+    // A position in the text for a link target.
+    let locate = |href: &str, warnings: &mut Vec<String>| {
+        let (path, id) = match href.split_once('#') {
+            Some((path, id)) => (path, Some(id)),
+            None => (href, None),
+        };
+        let Some(&i) = numbers.get(path) else {
+            warnings.push(format!("{href} is not a document of the book"));
+            return None;
+        };
+        let offset = target_offset(&documents[i], id).unwrap_or_default();
+        Some((i, offset))
+    };
+
+    let mut ncx = Vec::new();
+    let mut labels = Strings::default();
+    // The index of the last entry at each depth, for parents and children.
+    let mut ancestors: Vec<usize> = Vec::new();
+    for entry in &content.toc {
+        let Some((i, offset)) = locate(&entry.href, warnings) else {
+            continue;
+        };
+        ancestors.truncate(entry.depth as usize);
+        let depth = ancestors.len();
+        let index = ncx.len();
+        let position = inserts[i].start + offset;
+        let mut tags = vec![
+            (1, vec![position as u32]),
+            (2, vec![(inserts[i].end.max(position) - position) as u32]),
+            (3, vec![labels.add(&entry.title)]),
+            (4, vec![depth as u32]),
+        ];
+        if let Some(&parent) = ancestors.last() {
+            tags.push((21, vec![parent as u32]));
+            let parent_tags: &mut Vec<(u8, Vec<u32>)> = &mut ncx[parent];
+            if !parent_tags.iter().any(|(tag, _)| *tag == 22) {
+                parent_tags.push((22, vec![index as u32]));
+            }
+            parent_tags.retain(|(tag, _)| *tag != 23);
+            parent_tags.push((23, vec![index as u32]));
+        }
+        tags.push((6, vec![i as u32, offset as u32]));
+        ncx.push(tags);
+        ancestors.push(index);
+    }
+    let ncx: Vec<IndexEntry> = ncx
+        .into_iter()
+        .enumerate()
+        .map(|(i, tags)| IndexEntry::new(format!("{i:04}"), tags))
+        .collect();
+
+    let mut guide = Vec::new();
+    let mut titles = Strings::default();
+    let mut references: Vec<_> = content.guide.iter().collect();
+    references.sort_by(|a, b| a.kind.cmp(&b.kind));
+    references.dedup_by(|a, b| a.kind == b.kind);
+    for reference in references {
+        if let Some((i, offset)) = locate(&reference.href, warnings) {
+            guide.push(IndexEntry::new(
+                reference.kind.clone(),
+                vec![
+                    (1, vec![titles.add(&reference.title)]),
+                    (6, vec![i as u32, offset as u32]),
+                ],
+            ));
+        }
+    }
+
+    // Record 0 comes first but depends on where everything else goes.
+    let mut records = vec![Vec::new()];
+    records.extend(text_records(&text));
+    let text_records = records.len() - 1;
+    let first_resource = (!resources.is_empty()).then_some(records.len());
+    let resource_count = resources.len();
+    records.extend(resources);
+    let fragment = records.len();
+    records.extend(write_index(FRAGMENT_TAGS, &fragments, selectors));
+    let skeleton = records.len();
+    records.extend(write_index(SKELETON_TAGS, &skeletons, Strings::default()));
+    let ncx_index = records.len();
+    records.extend(write_index(NCX_TAGS, &ncx, labels));
+    let guide_index = (!guide.is_empty()).then_some(records.len());
+    if guide_index.is_some() {
+        records.extend(write_index(GUIDE_TAGS, &guide, titles));
+    }
+    let fdst = records.len();
+    records.push(fdst_record(&flow_ranges));
+    let flis = records.len();
+    records.push(FLIS.to_vec());
+    let fcis = records.len();
+    records.push(fcis_record(text.len()));
+    records.push(EOF_RECORD.to_vec());
+
+    let mut exth = Exth::default();
+    exth.set_metadata(metadata, TextEncoding::Utf8);
+    exth.set(exth::DOCUMENT_TYPE, ["EBOK"]);
+    exth.set_u32(exth::RESOURCE_COUNT, resource_count as u32);
+    if let (Some(cover), Some(thumbnail)) = (cover, thumbnail) {
+        exth.set_u32(exth::COVER_OFFSET, cover as u32 - 1);
+        exth.set_u32(exth::THUMBNAIL_OFFSET, thumbnail as u32 - 1);
+        exth.set_u32(exth::HAS_FAKE_COVER, 0);
+        exth.set(
+            exth::KF8_COVER_URI,
+            [format!("kindle:embed:{}", base32(cover, 4))],
+        );
+    }
+    let layout = Layout {
+        text_length: text.len(),
+        text_records,
+        first_resource,
+        fragment,
+        skeleton,
+        ncx: ncx_index,
+        guide: guide_index,
+        fdst,
+        flows: flow_ranges.len(),
+        flis,
+        fcis,
+    };
+    records[0] = record0(metadata, &exth, &layout);
+    Ok(records)
+}
+
+/// Rewrite the links of a document into `kindle:` URLs, and find its body
+/// and the elements links can point to.
+///
+/// A document that can't be parsed is kept as it is, with its links left
+/// unchanged.
+fn rewrite_document(
+    number: usize,
+    file: &ContentFile,
+    urls: &HashMap<&str, String>,
+    documents: &HashMap<&str, usize>,
+    warnings: &mut Vec<String>,
+) -> Document {
+    let source = String::from_utf8_lossy(&file.data);
+    let mut document = Document {
+        data: Vec::with_capacity(source.len()),
+        body: 0..0,
+        ids: HashMap::new(),
+        links: Vec::new(),
+    };
+    // Where the body's content starts, while it is being read, and its
+    // range once it is.
+    let mut body_start = None;
+    let mut body = None;
+
+    let mut reader = Reader::from_str(&source);
+    reader.config_mut().check_end_names = false;
+    loop {
+        let before = reader.buffer_position() as usize;
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => {
+                warnings.push(format!("cannot parse {}: {e}", file.href));
+                return Document {
+                    data: file.data.clone(),
+                    body: 0..file.data.len(),
+                    ids: HashMap::new(),
+                    links: Vec::new(),
+                };
+            }
+        };
+        let raw = &source[before..reader.buffer_position() as usize];
+        let data = &mut document.data;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                let name = e.name();
+                let name = local_name(name.as_ref());
+                let tag_start = data.len();
+                let mut tag = format!("<{}", String::from_utf8_lossy(e.name().as_ref()));
+                let mut changed = false;
+                for attr in e.attributes().flatten() {
+                    let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+                    let Ok(value) = attr.unescape_value() else {
+                        tag.push_str(&format!(
+                            " {key}=\"{}\"",
+                            String::from_utf8_lossy(&attr.value)
+                        ));
+                        continue;
+                    };
+                    if key == "id" || (key == "name" && name == b"a") {
+                        document.ids.entry(value.to_string()).or_insert(tag_start);
+                    }
+                    let link = link_attribute(name, &key, e);
+                    let mut value = value.into_owned();
+                    if link && !is_external(&value) {
+                        let (path, id) = resolve_href(&file.href, &value);
+                        if let Some(&target) = documents.get(path.as_str()) {
+                            tag.push_str(&format!(" {key}=\""));
+                            document.links.push(Link {
+                                at: tag_start + tag.len(),
+                                document: target,
+                                id,
+                            });
+                            tag.push_str(POS_PLACEHOLDER);
+                            tag.push('"');
+                            changed = true;
+                            continue;
+                        }
+                        if let Some(url) = urls.get(path.as_str()) {
+                            value = url.clone();
+                            changed = true;
+                        }
+                    }
+                    tag.push_str(&format!(" {key}=\"{}\"", html::escape(&value, true)));
+                }
+                if name == b"body" && !is_empty {
+                    tag.push_str(&format!(" aid=\"{}\"", base32(number, 4)));
+                    changed = true;
+                }
+                if changed {
+                    tag.push_str(if is_empty { "/>" } else { ">" });
+                    data.extend_from_slice(tag.as_bytes());
+                } else {
+                    data.extend_from_slice(raw.as_bytes());
+                }
+                if name == b"body" && !is_empty {
+                    body_start = Some(data.len());
+                }
+            }
+            Event::End(ref e) if local_name(e.name().as_ref()) == b"body" => {
+                if let Some(start) = body_start.take() {
+                    body = Some(start..data.len());
+                }
+                data.extend_from_slice(raw.as_bytes());
+            }
+            Event::Eof => break,
+            _ => data.extend_from_slice(raw.as_bytes()),
+        }
+    }
+    document.body = match (body, body_start) {
+        (Some(body), _) => body,
+        (None, Some(start)) => start..document.data.len(),
+        (None, None) => {
+            warnings.push(format!("{} has no body", file.href));
+            0..document.data.len()
+        }
+    };
+    document
+}
+
+/// Whether an attribute of an element holds a link to another file.
+fn link_attribute(name: &[u8], key: &str, element: &BytesStart) -> bool {
+    match (name, key) {
+        (b"a" | b"area", "href") => true,
+        (b"img", "src") => true,
+        (b"image", "href" | "xlink:href") => true,
+        (b"link", "href") => element
+            .try_get_attribute("rel")
+            .ok()
+            .flatten()
+            .is_some_and(|rel| rel.value.as_ref().eq_ignore_ascii_case(b"stylesheet")),
+        _ => false,
+    }
+}
+
+/// Whether a link points outside the book.
+fn is_external(href: &str) -> bool {
+    href.split_once(':')
+        .is_some_and(|(scheme, _)| !scheme.is_empty() && !scheme.contains(['/', '#', '.']))
+}
+
+/// Rewrite the `url()` references of a stylesheet into `kindle:` URLs.
+fn rewrite_css(css: &str, path: &str, urls: &HashMap<&str, String>) -> String {
+    CSS_URL
+        .replace_all(css, |caps: &Captures| {
+            if is_external(&caps[1]) {
+                return caps[0].to_string();
+            }
+            let (target, _) = resolve_href(path, &caps[1]);
+            match urls.get(target.as_str()) {
+                Some(url) => format!("url({url})"),
+                None => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// The offset of element `id` within a document's fragment, or of the
+/// fragment's start when there is no id. Elements outside the body count
+/// as its start.
+fn target_offset(document: &Document, id: Option<&str>) -> Option<usize> {
+    let Some(id) = id else {
+        return Some(0);
+    };
+    let position = *document.ids.get(id)?;
+    Some(
+        position
+            .saturating_sub(document.body.start)
+            .min(document.body.len()),
+    )
+}
+
+/// A `kindle:pos:fid:` link to an offset in a document's fragment, the
+/// same length as [`POS_PLACEHOLDER`].
+fn pos_url(document: usize, offset: usize) -> String {
+    format!(
+        "kindle:pos:fid:{}:off:{}",
+        base32(document, 4),
+        base32(offset, 10)
+    )
+}
+
+/// Split the text into PalmDOC-compressed records.
+///
+/// A character cut off at the end of a record is completed after the
+/// compressed data, followed by the number of bytes added, which readers
+/// skip as a trailing entry.
+fn text_records(text: &[u8]) -> Vec<Vec<u8>> {
+    text.chunks(RECORD_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let end = i * RECORD_SIZE + chunk.len();
+            let mut record = palmdoc::compress(chunk);
+            let overlap = text[end..]
+                .iter()
+                .take(3)
+                .take_while(|&&b| b & 0xC0 == 0x80)
+                .count();
+            record.extend_from_slice(&text[end..end + overlap]);
+            record.push(overlap as u8);
+            record
+        })
+        .collect()
+}
+
+/// Whether a file is a TrueType, OpenType or WOFF font.
+fn is_font(data: &[u8]) -> bool {
+    matches!(
+        data.get(..4),
+        Some(b"\0\x01\0\0" | b"true" | b"ttcf" | b"OTTO" | b"wOFF" | b"wOF2")
+    )
+}
+
+/// A FONT record holding a zlib-compressed font.
+fn font_record(font: &[u8]) -> crate::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(font)?;
+    let compressed = encoder.finish()?;
+
+    let mut record = Vec::with_capacity(24 + compressed.len());
+    record.extend_from_slice(b"FONT");
+    // Size, flags (compressed), data offset, key length and key offset.
+    for value in [font.len() as u32, 1, 24, 0, 24] {
+        record.extend_from_slice(&value.to_be_bytes());
+    }
+    record.extend_from_slice(&compressed);
+    Ok(record)
+}
+
+/// The FDST record, listing where each flow starts and ends.
+fn fdst_record(flows: &[Range<usize>]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(12 + flows.len() * 8);
+    record.extend_from_slice(b"FDST");
+    record.extend_from_slice(&12u32.to_be_bytes());
+    record.extend_from_slice(&(flows.len() as u32).to_be_bytes());
+    for flow in flows {
+        record.extend_from_slice(&(flow.start as u32).to_be_bytes());
+        record.extend_from_slice(&(flow.end as u32).to_be_bytes());
+    }
+    record
+}
+
+/// The FCIS record, which holds the text length.
+fn fcis_record(text_length: usize) -> Vec<u8> {
+    let mut record = b"FCIS\0\0\0\x14\0\0\0\x10\0\0\0\x02\0\0\0\0".to_vec();
+    record.extend_from_slice(&(text_length as u32).to_be_bytes());
+    record.extend_from_slice(b"\0\0\0\0\0\0\0\x28\0\0\0\0\0\0\0\x28\0\0\0\x08\0\x01\0\x01\0\0\0\0");
+    record
+}
+
+/// Record 0: the PalmDOC and MOBI headers, the EXTH header and the title.
+fn record0(metadata: &Metadata, exth: &Exth, layout: &Layout) -> Vec<u8> {
+    let title = metadata.title.as_deref().unwrap_or_default();
+    let exth = exth.to_bytes();
+    let mut hasher = Fnv1a::default();
+    hasher.field(title.as_bytes());
+    let asin = metadata.identifiers.get("asin");
+    hasher.field(asin.map(String::as_bytes).unwrap_or_default());
+    let index = |record: Option<usize>| record.map_or(NULL_INDEX, |r| r as u32);
+
+    let mut record = vec![0u8; 16 + MOBI_HEADER_LEN];
+    let mut put = |offset: usize, value: u32| {
+        record[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    };
+    // PalmDOC header: compression, text length, record count and size.
+    put(0, 2 << 16);
+    put(4, layout.text_length as u32);
+    put(8, ((layout.text_records as u32) << 16) | RECORD_SIZE as u32);
+
+    put(20, MOBI_HEADER_LEN as u32);
+    // A book, in UTF-8, and its unique id.
+    put(24, 2);
+    put(28, 65001);
+    put(32, hasher.finish() as u32);
+    put(36, 8);
+    // Dictionary indices, which books don't have.
+    for offset in (40..80).step_by(4) {
+        put(offset, NULL_INDEX);
+    }
+    put(80, layout.text_records as u32 + 1);
+    put(84, (16 + MOBI_HEADER_LEN + exth.len()) as u32);
+    put(88, title.len() as u32);
+    put(92, metadata.language.as_deref().map_or(0, header::locale));
+    put(104, 8);
+    put(108, index(layout.first_resource));
+    put(128, 0x50);
+    put(164, NULL_INDEX);
+    // No DRM.
+    put(168, NULL_INDEX);
+    put(192, layout.fdst as u32);
+    put(196, layout.flows as u32);
+    put(200, layout.fcis as u32);
+    put(204, 1);
+    put(208, layout.flis as u32);
+    put(212, 1);
+    for offset in [224, 232, 236, 256, 264, 272, 276] {
+        put(offset, NULL_INDEX);
+    }
+    // Text records end with multibyte character overlaps.
+    put(240, 1);
+    put(244, layout.ncx as u32);
+    put(248, layout.fragment as u32);
+    put(252, layout.skeleton as u32);
+    put(260, index(layout.guide));
+    record[16..20].copy_from_slice(b"MOBI");
+
+    record.extend_from_slice(&exth);
+    record.extend_from_slice(title.as_bytes());
+    record.resize((record.len() + 2).next_multiple_of(4), 0);
+    record
+}
+
+/// A 64-bit FNV-1a hash. Unlike `DefaultHasher`, its output is fixed, so
+/// identifiers derived from it don't change between Rust releases.
+pub(super) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Fnv1a {
+        Fnv1a(0xCBF2_9CE4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    /// Add a field, prefixed with its length so that neighbouring fields
+    /// can't run into each other.
+    pub fn field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100_0000_01B3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A number in base 32 with digits `0-9A-V`, padded to `width`.
+pub(super) fn base32(mut n: usize, width: usize) -> String {
+    const DIGITS: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+    let mut digits = Vec::with_capacity(width);
+    while n > 0 {
+        digits.push(DIGITS[n % 32]);
+        n /= 32;
+    }
+    digits.resize(digits.len().max(width), b'0');
+    digits.reverse();
+    String::from_utf8(digits).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv1a::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(hash(b"a"), 0xAF63_DC4C_8601_EC8C);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_F739_67E8);
+    }
+
+    #[test]
+    fn fnv1a_fields_are_separated() {
+        let hash = |fields: &[&[u8]]| {
+            let mut hasher = Fnv1a::default();
+            for field in fields {
+                hasher.field(field);
+            }
+            hasher.finish()
+        };
+        assert_ne!(hash(&[b"ab", b"c"]), hash(&[b"a", b"bc"]));
+    }
+}
@@ -0,0 +1,222 @@
+//! The content of MOBI and AZW3 books rebuilt as separate files: XHTML
+//! documents in reading order, stylesheets, images and fonts, with the
+//! table of contents and guide pointing into them.
+
+use std::io::Read;
+use std::ops::Range;
+
+use flate2::read::ZlibDecoder;
+
+use super::pdb::u32_at;
+use crate::image;
+
+/// The record that ends the resources of the MOBI section of a joint file.
+const BOUNDARY: &[u8] = b"BOUNDARY";
+
+/// The record that ends the book.
+pub(super) const EOF_RECORD: &[u8] = b"\xe9\x8e\r\n";
+
+/// A file rebuilt from a MOBI or AZW3 book.
+#[derive(Debug, Clone)]
+pub struct ContentFile {
+    /// Path of the file within the book, such as `text/part0000.xhtml`.
+    pub href: String,
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+/// An entry of a book's table of contents.
+#[derive(Debug, Clone)]
+pub struct TocEntry {
+    pub title: String,
+    /// The entry's target, relative to the book root.
+    pub href: String,
+    /// Nesting level, from 0 for top-level entries.
+    pub depth: u32,
+}
+
+/// A reference from a book's guide, such as where the text starts.
+#[derive(Debug, Clone)]
+pub struct GuideEntry {
+    /// The reference type, such as `text` or `toc`.
+    pub kind: String,
+    pub title: String,
+    /// The reference's target, relative to the book root.
+    pub href: String,
+}
+
+/// The content of a book, rebuilt into separate files.
+#[derive(Debug, Clone, Default)]
+pub struct BookContent {
+    /// The XHTML documents, in reading order.
+    pub documents: Vec<ContentFile>,
+    /// Stylesheets, SVG images, images and fonts.
+    pub resources: Vec<ContentFile>,
+    /// The href of the cover image, if the book has one.
+    pub cover: Option<String>,
+    pub toc: Vec<TocEntry>,
+    pub guide: Vec<GuideEntry>,
+    /// Problems that did not stop the rebuild, such as unreadable fonts.
+    pub warnings: Vec<String>,
+}
+
+/// The image and font records of a book.
+///
+/// Records are numbered from 1, counting every record from the first
+/// image on, as `kindle:embed:` links and `recindex` attributes refer to
+/// them.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Resources {
+    pub files: Vec<ContentFile>,
+    /// The href of each record's file, if it holds one.
+    hrefs: Vec<Option<String>>,
+    pub warnings: Vec<String>,
+}
+
+impl Resources {
+    /// Read the resource records in `records`, stopping at the end of the
+    /// book or of the MOBI section of a joint file.
+    pub fn read(
+        records: Range<usize>,
+        read: &mut dyn FnMut(usize) -> crate::Result<Vec<u8>>,
+    ) -> crate::Result<Resources> {
+        let mut resources = Resources::default();
+        for record in records {
+            let data = read(record)?;
+            if data.starts_with(BOUNDARY) || data.starts_with(EOF_RECORD) {
+                break;
+            }
+            let number = resources.hrefs.len() + 1;
+            let file = if let Some(media_type) = image::media_type(&data) {
+                Some(ContentFile {
+                    href: format!("images/{number:05}.{}", image::extension(media_type)),
+                    media_type: media_type.into(),
+                    data,
+                })
+            } else if data.starts_with(b"FONT") {
+                match read_font(&data) {
+                    Ok((extension, media_type, data)) => Some(ContentFile {
+                        href: format!("fonts/{number:05}.{extension}"),
+                        media_type: media_type.into(),
+                        data,
+                    }),
+                    Err(e) => {
+                        resources
+                            .warnings
+                            .push(format!("font record {record}: {e}"));
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            resources.hrefs.push(file.as_ref().map(|f| f.href.clone()));
+            resources.files.extend(file);
+        }
+        Ok(resources)
+    }
+
+    /// The href of the file in resource record `number`, counting from 1.
+    pub fn href(&self, number: usize) -> Option<&str> {
+        self.hrefs
+            .get(number.checked_sub(1)?)
+            .and_then(|href| href.as_deref())
+    }
+}
+
+/// Resolve `href`, a link in the file at path `from`, to the path of its
+/// target within the book, decoding percent escapes. The fragment is
+/// returned separately.
+pub(super) fn resolve_href(from: &str, href: &str) -> (String, Option<String>) {
+    let (path, fragment) = match href.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment.to_string())),
+        None => (href, None),
+    };
+    if path.is_empty() {
+        return (from.to_string(), fragment);
+    }
+    let mut parts: Vec<&str> = match from.rfind('/') {
+        Some(i) => from[..i].split('/').collect(),
+        None => Vec::new(),
+    };
+    let decoded = percent_decode(path);
+    for part in decoded.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    (parts.join("/"), fragment)
+}
+
+/// Decode the `%XX` escapes in a URL path.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Decode a FONT record, returning the font's file extension, media type
+/// and data.
+///
+/// The font may be compressed with zlib, and its first bytes may be
+/// obfuscated by XOR with a key stored in the record.
+fn read_font(record: &[u8]) -> Result<(&'static str, &'static str, Vec<u8>), String> {
+    let field = |offset| u32_at(record, offset).map(|v| v as usize);
+    let (Some(size), Some(flags), Some(start), Some(key_len), Some(key_start)) =
+        (field(4), field(8), field(12), field(16), field(20))
+    else {
+        return Err("header is truncated".into());
+    };
+    let mut data = record.get(start..).ok_or("data is missing")?.to_vec();
+    if flags & 0b10 != 0 {
+        let key = record
+            .get(key_start..key_start + key_len)
+            .filter(|key| !key.is_empty())
+            .ok_or("obfuscation key is missing")?;
+        for (i, byte) in data.iter_mut().take(1040).enumerate() {
+            *byte ^= key[i % key.len()];
+        }
+    }
+    if flags & 0b1 != 0 {
+        // The size comes from the file, so it only guides the allocation
+        // and bounds the output.
+        let mut inflated = Vec::with_capacity(size.min(record.len() * 16));
+        ZlibDecoder::new(data.as_slice())
+            .take(size as u64 + 1)
+            .read_to_end(&mut inflated)
+            .map_err(|e| format!("cannot decompress: {e}"))?;
+        if inflated.len() != size {
+            return Err("decompressed size does not match".into());
+        }
+        data = inflated;
+    }
+    let (extension, media_type) = match data.get(..4) {
+        Some(b"\0\x01\0\0" | b"true" | b"ttcf") => ("ttf", "font/ttf"),
+        Some(b"OTTO") => ("otf", "font/otf"),
+        Some(b"wOFF") => ("woff", "font/woff"),
+        Some(b"wOF2") => ("woff2", "font/woff2"),
+        _ => return Err("unknown font type".into()),
+    };
+    Ok((extension, media_type, data))
+}
@@ -0,0 +1,730 @@
+//! Converting between MOBI or AZW3 books and EPUB.
+//!
+//! To EPUB, the book's content is rebuilt into separate files by
+//! [`MobiBook::content`], and packaged as an EPUB 3 with a package document
+//! built from the EXTH metadata, a navigation document and an NCX for older
+//! readers.
+//!
+//! From EPUB, the spine, resources, table of contents and guide are
+//! gathered into a [`BookContent`] and written as an AZW3 book by
+//! [`azw3::build`].
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::content::{resolve_href, BookContent, ContentFile, GuideEntry, TocEntry};
+use super::{azw3, pdb, MobiBook};
+use crate::epub::opf::OpfDocument;
+use crate::epub::{self, local_name};
+use crate::{atomic, html, DrmStatus, Error, MetadataProvider};
+
+/// The directory holding the book's files within the EPUB.
+const ROOT: &str = "OEBPS";
+
+const CONTAINER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n  \
+<rootfiles>\n    \
+<rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/>\n  \
+</rootfiles>\n\
+</container>\n";
+
+/// Convert the MOBI or AZW3 book at `input` to an EPUB at `output`.
+///
+/// Books with a KF8 section are converted from it. Problems that don't stop
+/// the conversion, such as unreadable fonts or broken links, are logged.
+pub fn to_epub(input: &Path, output: &Path) -> crate::Result<()> {
+    let book = MobiBook::open(input)?;
+    let mut content = book.content()?;
+    for warning in &content.warnings {
+        log::warn!("{warning}");
+    }
+
+    let mut metadata = book.metadata()?;
+    if metadata.title.is_none() {
+        metadata.title = input
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned());
+    }
+    if metadata.language.is_none() {
+        metadata.language = Some("und".into());
+    }
+    let uuid = metadata
+        .identifiers
+        .entry("uuid".into())
+        .or_insert_with(|| book_uuid(&content))
+        .clone();
+    let title = metadata.title.clone().unwrap_or_default();
+
+    if content.toc.is_empty()
+        && let Some(first) = content.documents.first()
+    {
+        content.toc.push(TocEntry {
+            title: title.clone(),
+            href: first.href.clone(),
+            depth: 0,
+        });
+    }
+
+    let mut opf =
+        OpfDocument::parse(package(&content, &uuid)).expect("package template is valid XML");
+    opf.update_metadata(&metadata);
+    if let Some(cover) = &content.cover {
+        opf.set_named_meta("cover", Some(&manifest_id(cover)));
+    }
+    opf.touch_modified();
+
+    let mut files = vec![
+        ("content.opf".to_string(), opf.to_xml().into_bytes()),
+        (
+            "nav.xhtml".to_string(),
+            nav(&content.toc, &title).into_bytes(),
+        ),
+        (
+            "toc.ncx".to_string(),
+            ncx(&content.toc, &title, &uuid).into_bytes(),
+        ),
+    ];
+    for file in content.documents.into_iter().chain(content.resources) {
+        files.push((file.href, file.data));
+    }
+
+    atomic::write_with(output, |tmp_path| write_epub(tmp_path, &files))
+}
+
+/// Convert the EPUB at `input` to an AZW3 book at `output`.
+///
+/// The book gets `EBOK` as its document type and an ASIN, taken from its
+/// identifiers or derived from its content, so that Kindles show its cover
+/// when it is sideloaded. Problems that don't stop the conversion, such as
+/// broken links, are logged.
+pub fn from_epub(input: &Path, output: &Path) -> crate::Result<()> {
+    let mut zip = epub::open_archive(input)?;
+    if let DrmStatus::Protected(scheme) = epub::detect_drm(&mut zip) {
+        return Err(Error::InvalidBook(format!(
+            "the book is protected by {scheme}"
+        )));
+    }
+    let mut warnings = Vec::new();
+    let opf_path = epub::parse_container(&mut zip, &mut warnings)?;
+    let opf = epub::read_opf(&mut zip, &opf_path, &mut warnings)?;
+    let mut metadata = opf.to_metadata();
+
+    let mut content = BookContent::default();
+    let path = |href: &str| resolve_href(&opf_path, href).0;
+    for idref in &opf.spine_items {
+        let Some(item) = opf.manifest.iter().find(|item| &item.id == idref) else {
+            warnings.push(format!("spine item {idref} is not in the manifest"));
+            continue;
+        };
+        if let Some(data) = read_entry(&mut zip, &path(&item.href), &mut warnings) {
+            content.documents.push(ContentFile {
+                href: path(&item.href),
+                media_type: item.media_type.clone(),
+                data,
+            });
+        }
+    }
+    let cover_id = opf.named_meta("cover").map(str::to_string).or_else(|| {
+        opf.manifest
+            .iter()
+            .find(|item| item.has_property("cover-image"))
+            .map(|item| item.id.clone())
+    });
+    for item in &opf.manifest {
+        let href = path(&item.href);
+        if content.documents.iter().any(|file| file.href == href)
+            || item.has_property("nav")
+            || item.media_type == "application/x-dtbncx+xml"
+        {
+            continue;
+        }
+        if let Some(data) = read_entry(&mut zip, &href, &mut warnings) {
+            if cover_id.as_ref() == Some(&item.id) {
+                content.cover = Some(href.clone());
+            }
+            content.resources.push(ContentFile {
+                href,
+                media_type: item.media_type.clone(),
+                data,
+            });
+        }
+    }
+
+    let nav = opf.manifest.iter().find(|item| item.has_property("nav"));
+    let ncx = opf
+        .spine_attr("toc")
+        .and_then(|id| opf.manifest.iter().find(|item| item.id == id))
+        .or_else(|| {
+            opf.manifest
+                .iter()
+                .find(|item| item.media_type == "application/x-dtbncx+xml")
+        });
+    if let Some(nav) = nav {
+        let nav_path = path(&nav.href);
+        if let Some(data) = read_entry(&mut zip, &nav_path, &mut warnings) {
+            let (toc, landmarks) = read_nav(&String::from_utf8_lossy(&data), &nav_path);
+            content.toc = toc;
+            content.guide = landmarks;
+        }
+    }
+    if content.toc.is_empty()
+        && let Some(ncx) = ncx
+    {
+        let ncx_path = path(&ncx.href);
+        if let Some(data) = read_entry(&mut zip, &ncx_path, &mut warnings) {
+            content.toc = read_ncx(&String::from_utf8_lossy(&data), &ncx_path);
+        }
+    }
+    if !opf.guide.is_empty() {
+        content.guide = opf
+            .guide
+            .iter()
+            .map(|reference| GuideEntry {
+                kind: reference.kind.clone(),
+                title: reference
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| reference.kind.clone()),
+                href: {
+                    let (target, id) = resolve_href(&opf_path, &reference.href);
+                    id.map_or_else(|| target.clone(), |id| format!("{target}#{id}"))
+                },
+            })
+            .collect();
+    }
+    drop(zip);
+
+    if metadata.title.is_none() {
+        metadata.title = input
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned());
+    }
+    let title = metadata.title.clone().unwrap_or_default();
+    if content.toc.is_empty()
+        && let Some(first) = content.documents.first()
+    {
+        content.toc.push(TocEntry {
+            title: title.clone(),
+            href: first.href.clone(),
+            depth: 0,
+        });
+    }
+    let asin = ["asin", "mobi-asin", "amazon", "uuid"]
+        .iter()
+        .find_map(|scheme| metadata.identifiers.get(*scheme).cloned())
+        .unwrap_or_else(|| book_uuid(&content));
+    metadata.identifiers.insert("asin".into(), asin);
+
+    let records = azw3::build(&content, &metadata, &mut warnings)?;
+    for warning in &warnings {
+        log::warn!("{warning}");
+    }
+    let name: String = title
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(31)
+        .collect();
+    let data = pdb::write_database(&name, &records)?;
+    atomic::write(output, &data)
+}
+
+/// Read a file from the EPUB archive, noting a warning if it is missing.
+fn read_entry<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    path: &str,
+    warnings: &mut Vec<String>,
+) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    match zip.by_name(path) {
+        Ok(mut entry) => match entry.read_to_end(&mut data) {
+            Ok(_) => Some(data),
+            Err(e) => {
+                warnings.push(format!("cannot read {path}: {e}"));
+                None
+            }
+        },
+        Err(_) => {
+            warnings.push(format!("{path} is in the manifest but not in the EPUB"));
+            None
+        }
+    }
+}
+
+/// A link in a navigation document or NCX at `from`, as a path within the
+/// book with its fragment.
+fn book_href(from: &str, href: &str) -> String {
+    let (path, id) = resolve_href(from, href);
+    match id {
+        Some(id) => format!("{path}#{id}"),
+        None => path,
+    }
+}
+
+/// Read the table of contents and landmarks of an EPUB 3 navigation
+/// document at `path`.
+///
+/// Landmarks are returned as guide references, with their EPUB 3 types
+/// mapped to the guide's.
+fn read_nav(source: &str, path: &str) -> (Vec<TocEntry>, Vec<GuideEntry>) {
+    let mut toc = Vec::new();
+    let mut landmarks = Vec::new();
+    // The `epub:type` of the `<nav>` being read, and how deeply its lists
+    // are nested.
+    let mut nav: Option<String> = None;
+    let mut depth = 0u32;
+    // The link being read: its target, type and text.
+    let mut link: Option<(String, Option<String>, String)> = None;
+
+    let mut reader = Reader::from_str(source);
+    reader.config_mut().check_end_names = false;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match local_name(e.name().as_ref()) {
+                b"nav" => {
+                    nav = e.attributes().flatten().find_map(|attr| {
+                        (local_name(attr.key.as_ref()) == b"type")
+                            .then(|| String::from_utf8_lossy(&attr.value).into_owned())
+                    });
+                    depth = 0;
+                }
+                b"ol" => depth += 1,
+                b"a" if nav.is_some() => {
+                    let attr = |name: &[u8]| {
+                        e.attributes().flatten().find_map(|attr| {
+                            (local_name(attr.key.as_ref()) == name)
+                                .then(|| attr.unescape_value().ok().map(|v| v.into_owned()))
+                                .flatten()
+                        })
+                    };
+                    if let Some(href) = attr(b"href") {
+                        link = Some((book_href(path, &href), attr(b"type"), String::new()));
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Text(ref e)) => {
+                if let Some((_, _, text)) = &mut link
+                    && let Ok(t) = e.unescape()
+                {
+                    text.push_str(&t);
+                }
+            }
+            Ok(Event::End(ref e)) => match local_name(e.name().as_ref()) {
+                b"nav" => nav = None,
+                b"ol" => depth = depth.saturating_sub(1),
+                b"a" => {
+                    let Some((href, kind, text)) = link.take() else {
+                        continue;
+                    };
+                    let title = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                    match nav.as_deref() {
+                        Some("toc") => toc.push(TocEntry {
+                            title,
+                            href,
+                            depth: depth.saturating_sub(1),
+                        }),
+                        Some("landmarks") => {
+                            let kind = match kind.as_deref() {
+                                Some("bodymatter") => "text",
+                                Some("toc") => "toc",
+                                Some("cover") => "cover",
+                                _ => continue,
+                            };
+                            landmarks.push(GuideEntry {
+                                kind: kind.into(),
+                                title,
+                                href,
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    (toc, landmarks)
+}
+
+/// Read the table of contents of an NCX at `path`.
+fn read_ncx(source: &str, path: &str) -> Vec<TocEntry> {
+    let mut toc = Vec::new();
+    // The entries of the enclosing navPoints.
+    let mut open: Vec<usize> = Vec::new();
+    let mut label: Option<String> = None;
+
+    let mut reader = Reader::from_str(source);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match local_name(e.name().as_ref()) {
+                b"navPoint" => {
+                    toc.push(TocEntry {
+                        title: String::new(),
+                        href: String::new(),
+                        depth: open.len() as u32,
+                    });
+                    open.push(toc.len() - 1);
+                }
+                b"text" if !open.is_empty() => {
+                    label.get_or_insert_with(String::new);
+                }
+                _ => {}
+            },
+            Ok(Event::Empty(ref e)) if local_name(e.name().as_ref()) == b"content" => {
+                if let Some(&i) = open.last()
+                    && let Some(src) = e.attributes().flatten().find_map(|attr| {
+                        (attr.key.as_ref() == b"src")
+                            .then(|| attr.unescape_value().ok().map(|v| v.into_owned()))
+                            .flatten()
+                    })
+                {
+                    toc[i].href = book_href(path, &src);
+                }
+            }
+            Ok(Event::Text(ref e)) => {
+                if let Some(text) = &mut label
+                    && let Ok(t) = e.unescape()
+                {
+                    text.push_str(&t);
+                }
+            }
+            Ok(Event::End(ref e)) => match local_name(e.name().as_ref()) {
+                b"text" => {
+                    if let (Some(text), Some(&i)) = (label.take(), open.last())
+                        && toc[i].title.is_empty()
+                    {
+                        toc[i].title = text.trim().to_string();
+                    }
+                }
+                b"navPoint" => {
+                    open.pop();
+                }
+                _ => {}
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    toc.retain(|entry| !entry.href.is_empty());
+    toc
+}
+
+/// Write the EPUB archive, with the `mimetype` entry stored first as the
+/// specification requires.
+fn write_epub(dest: &Path, files: &[(String, Vec<u8>)]) -> crate::Result<()> {
+    let mut writer = ZipWriter::new(File::create(dest)?);
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    writer.start_file("mimetype", stored)?;
+    writer.write_all(b"application/epub+zip")?;
+    writer.start_file("META-INF/container.xml", SimpleFileOptions::default())?;
+    writer.write_all(CONTAINER.as_bytes())?;
+    for (name, data) in files {
+        writer.start_file(format!("{ROOT}/{name}"), SimpleFileOptions::default())?;
+        writer.write_all(data)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// The package document, before metadata is added: the manifest, spine
+/// and guide.
+fn package(content: &BookContent, uuid: &str) -> String {
+    let mut manifest = String::from(
+        "    <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n    \
+         <item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\"/>\n",
+    );
+    let mut spine = String::new();
+    for file in &content.documents {
+        let id = manifest_id(&file.href);
+        manifest.push_str(&item(&id, &file.href, &file.media_type, None));
+        spine.push_str(&format!(
+            "    <itemref idref=\"{}\"/>\n",
+            html::escape(&id, true)
+        ));
+    }
+    for file in &content.resources {
+        let cover = (content.cover.as_ref() == Some(&file.href)).then_some("cover-image");
+        manifest.push_str(&item(
+            &manifest_id(&file.href),
+            &file.href,
+            &file.media_type,
+            cover,
+        ));
+    }
+
+    let guide = if content.guide.is_empty() {
+        String::new()
+    } else {
+        let references: String = content
+            .guide
+            .iter()
+            .map(|reference| {
+                format!(
+                    "    <reference type=\"{}\" title=\"{}\" href=\"{}\"/>\n",
+                    html::escape(&reference.kind, true),
+                    html::escape(&reference.title, true),
+                    html::escape(&reference.href, true)
+                )
+            })
+            .collect();
+        format!("  <guide>\n{references}  </guide>\n")
+    };
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"uid\">\n  \
+         <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n    \
+         <dc:identifier id=\"uid\">urn:uuid:{uuid}</dc:identifier>\n  \
+         </metadata>\n  \
+         <manifest>\n{manifest}  </manifest>\n  \
+         <spine toc=\"ncx\">\n{spine}  </spine>\n\
+         {guide}\
+         </package>\n"
+    )
+}
+
+fn item(id: &str, href: &str, media_type: &str, properties: Option<&str>) -> String {
+    let properties = properties
+        .map(|p| format!(" properties=\"{p}\""))
+        .unwrap_or_default();
+    format!(
+        "    <item id=\"{}\" href=\"{}\" media-type=\"{}\"{properties}/>\n",
+        html::escape(id, true),
+        html::escape(href, true),
+        html::escape(media_type, true)
+    )
+}
+
+/// The manifest id of a file, from its path: `text/part0000.xhtml` becomes
+/// `text-part0000.xhtml`.
+fn manifest_id(href: &str) -> String {
+    href.replace('/', "-")
+}
+
+/// A UUID for a book that has none, derived from its content so that
+/// converting the same book twice gives the same identifier.
+fn book_uuid(content: &BookContent) -> String {
+    let hash = |seed: u64| {
+        let mut hasher = azw3::Fnv1a::default();
+        hasher.field(&seed.to_le_bytes());
+        for file in content.documents.iter().chain(&content.resources) {
+            hasher.field(file.href.as_bytes());
+            hasher.field(&file.data);
+        }
+        hasher.finish()
+    };
+    let (high, low) = (hash(0), hash(1));
+    // Version 4 and the RFC 4122 variant.
+    let high = (high & !0xF000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xFFFF,
+        high & 0xFFFF,
+        low >> 48,
+        low & 0xFFFF_FFFF_FFFF
+    )
+}
+
+/// The EPUB 3 navigation document, with the table of contents as nested
+/// lists.
+fn nav(toc: &[TocEntry], title: &str) -> String {
+    let mut list = String::new();
+    let mut depth = 0;
+    for (i, entry) in toc.iter().enumerate() {
+        // A level can't be skipped, so deeper entries nest one level at a
+        // time.
+        let level = entry.depth.min(if i == 0 { 0 } else { depth + 1 });
+        if i > 0 && level > depth {
+            list.push_str("\n<ol>\n");
+        } else if i > 0 {
+            list.push_str("</li>\n");
+            for _ in level..depth {
+                list.push_str("</ol>\n</li>\n");
+            }
+        }
+        depth = level;
+        list.push_str(&format!(
+            "<li><a href=\"{}\">{}</a>",
+            html::escape(&entry.href, true),
+            html::escape(&entry.title, false)
+        ));
+    }
+    if !toc.is_empty() {
+        list.push_str("</li>\n");
+        for _ in 0..depth {
+            list.push_str("</ol>\n</li>\n");
+        }
+    }
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+         <head>\n<title>{title}</title>\n</head>\n\
+         <body>\n\
+         <nav epub:type=\"toc\" id=\"toc\">\n\
+         <ol>\n{list}</ol>\n\
+         </nav>\n\
+         </body>\n\
+         </html>\n",
+        title = html::escape(title, false)
+    )
+}
+
+/// The NCX table of contents, for readers that predate EPUB 3.
+fn ncx(toc: &[TocEntry], title: &str, uuid: &str) -> String {
+    let mut points = String::new();
+    let mut depth = 0;
+    let mut max_depth = 0;
+    for (i, entry) in toc.iter().enumerate() {
+        let level = entry.depth.min(if i == 0 { 0 } else { depth + 1 });
+        if i > 0 && level <= depth {
+            for _ in level..=depth {
+                points.push_str("</navPoint>\n");
+            }
+        }
+        depth = level;
+        max_depth = max_depth.max(level + 1);
+        points.push_str(&format!(
+            "<navPoint id=\"navpoint-{n}\" playOrder=\"{n}\">\n\
+             <navLabel><text>{}</text></navLabel>\n\
+             <content src=\"{}\"/>\n",
+            html::escape(&entry.title, false),
+            html::escape(&entry.href, true),
+            n = i + 1
+        ));
+    }
+    if !toc.is_empty() {
+        for _ in 0..=depth {
+            points.push_str("</navPoint>\n");
+        }
+    }
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">\n\
+         <head>\n\
+         <meta name=\"dtb:uid\" content=\"urn:uuid:{uuid}\"/>\n\
+         <meta name=\"dtb:depth\" content=\"{max_depth}\"/>\n\
+         <meta name=\"dtb:totalPageCount\" content=\"0\"/>\n\
+         <meta name=\"dtb:maxPageNumber\" content=\"0\"/>\n\
+         </head>\n\
+         <docTitle><text>{title}</text></docTitle>\n\
+         <navMap>\n{points}</navMap>\n\
+         </ncx>\n",
+        title = html::escape(title, false)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mobi::exth::{self, Exth};
+    use crate::mobi::testing;
+    use crate::{CoverProvider, EpubBook};
+
+    fn toc(depths: &[u32]) -> Vec<TocEntry> {
+        depths
+            .iter()
+            .enumerate()
+            .map(|(i, &depth)| TocEntry {
+                title: format!("Entry {i}"),
+                href: format!("text/part{i:04}.xhtml"),
+                depth,
+            })
+            .collect()
+    }
+
+    /// The nesting depth of each `element` in `xml`, from 0, checking that
+    /// the document is well-formed.
+    fn depths(xml: &str, element: &[u8]) -> Vec<u32> {
+        let mut reader = Reader::from_str(xml);
+        let mut open = 0;
+        let mut depths = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) if local_name(e.name().as_ref()) == element => {
+                    depths.push(open);
+                    open += 1;
+                }
+                Event::End(e) if local_name(e.name().as_ref()) == element => open -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        assert_eq!(open, 0);
+        depths
+    }
+
+    #[test]
+    fn nav_and_ncx_nest_skipped_depths_one_level_at_a_time() {
+        let toc = toc(&[1, 0, 2, 3, 1, 3, 0]);
+        let expected = [0, 0, 1, 2, 1, 2, 0];
+        assert_eq!(depths(&nav(&toc, "Book"), b"li"), expected);
+
+        let ncx = ncx(&toc, "Book", "uuid");
+        assert_eq!(depths(&ncx, b"navPoint"), expected);
+        assert!(ncx.contains(r#"<meta name="dtb:depth" content="3"/>"#));
+    }
+
+    #[test]
+    fn nav_and_ncx_without_entries() {
+        assert!(depths(&nav(&[], "Book"), b"li").is_empty());
+        assert!(depths(&ncx(&[], "Book", "uuid"), b"navPoint").is_empty());
+    }
+
+    #[test]
+    fn to_epub_marks_the_cover() {
+        let mut exth = Exth::default();
+        exth.set(exth::AUTHOR, ["Jane Doe"]);
+        exth.set_u32(exth::COVER_OFFSET, 0);
+        let records = testing::mobi6(
+            "Book",
+            "<html><body><p>Text</p></body></html>",
+            &exth,
+            &[testing::JPEG],
+        );
+        let input = testing::write("to-epub.mobi", &records);
+        let output = testing::temp_path("to-epub.epub");
+        let result = to_epub(&input, &output);
+        let files = std::fs::read(&output).map(|data| {
+            let mut zip = ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+            let names: Vec<String> = zip.file_names().map(str::to_string).collect();
+            let mut opf = String::new();
+            zip.by_name("OEBPS/content.opf")
+                .unwrap()
+                .read_to_string(&mut opf)
+                .unwrap();
+            (names, opf)
+        });
+        let book = EpubBook::open(&output).and_then(|book| Ok((book.metadata()?, book.cover()?)));
+        std::fs::remove_file(&input).unwrap();
+        let _ = std::fs::remove_file(&output);
+        result.unwrap();
+
+        let (names, opf) = files.unwrap();
+        assert_eq!(names[0], "mimetype");
+        for name in ["OEBPS/nav.xhtml", "OEBPS/toc.ncx", "OEBPS/images/00001.jpg"] {
+            assert!(names.iter().any(|n| n == name), "{name} is missing");
+        }
+        assert!(opf.contains(
+            r#"<item id="images-00001.jpg" href="images/00001.jpg" media-type="image/jpeg" properties="cover-image"/>"#
+        ), "{opf}");
+        assert!(
+            opf.contains(r#"<meta name="cover" content="images-00001.jpg"/>"#),
+            "{opf}"
+        );
+
+        let (metadata, cover) = book.unwrap();
+        assert_eq!(metadata.authors, ["Jane Doe"]);
+        assert_eq!(cover.as_deref(), Some(testing::JPEG));
+    }
+}
@@ -0,0 +1,153 @@
+//! EXTH records, which carry the extended metadata of MOBI books.
+
+use super::header::TextEncoding;
+use super::pdb::u32_at;
+use crate::Metadata;
+
+pub(crate) const AUTHOR: u32 = 100;
+pub(crate) const PUBLISHER: u32 = 101;
+pub(crate) const DESCRIPTION: u32 = 103;
+pub(crate) const ISBN: u32 = 104;
+pub(crate) const SUBJECT: u32 = 105;
+pub(crate) const PUBLISHING_DATE: u32 = 106;
+pub(crate) const ASIN: u32 = 113;
+/// In joint MOBI files, the record holding the KF8 section's record 0.
+pub(crate) const KF8_BOUNDARY: u32 = 121;
+/// The number of resource records in a KF8 book.
+pub(crate) const RESOURCE_COUNT: u32 = 125;
+/// A `kindle:embed:` link to the cover image of a KF8 book.
+pub(crate) const KF8_COVER_URI: u32 = 129;
+pub(crate) const COVER_OFFSET: u32 = 201;
+pub(crate) const THUMBNAIL_OFFSET: u32 = 202;
+pub(crate) const HAS_FAKE_COVER: u32 = 203;
+/// The document type, such as `EBOK` for books or `PDOC` for personal
+/// documents.
+pub(crate) const DOCUMENT_TYPE: u32 = 501;
+pub(crate) const UPDATED_TITLE: u32 = 503;
+/// Newer Kindle books repeat the ASIN here.
+pub(crate) const ASIN_ALT: u32 = 504;
+pub(crate) const LANGUAGE: u32 = 524;
+
+/// A single EXTH record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExthRecord {
+    pub kind: u32,
+    pub data: Vec<u8>,
+}
+
+/// The EXTH records of a book, in file order.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Exth {
+    pub records: Vec<ExthRecord>,
+}
+
+impl Exth {
+    /// Parse the EXTH header that starts at `data`.
+    ///
+    /// Records that run past the end of the data are dropped with a warning.
+    pub fn parse(data: &[u8], warnings: &mut Vec<String>) -> Exth {
+        if !data.starts_with(b"EXTH") {
+            warnings.push("EXTH header is missing".into());
+            return Exth::default();
+        }
+        let count = u32_at(data, 8).unwrap_or_default();
+
+        let mut records = Vec::new();
+        let mut offset = 12;
+        for _ in 0..count {
+            let (Some(kind), Some(len)) = (u32_at(data, offset), u32_at(data, offset + 4)) else {
+                warnings.push("EXTH header is truncated".into());
+                break;
+            };
+            let len = len as usize;
+            let Some(value) = data.get(offset + 8..offset + len.max(8)) else {
+                warnings.push(format!("EXTH record {kind} is truncated"));
+                break;
+            };
+            records.push(ExthRecord {
+                kind,
+                data: value.to_vec(),
+            });
+            offset += len.max(8);
+        }
+        Exth { records }
+    }
+
+    /// The values of every record of a kind, decoded as text.
+    pub fn strings(&self, kind: u32, encoding: TextEncoding) -> Vec<String> {
+        self.records
+            .iter()
+            .filter(|r| r.kind == kind)
+            .map(|r| encoding.decode(&r.data).trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    /// The value of the first record of a kind, decoded as text.
+    pub fn string(&self, kind: u32, encoding: TextEncoding) -> Option<String> {
+        self.strings(kind, encoding).into_iter().next()
+    }
+
+    /// The value of the first record of a kind, read as a number.
+    pub fn u32(&self, kind: u32) -> Option<u32> {
+        let record = self.records.iter().find(|r| r.kind == kind)?;
+        u32_at(&record.data, 0)
+    }
+
+    /// Replace every record of a kind with one record for each value.
+    pub fn set<I>(&mut self, kind: u32, values: I)
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>>,
+    {
+        self.records.retain(|r| r.kind != kind);
+        self.records
+            .extend(values.into_iter().map(|data| ExthRecord {
+                kind,
+                data: data.into(),
+            }));
+    }
+
+    /// Replace the records of a kind with a single number.
+    pub fn set_u32(&mut self, kind: u32, value: u32) {
+        self.set(kind, [value.to_be_bytes()]);
+    }
+
+    /// Replace the records holding metadata with `metadata`'s values,
+    /// written in `encoding`. Records for missing fields are removed.
+    pub fn set_metadata(&mut self, metadata: &Metadata, encoding: TextEncoding) {
+        let encode = |values: &mut dyn Iterator<Item = &String>| {
+            values
+                .map(|value| encoding.encode(value))
+                .collect::<Vec<_>>()
+        };
+        self.set(UPDATED_TITLE, encode(&mut metadata.title.iter()));
+        self.set(AUTHOR, encode(&mut metadata.authors.iter()));
+        self.set(PUBLISHER, encode(&mut metadata.publisher.iter()));
+        self.set(DESCRIPTION, encode(&mut metadata.description.iter()));
+        self.set(ISBN, encode(&mut metadata.isbn.iter()));
+        self.set(SUBJECT, encode(&mut metadata.subjects.iter()));
+        let date = metadata.publication_date.as_ref().map(ToString::to_string);
+        self.set(PUBLISHING_DATE, encode(&mut date.iter()));
+        self.set(LANGUAGE, encode(&mut metadata.language.iter()));
+        let asin = encode(&mut metadata.identifiers.get("asin").into_iter());
+        self.set(ASIN, asin.clone());
+        self.set(ASIN_ALT, asin);
+    }
+
+    /// Write the EXTH header, padded to a multiple of four bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = 12 + self.records.iter().map(|r| 8 + r.data.len()).sum::<usize>();
+        let mut data = Vec::with_capacity(len + 3);
+        data.extend_from_slice(b"EXTH");
+        data.extend_from_slice(&(len as u32).to_be_bytes());
+        data.extend_from_slice(&(self.records.len() as u32).to_be_bytes());
+        for record in &self.records {
+            data.extend_from_slice(&record.kind.to_be_bytes());
+            data.extend_from_slice(&(8 + record.data.len() as u32).to_be_bytes());
+            data.extend_from_slice(&record.data);
+        }
+        data.resize(len.next_multiple_of(4), 0);
+        data
+    }
+}
@@ -0,0 +1,300 @@
+//! The PalmDOC and MOBI headers at the start of record 0.
+//!
+//! Offsets are from the start of the record: the 16-byte PalmDOC header
+//! comes first, followed by the MOBI header.
+
+use super::pdb::{u16_at, u32_at};
+use crate::Error;
+
+/// Start of the MOBI header within record 0.
+pub(crate) const MOBI_OFFSET: usize = 16;
+
+/// EXTH flag bit saying that an EXTH header follows the MOBI header.
+pub(crate) const HAS_EXTH: u32 = 0x40;
+
+/// Marks an unused record index.
+pub(crate) const NULL_INDEX: u32 = 0xFFFF_FFFF;
+
+/// How the text records are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    None,
+    PalmDoc,
+    HuffCdic,
+    Unknown(u16),
+}
+
+/// The character encoding of a book's text and metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TextEncoding {
+    Cp1252,
+    Utf8,
+}
+
+impl TextEncoding {
+    /// Decode text, replacing invalid sequences.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            TextEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            TextEncoding::Cp1252 => bytes.iter().map(|&b| cp1252_char(b)).collect(),
+        }
+    }
+
+    /// Encode text, writing `?` for characters cp1252 has no byte for.
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Cp1252 => text
+                .chars()
+                .map(|c| (0..=u8::MAX).find(|&b| cp1252_char(b) == c).unwrap_or(b'?'))
+                .collect(),
+        }
+    }
+}
+
+/// The fields of the PalmDOC and MOBI headers used when reading a book.
+#[derive(Debug, Clone)]
+pub(crate) struct MobiHeader {
+    pub compression: Compression,
+    /// The length of the uncompressed text.
+    pub text_length: u32,
+    /// The number of text records, which follow record 0.
+    pub text_records: u16,
+    /// The encryption type: 0 for none, 1 for the original Mobipocket
+    /// DRM and 2 for Kindle DRM.
+    pub encryption: u16,
+    pub encoding: TextEncoding,
+    /// The file version: 6 for MOBI and 8 for KF8 (AZW3).
+    pub version: u32,
+    /// The full title of the book.
+    pub full_name: Option<String>,
+    /// A Windows language identifier.
+    pub locale: u32,
+    /// The index of the first image record, which EXTH cover offsets are
+    /// relative to.
+    pub first_image: Option<u32>,
+    /// The index and count of the HUFF and CDIC records.
+    pub huffman_records: Option<(u32, u32)>,
+    /// Where the EXTH header starts within record 0, if there is one.
+    pub exth_offset: Option<usize>,
+    /// Flags for the trailing entries appended to each text record.
+    pub extra_data_flags: u16,
+    /// The first record of the table of contents index.
+    pub ncx_index: Option<u32>,
+    /// KF8 only: the records locating the book's parts, relative to the
+    /// KF8 record 0.
+    pub kf8_indices: Option<Kf8Indices>,
+}
+
+/// The records of a KF8 book that locate its parts, numbered from the KF8
+/// record 0.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Kf8Indices {
+    /// The FDST record, which splits the text into flows.
+    pub fdst: Option<u32>,
+    /// The index of skeletons, the outline of each XHTML part.
+    pub skeleton: u32,
+    /// The index of fragments, the content inserted into the skeletons.
+    pub fragment: u32,
+    /// The guide index.
+    pub guide: Option<u32>,
+}
+
+impl MobiHeader {
+    /// Parse the headers from record 0.
+    pub fn parse(record: &[u8]) -> crate::Result<MobiHeader> {
+        if record.get(MOBI_OFFSET..MOBI_OFFSET + 4) != Some(b"MOBI") {
+            return Err(Error::InvalidBook("record 0 has no MOBI header".into()));
+        }
+        let header_len = u32_at(record, MOBI_OFFSET + 4).unwrap_or_default() as usize;
+        // Fields past the declared header length belong to something else.
+        let header = &record[..record.len().min(MOBI_OFFSET + header_len)];
+        let field = |offset| u32_at(header, offset);
+
+        let encoding = match field(28) {
+            Some(65001) => TextEncoding::Utf8,
+            _ => TextEncoding::Cp1252,
+        };
+        let full_name = match (field(84), field(88)) {
+            (Some(offset), Some(len)) if len > 0 => offset
+                .checked_add(len)
+                .and_then(|end| record.get(offset as usize..end as usize))
+                .map(|name| encoding.decode(name)),
+            _ => None,
+        };
+        let exth_offset = field(128)
+            .is_some_and(|flags| flags & HAS_EXTH != 0)
+            .then_some(MOBI_OFFSET + header_len);
+
+        let compression = match u16_at(record, 0).unwrap_or_default() {
+            1 => Compression::None,
+            2 => Compression::PalmDoc,
+            17480 => Compression::HuffCdic,
+            other => Compression::Unknown(other),
+        };
+        let huffman_records = match (field(112), field(116)) {
+            (Some(first), Some(count)) if first != NULL_INDEX && count > 0 => Some((first, count)),
+            _ => None,
+        };
+        // Older headers are too short for the flags.
+        let extra_data_flags = if header_len >= 0xE4 {
+            u16_at(header, 0xF2).unwrap_or_default()
+        } else {
+            0
+        };
+
+        let index = |offset| field(offset).filter(|&i| i != NULL_INDEX);
+        let version = field(36).unwrap_or(1);
+        let kf8_indices = match (index(0xFC), index(0xF8)) {
+            (Some(skeleton), Some(fragment)) if version >= 8 => Some(Kf8Indices {
+                fdst: index(0xC0),
+                skeleton,
+                fragment,
+                guide: index(0x104),
+            }),
+            _ => None,
+        };
+
+        Ok(MobiHeader {
+            compression,
+            text_length: u32_at(record, 4).unwrap_or_default(),
+            text_records: u16_at(record, 8).unwrap_or_default(),
+            encryption: u16_at(record, 12).unwrap_or_default(),
+            encoding,
+            version,
+            full_name,
+            locale: field(92).unwrap_or_default(),
+            first_image: field(108).filter(|&i| i != NULL_INDEX),
+            huffman_records,
+            exth_offset,
+            extra_data_flags,
+            ncx_index: index(0xF4),
+            kf8_indices,
+        })
+    }
+
+    /// The book's language as an ISO 639-1 code, from its locale.
+    ///
+    /// Only the primary language is used; dialects are ignored.
+    pub fn language(&self) -> Option<&'static str> {
+        let primary = (self.locale & 0xFF) as u8;
+        LOCALES
+            .iter()
+            .find(|(id, _)| *id == primary)
+            .map(|(_, code)| *code)
+    }
+}
+
+/// The Windows primary language identifier for a language code such as
+/// `en` or `pt-BR`, or 0 if there is none.
+pub(crate) fn locale(language: &str) -> u32 {
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+    LOCALES
+        .iter()
+        .find(|(_, code)| code.eq_ignore_ascii_case(primary))
+        .map_or(0, |(id, _)| u32::from(*id))
+}
+
+/// Windows primary language identifiers and their ISO 639-1 codes.
+const LOCALES: &[(u8, &str)] = &[
+    (0x01, "ar"),
+    (0x02, "bg"),
+    (0x03, "ca"),
+    (0x04, "zh"),
+    (0x05, "cs"),
+    (0x06, "da"),
+    (0x07, "de"),
+    (0x08, "el"),
+    (0x09, "en"),
+    (0x0a, "es"),
+    (0x0b, "fi"),
+    (0x0c, "fr"),
+    (0x0d, "he"),
+    (0x0e, "hu"),
+    (0x0f, "is"),
+    (0x10, "it"),
+    (0x11, "ja"),
+    (0x12, "ko"),
+    (0x13, "nl"),
+    (0x14, "nb"),
+    (0x15, "pl"),
+    (0x16, "pt"),
+    (0x17, "rm"),
+    (0x18, "ro"),
+    (0x19, "ru"),
+    (0x1a, "hr"),
+    (0x1b, "sk"),
+    (0x1c, "sq"),
+    (0x1d, "sv"),
+    (0x1e, "th"),
+    (0x1f, "tr"),
+    (0x20, "ur"),
+    (0x21, "id"),
+    (0x22, "uk"),
+    (0x23, "be"),
+    (0x24, "sl"),
+    (0x25, "et"),
+    (0x26, "lv"),
+    (0x27, "lt"),
+    (0x29, "fa"),
+    (0x2a, "vi"),
+    (0x2b, "hy"),
+    (0x2c, "az"),
+    (0x2d, "eu"),
+    (0x2f, "mk"),
+    (0x36, "af"),
+    (0x37, "ka"),
+    (0x38, "fo"),
+    (0x39, "hi"),
+    (0x3e, "ms"),
+    (0x41, "sw"),
+    (0x45, "bn"),
+    (0x49, "ta"),
+    (0x4e, "mr"),
+];
+
+/// Decode a Windows-1252 byte. The five unassigned bytes map to the C1
+/// control characters, as in ISO 8859-1.
+fn cp1252_char(b: u8) -> char {
+    const HIGH: [char; 32] = [
+        '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž',
+        '\u{8f}', '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}',
+        'ž', 'Ÿ',
+    ];
+    match b {
+        0x80..=0x9F => HIGH[usize::from(b - 0x80)],
+        _ => char::from(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mobi::exth::Exth;
+    use crate::mobi::pdb::put_u32;
+    use crate::mobi::testing;
+
+    #[test]
+    fn full_name_past_the_end_of_the_record() {
+        let mut record = testing::record0(6, "Title", 0, 0, None, &Exth::default());
+        put_u32(&mut record, 84, 0xFFFF_FFF0);
+        put_u32(&mut record, 88, 0x20);
+        assert_eq!(MobiHeader::parse(&record).unwrap().full_name, None);
+
+        let near_end = record.len() as u32 - 2;
+        put_u32(&mut record, 84, near_end);
+        assert_eq!(MobiHeader::parse(&record).unwrap().full_name, None);
+    }
+
+    #[test]
+    fn parses_record0() {
+        let record = testing::record0(8, "Title", 10, 1, Some(2), &Exth::default());
+        let header = MobiHeader::parse(&record).unwrap();
+        assert_eq!(header.full_name.as_deref(), Some("Title"));
+        assert_eq!(header.version, 8);
+        assert_eq!(header.first_image, Some(2));
+        assert_eq!(header.language(), Some("en"));
+        assert!(header.exth_offset.is_some());
+        assert!(header.kf8_indices.is_none());
+    }
+}
@@ -0,0 +1,242 @@
+//! HUFF/CDIC compression, used by some Kindle books: text is a stream of
+//! Huffman codes indexing a dictionary of phrases, and phrases may be
+//! compressed the same way.
+
+use super::pdb::{u16_at, u32_at};
+use crate::Error;
+
+/// A dictionary phrase, expanded the first time it is used.
+#[derive(Debug, Clone)]
+enum Phrase {
+    Compressed(Vec<u8>),
+    /// Being expanded; meeting it again means the dictionary refers to
+    /// itself.
+    Expanding,
+    Expanded(Vec<u8>),
+}
+
+/// Decompresses text records with the code tables of a HUFF record and the
+/// phrases of the CDIC records that follow it.
+#[derive(Debug, Clone)]
+pub(crate) struct HuffCdicReader {
+    /// Code length, whether the length is final, and the largest code,
+    /// indexed by the top byte of a code.
+    lookup: Vec<(u32, bool, u64)>,
+    /// The smallest and largest codes of each length, left-aligned.
+    min_codes: [u64; 33],
+    max_codes: [u64; 33],
+    phrases: Vec<Phrase>,
+}
+
+impl HuffCdicReader {
+    /// Load the HUFF record and its CDIC records.
+    pub fn new(huff: &[u8], cdics: &[Vec<u8>]) -> crate::Result<HuffCdicReader> {
+        if !huff.starts_with(b"HUFF\x00\x00\x00\x18") {
+            return Err(invalid("HUFF record has a bad header"));
+        }
+        let (Some(lookup_offset), Some(limits_offset)) = (u32_at(huff, 8), u32_at(huff, 12)) else {
+            return Err(invalid("HUFF record is truncated"));
+        };
+
+        let mut lookup = Vec::with_capacity(256);
+        for i in 0..256 {
+            let v = u32_at(huff, lookup_offset as usize + i * 4)
+                .ok_or_else(|| invalid("HUFF record is truncated"))?;
+            let len = v & 0x1F;
+            if len == 0 {
+                return Err(invalid("HUFF record has a zero code length"));
+            }
+            let max_code = ((u64::from(v >> 8) + 1) << (32 - len)) - 1;
+            lookup.push((len, v & 0x80 != 0, max_code));
+        }
+
+        let mut min_codes = [0; 33];
+        let mut max_codes = [u64::from(u32::MAX); 33];
+        for len in 1..33 {
+            let at = limits_offset as usize + (len - 1) * 8;
+            let (Some(min), Some(max)) = (u32_at(huff, at), u32_at(huff, at + 4)) else {
+                return Err(invalid("HUFF record is truncated"));
+            };
+            min_codes[len] = u64::from(min) << (32 - len);
+            max_codes[len] = ((u64::from(max) + 1) << (32 - len)) - 1;
+        }
+
+        let mut phrases = Vec::new();
+        for cdic in cdics {
+            load_cdic(cdic, &mut phrases)?;
+        }
+
+        Ok(HuffCdicReader {
+            lookup,
+            min_codes,
+            max_codes,
+            phrases,
+        })
+    }
+
+    /// Decompress a text record.
+    pub fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = Vec::with_capacity(data.len() * 3);
+        let mut padded = data.to_vec();
+        padded.extend_from_slice(&[0; 8]);
+        let window = |pos: usize| {
+            let bytes: [u8; 8] = padded[pos..pos + 8].try_into().unwrap_or_default();
+            u64::from_be_bytes(bytes)
+        };
+
+        let mut bits_left = data.len() as i64 * 8;
+        let mut pos = 0;
+        let mut x = window(pos);
+        let mut n: i32 = 32;
+        loop {
+            if n <= 0 {
+                pos += 4;
+                x = window(pos);
+                n += 32;
+            }
+            let code = (x >> n) & 0xFFFF_FFFF;
+            let (mut len, terminal, mut max_code) = self.lookup[(code >> 24) as usize];
+            if !terminal {
+                while code < self.min_codes[len as usize] {
+                    len += 1;
+                    if len > 32 {
+                        return Err(bad_data("code is longer than 32 bits"));
+                    }
+                }
+                max_code = self.max_codes[len as usize];
+            }
+            n -= len as i32;
+            bits_left -= i64::from(len);
+            if bits_left < 0 {
+                break;
+            }
+
+            let index = max_code
+                .checked_sub(code)
+                .map(|d| (d >> (32 - len)) as usize)
+                .filter(|&i| i < self.phrases.len())
+                .ok_or_else(|| bad_data("code has no dictionary phrase"))?;
+            match std::mem::replace(&mut self.phrases[index], Phrase::Expanding) {
+                Phrase::Expanded(phrase) => {
+                    out.extend_from_slice(&phrase);
+                    self.phrases[index] = Phrase::Expanded(phrase);
+                }
+                Phrase::Compressed(phrase) => {
+                    let phrase = self.decompress(&phrase)?;
+                    out.extend_from_slice(&phrase);
+                    self.phrases[index] = Phrase::Expanded(phrase);
+                }
+                Phrase::Expanding => return Err(bad_data("dictionary phrase refers to itself")),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Add the phrases of a CDIC record to the dictionary.
+fn load_cdic(cdic: &[u8], phrases: &mut Vec<Phrase>) -> crate::Result<()> {
+    if !cdic.starts_with(b"CDIC\x00\x00\x00\x10") {
+        return Err(invalid("CDIC record has a bad header"));
+    }
+    let (Some(total), Some(bits)) = (u32_at(cdic, 8), u32_at(cdic, 12)) else {
+        return Err(invalid("CDIC record is truncated"));
+    };
+    // Each record holds up to 2^bits phrases; the last holds the rest.
+    let count = (1usize << bits.min(16)).min((total as usize).saturating_sub(phrases.len()));
+
+    for i in 0..count {
+        let offset = u16_at(cdic, 16 + i * 2)
+            .map(|o| 16 + usize::from(o))
+            .ok_or_else(|| invalid("CDIC record is truncated"))?;
+        let len = u16_at(cdic, offset).ok_or_else(|| invalid("CDIC phrase is truncated"))?;
+        let phrase = cdic
+            .get(offset + 2..offset + 2 + usize::from(len & 0x7FFF))
+            .ok_or_else(|| invalid("CDIC phrase is truncated"))?
+            .to_vec();
+        // The high bit marks phrases stored as they are.
+        phrases.push(if len & 0x8000 != 0 {
+            Phrase::Expanded(phrase)
+        } else {
+            Phrase::Compressed(phrase)
+        });
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(bad_data(message))
+}
+
+fn bad_data(message: &str) -> String {
+    format!("bad HUFF/CDIC data: {message}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A HUFF record giving every phrase a terminal two-bit code: `11` is
+    /// phrase 0, `10` phrase 1, `01` phrase 2 and `00` phrase 3.
+    fn huff() -> Vec<u8> {
+        let mut huff = b"HUFF\x00\x00\x00\x18".to_vec();
+        huff.extend_from_slice(&24u32.to_be_bytes());
+        huff.extend_from_slice(&(24 + 256 * 4u32).to_be_bytes());
+        // The little-endian copies of the tables, which aren't read.
+        huff.resize(24, 0);
+        for _ in 0..256 {
+            huff.extend_from_slice(&(3 << 8 | 0x80 | 2u32).to_be_bytes());
+        }
+        huff.resize(huff.len() + 32 * 8, 0);
+        huff
+    }
+
+    /// A CDIC record of phrases, each stored as it is or compressed.
+    fn cdic(phrases: &[(&[u8], bool)]) -> Vec<u8> {
+        let mut cdic = b"CDIC\x00\x00\x00\x10".to_vec();
+        cdic.extend_from_slice(&(phrases.len() as u32).to_be_bytes());
+        cdic.extend_from_slice(&2u32.to_be_bytes());
+        let mut data = Vec::new();
+        for (phrase, raw) in phrases {
+            let offset = phrases.len() * 2 + data.len();
+            cdic.extend_from_slice(&(offset as u16).to_be_bytes());
+            let flag = if *raw { 0x8000 } else { 0 };
+            data.extend_from_slice(&(phrase.len() as u16 | flag).to_be_bytes());
+            data.extend_from_slice(phrase);
+        }
+        cdic.extend_from_slice(&data);
+        cdic
+    }
+
+    #[test]
+    fn decodes_nested_phrases() {
+        // Phrase 3 is itself compressed: 11 10 01 10.
+        let cdic = cdic(&[
+            (b"Hello", true),
+            (b" ", true),
+            (b"world", true),
+            (&[0b1110_0110], false),
+        ]);
+        let mut reader = HuffCdicReader::new(&huff(), &[cdic]).unwrap();
+        // 00 11 10 01: phrase 3, then phrases 0, 1 and 2.
+        assert_eq!(
+            reader.decompress(&[0b0011_1001]).unwrap(),
+            b"Hello world Hello world"
+        );
+        // The expanded phrase is reused.
+        assert_eq!(reader.decompress(&[0b0000_0000]).unwrap().len(), 48);
+    }
+
+    #[test]
+    fn rejects_self_reference() {
+        let cdic = cdic(&[(b"a", true), (b"b", true), (b"c", true), (&[0], false)]);
+        let mut reader = HuffCdicReader::new(&huff(), &[cdic]).unwrap();
+        let err = reader.decompress(&[0b0000_0000]).unwrap_err();
+        assert!(err.contains("refers to itself"), "{err}");
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(HuffCdicReader::new(b"HUFF", &[]).is_err());
+        assert!(HuffCdicReader::new(&huff(), &[b"CDIC".to_vec()]).is_err());
+    }
+}
@@ -0,0 +1,419 @@
+//! INDX records: the lookup tables KF8 books use for their skeletons,
+//! fragments, table of contents and guide.
+//!
+//! An index is a header record followed by the records holding its entries
+//! and then CNCX records holding the strings entries refer to. The header
+//! has a TAGX section describing which tags an entry may carry; each entry
+//! is an identifier, control bytes saying which tags are present, and the
+//! tag values as forward variable-length integers.
+//!
+//! Indices are written the same way, with all tags sharing one control
+//! byte.
+
+use std::collections::HashMap;
+
+use super::pdb::{put_u32, u16_at, u32_at};
+use crate::Error;
+
+/// One entry of an index.
+#[derive(Debug, Clone)]
+pub(crate) struct IndexEntry {
+    /// The entry's identifier, such as `SKEL0000000000` or a guide type.
+    pub ident: Vec<u8>,
+    tags: Vec<(u8, Vec<u32>)>,
+}
+
+impl IndexEntry {
+    /// An entry with an identifier and the values of each of its tags.
+    pub fn new(ident: impl Into<Vec<u8>>, tags: Vec<(u8, Vec<u32>)>) -> IndexEntry {
+        IndexEntry {
+            ident: ident.into(),
+            tags,
+        }
+    }
+
+    /// The values of `tag`, if the entry has it.
+    pub fn tag(&self, tag: u8) -> Option<&[u32]> {
+        self.tags
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, values)| values.as_slice())
+    }
+
+    /// The value at `index` of `tag`.
+    pub fn value(&self, tag: u8, index: usize) -> Option<u32> {
+        self.tag(tag)?.get(index).copied()
+    }
+}
+
+/// A parsed index and its strings.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Index {
+    pub entries: Vec<IndexEntry>,
+    /// CNCX strings by offset; each record adds 0x10000 to the offsets of
+    /// the strings in it.
+    strings: HashMap<u32, String>,
+}
+
+impl Index {
+    /// Read the index whose header is at record `first`, using `read` to
+    /// load records.
+    pub fn read(
+        first: usize,
+        read: &mut dyn FnMut(usize) -> crate::Result<Vec<u8>>,
+    ) -> crate::Result<Index> {
+        let header = read(first)?;
+        let fields = IndxHeader::parse(&header)?;
+        let tagx_offset = match u32_at(&header, 180) {
+            Some(offset) if offset != 0 => offset as usize,
+            _ => fields.len,
+        };
+        let tagx = TagTable::parse(header.get(tagx_offset..).unwrap_or_default())?;
+
+        let mut entries = Vec::new();
+        for index in first + 1..=first + fields.count {
+            let record = read(index)?;
+            let header = IndxHeader::parse(&record)?;
+            let offsets = (0..header.count)
+                .map(|i| u16_at(&record, header.idxt + 4 + i * 2).map(usize::from))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| invalid("IDXT is truncated"))?;
+            for (i, &start) in offsets.iter().enumerate() {
+                let end = offsets.get(i + 1).copied().unwrap_or(header.idxt);
+                let entry = record
+                    .get(start..end)
+                    .ok_or_else(|| invalid("index entry is out of bounds"))?;
+                entries.push(tagx.parse_entry(entry)?);
+            }
+        }
+
+        let mut strings = HashMap::new();
+        let cncx_first = first + fields.count + 1;
+        for (i, index) in (cncx_first..cncx_first + fields.cncx_records).enumerate() {
+            let record = read(index)?;
+            let mut pos = 0;
+            while pos < record.len() {
+                let Some((len, used)) = forward_varint(&record[pos..]) else {
+                    break;
+                };
+                let Some(text) = record.get(pos + used..pos + used + len as usize) else {
+                    break;
+                };
+                let key = ((i as u32) << 16) | pos as u32;
+                strings.insert(key, String::from_utf8_lossy(text).into_owned());
+                pos += used + len as usize;
+            }
+        }
+
+        Ok(Index { entries, strings })
+    }
+
+    /// The CNCX string at `offset`.
+    pub fn string(&self, offset: u32) -> Option<&str> {
+        self.strings.get(&offset).map(String::as_str)
+    }
+}
+
+/// Length of the INDX header written at the start of each index record.
+const INDX_HEADER_LEN: usize = 192;
+
+/// The most an index or CNCX record may hold, as entries are located by
+/// 16-bit offsets.
+const MAX_RECORD_LEN: usize = 0xFFF0;
+
+/// The strings of an index being written, split into CNCX records.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Strings {
+    records: Vec<Vec<u8>>,
+}
+
+impl Strings {
+    /// Add a string, returning the offset that entries refer to it by.
+    pub fn add(&mut self, text: &str) -> u32 {
+        let mut encoded = write_forward_varint(text.len() as u32);
+        encoded.extend_from_slice(text.as_bytes());
+        if self
+            .records
+            .last()
+            .is_none_or(|record| record.len() + encoded.len() > MAX_RECORD_LEN)
+        {
+            self.records.push(Vec::new());
+        }
+        let i = self.records.len() - 1;
+        let record = &mut self.records[i];
+        let offset = ((i as u32) << 16) | record.len() as u32;
+        record.extend_from_slice(&encoded);
+        offset
+    }
+}
+
+/// Write an index: its header record, the records holding `entries` and
+/// the CNCX records holding `strings`.
+///
+/// `tags` lists the tags entries may carry as (tag, values per entry,
+/// control byte mask), and entries must be sorted by identifier.
+pub(crate) fn write_index(
+    tags: &[(u8, u8, u8)],
+    entries: &[IndexEntry],
+    strings: Strings,
+) -> Vec<Vec<u8>> {
+    // Entry records, with the last identifier and entry count of each.
+    let mut records = Vec::new();
+    let mut geometry: Vec<(&[u8], usize)> = Vec::new();
+    let mut body = Vec::new();
+    let mut offsets = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let encoded = encode_entry(tags, entry);
+        let len = INDX_HEADER_LEN + body.len() + encoded.len() + 8 + 2 * (offsets.len() + 1);
+        if !offsets.is_empty() && len > MAX_RECORD_LEN {
+            records.push(index_record(&body, &offsets, offsets.len()));
+            geometry.push((&entries[i - 1].ident, offsets.len()));
+            body.clear();
+            offsets.clear();
+        }
+        offsets.push(INDX_HEADER_LEN + body.len());
+        body.extend_from_slice(&encoded);
+    }
+    if let Some(last) = entries.last() {
+        records.push(index_record(&body, &offsets, offsets.len()));
+        geometry.push((&last.ident, offsets.len()));
+    }
+
+    let mut tagx = Vec::with_capacity(16 + tags.len() * 4);
+    tagx.extend_from_slice(b"TAGX");
+    tagx.extend_from_slice(&(12 + 4 * (tags.len() as u32 + 1)).to_be_bytes());
+    tagx.extend_from_slice(&1u32.to_be_bytes());
+    for &(tag, values_per_entry, mask) in tags {
+        tagx.extend_from_slice(&[tag, values_per_entry, mask, 0]);
+    }
+    tagx.extend_from_slice(&[0, 0, 0, 1]);
+
+    // The header lists the last identifier of each entry record, so that
+    // readers can find an entry without reading every record.
+    let mut body = tagx;
+    let mut offsets = Vec::with_capacity(geometry.len());
+    for (ident, count) in geometry {
+        offsets.push(INDX_HEADER_LEN + body.len());
+        body.push(ident.len() as u8);
+        body.extend_from_slice(ident);
+        body.extend_from_slice(&(count as u16).to_be_bytes());
+    }
+    let mut header = index_record(&body, &offsets, records.len());
+    put_u32(&mut header, 28, 65001);
+    put_u32(&mut header, 32, 0xFFFF_FFFF);
+    put_u32(&mut header, 36, entries.len() as u32);
+    put_u32(&mut header, 52, strings.records.len() as u32);
+    put_u32(&mut header, 180, INDX_HEADER_LEN as u32);
+
+    let mut out = vec![header];
+    out.extend(records);
+    out.extend(strings.records);
+    out
+}
+
+/// An index record: the INDX header, `body`, and the IDXT table of the
+/// offsets of the items in the body.
+fn index_record(body: &[u8], offsets: &[usize], count: usize) -> Vec<u8> {
+    let mut record = vec![0u8; INDX_HEADER_LEN];
+    record[..4].copy_from_slice(b"INDX");
+    put_u32(&mut record, 4, INDX_HEADER_LEN as u32);
+    record.extend_from_slice(body);
+    record.resize(record.len().next_multiple_of(4), 0);
+    let idxt = record.len();
+    put_u32(&mut record, 20, idxt as u32);
+    put_u32(&mut record, 24, count as u32);
+    record.extend_from_slice(b"IDXT");
+    for &offset in offsets {
+        record.extend_from_slice(&(offset as u16).to_be_bytes());
+    }
+    record.resize(record.len().next_multiple_of(4), 0);
+    record
+}
+
+/// Encode an entry: its length-prefixed identifier, the control byte and
+/// the tag values.
+fn encode_entry(tags: &[(u8, u8, u8)], entry: &IndexEntry) -> Vec<u8> {
+    let mut data = vec![entry.ident.len() as u8];
+    data.extend_from_slice(&entry.ident);
+    let mut control = 0u8;
+    let mut values = Vec::new();
+    for &(tag, values_per_entry, mask) in tags {
+        let Some(tag_values) = entry.tag(tag).filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        let count = tag_values.len() / usize::from(values_per_entry.max(1));
+        control |= ((count as u8) << mask.trailing_zeros()) & mask;
+        for &value in tag_values {
+            values.extend_from_slice(&write_forward_varint(value));
+        }
+    }
+    data.push(control);
+    data.extend_from_slice(&values);
+    data
+}
+
+/// The fields of an INDX header used when reading an index.
+struct IndxHeader {
+    /// Length of the header.
+    len: usize,
+    /// Where the IDXT table of entry offsets starts.
+    idxt: usize,
+    /// In the first record the number of entry records, in the others the
+    /// number of entries.
+    count: usize,
+    cncx_records: usize,
+}
+
+impl IndxHeader {
+    fn parse(record: &[u8]) -> crate::Result<IndxHeader> {
+        if !record.starts_with(b"INDX") {
+            return Err(invalid("index record has no INDX header"));
+        }
+        let field = |offset| u32_at(record, offset).map(|v| v as usize);
+        match (field(4), field(20), field(24), field(52)) {
+            (Some(len), Some(idxt), Some(count), Some(cncx_records)) => Ok(IndxHeader {
+                len,
+                idxt,
+                count,
+                cncx_records,
+            }),
+            _ => Err(invalid("INDX header is truncated")),
+        }
+    }
+}
+
+/// A tag definition from the TAGX section.
+struct TagDef {
+    tag: u8,
+    values_per_entry: u8,
+    mask: u8,
+    /// Marks the end of the tags described by one control byte.
+    end_of_control_byte: bool,
+}
+
+/// The TAGX section of an index header.
+struct TagTable {
+    control_bytes: usize,
+    tags: Vec<TagDef>,
+}
+
+impl TagTable {
+    fn parse(data: &[u8]) -> crate::Result<TagTable> {
+        if !data.starts_with(b"TAGX") {
+            return Err(invalid("index has no TAGX section"));
+        }
+        let (Some(len), Some(control_bytes)) = (u32_at(data, 4), u32_at(data, 8)) else {
+            return Err(invalid("TAGX section is truncated"));
+        };
+        let tags = data
+            .get(12..len as usize)
+            .ok_or_else(|| invalid("TAGX section is truncated"))?
+            .chunks_exact(4)
+            .map(|def| TagDef {
+                tag: def[0],
+                values_per_entry: def[1],
+                mask: def[2],
+                end_of_control_byte: def[3] & 1 != 0,
+            })
+            .collect();
+        Ok(TagTable {
+            control_bytes: control_bytes as usize,
+            tags,
+        })
+    }
+
+    /// Parse an entry: a length-prefixed identifier, the control bytes and
+    /// the tag values.
+    fn parse_entry(&self, entry: &[u8]) -> crate::Result<IndexEntry> {
+        let truncated = || invalid("index entry is truncated");
+        let ident_len = usize::from(*entry.first().ok_or_else(truncated)?);
+        let ident = entry.get(1..1 + ident_len).ok_or_else(truncated)?.to_vec();
+        let rest = &entry[1 + ident_len..];
+        let controls = rest.get(..self.control_bytes).ok_or_else(truncated)?;
+        let mut data = &rest[self.control_bytes..];
+
+        // Each present tag has either a count of values or, when all bits
+        // of a multi-bit mask are set, a byte length to read values from.
+        let mut present = Vec::new();
+        let mut control = 0;
+        for def in &self.tags {
+            if def.end_of_control_byte {
+                control += 1;
+                continue;
+            }
+            let value = controls.get(control).ok_or_else(truncated)? & def.mask;
+            if value == 0 {
+                continue;
+            }
+            let count = if value == def.mask && def.mask.count_ones() > 1 {
+                let (len, used) = forward_varint(data).ok_or_else(truncated)?;
+                data = &data[used..];
+                ValueCount::Bytes(len as usize)
+            } else {
+                ValueCount::Values(
+                    usize::from(value >> def.mask.trailing_zeros())
+                        * usize::from(def.values_per_entry),
+                )
+            };
+            present.push((def.tag, count));
+        }
+
+        let mut tags = Vec::with_capacity(present.len());
+        for (tag, count) in present {
+            let mut values = Vec::new();
+            match count {
+                ValueCount::Values(n) => {
+                    for _ in 0..n {
+                        let (value, used) = forward_varint(data).ok_or_else(truncated)?;
+                        data = &data[used..];
+                        values.push(value);
+                    }
+                }
+                ValueCount::Bytes(mut len) => {
+                    while len > 0 {
+                        let (value, used) = forward_varint(data).ok_or_else(truncated)?;
+                        data = &data[used..];
+                        values.push(value);
+                        len = len.saturating_sub(used);
+                    }
+                }
+            }
+            tags.push((tag, values));
+        }
+        Ok(IndexEntry { ident, tags })
+    }
+}
+
+enum ValueCount {
+    Values(usize),
+    Bytes(usize),
+}
+
+/// Read a forward variable-length integer: seven bits per byte, most
+/// significant first, with the high bit set on the last byte. Returns the
+/// value and the number of bytes used.
+pub(crate) fn forward_varint(data: &[u8]) -> Option<(u32, usize)> {
+    let mut value: u32 = 0;
+    for (i, &byte) in data.iter().take(5).enumerate() {
+        value = (value << 7) | u32::from(byte & 0x7F);
+        if byte & 0x80 != 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Write a forward variable-length integer, as read by [`forward_varint`].
+pub(crate) fn write_forward_varint(value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8 | 0x80];
+    let mut rest = value >> 7;
+    while rest != 0 {
+        bytes.insert(0, (rest & 0x7F) as u8);
+        rest >>= 7;
+    }
+    bytes
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(message.to_string())
+}
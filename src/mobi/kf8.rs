@@ -0,0 +1,629 @@
+//! KF8, the format of AZW3 books and of the second section of joint MOBI
+//! files.
+//!
+//! The FDST record splits KF8 text into flows: the first holds the XHTML
+//! parts and the others hold stylesheets and SVG images. Each part is
+//! stored as a skeleton, the markup around its content, followed by
+//! fragments to insert into the skeleton at positions given by the
+//! fragment index. Kindle tools rewrite links to other parts, to images and
+//! fonts, and to flows as `kindle:pos:fid:`, `kindle:embed:` and
+//! `kindle:flow:` URLs, which are resolved back into relative links here.
+
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+use regex_lite::{Captures, Regex};
+
+use super::content::{BookContent, ContentFile, GuideEntry, Resources, TocEntry};
+use super::header::MobiHeader;
+use super::index::Index;
+use super::pdb::u32_at;
+use crate::Error;
+
+/// `kindle:pos:fid:` links: a fragment number and an offset into it, both
+/// in base 32.
+static POS_FID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"kindle:pos:fid:([0-9A-Va-v]{4}):off:([0-9A-Va-v]{10})").unwrap());
+
+/// `kindle:embed:` links to resource records, numbered from 1 in base 32.
+static EMBED: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"kindle:embed:([0-9A-Va-v]{4})(?:\?mime=[^'"()\s]*)?"#).unwrap());
+
+/// `kindle:flow:` links to flows, numbered in base 32.
+static FLOW: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"kindle:flow:([0-9A-Va-v]{4})(?:\?mime=[^'"()\s]*)?"#).unwrap());
+
+/// Link targets within a part, in order of preference: `id` attributes,
+/// named anchors, and the `aid` attributes Kindle tools add to elements.
+static ANCHORS: LazyLock<[Regex; 3]> = LazyLock::new(|| {
+    [
+        Regex::new(r#"<[^>]*\s(?:id|ID)\s*=\s*['"]([^'"]*)['"]"#).unwrap(),
+        Regex::new(r#"<\s*a\s[^>]*?(?:name|NAME)\s*=\s*['"]([^'"]*)['"]"#).unwrap(),
+        Regex::new(r#"<[^>]*\s(?:aid|AID)\s*=\s*['"]([^'"]+)['"]"#).unwrap(),
+    ]
+});
+
+/// `aid` attributes, which are removed from the rebuilt parts unless a
+/// link targets them, in which case they become `aid-` prefixed ids.
+static AID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\s(?:aid|AID)\s*=\s*['"]([^'"]*)['"]"#).unwrap());
+
+/// The KF8 section of a book: its record 0 and the header there. Record
+/// numbers in the header are relative to this record.
+#[derive(Debug, Clone)]
+pub(crate) struct Kf8Section {
+    pub record: usize,
+    pub header: MobiHeader,
+}
+
+/// A fragment from the fragment index.
+struct Fragment {
+    /// Where the fragment goes, as a position in the rebuilt text.
+    insert_at: usize,
+    len: usize,
+}
+
+/// A rebuilt XHTML part.
+struct Part {
+    /// The position of the part in the rebuilt text.
+    start: usize,
+    data: Vec<u8>,
+    /// The end position and value of each anchor, by kind.
+    anchors: [Vec<(usize, String)>; 3],
+}
+
+/// Rebuild the files of a KF8 section from its decompressed text and the
+/// book's resources.
+pub(super) fn rebuild(
+    section: &Kf8Section,
+    text: &[u8],
+    resources: Resources,
+    read: &mut dyn FnMut(usize) -> crate::Result<Vec<u8>>,
+) -> crate::Result<BookContent> {
+    let base = section.record;
+    let indices = section
+        .header
+        .kf8_indices
+        .ok_or_else(|| invalid("KF8 header has no skeleton or fragment index"))?;
+    let mut content = BookContent {
+        warnings: resources.warnings.clone(),
+        ..BookContent::default()
+    };
+
+    let flows = match indices.fdst {
+        Some(fdst) => read_flows(&read(base + fdst as usize)?, text)?,
+        None => vec![text],
+    };
+
+    let fragments = Index::read(base + indices.fragment as usize, read)?
+        .entries
+        .iter()
+        .map(|entry| {
+            let insert_at = std::str::from_utf8(&entry.ident)
+                .ok()
+                .and_then(|ident| ident.parse().ok());
+            match (insert_at, entry.value(6, 1)) {
+                (Some(insert_at), Some(len)) => Ok(Fragment {
+                    insert_at,
+                    len: len as usize,
+                }),
+                _ => Err(invalid("fragment index entry is incomplete")),
+            }
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    let skeletons = Index::read(base + indices.skeleton as usize, read)?;
+    let parts = build_parts(&skeletons, &fragments, flows[0])?;
+
+    let flow_hrefs: Vec<Option<String>> = flows
+        .iter()
+        .enumerate()
+        .map(|(i, flow)| (i > 0).then(|| flow_href(i, flow)))
+        .collect();
+
+    // Resolve links to parts first, so that `aid` attributes used as
+    // targets are known before they are removed.
+    let mut targets = HashSet::new();
+    let mut target = |pos: usize| -> Option<String> {
+        let (index, anchor) = find_anchor(&parts, pos)?;
+        let href = part_href(index);
+        Some(match anchor {
+            Some((id, from_aid)) => {
+                if from_aid {
+                    targets.insert((index, id.clone()));
+                    format!("{href}#aid-{id}")
+                } else {
+                    format!("{href}#{id}")
+                }
+            }
+            None => href,
+        })
+    };
+    let fragment_pos = |fid: u32, offset: u32| {
+        fragments
+            .get(fid as usize)
+            .map(|f| f.insert_at + offset as usize)
+    };
+
+    let mut links = Vec::with_capacity(parts.len());
+    for part in &parts {
+        let mut part_links = Vec::new();
+        for caps in POS_FID.captures_iter(&String::from_utf8_lossy(&part.data)) {
+            let link = match (base32(&caps[1]), base32(&caps[2])) {
+                (Some(fid), Some(offset)) => fragment_pos(fid, offset).and_then(&mut target),
+                _ => None,
+            };
+            part_links.push(link);
+        }
+        links.push(part_links);
+    }
+
+    if let Some(ncx) = section.header.ncx_index {
+        let index = Index::read(base + ncx as usize, read)?;
+        for entry in &index.entries {
+            let pos = match entry.tag(6) {
+                Some(&[fid, offset, ..]) => fragment_pos(fid, offset),
+                _ => entry.value(1, 0).map(|pos| pos as usize),
+            };
+            let title = entry.value(3, 0).and_then(|offset| index.string(offset));
+            if let (Some(title), Some(href)) = (title, pos.and_then(&mut target)) {
+                content.toc.push(TocEntry {
+                    title: title.to_string(),
+                    href: format!("text/{href}"),
+                    depth: entry.value(4, 0).unwrap_or_default(),
+                });
+            }
+        }
+    }
+
+    if let Some(guide) = indices.guide {
+        let index = Index::read(base + guide as usize, read)?;
+        for entry in &index.entries {
+            let pos = match (entry.tag(6), entry.value(3, 0)) {
+                (Some(&[fid, offset, ..]), _) => fragment_pos(fid, offset),
+                (_, Some(fid)) => fragment_pos(fid, 0),
+                _ => None,
+            };
+            if let Some(href) = pos.and_then(&mut target) {
+                content.guide.push(GuideEntry {
+                    kind: String::from_utf8_lossy(&entry.ident).into_owned(),
+                    title: entry
+                        .value(1, 0)
+                        .and_then(|offset| index.string(offset))
+                        .unwrap_or_default()
+                        .to_string(),
+                    href: format!("text/{href}"),
+                });
+            }
+        }
+    }
+
+    let mut resolve = |text: &str, links: Option<Vec<Option<String>>>| {
+        let mut links = links.unwrap_or_default().into_iter();
+        let text = POS_FID.replace_all(text, |caps: &Captures| {
+            links
+                .next()
+                .flatten()
+                .unwrap_or_else(|| caps[0].to_string())
+        });
+        let text = EMBED.replace_all(&text, |caps: &Captures| {
+            match base32(&caps[1]).and_then(|n| resources.href(n as usize)) {
+                Some(href) => format!("../{href}"),
+                None => {
+                    content
+                        .warnings
+                        .push(format!("{} has no resource", &caps[0]));
+                    caps[0].to_string()
+                }
+            }
+        });
+        FLOW.replace_all(&text, |caps: &Captures| {
+            match base32(&caps[1]).and_then(|n| flow_hrefs.get(n as usize).cloned().flatten()) {
+                Some(href) => format!("../{href}"),
+                None => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+    };
+
+    for (i, flow) in flows.iter().enumerate().skip(1) {
+        let (href, media_type) = match &flow_hrefs[i] {
+            Some(href) if href.ends_with(".svg") => (href.clone(), "image/svg+xml"),
+            Some(href) => (href.clone(), "text/css"),
+            None => continue,
+        };
+        let mut data = String::from_utf8_lossy(flow).into_owned();
+        if media_type == "text/css" {
+            data = strip_cdata(&data).to_string();
+        }
+        content.resources.push(ContentFile {
+            href,
+            media_type: media_type.into(),
+            data: resolve(&data, None).into_bytes(),
+        });
+    }
+
+    for (i, (part, part_links)) in parts.iter().zip(links).enumerate() {
+        let text = resolve(&String::from_utf8_lossy(&part.data), Some(part_links));
+        let text = AID.replace_all(&text, |caps: &Captures| {
+            if targets.contains(&(i, caps[1].to_string())) {
+                format!(r#" id="aid-{}""#, &caps[1])
+            } else {
+                String::new()
+            }
+        });
+        content.documents.push(ContentFile {
+            href: format!("text/{}", part_href(i)),
+            media_type: "application/xhtml+xml".into(),
+            data: text.into_owned().into_bytes(),
+        });
+    }
+
+    content.resources.splice(0..0, resources.files);
+    Ok(content)
+}
+
+/// Split the text into flows at the boundaries listed in the FDST record.
+fn read_flows<'a>(fdst: &[u8], text: &'a [u8]) -> crate::Result<Vec<&'a [u8]>> {
+    if !fdst.starts_with(b"FDST") {
+        return Err(invalid("FDST record has a bad header"));
+    }
+    let count = u32_at(fdst, 8).unwrap_or_default() as usize;
+    // Each flow takes 8 bytes, so a corrupt count can't reserve more than
+    // the record holds.
+    let mut flows = Vec::with_capacity(count.min(fdst.len().saturating_sub(12) / 8));
+    for i in 0..count {
+        let (Some(start), Some(end)) = (u32_at(fdst, 12 + i * 8), u32_at(fdst, 16 + i * 8)) else {
+            return Err(invalid("FDST record is truncated"));
+        };
+        let flow = text
+            .get(start as usize..(end as usize).min(text.len()))
+            .ok_or_else(|| invalid("flow is outside the text"))?;
+        flows.push(flow);
+    }
+    if flows.is_empty() {
+        flows.push(text);
+    }
+    Ok(flows)
+}
+
+/// Rebuild the XHTML parts by inserting each skeleton's fragments into it.
+///
+/// A skeleton is followed in the text by its fragments, and each fragment's
+/// insert position counts the content already inserted before it.
+fn build_parts(skeletons: &Index, fragments: &[Fragment], text: &[u8]) -> crate::Result<Vec<Part>> {
+    let mut parts = Vec::with_capacity(skeletons.entries.len());
+    let mut next_fragment = fragments.iter();
+    for entry in &skeletons.entries {
+        let (Some(count), Some(start), Some(len)) =
+            (entry.value(1, 0), entry.value(6, 0), entry.value(6, 1))
+        else {
+            return Err(invalid("skeleton index entry is incomplete"));
+        };
+        let (start, len) = (start as usize, len as usize);
+        let mut data = text
+            .get(start..start + len)
+            .ok_or_else(|| invalid("skeleton is outside the text"))?
+            .to_vec();
+        let mut pos = start + len;
+        for _ in 0..count {
+            let fragment = next_fragment
+                .next()
+                .ok_or_else(|| invalid("skeleton refers to a missing fragment"))?;
+            let inserted = text
+                .get(pos..pos + fragment.len)
+                .ok_or_else(|| invalid("fragment is outside the text"))?;
+            let at = fragment
+                .insert_at
+                .checked_sub(start)
+                .filter(|&at| at <= data.len())
+                .ok_or_else(|| invalid("fragment is inserted outside its skeleton"))?;
+            data.splice(at..at, inserted.iter().copied());
+            pos += fragment.len;
+        }
+
+        let source = String::from_utf8_lossy(&data);
+        let anchors = ANCHORS.each_ref().map(|pattern| {
+            pattern
+                .captures_iter(&source)
+                .map(|caps| (caps.get(0).map_or(0, |m| m.end()), caps[1].to_string()))
+                .collect()
+        });
+        parts.push(Part {
+            start,
+            data,
+            anchors,
+        });
+    }
+    Ok(parts)
+}
+
+/// Find the part holding text position `pos` and the nearest anchor at or
+/// before it, with whether that anchor is an `aid` attribute.
+fn find_anchor(parts: &[Part], pos: usize) -> Option<(usize, Option<(String, bool)>)> {
+    let index = parts
+        .iter()
+        .position(|p| p.start <= pos && pos < p.start + p.data.len())?;
+    let data = &parts[index].data;
+    let mut end = pos - parts[index].start;
+    // A position inside or at the start of a tag counts the whole tag.
+    let next_close = data[end..].iter().position(|&b| b == b'>');
+    let next_open = data[end..].iter().position(|&b| b == b'<');
+    if let Some(close) = next_close
+        && (next_open == Some(0) || next_open.is_none_or(|open| close < open))
+    {
+        end += close + 1;
+    }
+    let anchor = parts[index]
+        .anchors
+        .iter()
+        .enumerate()
+        .find_map(|(kind, anchors)| {
+            anchors
+                .iter()
+                .rev()
+                .find(|(anchor_end, _)| *anchor_end <= end)
+                .map(|(_, id)| (id.clone(), kind == 2))
+        });
+    Some((index, anchor))
+}
+
+/// The file name of part `index`.
+fn part_href(index: usize) -> String {
+    format!("part{index:04}.xhtml")
+}
+
+/// Where flow `index` is stored: SVG images go with the images and
+/// everything else is a stylesheet.
+fn flow_href(index: usize, flow: &[u8]) -> String {
+    let text = String::from_utf8_lossy(flow);
+    if text.contains("<svg") || text.contains("<svg:svg") {
+        format!("images/flow{index:04}.svg")
+    } else {
+        format!("styles/flow{index:04}.css")
+    }
+}
+
+/// Remove the CDATA wrapper Kindle tools put around stylesheets moved out
+/// of a part.
+fn strip_cdata(css: &str) -> &str {
+    let trimmed = css.trim();
+    trimmed
+        .strip_prefix("<![CDATA[")
+        .and_then(|css| css.strip_suffix("]]>"))
+        .unwrap_or(css)
+}
+
+/// Parse a base-32 number as used in `kindle:` links.
+fn base32(digits: &str) -> Option<u32> {
+    u32::from_str_radix(digits, 32).ok()
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mobi::index::{write_index, IndexEntry, Strings};
+    use crate::mobi::{azw3, testing, MobiBook};
+    use crate::Metadata;
+
+    fn xhtml(body: &str) -> Vec<u8> {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>T</title>\
+             <link rel=\"stylesheet\" href=\"../styles/book.css\"/></head>\
+             <body>{body}</body></html>"
+        )
+        .into_bytes()
+    }
+
+    fn file(href: &str, media_type: &str, data: Vec<u8>) -> ContentFile {
+        ContentFile {
+            href: href.into(),
+            media_type: media_type.into(),
+            data,
+        }
+    }
+
+    /// Build an AZW3 book from `content`, then open it and rebuild its
+    /// content.
+    fn round_trip(name: &str, content: &BookContent) -> BookContent {
+        let metadata = Metadata {
+            title: Some("Book".into()),
+            ..Metadata::default()
+        };
+        let mut warnings = Vec::new();
+        let records = azw3::build(content, &metadata, &mut warnings).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let path = testing::write(name, &records);
+        let book = MobiBook::open(&path);
+        let rebuilt = book.and_then(|book| book.content());
+        std::fs::remove_file(&path).unwrap();
+        rebuilt.unwrap()
+    }
+
+    #[test]
+    fn rebuilds_parts_links_and_flows() {
+        let content = BookContent {
+            documents: vec![
+                file(
+                    "text/one.xhtml",
+                    "application/xhtml+xml",
+                    xhtml(r#"<p>One</p><p><a href="two.xhtml#second">next</a></p>"#),
+                ),
+                file(
+                    "text/two.xhtml",
+                    "application/xhtml+xml",
+                    xhtml(r#"<p>Two</p><p id="second"><img src="../images/a.jpg"/></p>"#),
+                ),
+            ],
+            resources: vec![
+                file("styles/book.css", "text/css", b"p { margin: 0 }".to_vec()),
+                file("images/a.jpg", "image/jpeg", testing::JPEG.to_vec()),
+            ],
+            toc: vec![
+                TocEntry {
+                    title: "One".into(),
+                    href: "text/one.xhtml".into(),
+                    depth: 0,
+                },
+                TocEntry {
+                    title: "Second".into(),
+                    href: "text/two.xhtml#second".into(),
+                    depth: 1,
+                },
+            ],
+            guide: vec![GuideEntry {
+                kind: "text".into(),
+                title: "Start".into(),
+                href: "text/one.xhtml".into(),
+            }],
+            ..BookContent::default()
+        };
+        let rebuilt = round_trip("kf8-round-trip.azw3", &content);
+
+        let parts: Vec<String> = rebuilt
+            .documents
+            .iter()
+            .map(|d| String::from_utf8(d.data.clone()).unwrap())
+            .collect();
+        assert_eq!(rebuilt.documents.len(), 2);
+        assert_eq!(rebuilt.documents[1].href, "text/part0001.xhtml");
+        assert!(parts[0].contains("<p>One</p>"), "{}", parts[0]);
+        assert!(
+            parts[0].contains(r#"href="part0001.xhtml#second""#),
+            "{}",
+            parts[0]
+        );
+        assert!(parts[1].contains(r#"<p id="second"><img src="../images/00001.jpg"/>"#));
+        assert!(parts[1].contains(r#"href="../styles/flow0001.css""#));
+        // The body `aid` the TOC targets becomes an id; the others are
+        // removed.
+        assert!(parts[0].contains(r#"<body id="aid-0000">"#), "{}", parts[0]);
+        assert!(!parts.iter().any(|part| part.contains("aid=")));
+
+        let css = rebuilt
+            .resources
+            .iter()
+            .find(|r| r.href == "styles/flow0001.css")
+            .unwrap();
+        assert_eq!(css.data, b"p { margin: 0 }");
+
+        let toc: Vec<(&str, &str, u32)> = rebuilt
+            .toc
+            .iter()
+            .map(|e| (e.title.as_str(), e.href.as_str(), e.depth))
+            .collect();
+        assert_eq!(
+            toc,
+            [
+                ("One", "text/part0000.xhtml#aid-0000", 0),
+                ("Second", "text/part0001.xhtml#second", 1),
+            ]
+        );
+        assert_eq!(rebuilt.guide.len(), 1);
+        assert_eq!(rebuilt.guide[0].kind, "text");
+        assert_eq!(rebuilt.guide[0].href, "text/part0000.xhtml#aid-0000");
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let mut strings = Strings::default();
+        let first = strings.add("First");
+        let second = strings.add("Second");
+        let entries = vec![
+            IndexEntry::new("0000", vec![(1, vec![10]), (3, vec![first])]),
+            IndexEntry::new(
+                "0001",
+                vec![(1, vec![20]), (3, vec![second]), (6, vec![1, 2])],
+            ),
+        ];
+        let records = write_index(&[(1, 1, 1), (3, 1, 2), (6, 2, 4)], &entries, strings);
+        let index = Index::read(0, &mut |i| Ok(records[i].clone())).unwrap();
+
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(index.entries[1].ident, b"0001");
+        assert_eq!(index.entries[0].value(1, 0), Some(10));
+        assert_eq!(index.entries[0].tag(6), None);
+        assert_eq!(index.entries[1].tag(6), Some(&[1, 2][..]));
+        let title = |entry: &IndexEntry| index.string(entry.value(3, 0).unwrap());
+        assert_eq!(title(&index.entries[0]), Some("First"));
+        assert_eq!(title(&index.entries[1]), Some("Second"));
+    }
+
+    /// One skeleton with a fragment inserted into its body.
+    fn parts() -> Vec<Part> {
+        let skeleton = b"<html><body></body></html>";
+        let fragment = br#"<p>x</p><p id="a">y</p>"#;
+        let text = [&skeleton[..], fragment].concat();
+        let mut skeletons = Index::default();
+        skeletons.entries.push(IndexEntry::new(
+            "SKEL0000000000",
+            vec![(1, vec![1]), (6, vec![0, skeleton.len() as u32])],
+        ));
+        let fragments = [Fragment {
+            insert_at: 12,
+            len: fragment.len(),
+        }];
+        build_parts(&skeletons, &fragments, &text).unwrap()
+    }
+
+    #[test]
+    fn build_parts_inserts_fragments() {
+        let parts = parts();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(
+            parts[0].data,
+            br#"<html><body><p>x</p><p id="a">y</p></body></html>"#
+        );
+        assert_eq!(parts[0].anchors[0].len(), 1);
+    }
+
+    #[test]
+    fn build_parts_rejects_fragments_outside_the_text() {
+        let mut skeletons = Index::default();
+        skeletons.entries.push(IndexEntry::new(
+            "SKEL0000000000",
+            vec![(1, vec![1]), (6, vec![0, 4])],
+        ));
+        let fragments = [Fragment {
+            insert_at: 2,
+            len: 100,
+        }];
+        assert!(build_parts(&skeletons, &fragments, b"<p></p>").is_err());
+    }
+
+    #[test]
+    fn find_anchor_counts_the_tag_around_a_position() {
+        let parts = parts();
+        let data = &parts[0].data;
+        let tag = data.windows(2).rposition(|w| w == b"<p").unwrap();
+        // Inside `<p id="a">` the anchor is the tag's own id.
+        assert_eq!(
+            find_anchor(&parts, tag + 3),
+            Some((0, Some(("a".to_string(), false))))
+        );
+        assert_eq!(
+            find_anchor(&parts, tag),
+            Some((0, Some(("a".to_string(), false))))
+        );
+        // Before it there is no anchor.
+        assert_eq!(find_anchor(&parts, tag - 1), Some((0, None)));
+        assert_eq!(find_anchor(&parts, data.len()), None);
+    }
+
+    #[test]
+    fn read_flows_with_a_corrupt_count() {
+        let mut fdst = b"FDST\0\0\0\x0c".to_vec();
+        fdst.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_flows(&fdst, b"text").is_err());
+
+        fdst[8..12].copy_from_slice(&2u32.to_be_bytes());
+        for bound in [0u32, 2, 2, 4] {
+            fdst.extend_from_slice(&bound.to_be_bytes());
+        }
+        assert_eq!(read_flows(&fdst, b"text").unwrap(), [&b"te"[..], b"xt"]);
+    }
+}
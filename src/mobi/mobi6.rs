@@ -0,0 +1,687 @@
+//! Rebuilding the text of MOBI 6 books as XHTML.
+//!
+//! MOBI 6 text is a single HTML stream in an old, loose dialect: pages are
+//! separated by `<mbp:pagebreak/>`, links point at byte positions in the
+//! stream with `filepos` attributes, and images name their record with
+//! `recindex`. The stream is split into documents at page breaks, an anchor
+//! is added at each linked position, and the markup is cleaned up into
+//! XHTML.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::content::{BookContent, ContentFile, GuideEntry, Resources, TocEntry};
+use super::header::TextEncoding;
+use super::index::Index;
+use crate::html::{self, Token};
+
+/// Elements kept with their name.
+const KEPT: &[&str] = &[
+    "a",
+    "abbr",
+    "b",
+    "blockquote",
+    "br",
+    "caption",
+    "cite",
+    "code",
+    "dd",
+    "del",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "s",
+    "samp",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+    "var",
+];
+
+/// Elements that XHTML no longer has, and what replaces them.
+const RENAMED: &[(&str, &str)] = &[
+    ("big", "span"),
+    ("center", "div"),
+    ("font", "span"),
+    ("strike", "s"),
+    ("tt", "code"),
+];
+
+/// Attributes kept on any element.
+const ATTRIBUTES: &[&str] = &["id", "class", "style", "title", "dir", "lang"];
+
+/// Elements that never have content.
+const VOID: &[&str] = &["br", "hr", "img"];
+
+/// Block-level elements, which close an open paragraph.
+const BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "ul",
+    "ol",
+    "dl",
+    "li",
+    "dt",
+    "dd",
+    "blockquote",
+    "pre",
+    "hr",
+    "table",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+];
+
+/// Prefix of the ids of anchors added at linked positions.
+const ANCHOR_PREFIX: &str = "filepos";
+
+/// Rebuild the documents of a MOBI 6 book from its decompressed text.
+///
+/// `title` is used for each document's `<title>`, and `ncx` is the book's
+/// table of contents index, if it has one.
+pub(super) fn rebuild(
+    raw: &[u8],
+    encoding: TextEncoding,
+    title: &str,
+    ncx: Option<&Index>,
+    resources: Resources,
+) -> BookContent {
+    let mut content = BookContent {
+        warnings: resources.warnings.clone(),
+        ..BookContent::default()
+    };
+
+    let toc_positions: Vec<_> = ncx
+        .map(|index| {
+            index
+                .entries
+                .iter()
+                .filter_map(|entry| {
+                    let pos = entry.value(1, 0)? as usize;
+                    let title = index.string(entry.value(3, 0)?)?;
+                    Some((
+                        pos,
+                        title.to_string(),
+                        entry.value(4, 0).unwrap_or_default(),
+                    ))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut positions = filepos_targets(raw);
+    positions.extend(toc_positions.iter().map(|(pos, _, _)| *pos));
+    let text = encoding.decode(&insert_anchors(raw, &positions));
+    let (parts, references) = split_parts(html::tokenize(&text));
+
+    // Where each anchor ended up.
+    let mut anchors = HashMap::new();
+    for (i, part) in parts.iter().enumerate() {
+        for token in part {
+            if let Some(pos) = anchor_position(token) {
+                anchors.insert(pos, i);
+            }
+        }
+    }
+    let link = |pos: usize| {
+        anchors
+            .get(&pos)
+            .map(|&part| format!("{}#{ANCHOR_PREFIX}{pos}", part_href(part)))
+    };
+
+    for (pos, title, depth) in toc_positions {
+        if let Some(href) = link(pos) {
+            content.toc.push(TocEntry {
+                title,
+                href: format!("text/{href}"),
+                depth,
+            });
+        }
+    }
+    for reference in references {
+        if let Some(href) = link(reference.pos) {
+            content.guide.push(GuideEntry {
+                kind: reference.kind,
+                title: reference.title,
+                href: format!("text/{href}"),
+            });
+        }
+    }
+
+    for (i, part) in parts.into_iter().enumerate() {
+        let body = write_xhtml(part, &link, &resources, &mut content.warnings);
+        content.documents.push(ContentFile {
+            href: format!("text/{}", part_href(i)),
+            media_type: "application/xhtml+xml".into(),
+            data: document(title, &body).into_bytes(),
+        });
+    }
+    content.resources = resources.files;
+    content
+}
+
+/// The positions that `filepos` attributes point to.
+fn filepos_targets(raw: &[u8]) -> BTreeSet<usize> {
+    const NEEDLE: &[u8] = b"filepos=";
+    let mut positions = BTreeSet::new();
+    let mut i = 0;
+    while let Some(found) = raw[i..].windows(NEEDLE.len()).position(|w| w == NEEDLE) {
+        let start = i + found + NEEDLE.len();
+        let value = &raw[start..];
+        let quotes = value
+            .iter()
+            .take_while(|&&b| b == b'"' || b == b'\'')
+            .count();
+        let digits = value[quotes..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        if let Some(pos) = std::str::from_utf8(&value[quotes..quotes + digits])
+            .ok()
+            .and_then(|d| d.parse().ok())
+        {
+            positions.insert(pos);
+        }
+        i = start + quotes + digits;
+    }
+    positions
+}
+
+/// Add an empty anchor at each position. Positions inside a tag move to
+/// just after it.
+fn insert_anchors(raw: &[u8], positions: &BTreeSet<usize>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len() + positions.len() * 32);
+    let mut copied = 0;
+    for &pos in positions.iter().filter(|&&pos| pos <= raw.len()) {
+        let before = &raw[..pos];
+        let in_tag = match before.iter().rposition(|&b| b == b'<') {
+            Some(open) => before
+                .iter()
+                .rposition(|&b| b == b'>')
+                .is_none_or(|close| close < open),
+            None => false,
+        };
+        let at = if in_tag {
+            raw[pos..]
+                .iter()
+                .position(|&b| b == b'>')
+                .map_or(raw.len(), |i| pos + i + 1)
+        } else {
+            pos
+        }
+        .max(copied);
+        out.extend_from_slice(&raw[copied..at]);
+        out.extend_from_slice(format!("<a id=\"{ANCHOR_PREFIX}{pos}\"></a>").as_bytes());
+        copied = at;
+    }
+    out.extend_from_slice(&raw[copied..]);
+    out
+}
+
+/// The position an added anchor marks, if `token` starts one.
+fn anchor_position(token: &Token) -> Option<usize> {
+    let Token::Start { name, attrs } = token else {
+        return None;
+    };
+    if name != "a" {
+        return None;
+    }
+    let (_, id) = attrs.iter().find(|(k, _)| k == "id")?;
+    id.strip_prefix(ANCHOR_PREFIX)?.parse().ok()
+}
+
+/// A guide reference from the head of the text.
+struct Reference {
+    kind: String,
+    title: String,
+    pos: usize,
+}
+
+/// Split the body into parts at page breaks, and collect the guide
+/// references from the head.
+fn split_parts(tokens: Vec<Token>) -> (Vec<Vec<Token>>, Vec<Reference>) {
+    let mut parts = vec![Vec::new()];
+    let mut references = Vec::new();
+    let mut in_body = !tokens
+        .iter()
+        .any(|t| matches!(t, Token::Start { name, .. } if name == "body"));
+
+    for token in tokens {
+        match &token {
+            Token::Start { name, attrs } if name == "reference" => {
+                let attr = |key: &str| {
+                    attrs
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .map(|(_, v)| v.trim().to_string())
+                };
+                if let (Some(kind), Some(pos)) =
+                    (attr("type"), attr("filepos").and_then(|p| p.parse().ok()))
+                {
+                    references.push(Reference {
+                        kind,
+                        title: attr("title").unwrap_or_default(),
+                        pos,
+                    });
+                }
+            }
+            Token::Start { name, .. } if name == "body" => in_body = true,
+            Token::End(name) if name == "body" => in_body = false,
+            Token::Start { name, .. } if name == "pagebreak" => {
+                // Anchors just before a break belong to the next page.
+                let current = parts.last_mut().expect("there is always a part");
+                let mut carried = Vec::new();
+                loop {
+                    match current.as_slice() {
+                        [.., Token::Text(text)] if text.trim().is_empty() => {}
+                        [.., start, Token::End(end)]
+                            if end == "a" && anchor_position(start).is_some() =>
+                        {
+                            carried.push(current.pop().expect("matched above"));
+                        }
+                        _ => break,
+                    }
+                    carried.push(current.pop().expect("matched above"));
+                }
+                carried.reverse();
+                if current.iter().all(is_blank) {
+                    current.extend(carried);
+                } else {
+                    parts.push(carried);
+                }
+            }
+            _ if in_body => parts
+                .last_mut()
+                .expect("there is always a part")
+                .push(token),
+            _ => {}
+        }
+    }
+
+    // A break at the very end leaves an empty part, whose anchors go to
+    // the previous one.
+    if parts.len() > 1 && parts.last().is_some_and(|p| p.iter().all(is_blank)) {
+        let last = parts.pop().unwrap_or_default();
+        parts.last_mut().expect("checked above").extend(last);
+    }
+    (parts, references)
+}
+
+/// Whether a token adds nothing visible to a page.
+fn is_blank(token: &Token) -> bool {
+    match token {
+        Token::Text(text) => text.trim().is_empty(),
+        Token::Start { .. } => anchor_position(token).is_some(),
+        Token::End(name) => name == "a",
+    }
+}
+
+/// Write a part's tokens as well-formed XHTML body content.
+///
+/// Obsolete elements are replaced, unknown ones dropped keeping their text,
+/// and presentational attributes turned into styles. `filepos` links and
+/// `recindex` images are pointed at the rebuilt files.
+fn write_xhtml(
+    tokens: Vec<Token>,
+    link: &dyn Fn(usize) -> Option<String>,
+    resources: &Resources,
+    warnings: &mut Vec<String>,
+) -> String {
+    let mut out = String::new();
+    let mut open: Vec<String> = Vec::new();
+
+    for token in tokens {
+        match token {
+            Token::Start { name, attrs } => {
+                let renamed = RENAMED.iter().find(|(old, _)| *old == name);
+                let element = match renamed {
+                    Some((_, new)) => *new,
+                    None if KEPT.contains(&name.as_str()) => name.as_str(),
+                    None => continue,
+                };
+
+                if BLOCKS.contains(&element) {
+                    // A block can't sit inside a paragraph or inline element.
+                    while let Some(top) = open.last()
+                        && !BLOCKS.contains(&top.as_str())
+                    {
+                        close(&mut out, &mut open);
+                    }
+                    if open.last().is_some_and(|top| top == "p") {
+                        close(&mut out, &mut open);
+                    }
+                }
+                if element == "li"
+                    && let Some(li) = open.iter().rposition(|t| t == "li")
+                    && !open[li..].iter().any(|t| t == "ul" || t == "ol")
+                {
+                    while open.len() > li {
+                        close(&mut out, &mut open);
+                    }
+                }
+
+                let mut kept = Vec::new();
+                let mut styles = Vec::new();
+                if name == "center" {
+                    styles.push("text-align: center".to_string());
+                }
+                for (key, value) in &attrs {
+                    match key.as_str() {
+                        "style" => styles.insert(0, value.trim().trim_end_matches(';').into()),
+                        key if ATTRIBUTES.contains(&key) => kept.push((key, value.clone())),
+                        "name" if element == "a" && !attrs.iter().any(|(k, _)| k == "id") => {
+                            kept.push(("id", value.clone()))
+                        }
+                        "href" if element == "a" && !value.trim().is_empty() => {
+                            kept.push(("href", value.trim().to_string()))
+                        }
+                        "filepos" if element == "a" => {
+                            match value.trim_matches(['"', '\'']).parse().ok().and_then(link) {
+                                Some(href) => kept.push(("href", href)),
+                                None => warnings.push(format!("link to missing position {value}")),
+                            }
+                        }
+                        "recindex" if element == "img" => {
+                            match value.parse().ok().and_then(|n| resources.href(n)) {
+                                Some(href) => kept.push(("src", format!("../{href}"))),
+                                None => warnings.push(format!("image record {value} is missing")),
+                            }
+                        }
+                        "alt" | "width" | "height" if element == "img" => {
+                            kept.push((key, value.clone()))
+                        }
+                        "align" if element != "img" => styles.push(format!("text-align: {value}")),
+                        // MOBI writers put paragraph indents and spacing in
+                        // these.
+                        "width" if element == "p" => {
+                            styles.push(format!("text-indent: {}", length(value)))
+                        }
+                        "height" if element == "p" => {
+                            styles.push(format!("margin-top: {}", length(value)))
+                        }
+                        "size" if name == "font" => {
+                            if let Some(size) = font_size(value) {
+                                styles.push(format!("font-size: {size}"));
+                            }
+                        }
+                        "color" if name == "font" => styles.push(format!("color: {value}")),
+                        _ => {}
+                    }
+                }
+                if name == "big" {
+                    styles.push("font-size: larger".to_string());
+                }
+                if element == "img" {
+                    if !kept.iter().any(|(k, _)| *k == "src") {
+                        continue;
+                    }
+                    if !kept.iter().any(|(k, _)| *k == "alt") {
+                        kept.push(("alt", String::new()));
+                    }
+                }
+                styles.retain(|s| !s.is_empty());
+                if !styles.is_empty() {
+                    kept.retain(|(k, _)| *k != "style");
+                    kept.push(("style", styles.join("; ")));
+                }
+
+                out.push('<');
+                out.push_str(element);
+                for (key, value) in kept {
+                    out.push(' ');
+                    out.push_str(key);
+                    out.push_str("=\"");
+                    out.push_str(&html::escape(&value, true));
+                    out.push('"');
+                }
+                if VOID.contains(&element) {
+                    out.push_str("/>");
+                } else {
+                    out.push('>');
+                    open.push(element.to_string());
+                }
+            }
+            Token::End(name) => {
+                let element = RENAMED
+                    .iter()
+                    .find(|(old, _)| *old == name)
+                    .map_or(name.as_str(), |(_, new)| new);
+                if let Some(i) = open.iter().rposition(|t| t == element) {
+                    while open.len() > i {
+                        close(&mut out, &mut open);
+                    }
+                }
+            }
+            Token::Text(text) => {
+                // Text can't sit directly in a list or table.
+                if !open.last().is_some_and(|top| {
+                    ["ul", "ol", "dl", "table", "tbody", "thead", "tfoot", "tr"]
+                        .contains(&top.as_str())
+                }) || text.trim().is_empty()
+                {
+                    out.push_str(&html::escape(&text, false));
+                }
+            }
+        }
+    }
+
+    while !open.is_empty() {
+        close(&mut out, &mut open);
+    }
+    out
+}
+
+fn close(out: &mut String, open: &mut Vec<String>) {
+    if let Some(name) = open.pop() {
+        out.push_str("</");
+        out.push_str(&name);
+        out.push('>');
+    }
+}
+
+/// A CSS length from a MOBI width or height, which may lack a unit.
+fn length(value: &str) -> String {
+    let value = value.trim();
+    if value.parse::<f64>().is_ok() {
+        format!("{value}px")
+    } else {
+        value.to_string()
+    }
+}
+
+/// The CSS font size for a `<font size>` from 1 to 7, or relative to 3
+/// with a sign.
+fn font_size(value: &str) -> Option<&'static str> {
+    const SIZES: [&str; 7] = [
+        "x-small",
+        "small",
+        "medium",
+        "large",
+        "x-large",
+        "xx-large",
+        "xxx-large",
+    ];
+    let value = value.trim();
+    let size: i32 = match value.strip_prefix('+') {
+        Some(rest) => 3 + rest.parse::<i32>().ok()?,
+        None if value.starts_with('-') => 3 + value.parse::<i32>().ok()?,
+        None => value.parse().ok()?,
+    };
+    Some(SIZES[(size.clamp(1, 7) - 1) as usize])
+}
+
+/// A complete XHTML document around a part's body content.
+fn document(title: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\">\n\
+         <head>\n<title>{}</title>\n</head>\n\
+         <body>\n{body}\n</body>\n\
+         </html>\n",
+        html::escape(title, false)
+    )
+}
+
+/// The file name of part `index`.
+fn part_href(index: usize) -> String {
+    format!("part{index:04}.xhtml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mobi::index::{write_index, IndexEntry, Strings};
+
+    #[test]
+    fn filepos_targets_with_and_without_quotes() {
+        let raw = br#"<a filepos=0000000120>a</a> <a href="" filepos="42">b</a> filepos=x"#;
+        assert_eq!(filepos_targets(raw), BTreeSet::from([42, 120]));
+    }
+
+    #[test]
+    fn insert_anchors_moves_positions_out_of_tags() {
+        let raw = br#"<p>ab</p><p class="x">cd</p>"#;
+        // Inside `<p>`, between `a` and `b`, inside `<p class="x">` and
+        // past the end of the text.
+        let positions = BTreeSet::from([2, 4, 14, 100]);
+        assert_eq!(
+            String::from_utf8(insert_anchors(raw, &positions)).unwrap(),
+            r#"<p><a id="filepos2"></a>a<a id="filepos4"></a>b</p><p class="x"><a id="filepos14"></a>cd</p>"#
+        );
+    }
+
+    #[test]
+    fn insert_anchors_keeps_anchors_in_the_same_tag_in_order() {
+        let positions = BTreeSet::from([1, 2]);
+        assert_eq!(
+            String::from_utf8(insert_anchors(b"<br/>x", &positions)).unwrap(),
+            r#"<br/><a id="filepos1"></a><a id="filepos2"></a>x"#
+        );
+    }
+
+    /// `text` with each `{name}` replaced by the zero-padded position of
+    /// `<p>name`.
+    fn with_positions(text: &str, names: &[&str]) -> (String, Vec<usize>) {
+        let mut text = text.to_string();
+        for name in names {
+            text = text.replace(&format!("{{{name}}}"), "0000000000");
+        }
+        let mut positions = Vec::new();
+        for name in names {
+            let pos = text.find(&format!("<p>{name}")).unwrap();
+            text = text.replacen("0000000000", &format!("{pos:010}"), 1);
+            positions.push(pos);
+        }
+        (text, positions)
+    }
+
+    #[test]
+    fn rebuild_links_toc_and_guide() {
+        let (raw, positions) = with_positions(
+            "<html><head><guide><reference type=\"toc\" title=\"Contents\" filepos={One} />\
+             </guide></head><body><p>One</p><p><a filepos={Two}>next</a></p>\
+             <mbp:pagebreak/><p>Two</p><p>Three</p></body></html>",
+            &["One", "Two"],
+        );
+        let three = raw.find("<p>Three").unwrap();
+
+        let mut labels = Strings::default();
+        let entries = vec![
+            IndexEntry::new(
+                "0000",
+                vec![
+                    (1, vec![positions[0] as u32]),
+                    (3, vec![labels.add("One")]),
+                    (4, vec![0]),
+                ],
+            ),
+            IndexEntry::new(
+                "0001",
+                vec![
+                    (1, vec![three as u32]),
+                    (3, vec![labels.add("Three")]),
+                    (4, vec![1]),
+                ],
+            ),
+        ];
+        let records = write_index(&[(1, 1, 1), (3, 1, 2), (4, 1, 4)], &entries, labels);
+        let ncx = Index::read(0, &mut |i| Ok(records[i].clone())).unwrap();
+
+        let content = rebuild(
+            raw.as_bytes(),
+            TextEncoding::Utf8,
+            "Book",
+            Some(&ncx),
+            Resources::default(),
+        );
+        assert!(content.warnings.is_empty(), "{:?}", content.warnings);
+        assert_eq!(content.documents.len(), 2);
+        let parts: Vec<String> = content
+            .documents
+            .iter()
+            .map(|d| String::from_utf8(d.data.clone()).unwrap())
+            .collect();
+        let two = positions[1];
+        assert!(
+            parts[0].contains(&format!(
+                r#"<a href="part0001.xhtml#filepos{two}">next</a>"#
+            )),
+            "{}",
+            parts[0]
+        );
+        assert!(parts[1].contains(&format!(r#"<a id="filepos{two}"></a>"#)));
+
+        let toc: Vec<(&str, String, u32)> = content
+            .toc
+            .iter()
+            .map(|e| (e.title.as_str(), e.href.clone(), e.depth))
+            .collect();
+        assert_eq!(
+            toc,
+            [
+                (
+                    "One",
+                    format!("text/part0000.xhtml#filepos{}", positions[0]),
+                    0
+                ),
+                ("Three", format!("text/part0001.xhtml#filepos{three}"), 1),
+            ]
+        );
+        assert_eq!(content.guide.len(), 1);
+        assert_eq!(content.guide[0].kind, "toc");
+        assert_eq!(content.guide[0].title, "Contents");
+        assert_eq!(content.guide[0].href, toc[0].1);
+    }
+}
@@ -0,0 +1,803 @@
+//! MOBI and AZW3 books.
+//!
+//! Both are Palm databases whose first record holds a PalmDOC header, a
+//! MOBI header and usually an EXTH header with the book's metadata. AZW3
+//! (KF8) books use a newer MOBI header version, and joint MOBI files carry
+//! a KF8 section after the MOBI one, starting at the record named by EXTH
+//! record 121.
+//!
+//! The text follows record 0 as a series of records, each compressed on its
+//! own with PalmDOC or HUFF/CDIC compression and followed by optional
+//! trailing entries.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    author_display_name, image, is_sort_form, normalize_language, BookReader, CoverProvider,
+    CoverWriter, DrmDetector, DrmScheme, DrmStatus, Error, Format, Metadata, MetadataProvider,
+    MetadataWriter, W3cDate,
+};
+
+mod azw3;
+mod content;
+mod convert;
+mod exth;
+mod header;
+mod huffcdic;
+mod index;
+mod kf8;
+mod mobi6;
+mod palmdoc;
+mod pdb;
+mod record0;
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use content::{BookContent, ContentFile, GuideEntry, TocEntry};
+pub use convert::{from_epub, to_epub};
+
+use content::{Resources, EOF_RECORD};
+
+use exth::Exth;
+use header::{Compression, MobiHeader, NULL_INDEX};
+use huffcdic::HuffCdicReader;
+use index::Index;
+use kf8::Kf8Section;
+use pdb::PalmDatabase;
+use record0::Record0;
+
+/// An image record referenced by the EXTH header.
+#[derive(Debug, Clone)]
+pub struct CoverInfo {
+    /// The index of the record holding the image.
+    pub record: usize,
+    /// Size of the image in bytes.
+    pub size: u64,
+}
+
+/// A parsed MOBI or AZW3 book.
+pub struct MobiBook {
+    path: PathBuf,
+    format: Format,
+    header: MobiHeader,
+    metadata: Metadata,
+    drm_status: DrmStatus,
+    cover_info: Option<CoverInfo>,
+    thumbnail_info: Option<CoverInfo>,
+    kf8: Option<Kf8Section>,
+    database: PalmDatabase,
+    warnings: Vec<String>,
+}
+
+impl MobiBook {
+    /// Open and parse a MOBI or AZW3 file at the given path.
+    pub fn open(path: &Path) -> crate::Result<Self> {
+        let file = File::open(path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Error::FileNotFound(path.into())
+            } else {
+                Error::Io(e)
+            }
+        })?;
+        let mut reader = BufReader::new(file);
+
+        let database = PalmDatabase::read(&mut reader)?;
+        let record0 = database.read_record(&mut reader, 0)?;
+        let header = MobiHeader::parse(&record0)?;
+
+        let mut warnings = Vec::new();
+        let exth = match header.exth_offset {
+            Some(offset) => Exth::parse(record0.get(offset..).unwrap_or_default(), &mut warnings),
+            None => Exth::default(),
+        };
+
+        let metadata = read_metadata(&database, &header, &exth);
+        let mut image = |kind| {
+            let offset = exth.u32(kind).filter(|&o| o != NULL_INDEX)?;
+            let Some(first) = header.first_image else {
+                warnings.push("book has a cover offset but no image records".into());
+                return None;
+            };
+            let Some(record) = first.checked_add(offset).map(|r| r as usize) else {
+                warnings.push(format!("cover image offset {offset} is out of range"));
+                return None;
+            };
+            match database.record_len(record) {
+                Some(size) => Some(CoverInfo { record, size }),
+                None => {
+                    warnings.push(format!("cover image record {record} does not exist"));
+                    None
+                }
+            }
+        };
+        let cover_info = image(exth::COVER_OFFSET);
+        let thumbnail_info = image(exth::THUMBNAIL_OFFSET);
+
+        let kf8 = if header.version >= 8 {
+            Some(Kf8Section {
+                record: 0,
+                header: header.clone(),
+            })
+        } else if let Some(record) = exth.u32(exth::KF8_BOUNDARY).filter(|&r| r != NULL_INDEX) {
+            let record = record as usize;
+            match database
+                .read_record(&mut reader, record)
+                .and_then(|data| MobiHeader::parse(&data))
+            {
+                Ok(header) => Some(Kf8Section { record, header }),
+                Err(e) => {
+                    warnings.push(format!("cannot read the KF8 section: {e}"));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let drm_status = match header.encryption {
+            0 => DrmStatus::None,
+            1 => DrmStatus::Protected(DrmScheme::Mobipocket),
+            2 => DrmStatus::Protected(DrmScheme::AmazonKindle),
+            _ => DrmStatus::Unknown,
+        };
+
+        Ok(MobiBook {
+            path: path.into(),
+            format: book_format(&header, &exth),
+            header,
+            metadata,
+            drm_status,
+            cover_info,
+            thumbnail_info,
+            kf8,
+            database,
+            warnings,
+        })
+    }
+
+    /// The file path this book was opened from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The detected format (MOBI or AZW3).
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The file version from the MOBI header: 6 for MOBI, 8 for AZW3.
+    pub fn mobi_version(&self) -> u32 {
+        self.header.version
+    }
+
+    /// Any validation warnings collected during parsing.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Information about the cover image, if found.
+    pub fn cover_info(&self) -> Option<&CoverInfo> {
+        self.cover_info.as_ref()
+    }
+
+    /// Information about the thumbnail image, if found.
+    pub fn thumbnail_info(&self) -> Option<&CoverInfo> {
+        self.thumbnail_info.as_ref()
+    }
+
+    /// The thumbnail image, a smaller copy of the cover.
+    pub fn thumbnail(&self) -> crate::Result<Option<Vec<u8>>> {
+        self.read_image(self.thumbnail_info.as_ref())
+    }
+
+    /// Whether the book has KF8 content: AZW3 books and joint MOBI files.
+    pub fn has_kf8(&self) -> bool {
+        self.kf8.is_some()
+    }
+
+    /// The book's text: the HTML stream stored across the text records,
+    /// decompressed but still in the book's own character encoding.
+    pub fn raw_text(&self) -> crate::Result<Vec<u8>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        self.read_text(&mut reader, &self.header, 0)
+    }
+
+    /// The book's text as HTML, decoded from its character encoding.
+    pub fn text(&self) -> crate::Result<String> {
+        Ok(self.header.encoding.decode(&self.raw_text()?))
+    }
+
+    /// The book's content rebuilt into XHTML documents, stylesheets,
+    /// images and fonts, with its table of contents and guide.
+    ///
+    /// Books with a KF8 section are rebuilt from it, turning `kindle:` links
+    /// back into relative links. MOBI 6 books are split at page breaks, with
+    /// anchors added wherever `filepos` links point.
+    pub fn content(&self) -> crate::Result<BookContent> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let (header, base) = match &self.kf8 {
+            Some(section) => (&section.header, section.record),
+            None => (&self.header, 0),
+        };
+        let text = self.read_text(&mut reader, header, base)?;
+        let mut read = |index| self.database.read_record(&mut reader, index);
+
+        // Joint files share the resources of their MOBI section, so
+        // resources are numbered from the first image of record 0 either
+        // way.
+        let first_image = self.header.first_image.map(|first| first as usize);
+        let resources = match first_image {
+            Some(first) => Resources::read(first..self.database.records.len(), &mut read)?,
+            None => Resources::default(),
+        };
+        let cover = first_image
+            .zip(self.cover_info.as_ref())
+            .and_then(|(first, info)| resources.href(info.record.checked_sub(first)? + 1))
+            .map(str::to_string);
+
+        let mut content = match &self.kf8 {
+            Some(section) => kf8::rebuild(section, &text, resources, &mut read)?,
+            None => {
+                // The text is still usable without its table of contents.
+                let ncx = self
+                    .header
+                    .ncx_index
+                    .map(|index| Index::read(index as usize, &mut read));
+                let title = self.metadata.title.as_deref().unwrap_or_default();
+                let ncx_error = ncx.as_ref().and_then(|ncx| ncx.as_ref().err());
+                let mut content = mobi6::rebuild(
+                    &text,
+                    self.header.encoding,
+                    title,
+                    ncx.as_ref().and_then(|ncx| ncx.as_ref().ok()),
+                    resources,
+                );
+                if let Some(e) = ncx_error {
+                    content
+                        .warnings
+                        .push(format!("cannot read the table of contents: {e}"));
+                }
+                content
+            }
+        };
+        content.cover = cover;
+        Ok(content)
+    }
+
+    /// Read and decompress the text records described by `header`, whose
+    /// record numbers are relative to record `base`.
+    fn read_text<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        header: &MobiHeader,
+        base: usize,
+    ) -> crate::Result<Vec<u8>> {
+        if header.encryption != 0 {
+            return Err(Error::InvalidBook("the text is DRM-protected".into()));
+        }
+        let mut decompressor = self.decompressor(reader, header, base)?;
+
+        let mut text = Vec::with_capacity(header.text_length as usize);
+        for index in base + 1..=base + usize::from(header.text_records) {
+            let record = self.database.read_record(reader, index)?;
+            let len = record.len() - trailing_entries_len(&record, header.extra_data_flags);
+            let decompressed = decompressor
+                .decompress(&record[..len])
+                .map_err(|e| Error::InvalidBook(format!("text record {index}: {e}")))?;
+            text.extend_from_slice(&decompressed);
+        }
+        text.truncate(header.text_length as usize);
+        Ok(text)
+    }
+
+    /// Set up decompression of the text records.
+    fn decompressor<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        header: &MobiHeader,
+        base: usize,
+    ) -> crate::Result<Decompressor> {
+        Ok(match header.compression {
+            Compression::None => Decompressor::None,
+            Compression::PalmDoc => Decompressor::PalmDoc,
+            Compression::HuffCdic => {
+                let Some((first, count)) = header.huffman_records else {
+                    return Err(Error::InvalidBook("book has no HUFF record".into()));
+                };
+                let first = base + first as usize;
+                let huff = self.database.read_record(reader, first)?;
+                let cdics = (first + 1..first + count as usize)
+                    .map(|index| self.database.read_record(reader, index))
+                    .collect::<crate::Result<Vec<_>>>()?;
+                Decompressor::HuffCdic(Box::new(HuffCdicReader::new(&huff, &cdics)?))
+            }
+            Compression::Unknown(kind) => {
+                return Err(Error::InvalidBook(format!(
+                    "unknown text compression type {kind}"
+                )));
+            }
+        })
+    }
+
+    /// The records that hold a record 0: the first record, and the start
+    /// of the KF8 section of a joint file.
+    fn record0s(&self) -> Vec<usize> {
+        let mut records = vec![0];
+        records.extend(self.kf8.as_ref().map(|s| s.record).filter(|&r| r != 0));
+        records
+    }
+
+    fn read_image(&self, info: Option<&CoverInfo>) -> crate::Result<Option<Vec<u8>>> {
+        let Some(info) = info else {
+            return Ok(None);
+        };
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        Ok(Some(self.database.read_record(&mut reader, info.record)?))
+    }
+}
+
+impl BookReader for MobiBook {
+    type Book = MobiBook;
+
+    fn open(path: &Path) -> crate::Result<Self::Book> {
+        MobiBook::open(path)
+    }
+}
+
+impl MetadataProvider for MobiBook {
+    fn metadata(&self) -> crate::Result<Metadata> {
+        Ok(self.metadata.clone())
+    }
+}
+
+impl MetadataWriter for MobiBook {
+    /// Rewrite the EXTH records and title of each record 0, leaving the
+    /// text and every other record as they are.
+    ///
+    /// Author records are kept unless the authors change, so that names
+    /// stored in sort form stay that way. Text is written in the book's
+    /// own encoding, with `?` for characters cp1252 books can't hold.
+    fn set_metadata(&mut self, metadata: &Metadata) -> crate::Result<()> {
+        let keep_authors = metadata.authors == self.metadata.authors
+            && metadata.author_sort == self.metadata.author_sort;
+        let sections = self.record0s();
+        pdb::rewrite_database(&self.path, |records| {
+            for &section in &sections {
+                let mut record0 = Record0::parse(&records[section])?;
+                let authors: Vec<Vec<u8>> = record0
+                    .exth
+                    .records
+                    .iter()
+                    .filter(|r| r.kind == exth::AUTHOR)
+                    .map(|r| r.data.clone())
+                    .collect();
+                record0.exth.set_metadata(metadata, record0.encoding);
+                if keep_authors {
+                    record0.exth.set(exth::AUTHOR, authors);
+                }
+                if let Some(title) = &metadata.title {
+                    record0.set_title(title);
+                }
+                if let Some(locale) = metadata
+                    .language
+                    .as_deref()
+                    .map(header::locale)
+                    .filter(|&l| l != 0)
+                {
+                    record0.set_locale(locale);
+                }
+                records[section] = record0.to_bytes();
+            }
+            Ok(())
+        })?;
+
+        // Re-read so metadata and record offsets reflect the new file.
+        *self = MobiBook::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl CoverWriter for MobiBook {
+    /// Replace the cover and thumbnail records with the image.
+    ///
+    /// A book without a cover gets new records for both, placed before the
+    /// end-of-file record so that no other record moves. The thumbnail is a
+    /// copy of the cover, as images can't be scaled here.
+    fn set_cover(&mut self, image_data: &[u8]) -> crate::Result<()> {
+        image::media_type(image_data).ok_or(Error::UnsupportedImage)?;
+        let cover = self.cover_info.as_ref().map(|info| info.record);
+        let thumbnail = self.thumbnail_info.as_ref().map(|info| info.record);
+        let first_image = self.header.first_image.map(|first| first as usize);
+        let sections = self.record0s();
+        let kf8 = self.kf8.as_ref().map(|section| section.record);
+
+        pdb::rewrite_database(&self.path, |records| {
+            if let Some(cover) = cover {
+                for record in [Some(cover), thumbnail].into_iter().flatten() {
+                    records[record] = image_data.to_vec();
+                }
+                return Ok(());
+            }
+
+            let at = match records.last() {
+                Some(last) if last.starts_with(EOF_RECORD) => records.len() - 1,
+                _ => records.len(),
+            };
+            records.splice(at..at, [image_data.to_vec(), image_data.to_vec()]);
+            let first = first_image.unwrap_or(at);
+            let offset = at
+                .checked_sub(first)
+                .ok_or_else(|| Error::InvalidBook("first image record is past the end".into()))?
+                as u32;
+            for &section in &sections {
+                let mut record0 = Record0::parse(&records[section])?;
+                let exth = &mut record0.exth;
+                exth.set_u32(exth::COVER_OFFSET, offset);
+                exth.set_u32(exth::THUMBNAIL_OFFSET, offset + 1);
+                exth.set_u32(exth::HAS_FAKE_COVER, 0);
+                if kf8 == Some(section) {
+                    let uri = format!("kindle:embed:{}", azw3::base32(offset as usize + 1, 4));
+                    exth.set(exth::KF8_COVER_URI, [uri]);
+                    if let Some(count) = exth.u32(exth::RESOURCE_COUNT) {
+                        exth.set_u32(exth::RESOURCE_COUNT, count.saturating_add(2));
+                    }
+                }
+                if first_image.is_none() {
+                    record0.set_first_image(first as u32);
+                }
+                records[section] = record0.to_bytes();
+            }
+            Ok(())
+        })?;
+
+        *self = MobiBook::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl DrmDetector for MobiBook {
+    fn drm_status(&self) -> crate::Result<DrmStatus> {
+        Ok(self.drm_status.clone())
+    }
+}
+
+impl CoverProvider for MobiBook {
+    fn cover(&self) -> crate::Result<Option<Vec<u8>>> {
+        self.read_image(self.cover_info.as_ref())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Internal helpers
+// ---------------------------------------------------------------------------
+
+/// Build the book's metadata from its headers.
+fn read_metadata(database: &PalmDatabase, header: &MobiHeader, exth: &Exth) -> Metadata {
+    let encoding = header.encoding;
+    let mut metadata = Metadata {
+        title: exth
+            .string(exth::UPDATED_TITLE, encoding)
+            .or_else(|| header.full_name.clone())
+            .or_else(|| Some(database.name.clone()).filter(|n| !n.is_empty())),
+        publisher: exth.string(exth::PUBLISHER, encoding),
+        description: exth.string(exth::DESCRIPTION, encoding),
+        isbn: exth.string(exth::ISBN, encoding),
+        ..Metadata::default()
+    };
+
+    // Authors may be in sort form, and several may share a record.
+    for value in exth.strings(exth::AUTHOR, encoding) {
+        for name in value
+            .split(['&', ';'])
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+        {
+            if is_sort_form(name) {
+                let display = author_display_name(name);
+                metadata
+                    .author_sort
+                    .insert(display.clone(), name.to_string());
+                metadata.authors.push(display);
+            } else {
+                metadata.authors.push(name.to_string());
+            }
+        }
+    }
+
+    for value in exth.strings(exth::SUBJECT, encoding) {
+        for subject in value.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            if !metadata.subjects.iter().any(|s| s == subject) {
+                metadata.subjects.push(subject.to_string());
+            }
+        }
+    }
+
+    if let Some(asin) = exth
+        .string(exth::ASIN, encoding)
+        .or_else(|| exth.string(exth::ASIN_ALT, encoding))
+    {
+        metadata.identifiers.insert("asin".into(), asin);
+    }
+
+    metadata.language = exth
+        .string(exth::LANGUAGE, encoding)
+        .and_then(|l| normalize_language(&l))
+        .or_else(|| header.language().map(str::to_string));
+
+    metadata.publication_date = exth
+        .string(exth::PUBLISHING_DATE, encoding)
+        .and_then(|date| parse_date(&date));
+
+    metadata
+}
+
+/// Decompresses text records.
+enum Decompressor {
+    None,
+    PalmDoc,
+    HuffCdic(Box<HuffCdicReader>),
+}
+
+impl Decompressor {
+    fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Decompressor::None => Ok(data.to_vec()),
+            Decompressor::PalmDoc => palmdoc::decompress(data),
+            Decompressor::HuffCdic(reader) => reader.decompress(data),
+        }
+    }
+}
+
+/// The length of the trailing entries at the end of a text record.
+///
+/// Each set bit of `flags` above the lowest adds an entry that ends with its
+/// own size, written as a backward variable-length integer. The lowest bit
+/// adds the bytes of a multibyte character cut off at the end of the
+/// record, whose count is in the low two bits of the last remaining byte.
+fn trailing_entries_len(record: &[u8], flags: u16) -> usize {
+    let mut len = 0;
+    let mut entries = flags >> 1;
+    while entries != 0 {
+        if entries & 1 != 0 {
+            len += backward_varint(&record[..record.len().saturating_sub(len)]);
+        }
+        entries >>= 1;
+    }
+    if flags & 1 != 0
+        && let Some(&last) = record
+            .len()
+            .checked_sub(len + 1)
+            .and_then(|i| record.get(i))
+    {
+        len += usize::from(last & 0x03) + 1;
+    }
+    len.min(record.len())
+}
+
+/// Read a variable-length integer backwards from the end of `data`. The
+/// last byte holds the lowest seven bits, and the first byte has its high
+/// bit set.
+fn backward_varint(data: &[u8]) -> usize {
+    let mut value = 0;
+    for (shift, &byte) in data.iter().rev().take(4).enumerate() {
+        value |= usize::from(byte & 0x7F) << (7 * shift);
+        if byte & 0x80 != 0 {
+            break;
+        }
+    }
+    value
+}
+
+/// Tell MOBI from AZW3 by the first record 0, for [`Format::detect`].
+/// Files whose record 0 can't be read are taken as MOBI.
+pub(crate) fn detect_format<R: Read + Seek>(mut reader: R) -> Format {
+    let Ok(database) = PalmDatabase::read(&mut reader) else {
+        return Format::Mobi;
+    };
+    let header = database
+        .read_record(&mut reader, 0)
+        .and_then(|record| Ok((MobiHeader::parse(&record)?, record)));
+    let Ok((header, record0)) = header else {
+        return Format::Mobi;
+    };
+    let exth = match header.exth_offset {
+        Some(offset) => Exth::parse(record0.get(offset..).unwrap_or_default(), &mut Vec::new()),
+        None => Exth::default(),
+    };
+    book_format(&header, &exth)
+}
+
+/// The format of a book from its first record 0.
+///
+/// KF8 books have a version 8 header. A KF8 boundary (EXTH record 121)
+/// marks a joint file, whose first record 0 starts a MOBI 6 section with a
+/// KF8 section after it. Joint files are reported as MOBI on purpose: every
+/// Kindle can read the MOBI section, but only newer ones the KF8 section.
+fn book_format(header: &MobiHeader, exth: &Exth) -> Format {
+    let kf8_boundary = exth.u32(exth::KF8_BOUNDARY).filter(|&r| r != NULL_INDEX);
+    match kf8_boundary {
+        Some(_) => Format::Mobi,
+        None if header.version >= 8 => Format::Azw3,
+        None => Format::Mobi,
+    }
+}
+
+/// Parse a publishing date, which MOBI writers give in various forms, from
+/// a full timestamp down to a bare year.
+fn parse_date(date: &str) -> Option<W3cDate> {
+    W3cDate::parse(date)
+        .or_else(|| date.get(..10).and_then(W3cDate::parse))
+        .or_else(|| date.get(..4).and_then(W3cDate::parse))
+        .filter(|d| !d.is_undefined())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_trailing_entries() {
+        assert_eq!(trailing_entries_len(b"text", 0), 0);
+    }
+
+    #[test]
+    fn multibyte_and_tbs_entries() {
+        // A cut-off character of one byte plus its count byte, then a
+        // three-byte TBS entry that ends with its own size.
+        let record = b"abc\xe2\x01\xaa\xbb\x83";
+        assert_eq!(trailing_entries_len(record, 0b11), 5);
+        assert_eq!(trailing_entries_len(record, 0b10), 3);
+
+        // Two entries, the last with a two-byte size of 130.
+        let mut record = b"text\x82".to_vec();
+        record.extend_from_slice(&[0; 128]);
+        record.extend_from_slice(&[0x81, 0x02]);
+        assert_eq!(trailing_entries_len(&record, 0b110), 132);
+        assert_eq!(trailing_entries_len(&record, 0b111), 133);
+    }
+
+    #[test]
+    fn trailing_entries_never_exceed_the_record() {
+        assert_eq!(trailing_entries_len(b"\x80\xff", 0b10), 2);
+        assert_eq!(trailing_entries_len(b"", 0b111), 0);
+    }
+
+    #[test]
+    fn cover_offset_out_of_range() {
+        let mut exth = Exth::default();
+        exth.set_u32(exth::COVER_OFFSET, u32::MAX - 1);
+        exth.set_u32(exth::THUMBNAIL_OFFSET, 0);
+        let records = testing::mobi6("Book", "Text", &exth, &[testing::JPEG]);
+        let path = testing::write("cover-offset.mobi", &records);
+        let book = MobiBook::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let book = book.unwrap();
+        assert!(book.cover_info.is_none());
+        assert_eq!(
+            book.thumbnail_info.as_ref().map(|info| info.record),
+            Some(2)
+        );
+        assert!(book.warnings().iter().any(|w| w.contains("out of range")));
+    }
+
+    /// The EXTH and MOBI headers of record 0 in `records[section]`.
+    fn section(records: &[Vec<u8>], section: usize) -> (Exth, MobiHeader) {
+        let record0 = Record0::parse(&records[section]).unwrap();
+        (record0.exth, MobiHeader::parse(&records[section]).unwrap())
+    }
+
+    /// Check that every record but those listed is unchanged.
+    fn assert_unchanged(before: &[Vec<u8>], after: &[Vec<u8>], changed: &[usize]) {
+        for (i, (before, after)) in before.iter().zip(after).enumerate() {
+            if !changed.contains(&i) {
+                assert_eq!(before, after, "record {i} changed");
+            }
+        }
+    }
+
+    #[test]
+    fn set_metadata_rewrites_both_sections_of_a_joint_file() {
+        let mut exth = Exth::default();
+        exth.set(exth::AUTHOR, ["Doe, Jane"]);
+        let path = testing::write("set-metadata.mobi", &testing::joint("Old", "Text", &exth));
+        let before = testing::read(&path);
+        let mut book = MobiBook::open(&path).unwrap();
+        let kf8 = book.kf8.as_ref().unwrap().record;
+        assert_eq!(kf8, 3);
+
+        let mut metadata = book.metadata().unwrap();
+        assert_eq!(metadata.authors, ["Jane Doe"]);
+        metadata.title = Some("New Title".into());
+        metadata.language = Some("fr".into());
+        let result = book.set_metadata(&metadata);
+        let after = testing::read(&path);
+
+        // New authors replace the old ones.
+        metadata.authors = vec!["John Smith".into()];
+        metadata.author_sort.clear();
+        let changed_authors = book.set_metadata(&metadata);
+        let reopened = MobiBook::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        changed_authors.unwrap();
+
+        assert_eq!(before.len(), after.len());
+        assert_unchanged(&before, &after, &[0, kf8]);
+        // The authors were unchanged, so they stay in sort form.
+        for record in [0, kf8] {
+            let (exth, header) = section(&after, record);
+            assert_eq!(exth.strings(exth::AUTHOR, header.encoding), ["Doe, Jane"]);
+            assert_eq!(header.full_name.as_deref(), Some("New Title"));
+            assert_eq!(header.language(), Some("fr"));
+        }
+
+        let metadata = reopened.unwrap().metadata().unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("New Title"));
+        assert_eq!(metadata.authors, ["John Smith"]);
+    }
+
+    #[test]
+    fn set_cover_adds_cover_and_thumbnail_records_before_the_end() {
+        let mut exth = Exth::default();
+        exth.set_u32(exth::RESOURCE_COUNT, 0);
+        let path = testing::write("add-cover.mobi", &testing::joint("Book", "Text", &exth));
+        let before = testing::read(&path);
+        let mut book = MobiBook::open(&path).unwrap();
+        let kf8 = book.kf8.as_ref().unwrap().record;
+        let result = book.set_cover(testing::JPEG);
+        let after = testing::read(&path);
+        let (cover, thumbnail) = (book.cover(), book.thumbnail());
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        // The image records go between the last record and the
+        // end-of-file record.
+        let at = before.len() - 1;
+        assert_eq!(after.len(), before.len() + 2);
+        assert_unchanged(&before[..at], &after[..at], &[0, kf8]);
+        assert_eq!(after[at], testing::JPEG);
+        assert_eq!(after[at + 1], testing::JPEG);
+        assert_eq!(after[at + 2], EOF_RECORD);
+
+        let (exth, header) = section(&after, 0);
+        assert_eq!(header.first_image, Some(at as u32));
+        assert_eq!(exth.u32(exth::COVER_OFFSET), Some(0));
+        assert_eq!(exth.u32(exth::THUMBNAIL_OFFSET), Some(1));
+        assert_eq!(exth.u32(exth::RESOURCE_COUNT), Some(0));
+
+        let (exth, _) = section(&after, kf8);
+        assert_eq!(exth.u32(exth::COVER_OFFSET), Some(0));
+        assert_eq!(exth.u32(exth::RESOURCE_COUNT), Some(2));
+        assert_eq!(
+            exth.string(exth::KF8_COVER_URI, header::TextEncoding::Utf8)
+                .as_deref(),
+            Some("kindle:embed:0001")
+        );
+
+        assert_eq!(cover.unwrap().as_deref(), Some(testing::JPEG));
+        assert_eq!(thumbnail.unwrap().as_deref(), Some(testing::JPEG));
+    }
+
+    #[test]
+    fn set_cover_replaces_existing_records() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\0";
+        let mut exth = Exth::default();
+        exth.set_u32(exth::COVER_OFFSET, 0);
+        exth.set_u32(exth::THUMBNAIL_OFFSET, 1);
+        let records = testing::mobi6("Book", "Text", &exth, &[testing::JPEG, testing::JPEG]);
+        let path = testing::write("replace-cover.mobi", &records);
+        let before = testing::read(&path);
+        let result = MobiBook::open(&path).and_then(|mut book| book.set_cover(png));
+        let after = testing::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(after.len(), before.len());
+        assert_unchanged(&before, &after, &[2, 3]);
+        assert_eq!(after[2], png);
+        assert_eq!(after[3], png);
+    }
+}
@@ -0,0 +1,216 @@
+//! PalmDOC compression, a simple LZ77 variant used for most MOBI text.
+
+use std::collections::HashMap;
+
+/// How far back a back reference can reach.
+const WINDOW: usize = 2047;
+
+/// The shortest and longest repeats a back reference can copy.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 10;
+
+/// How many earlier positions to try when looking for a repeat.
+const MAX_CANDIDATES: usize = 64;
+
+/// Decompress a PalmDOC-compressed text record.
+///
+/// Each byte is a literal (`0x00`, `0x09`–`0x7F`), a count of literals to
+/// follow (`0x01`–`0x08`), the first half of a back reference (`0x80`–`0xBF`)
+/// or a space followed by an ASCII character (`0xC0`–`0xFF`).
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+    while i < data.len() {
+        let c = data[i];
+        i += 1;
+        match c {
+            0x01..=0x08 => {
+                let end = i + usize::from(c);
+                let literals = data
+                    .get(i..end)
+                    .ok_or_else(|| invalid("literal run past the end of the record"))?;
+                out.extend_from_slice(literals);
+                i = end;
+            }
+            0x00 | 0x09..=0x7F => out.push(c),
+            0x80..=0xBF => {
+                let next = *data
+                    .get(i)
+                    .ok_or_else(|| invalid("back reference past the end of the record"))?;
+                i += 1;
+                let pair = (u16::from(c) << 8) | u16::from(next);
+                let distance = usize::from((pair >> 3) & 0x07FF);
+                let length = usize::from(pair & 0x07) + 3;
+                if distance == 0 || distance > out.len() {
+                    return Err(invalid("back reference before the start of the text"));
+                }
+                // The source may overlap the bytes being written, so copy
+                // one byte at a time.
+                let start = out.len() - distance;
+                for j in 0..length {
+                    out.push(out[start + j]);
+                }
+            }
+            0xC0..=0xFF => {
+                out.push(b' ');
+                out.push(c ^ 0x80);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Compress a text record with PalmDOC compression.
+///
+/// Repeats found in the preceding text become back references, a space
+/// followed by a character from `0x40` to `0x7F` becomes a single byte, and
+/// bytes that would otherwise be misread are written in literal runs.
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut literals = Vec::with_capacity(8);
+    // The most recent position of each three-byte sequence, and for each
+    // position the previous one with the same sequence.
+    let mut latest: HashMap<[u8; 3], usize> = HashMap::new();
+    let mut previous = vec![usize::MAX; data.len()];
+    let mut indexed = 0;
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best: Option<(usize, usize)> = None;
+        if let Some(key) = key_at(data, i) {
+            let mut candidate = latest.get(&key).copied();
+            for _ in 0..MAX_CANDIDATES {
+                let Some(j) = candidate.filter(|&j| i - j <= WINDOW) else {
+                    break;
+                };
+                let len = data[i..]
+                    .iter()
+                    .take(MAX_MATCH)
+                    .zip(&data[j..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len >= MIN_MATCH && best.is_none_or(|(_, best_len)| len > best_len) {
+                    best = Some((i - j, len));
+                }
+                candidate = Some(previous[j]).filter(|&p| p != usize::MAX);
+            }
+        }
+
+        let step = match best {
+            Some((distance, len)) => {
+                flush_literals(&mut out, &mut literals);
+                let pair = 0x8000 | (distance << 3) as u16 | (len - MIN_MATCH) as u16;
+                out.extend_from_slice(&pair.to_be_bytes());
+                len
+            }
+            None => {
+                let c = data[i];
+                match data.get(i + 1) {
+                    Some(&next) if c == b' ' && (0x40..=0x7F).contains(&next) => {
+                        flush_literals(&mut out, &mut literals);
+                        out.push(next ^ 0x80);
+                        2
+                    }
+                    _ if c == 0x00 || (0x09..=0x7F).contains(&c) => {
+                        flush_literals(&mut out, &mut literals);
+                        out.push(c);
+                        1
+                    }
+                    _ => {
+                        literals.push(c);
+                        if literals.len() == 8 {
+                            flush_literals(&mut out, &mut literals);
+                        }
+                        1
+                    }
+                }
+            }
+        };
+
+        i += step;
+        while indexed < i {
+            if let Some(key) = key_at(data, indexed)
+                && let Some(last) = latest.insert(key, indexed)
+            {
+                previous[indexed] = last;
+            }
+            indexed += 1;
+        }
+    }
+    flush_literals(&mut out, &mut literals);
+    out
+}
+
+fn key_at(data: &[u8], i: usize) -> Option<[u8; 3]> {
+    data.get(i..i + 3).map(|k| [k[0], k[1], k[2]])
+}
+
+/// Write pending bytes as a literal run: their count, then the bytes.
+fn flush_literals(out: &mut Vec<u8>, literals: &mut Vec<u8>) {
+    if !literals.is_empty() {
+        out.push(literals.len() as u8);
+        out.append(literals);
+    }
+}
+
+fn invalid(message: &str) -> String {
+    format!("bad PalmDOC data: {message}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        compressed
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(round_trip(b"abc\x00\x7f"), b"abc\x00\x7f");
+        assert_eq!(round_trip(b""), b"");
+    }
+
+    #[test]
+    fn bytes_that_need_a_literal_run() {
+        let data: Vec<u8> = (0x01..=0x08).chain(0x80..=0xFF).collect();
+        round_trip(&data);
+        assert_eq!(compress(b"\x01\x80"), b"\x02\x01\x80");
+    }
+
+    #[test]
+    fn space_pairs() {
+        assert_eq!(round_trip(b" A z"), [b'A' ^ 0x80, b'z' ^ 0x80]);
+        // A space before a byte outside 0x40-0x7F stays as it is.
+        assert_eq!(round_trip(b" 1"), b" 1");
+    }
+
+    #[test]
+    fn back_references() {
+        let compressed = round_trip(b"abcabcabcabc");
+        assert!(compressed.len() < 12);
+        // Overlapping copies repeat the bytes they have just written.
+        assert_eq!(decompress(b"ab\x80\x17").unwrap(), b"abababababab");
+    }
+
+    #[test]
+    fn full_record() {
+        let text: Vec<u8> = (0..4096u32)
+            .map(|i| match i % 97 {
+                0 => b' ',
+                n if n % 13 == 0 => 0x80 | (i % 128) as u8,
+                n => b'a' + (n * 7 % 26) as u8,
+            })
+            .collect();
+        round_trip(&text);
+    }
+
+    #[test]
+    fn bad_data() {
+        assert!(decompress(b"\x05ab").is_err());
+        assert!(decompress(b"\x80").is_err());
+        assert!(decompress(b"a\x80\x20").is_err());
+    }
+}
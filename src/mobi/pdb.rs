@@ -0,0 +1,174 @@
+//! The Palm database (PDB) container that MOBI and AZW3 books are stored in.
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{atomic, Error};
+
+/// Length of the database header, which the record list follows.
+const HEADER_LEN: usize = 78;
+
+/// Length of each entry in the record list.
+const RECORD_ENTRY_LEN: usize = 8;
+
+/// The header and record list of a Palm database.
+#[derive(Debug, Clone)]
+pub(crate) struct PalmDatabase {
+    /// The database name, which MOBI writers fill with a shortened title.
+    pub name: String,
+    /// The byte range of each record within the file.
+    pub records: Vec<Range<u64>>,
+}
+
+impl PalmDatabase {
+    /// Read the database header and record list.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> crate::Result<PalmDatabase> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.rewind()?;
+
+        let mut header = [0u8; HEADER_LEN];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| invalid("file is too short for a Palm database header"))?;
+        let count = usize::from(u16_at(&header, 76).unwrap_or_default());
+
+        let mut list = vec![0u8; count * RECORD_ENTRY_LEN];
+        reader
+            .read_exact(&mut list)
+            .map_err(|_| invalid("the record list is truncated"))?;
+        let offsets: Vec<u64> = list
+            .chunks_exact(RECORD_ENTRY_LEN)
+            .map(|entry| u64::from(u32_at(entry, 0).unwrap_or_default()))
+            .collect();
+
+        let mut records = Vec::with_capacity(count);
+        for (i, &start) in offsets.iter().enumerate() {
+            let end = offsets.get(i + 1).copied().unwrap_or(file_len);
+            if start > end || end > file_len {
+                return Err(invalid(&format!("record {i} has an invalid offset")));
+            }
+            records.push(start..end);
+        }
+
+        let name = &header[..32];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        Ok(PalmDatabase {
+            name: String::from_utf8_lossy(name).into_owned(),
+            records,
+        })
+    }
+
+    /// The length of record `index` in bytes, if it exists.
+    pub fn record_len(&self, index: usize) -> Option<u64> {
+        self.records.get(index).map(|r| r.end - r.start)
+    }
+
+    /// Read record `index` from the file.
+    pub fn read_record<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        index: usize,
+    ) -> crate::Result<Vec<u8>> {
+        let range = self
+            .records
+            .get(index)
+            .ok_or_else(|| invalid(&format!("record {index} does not exist")))?;
+        reader.seek(SeekFrom::Start(range.start))?;
+        let mut data = vec![0u8; (range.end - range.start) as usize];
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Build a Palm database of MOBI type from its name and records.
+///
+/// The name is cut to the 31 bytes the header has room for.
+pub(crate) fn write_database(name: &str, records: &[Vec<u8>]) -> crate::Result<Vec<u8>> {
+    let mut header = [0u8; HEADER_LEN];
+    let name = &name.as_bytes()[..name.len().min(31)];
+    header[..name.len()].copy_from_slice(name);
+    header[36..40].copy_from_slice(&now().to_be_bytes());
+    header[60..68].copy_from_slice(b"BOOKMOBI");
+    database_bytes(header, records)
+}
+
+/// Rewrite the Palm database at `path` with its records changed by `edit`.
+///
+/// The header is kept apart from the record count and modification time.
+/// The new file is written next to the original and then renamed over it,
+/// so a failure part-way through leaves the original untouched.
+pub(crate) fn rewrite_database(
+    path: &Path,
+    edit: impl FnOnce(&mut Vec<Vec<u8>>) -> crate::Result<()>,
+) -> crate::Result<()> {
+    let data = std::fs::read(path)?;
+    let database = PalmDatabase::read(&mut Cursor::new(&data))?;
+    let mut records: Vec<Vec<u8>> = database
+        .records
+        .iter()
+        .map(|range| data[range.start as usize..range.end as usize].to_vec())
+        .collect();
+    edit(&mut records)?;
+
+    let mut header = [0u8; HEADER_LEN];
+    header.copy_from_slice(&data[..HEADER_LEN]);
+    let data = database_bytes(header, &records)?;
+    atomic::write(path, &data)
+}
+
+/// Write a Palm database from its header and records, filling in the
+/// modification time, record count and record list.
+fn database_bytes(mut header: [u8; HEADER_LEN], records: &[Vec<u8>]) -> crate::Result<Vec<u8>> {
+    let count = u16::try_from(records.len())
+        .map_err(|_| invalid("too many records for a Palm database"))?;
+    header[40..44].copy_from_slice(&now().to_be_bytes());
+    header[68..72].copy_from_slice(&(u32::from(count) * 2).saturating_sub(1).to_be_bytes());
+    header[76..78].copy_from_slice(&count.to_be_bytes());
+
+    let mut data = header.to_vec();
+    // The record list is followed by two bytes of padding.
+    let mut offset = HEADER_LEN + records.len() * RECORD_ENTRY_LEN + 2;
+    for (i, record) in records.iter().enumerate() {
+        let offset_field =
+            u32::try_from(offset).map_err(|_| invalid("book is too large for a Palm database"))?;
+        data.extend_from_slice(&offset_field.to_be_bytes());
+        // Attributes in the high byte, then a unique id.
+        data.extend_from_slice(&(i as u32 * 2).to_be_bytes());
+        offset += record.len();
+    }
+    data.extend_from_slice(&[0, 0]);
+    for record in records {
+        data.extend_from_slice(record);
+    }
+    Ok(data)
+}
+
+/// The current time in seconds, as Palm database headers store it.
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// The big-endian `u16` at `offset`, if `data` is long enough.
+pub(crate) fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// The big-endian `u32` at `offset`, if `data` is long enough.
+pub(crate) fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Write `value` as a big-endian `u32` at `offset`.
+pub(crate) fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBook(message.to_string())
+}
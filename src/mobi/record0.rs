@@ -0,0 +1,114 @@
+//! Record 0 of a book, taken apart so that its EXTH header and title can
+//! be changed.
+//!
+//! The PalmDOC and MOBI headers are kept as they are, apart from the EXTH
+//! flag, the title's offset and length, and the locale. The EXTH header and
+//! the title follow them; anything else after the MOBI header is padding.
+
+use super::exth::Exth;
+use super::header::{MobiHeader, TextEncoding, HAS_EXTH, MOBI_OFFSET};
+use super::pdb::{put_u32, u32_at};
+use crate::Error;
+
+pub(crate) struct Record0 {
+    /// The PalmDOC and MOBI headers.
+    headers: Vec<u8>,
+    pub encoding: TextEncoding,
+    pub exth: Exth,
+    /// The full title, in the book's encoding.
+    title: Vec<u8>,
+    /// The length of the original record, which the new one is padded to
+    /// if it is shorter.
+    len: usize,
+}
+
+impl Record0 {
+    /// Take record 0 apart.
+    ///
+    /// Books with DRM keep their vouchers after the EXTH header, where a
+    /// rebuilt record would lose them, so they can't be changed.
+    pub fn parse(record: &[u8]) -> crate::Result<Record0> {
+        let header = MobiHeader::parse(record)?;
+        if header.encryption != 0 {
+            return Err(Error::InvalidBook(
+                "cannot edit a DRM-protected book".into(),
+            ));
+        }
+        let header_len = u32_at(record, MOBI_OFFSET + 4).unwrap_or_default() as usize;
+        let headers = record[..record.len().min(MOBI_OFFSET + header_len)].to_vec();
+        // The header must reach the EXTH flags.
+        if headers.len() < 132 {
+            return Err(Error::InvalidBook(
+                "MOBI header is too short to edit".into(),
+            ));
+        }
+        let exth = match header.exth_offset {
+            Some(offset) => Exth::parse(record.get(offset..).unwrap_or_default(), &mut Vec::new()),
+            None => Exth::default(),
+        };
+        let title = match (u32_at(record, 84), u32_at(record, 88)) {
+            (Some(offset), Some(len)) => offset
+                .checked_add(len)
+                .and_then(|end| record.get(offset as usize..end as usize))
+                .unwrap_or_default()
+                .to_vec(),
+            _ => Vec::new(),
+        };
+        Ok(Record0 {
+            headers,
+            encoding: header.encoding,
+            exth,
+            title,
+            len: record.len(),
+        })
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = self.encoding.encode(title);
+    }
+
+    /// Set the Windows language identifier of the book's language.
+    pub fn set_locale(&mut self, locale: u32) {
+        put_u32(&mut self.headers, 92, locale);
+    }
+
+    /// Set the index of the first image record, which cover offsets are
+    /// relative to.
+    pub fn set_first_image(&mut self, record: u32) {
+        put_u32(&mut self.headers, 108, record);
+    }
+
+    /// Put the record back together.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut record = self.headers.clone();
+        let flags = u32_at(&record, 128).unwrap_or_default();
+        let exth = self.exth.to_bytes();
+        let title_offset = record.len() + exth.len();
+        record.extend_from_slice(&exth);
+        record.extend_from_slice(&self.title);
+        record.resize((record.len() + 2).next_multiple_of(4).max(self.len), 0);
+
+        put_u32(&mut record, 84, title_offset as u32);
+        put_u32(&mut record, 88, self.title.len() as u32);
+        put_u32(&mut record, 128, flags | HAS_EXTH);
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mobi::testing;
+
+    #[test]
+    fn title_past_the_end_of_the_record() {
+        let mut record = testing::record0(6, "Title", 0, 0, None, &Exth::default());
+        put_u32(&mut record, 84, 0xFFFF_FFF0);
+        put_u32(&mut record, 88, 0x20);
+        let record0 = Record0::parse(&record).unwrap();
+        assert!(record0.title.is_empty());
+        // The rebuilt record points at an empty title.
+        let rebuilt = record0.to_bytes();
+        assert_eq!(u32_at(&rebuilt, 88), Some(0));
+    }
+}
@@ -0,0 +1,140 @@
+//! Small books built in memory for tests.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use super::content::EOF_RECORD;
+use super::exth::{self, Exth};
+use super::header::NULL_INDEX;
+use super::pdb::{self, put_u32, PalmDatabase};
+
+/// The length of the MOBI header written by [`record0`].
+const MOBI_HEADER_LEN: usize = 0x108;
+
+/// Record 0 of an uncompressed UTF-8 book: the PalmDOC and MOBI headers,
+/// the EXTH header and the title.
+pub(super) fn record0(
+    version: u32,
+    title: &str,
+    text_length: usize,
+    text_records: usize,
+    first_image: Option<usize>,
+    exth: &Exth,
+) -> Vec<u8> {
+    let mut record = vec![0u8; 16 + MOBI_HEADER_LEN];
+    // PalmDOC header: no compression, text length, record count and size.
+    record[0..2].copy_from_slice(&1u16.to_be_bytes());
+    put_u32(&mut record, 4, text_length as u32);
+    record[8..10].copy_from_slice(&(text_records as u16).to_be_bytes());
+    record[10..12].copy_from_slice(&4096u16.to_be_bytes());
+
+    record[16..20].copy_from_slice(b"MOBI");
+    put_u32(&mut record, 20, MOBI_HEADER_LEN as u32);
+    put_u32(&mut record, 24, 2);
+    put_u32(&mut record, 28, 65001);
+    put_u32(&mut record, 36, version);
+    put_u32(&mut record, 92, 9);
+    put_u32(
+        &mut record,
+        108,
+        first_image.map_or(NULL_INDEX, |i| i as u32),
+    );
+    put_u32(&mut record, 128, 0x40);
+    for offset in [0xC0, 0xF4, 0xF8, 0xFC, 0x104] {
+        put_u32(&mut record, offset, NULL_INDEX);
+    }
+
+    record.extend_from_slice(&exth.to_bytes());
+    let title_offset = record.len() as u32;
+    put_u32(&mut record, 84, title_offset);
+    put_u32(&mut record, 88, title.len() as u32);
+    record.extend_from_slice(title.as_bytes());
+    record.resize((record.len() + 2).next_multiple_of(4), 0);
+    record
+}
+
+/// The records of a MOBI 6 book: record 0, the text in 4096-byte records,
+/// the images and the end-of-file record. The cover, if any, is the first
+/// image.
+pub(super) fn mobi6(title: &str, text: &str, exth: &Exth, images: &[&[u8]]) -> Vec<Vec<u8>> {
+    let text_records: Vec<Vec<u8>> = text.as_bytes().chunks(4096).map(<[u8]>::to_vec).collect();
+    let first_image = (!images.is_empty()).then_some(text_records.len() + 1);
+    let mut records = vec![record0(
+        6,
+        title,
+        text.len(),
+        text_records.len(),
+        first_image,
+        exth,
+    )];
+    records.extend(text_records);
+    records.extend(images.iter().map(|image| image.to_vec()));
+    records.push(EOF_RECORD.to_vec());
+    records
+}
+
+/// The records of an AZW3 book with no resources or indices: record 0,
+/// the text and the end-of-file record.
+pub(super) fn azw3(title: &str, text: &str, exth: &Exth) -> Vec<Vec<u8>> {
+    let mut records = mobi6(title, text, exth, &[]);
+    records[0] = record0(8, title, text.len(), records.len() - 2, None, exth);
+    records
+}
+
+/// The records of a joint file: a MOBI 6 section, a boundary record and a
+/// KF8 section, with `exth` in both record 0s.
+pub(super) fn joint(title: &str, text: &str, exth: &Exth) -> Vec<Vec<u8>> {
+    let kf8 = azw3(title, text, exth);
+    let mut exth = exth.clone();
+    // The KF8 record 0 follows the MOBI section's text and the boundary.
+    let text_records = text.len().div_ceil(4096);
+    exth.set_u32(exth::KF8_BOUNDARY, text_records as u32 + 2);
+    let mut records = mobi6(title, text, &exth, &[]);
+    records.pop();
+    records.push(b"BOUNDARY".to_vec());
+    records.extend(kf8);
+    records
+}
+
+/// The bytes of a Palm database holding `records`.
+pub(super) fn database(records: &[Vec<u8>]) -> Vec<u8> {
+    pdb::write_database("Book", records).unwrap()
+}
+
+/// Small MOBI 6, AZW3 and joint files, for format detection.
+pub(crate) fn mobi6_file() -> Vec<u8> {
+    database(&mobi6("Book", "Text", &Exth::default(), &[]))
+}
+
+pub(crate) fn azw3_file() -> Vec<u8> {
+    database(&azw3("Book", "Text", &Exth::default()))
+}
+
+pub(crate) fn joint_file() -> Vec<u8> {
+    database(&joint("Book", "Text", &Exth::default()))
+}
+
+/// Read back every record of a book.
+pub(super) fn read(path: &Path) -> Vec<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path).unwrap());
+    let database = PalmDatabase::read(&mut reader).unwrap();
+    (0..database.records.len())
+        .map(|index| database.read_record(&mut reader, index).unwrap())
+        .collect()
+}
+
+/// Write a book's records to a file in the temporary directory.
+pub(super) fn write(name: &str, records: &[Vec<u8>]) -> PathBuf {
+    let path = temp_path(name);
+    std::fs::write(&path, database(records)).unwrap();
+    path
+}
+
+/// A path in the temporary directory that no other test uses.
+pub(super) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ebook-tools-mobi-{}-{name}", std::process::id()))
+}
+
+/// The smallest valid JPEG header, enough to be recognized as an image.
+pub(super) const JPEG: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF\0\x01\x01\0\0\x01\0\x01\0\0\xff\xd9";
@@ -1,6 +1,6 @@
 use std::path::Path;
 
-use crate::{DrmStatus, Metadata};
+use crate::{DrmStatus, Metadata, MetadataPatch};
 
 /// Open a book from a file path.
 pub trait BookReader {
@@ -17,6 +17,16 @@ pub trait MetadataProvider {
 /// Write metadata to an ebook.
 pub trait MetadataWriter {
     fn set_metadata(&mut self, metadata: &Metadata) -> crate::Result<()>;
+
+    /// Apply field-level changes on top of the book's current metadata.
+    fn apply_patch(&mut self, patch: &MetadataPatch) -> crate::Result<()>
+    where
+        Self: MetadataProvider,
+    {
+        let mut metadata = self.metadata()?;
+        patch.apply(&mut metadata);
+        self.set_metadata(&metadata)
+    }
 }
 
 /// Detect DRM status of an ebook.